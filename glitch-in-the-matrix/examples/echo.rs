@@ -27,7 +27,7 @@ fn main() {
     let hdl = core.handle();
     let mut mx = core.run(MatrixClient::login(username, password, server, &hdl)).unwrap();
     println!("[+] Connected to {} as {}", server, username);
-    let ss = mx.get_sync_stream();
+    let ss = mx.sync_stream();
     // We discard the results of the initial `/sync`, because we only want to echo
     // new requests.
     let fut = ss.skip(1).for_each(|sync| {
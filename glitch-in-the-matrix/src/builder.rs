@@ -0,0 +1,170 @@
+//! A builder for `MatrixClient`s, for setting up their connection to the
+//! homeserver.
+
+use super::{MatrixClient, MatrixFuture};
+use http::{self, HttpsConnector, MatrixHyper};
+use proxy::{Proxy, ProxyConnector};
+use hyper::{self, Uri};
+use tokio_core::reactor::Handle;
+use futures;
+use errors::*;
+use std::time::Duration;
+
+/// Sets up a `MatrixClient`, with control over how it connects to the
+/// homeserver.
+///
+/// Obtain one of these with `MatrixClient::builder()`, or
+/// `MatrixClientBuilder::new()`. The defaults are the same as those of
+/// `MatrixClient`'s other constructors, which use this.
+pub struct MatrixClientBuilder {
+    url: String,
+    hdl: Handle,
+    access_token: Option<(String, String)>,
+    timeout: Option<Duration>,
+    keep_alive: bool,
+    keep_alive_timeout: Option<Duration>,
+    user_agent: Option<String>,
+    compression: bool,
+    proxy: Option<Proxy>,
+    proxy_from_env: bool,
+    allow_insecure_http: bool
+}
+impl MatrixClientBuilder {
+    /// Start building a client for the homeserver at `url`, like
+    /// `https://matrix.example.org`.
+    pub fn new(url: &str, hdl: &Handle) -> Self {
+        MatrixClientBuilder {
+            url: url.to_string(),
+            hdl: hdl.clone(),
+            access_token: None,
+            timeout: None,
+            keep_alive: true,
+            keep_alive_timeout: Some(Duration::from_secs(90)),
+            user_agent: None,
+            compression: true,
+            proxy: None,
+            proxy_from_env: true,
+            allow_insecure_http: false
+        }
+    }
+    /// Use an existing access token, belonging to the MXID `user_id`,
+    /// instead of leaving the client logged out.
+    pub fn access_token(mut self, token: &str, user_id: &str) -> Self {
+        self.access_token = Some((token.to_string(), user_id.to_string()));
+        self
+    }
+    /// Set the default timeout for requests (see
+    /// `MatrixClient::set_request_timeout()`).
+    ///
+    /// The default is no timeout.
+    pub fn request_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    /// Set whether connections to the homeserver are kept open and reused
+    /// between requests.
+    ///
+    /// The default is `true`.
+    pub fn keep_alive(mut self, v: bool) -> Self {
+        self.keep_alive = v;
+        self
+    }
+    /// Set how long an idle connection is kept open for, or `None` to keep
+    /// them open until the homeserver (or a proxy) closes them.
+    ///
+    /// The default is 90 seconds. Note that `hyper` doesn't limit how many
+    /// idle connections it keeps; there's one for each request that was in
+    /// flight at the same time.
+    pub fn keep_alive_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.keep_alive_timeout = timeout;
+        self
+    }
+    /// Send `user_agent` as the `User-Agent` of every request (see
+    /// `MatrixClient::set_user_agent()`).
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = Some(user_agent.to_string());
+        self
+    }
+    /// Set whether to ask the homeserver to compress its responses (see
+    /// `MatrixClient::set_compression()`).
+    ///
+    /// The default is `true`.
+    pub fn compression(mut self, v: bool) -> Self {
+        self.compression = v;
+        self
+    }
+    /// Send requests through `proxy`, or connect directly if it's `None`.
+    ///
+    /// The default is the proxy in the environment (see
+    /// `Proxy::from_env()`), if there is one; `build()` fails if it's set to
+    /// something that isn't a valid proxy URL.
+    pub fn proxy(mut self, proxy: Option<Proxy>) -> Self {
+        self.proxy = proxy;
+        self.proxy_from_env = false;
+        self
+    }
+    /// Set whether requests can be sent over plain HTTP (see
+    /// `MatrixClient::set_allow_insecure_http()`).
+    pub fn allow_insecure_http(mut self, v: bool) -> Self {
+        self.allow_insecure_http = v;
+        self
+    }
+    /// Check that the homeserver URL makes sense, and strip any trailing
+    /// slashes from it (which would otherwise end up in the middle of every
+    /// request's path).
+    fn validate_url(&self) -> MatrixResult<String> {
+        let url = self.url.trim_end_matches('/');
+        let uri: Uri = match url.parse() {
+            Ok(u) => u,
+            Err(_) => bail!(MatrixErrorKind::InvalidHomeserverUrl(self.url.clone()))
+        };
+        match uri.scheme() {
+            Some("http") | Some("https") if uri.host().is_some() => {},
+            _ => bail!(MatrixErrorKind::InvalidHomeserverUrl(self.url.clone()))
+        }
+        if uri.query().is_some() {
+            bail!(MatrixErrorKind::InvalidHomeserverUrl(self.url.clone()));
+        }
+        Ok(url.to_string())
+    }
+    fn make_hyper(&self) -> MatrixResult<MatrixHyper> {
+        let ssl = http::SslConnectorBuilder::new(http::SslMethod::tls())
+            .chain_err(|| MatrixErrorKind::TlsUnavailable)?;
+        let conn = HttpsConnector::with_connector(ProxyConnector::new(self.proxy.clone(), &self.hdl), ssl.build());
+        Ok(hyper::Client::configure()
+           .connector(conn)
+           .keep_alive(self.keep_alive)
+           .keep_alive_timeout(self.keep_alive_timeout)
+           .build(&self.hdl))
+    }
+    /// Make the client.
+    ///
+    /// This fails with an `InvalidHomeserverUrl` error if the homeserver URL
+    /// isn't an `http://` or `https://` URL.
+    pub fn build(mut self) -> MatrixResult<MatrixClient> {
+        let url = self.validate_url()?;
+        if self.proxy_from_env {
+            self.proxy = Proxy::from_env()?;
+        }
+        let hyper = self.make_hyper()?;
+        let (token, user_id) = match self.access_token {
+            Some((t, u)) => (Some(t), u),
+            None => (None, String::new())
+        };
+        let mut ret = MatrixClient::from_parts(hyper, token, user_id, url, &self.hdl);
+        ret.timeout = self.timeout;
+        ret.user_agent = self.user_agent;
+        ret.compression = self.compression;
+        ret.proxy = self.proxy;
+        ret.allow_insecure_http = self.allow_insecure_http;
+        Ok(ret)
+    }
+    /// Make the client, and log in with a username (or MXID) and password
+    /// (see `MatrixClient::login_with_password()`).
+    pub fn login(self, username: &str, password: &str) -> MatrixFuture<MatrixClient> {
+        match self.build() {
+            Ok(c) => c.login_with_password(username, password, None, None),
+            Err(e) => Box::new(futures::future::err(e))
+        }
+    }
+}
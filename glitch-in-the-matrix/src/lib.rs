@@ -17,7 +17,8 @@ extern crate hyper_openssl;
 #[macro_use] extern crate error_chain;
 extern crate tokio_core;
 #[macro_use] extern crate futures;
-extern crate percent_encoding;
+#[macro_use] extern crate percent_encoding;
+extern crate flate2;
 pub extern crate gm_types as types;
 
 pub mod errors {
@@ -37,11 +38,116 @@ pub mod errors {
             Openssl(::hyper_openssl::openssl::error::ErrorStack);
         }
         errors {
+            Timeout {
+                display("Request timed out")
+            }
             HttpCode(c: ::hyper::StatusCode) {
                 display("HTTP error: {}", c.canonical_reason().unwrap_or("unknown"))
             }
-            BadRequest(e: super::types::replies::BadRequestReply) {
-                display("Bad request: {:?}", e)
+            BadRequest(e: super::types::replies::BadRequestReply, c: ::hyper::StatusCode) {
+                display("Bad request ({}): {}: {}", c, e.errcode, e.error)
+            }
+            SoftLogout(e: super::types::replies::BadRequestReply, c: ::hyper::StatusCode) {
+                display("Soft logout ({}): {}: {}", c, e.errcode, e.error)
+            }
+            AuthRequired(info: Box<super::types::replies::UiaaInfo>) {
+                display("User-Interactive Authentication required")
+            }
+            LoggedOut {
+                display("This client has been logged out")
+            }
+            Aborted {
+                display("The request was aborted")
+            }
+            NoRefreshToken {
+                display("This client has no refresh token")
+            }
+            TooManyRedirects {
+                display("Too many redirects")
+            }
+            UploadTooLarge {
+                display("The upload was too large for the homeserver")
+            }
+            UploadOverLimit(size: u64, limit: u64) {
+                display("The upload ({} bytes) is bigger than the homeserver's limit of {} bytes", size, limit)
+            }
+            ResponseTooLarge(limit: u64) {
+                display("The homeserver's response decompressed to more than {} bytes", limit)
+            }
+            InvalidContentType(ct: String) {
+                display("Invalid content type: {}", ct)
+            }
+            PresenceDisabled {
+                display("Presence is disabled on the homeserver")
+            }
+            InsecureHttp(url: String) {
+                display("Refusing to talk to {} over plain HTTP; use HTTPS, or allow it with set_allow_insecure_http()", url)
+            }
+            InvalidHomeserverUrl(url: String) {
+                display("Invalid homeserver URL {}; it should look like https://matrix.example.org", url)
+            }
+            TlsUnavailable {
+                display("TLS support (OpenSSL) couldn't be set up, so HTTPS can't be used")
+            }
+            AliasInUse {
+                display("The room alias is already in use")
+            }
+            NoEventId {
+                display("The event has no event ID to refer to")
+            }
+            UserInUse(e: super::types::replies::BadRequestReply, c: ::hyper::StatusCode) {
+                display("The username is already taken")
+            }
+            InvalidUsername(e: super::types::replies::BadRequestReply, c: ::hyper::StatusCode) {
+                display("The username is invalid")
+            }
+            UrlPreviewsDisabled {
+                display("URL previews are disabled on the homeserver")
+            }
+            DiscoveryFailPrompt(why: String) {
+                display("Homeserver discovery failed: {}", why)
+            }
+            DiscoveryFailError(why: String) {
+                display("Homeserver discovery found an invalid homeserver: {}", why)
+            }
+        }
+    }
+    impl MatrixError {
+        /// Get the Matrix error code (like `M_FORBIDDEN`) that the homeserver
+        /// sent back, if this is a `BadRequest` error.
+        pub fn errcode(&self) -> Option<&str> {
+            self.api_error().map(|e| &e.errcode as &str)
+        }
+        /// Get the error that the homeserver sent back (with its error code,
+        /// message and rate-limiting advice), if this is a `BadRequest`,
+        /// `SoftLogout`, `UserInUse` or `InvalidUsername` error.
+        ///
+        /// Error responses whose body isn't a Matrix error (like an HTML
+        /// error page from a proxy) are `HttpCode` errors instead, so this is
+        /// `None` for them.
+        pub fn api_error(&self) -> Option<&super::types::replies::BadRequestReply> {
+            match *self.kind() {
+                MatrixErrorKind::BadRequest(ref e, _) |
+                MatrixErrorKind::SoftLogout(ref e, _) |
+                MatrixErrorKind::UserInUse(ref e, _) |
+                MatrixErrorKind::InvalidUsername(ref e, _) => Some(e),
+                _ => None
+            }
+        }
+        /// Ascertain whether this is a `Timeout` error, meaning the homeserver
+        /// didn't respond in time; the request can probably be retried.
+        pub fn is_timeout(&self) -> bool {
+            matches!(*self.kind(), MatrixErrorKind::Timeout)
+        }
+        /// Get the HTTP status code of the response, if this is a
+        /// `BadRequest`, `SoftLogout`, `UserInUse`, `InvalidUsername` or
+        /// `HttpCode` error.
+        pub fn status_code(&self) -> Option<::hyper::StatusCode> {
+            match *self.kind() {
+                MatrixErrorKind::BadRequest(_, sc) | MatrixErrorKind::SoftLogout(_, sc) |
+                MatrixErrorKind::UserInUse(_, sc) | MatrixErrorKind::InvalidUsername(_, sc) |
+                MatrixErrorKind::HttpCode(sc) => Some(sc),
+                _ => None
             }
         }
     }
@@ -50,170 +156,2671 @@ pub mod http {
     //! Types reexported from `hyper`.
     pub use hyper::Method;
     pub use hyper::Body;
-    pub use hyper::header::ContentType;
+    pub use hyper::header::{ContentType, Headers};
     pub use hyper::StatusCode;
     pub use hyper::Client;
     pub use hyper_openssl::HttpsConnector;
     pub use hyper::client::HttpConnector;
-    pub type MatrixHyper = Client<HttpsConnector<HttpConnector>>;
+    pub use hyper_openssl::openssl::ssl::{SslConnectorBuilder, SslMethod};
+    pub use proxy::ProxyConnector;
+    pub type MatrixHyper = Client<HttpsConnector<ProxyConnector>>;
 }
 pub mod room;
 pub mod request;
+pub mod media;
 pub mod sync;
+pub mod search;
+pub mod push;
+pub mod proxy;
+pub mod builder;
 mod util;
 
+use hyper_openssl::openssl::rand::rand_bytes;
+use hyper_openssl::openssl::x509::X509;
 use util::*;
 use errors::*;
 use types::replies::*;
-use types::content::root::types::Presence;
+use types::sync::SyncReply;
+use types::media::{ContentUri, MediaConfig, UrlPreview};
+use media::{MediaDownload, MediaStream, ThumbnailMethod};
+use types::content::root::types::{Presence, RoomTag};
+use types::content::root::{Direct, IgnoredUserList, Reaction, Tag};
+use types::messages::{escape_html, FileInfo, ImageInfo, Message, RelatesTo, ReplyTarget};
+use types::events::Event;
+use types::filter::Filter;
+use types::account::{RegisterParams, RegistrationKind, RequestTokenReply, ThreePid, ThreePidSession, ThreePidsReply};
+use types::push::{NotificationsReply, PushAction, PushRule, PushRuleKind, PushRulesReply, Pusher, PushersReply, Ruleset};
+use push::NotificationsStream;
+use proxy::{Proxy, ProxyConnector};
+use builder::MatrixClientBuilder;
+use types::content::room::types::Membership;
 use hyper::{Method, Body};
 use Method::*;
 use hyper::client::Request;
 use hyper_openssl::HttpsConnector;
-use hyper::header::ContentType;
+use hyper::header::{ContentLength, ContentType};
+use serde::Serialize;
 use serde::de::DeserializeOwned;
 use tokio_core::reactor::Handle;
 use futures::*;
+use futures::future::Either;
+use futures::task::{self, Task};
 use request::MatrixRequest;
-use sync::SyncStream;
+use sync::{SyncRequest, SyncStream};
+use search::{RoomEventsCriteria, SearchReply, SearchStream};
+use room::{CreateRoomParams, Direction, MessagesStream, PublicRoomsParams, PublicRoomsStream, Room, TypingGuard};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// A `Future` with a `MatrixError` error type. Returned by most library
 /// functions.
 ///
 /// Yes, I know this is a `Box`, and that sucks a whoole bunch. I'm waiting
 /// for `impl Trait` to arrive to save us from this madness.
+///
+/// Dropping one of these before it resolves cancels the request: its
+/// connection to the homeserver is closed (rather than kept around for
+/// another request, since the response would still be on its way), and it
+/// isn't retried. Use `abortable()` to cancel one from elsewhere.
 pub type MatrixFuture<T> = Box<Future<Item=T, Error=MatrixError>>;
 
+/// A handle for cancelling a future made with `abortable()`.
+#[derive(Clone)]
+pub struct AbortHandle {
+    aborted: Rc<Cell<bool>>,
+    task: Rc<RefCell<Option<Task>>>
+}
+impl AbortHandle {
+    /// Cancel the future, which then fails with an `Aborted` error.
+    ///
+    /// The task polling the future is woken up, so that the request is
+    /// dropped (closing its connection) straight away.
+    pub fn abort(&self) {
+        self.aborted.set(true);
+        if let Some(t) = self.task.borrow_mut().take() {
+            t.notify();
+        }
+    }
+    /// Ascertain whether `abort()` has been called.
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.get()
+    }
+}
+struct Abortable<T> {
+    inner: Option<MatrixFuture<T>>,
+    handle: AbortHandle
+}
+impl<T> Future for Abortable<T> {
+    type Item = T;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<T, MatrixError> {
+        if self.handle.is_aborted() {
+            self.inner = None;
+            bail!(MatrixErrorKind::Aborted);
+        }
+        *self.handle.task.borrow_mut() = Some(task::current());
+        match self.inner {
+            Some(ref mut f) => f.poll(),
+            None => bail!(MatrixErrorKind::Aborted)
+        }
+    }
+}
+/// Wrap `fut` so that it can be cancelled with the `AbortHandle` returned
+/// alongside it.
+///
+/// This is for when the code that might want to cancel a request isn't the
+/// code that's waiting on it (otherwise, just drop the future).
+pub fn abortable<T: 'static>(fut: MatrixFuture<T>) -> (MatrixFuture<T>, AbortHandle) {
+    let handle = AbortHandle {
+        aborted: Rc::new(Cell::new(false)),
+        task: Rc::new(RefCell::new(None))
+    };
+    let fut = Abortable {
+        inner: Some(fut),
+        handle: handle.clone()
+    };
+    (Box::new(fut), handle)
+}
+
+/// What the function given to `MatrixClient::set_metrics_hook()` is told
+/// about each request, once it's finished.
+#[derive(Debug)]
+pub struct RequestMetrics<'a> {
+    /// The request method.
+    pub method: &'a Method,
+    /// The path of the endpoint, with IDs and the like replaced by
+    /// placeholders (see `MatrixRequest::endpoint_template()`), like
+    /// `/_matrix/client/v3/rooms/{roomId}/send/m.room.message/{txnId}`.
+    pub endpoint: &'a str,
+    /// The HTTP status code of the last response the homeserver sent (if the
+    /// request was retried, the one to the last try), or `None` if there
+    /// wasn't one, like when the request timed out.
+    pub status: Option<http::StatusCode>,
+    /// How long the request took, including any retries.
+    pub elapsed: Duration,
+    /// The error the request failed with, if any.
+    pub error: Option<&'a MatrixError>
+}
+impl<'a> RequestMetrics<'a> {
+    /// Ascertain whether the request succeeded.
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+/// Configuration for retrying requests that the homeserver rate-limited
+/// (with an `M_LIMIT_EXCEEDED` error, or an HTTP 429).
+///
+/// Retrying is disabled by default; set `max_retries` to enable it.
+#[derive(Clone, Debug)]
+pub struct RateLimitConfig {
+    /// The maximum number of times to retry a request. The default value is
+    /// `0`, meaning rate-limited requests fail straight away.
+    pub max_retries: u32,
+    /// Whether to wait for as long as the homeserver asks us to (via the
+    /// `retry_after_ms` field or the `Retry-After` header), instead of `delay`.
+    ///
+    /// The default value is `true`.
+    pub honor_retry_after: bool,
+    /// How long to wait before retrying, if the homeserver doesn't say (or
+    /// `honor_retry_after` is `false`).
+    ///
+    /// The default value is 1 second.
+    pub delay: Duration,
+    /// The maximum total time to spend waiting to retry a single request. If
+    /// waiting any longer would exceed this limit, the rate-limiting error is
+    /// returned instead.
+    ///
+    /// The default value is 30 seconds.
+    pub max_total_wait: Duration
+}
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            max_retries: 0,
+            honor_retry_after: true,
+            delay: Duration::from_secs(1),
+            max_total_wait: Duration::from_secs(30)
+        }
+    }
+}
 /// A connection to a Matrix homeserver.
 pub struct MatrixClient {
     hyper: http::MatrixHyper,
-    access_token: String,
+    access_token: SharedToken,
+    refresh_token: Rc<RefCell<Option<String>>>,
+    token_expiry: Rc<Cell<Option<Instant>>>,
+    reauth_handler: Option<Rc<dyn Fn() -> MatrixFuture<String>>>,
+    metrics_hook: Option<MetricsHook>,
+    logged_out: Rc<Cell<bool>>,
+    guest: bool,
     hdl: Handle,
     user_id: String,
+    device_id: Option<String>,
     url: String,
-    txnid: u32
+    txnid: u32,
+    txn_prefix: String,
+    token_in_query: bool,
+    api_prefix: Cow<'static, str>,
+    rate_limit: RateLimitConfig,
+    timeout: Option<Duration>,
+    allow_remote_media: bool,
+    allow_insecure_http: bool,
+    proxy: Option<Proxy>,
+    user_agent: Option<String>,
+    compression: bool,
+    identity_server: Option<String>,
+    server_versions: Rc<RefCell<Option<VersionsReply>>>,
+    upload_size_limit: Rc<Cell<Option<u64>>>
 }
 impl MatrixClient {
-    /// Log in to a Matrix homeserver, and return a client object.
-    pub fn login(username: &str, password: &str, url: &str, hdl: &Handle) -> MatrixFuture<Self> {
-        let conn = match HttpsConnector::new(4, hdl) {
+    fn make_hyper_client(hdl: &Handle) -> MatrixResult<http::MatrixHyper> {
+        let ssl = http::SslConnectorBuilder::new(http::SslMethod::tls())
+            .chain_err(|| MatrixErrorKind::TlsUnavailable)?;
+        Self::hyper_with_tls_proxy(ssl, Proxy::from_env()?, hdl)
+    }
+    /// Make a `hyper::Client` that uses `ssl` for its TLS connections, to
+    /// give to `new_with_hyper()`.
+    ///
+    /// This is for setting up TLS differently from the default (like
+    /// requiring a newer protocol version, or using a client certificate).
+    /// Start with `SslConnectorBuilder::new(SslMethod::tls())`, which trusts
+    /// the system's CA certificates.
+    ///
+    /// Like the client objects' own, this uses the proxy from the
+    /// environment (see `Proxy::from_env()`), if there is one.
+    pub fn hyper_with_tls(ssl: http::SslConnectorBuilder, hdl: &Handle) -> MatrixResult<http::MatrixHyper> {
+        Self::hyper_with_tls_proxy(ssl, Proxy::from_env()?, hdl)
+    }
+    /// Like `hyper_with_tls()`, but connecting through `proxy`, or directly
+    /// if it's `None`.
+    pub fn hyper_with_tls_proxy(ssl: http::SslConnectorBuilder, proxy: Option<Proxy>, hdl: &Handle) -> MatrixResult<http::MatrixHyper> {
+        let conn = HttpsConnector::with_connector(ProxyConnector::new(proxy, hdl), ssl.build());
+        Ok(hyper::Client::configure()
+           .connector(conn)
+           .build(hdl))
+    }
+    /// Make a `hyper::Client` that trusts the CA certificate in `pem` (as
+    /// well as the system's), to give to `new_with_hyper()`.
+    ///
+    /// This is for self-hosted homeservers whose certificates are signed by
+    /// a private CA.
+    pub fn hyper_with_ca_cert(pem: &[u8], hdl: &Handle) -> MatrixResult<http::MatrixHyper> {
+        let cert = X509::from_pem(pem)?;
+        let mut ssl = http::SslConnectorBuilder::new(http::SslMethod::tls())
+            .chain_err(|| MatrixErrorKind::TlsUnavailable)?;
+        ssl.cert_store_mut().add_cert(cert)?;
+        Self::hyper_with_tls(ssl, hdl)
+    }
+    /// Makes a random prefix for transaction IDs, so they won't clash with
+    /// those of any other client with the same access token.
+    fn make_txn_prefix() -> String {
+        let mut buf = [0; 12];
+        if rand_bytes(&mut buf).is_err() {
+            // OpenSSL only fails if it can't seed its generator; the time is
+            // the best we can do then.
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+            return format!("gm{}{:09}", now.as_secs(), now.subsec_nanos());
+        }
+        let hex: String = buf.iter().map(|b| format!("{:02x}", b)).collect();
+        format!("gm{}", hex)
+    }
+    fn from_parts(hyper: http::MatrixHyper, token: Option<String>, user_id: String, url: String, hdl: &Handle) -> Self {
+        MatrixClient {
+            hyper,
+            access_token: Rc::new(RefCell::new(token)),
+            refresh_token: Rc::new(RefCell::new(None)),
+            token_expiry: Rc::new(Cell::new(None)),
+            reauth_handler: None,
+            metrics_hook: None,
+            logged_out: Rc::new(Cell::new(false)),
+            guest: false,
+            user_id,
+            device_id: None,
+            url,
+            hdl: hdl.clone(),
+            txnid: 0,
+            txn_prefix: Self::make_txn_prefix(),
+            token_in_query: false,
+            api_prefix: "/_matrix/client/v3".into(),
+            rate_limit: Default::default(),
+            timeout: None,
+            allow_remote_media: true,
+            allow_insecure_http: false,
+            proxy: None,
+            user_agent: None,
+            compression: true,
+            identity_server: None,
+            server_versions: Rc::new(RefCell::new(None)),
+            upload_size_limit: Rc::new(Cell::new(None))
+        }
+    }
+    /// Make a client object from an existing access token, without logging
+    /// in.
+    ///
+    /// `user_id` should be the MXID that the access token belongs to.
+    pub fn new_from_access_token(token: &str, user_id: &str, url: &str, hdl: &Handle) -> MatrixResult<Self> {
+        MatrixClientBuilder::new(url, hdl)
+            .access_token(token, user_id)
+            .build()
+    }
+    /// Start setting up a client object for the homeserver at `url`, with
+    /// control over how it connects (like timeouts, keep-alive, the
+    /// `User-Agent`, and proxies).
+    pub fn builder(url: &str, hdl: &Handle) -> MatrixClientBuilder {
+        MatrixClientBuilder::new(url, hdl)
+    }
+    /// Make a client object that sends its requests with `hyper`, instead of
+    /// making its own `hyper::Client`, like `new_from_access_token()` (or
+    /// `new_unauthenticated()`, if `token` is `None`).
+    ///
+    /// Every client object keeps its `hyper::Client` for as long as it
+    /// lives, so connections to the homeserver are kept alive and reused
+    /// between requests (including by the `SyncStream`s and typing
+    /// notifications it makes). This lets several client objects share one
+    /// pool of connections, or use custom TLS settings.
+    ///
+    /// `hyper` has to connect through a `ProxyConnector`, so make it with
+    /// `hyper_with_tls()` or `hyper_with_tls_proxy()` (which take an
+    /// `SslConnectorBuilder`) rather than `HttpsConnector::new()`.
+    pub fn new_with_hyper(hyper: http::MatrixHyper, token: Option<&str>, user_id: &str, url: &str, hdl: &Handle) -> Self {
+        Self::from_parts(hyper, token.map(|t| t.to_string()), user_id.to_string(), url.to_string(), hdl)
+    }
+    /// Make a client object that isn't logged in, for calling endpoints that
+    /// don't need an access token.
+    ///
+    /// The client's `user_id()` is empty until it's logged in.
+    pub fn new_unauthenticated(url: &str, hdl: &Handle) -> MatrixResult<Self> {
+        MatrixClientBuilder::new(url, hdl).build()
+    }
+    /// Find the homeserver for `domain` (like `example.org`) using the
+    /// `/.well-known/matrix/client` file, and return an unauthenticated
+    /// client object for it.
+    ///
+    /// `domain` can also be a URL with a scheme (like `http://localhost:8008`),
+    /// in which case the well-known file is fetched from there. If the file
+    /// doesn't exist (404), the domain itself is used as the homeserver.
+    /// The client's `url()` is the homeserver's base URL, without a trailing
+    /// slash, and its `identity_server()` is the identity server that the
+    /// well-known file advertises, if any.
+    ///
+    /// The homeserver found is checked by calling `/_matrix/client/versions`
+    /// on it. Following the spec, this fails with:
+    ///
+    /// - `DiscoveryFailPrompt` if the well-known file couldn't be fetched or
+    ///   doesn't make sense; clients should ask the user for a homeserver URL.
+    /// - `DiscoveryFailError` if the homeserver it points to is invalid or
+    ///   unreachable; clients should tell the user and stop there.
+    pub fn discover(domain: &str, hdl: &Handle) -> MatrixFuture<Self> {
+        let client = match Self::make_hyper_client(hdl) {
             Ok(c) => c,
-            Err(e) => return Box::new(futures::future::err(e.into()))
+            Err(e) => return Box::new(futures::future::err(e))
         };
-        let client = hyper::Client::configure()
-            .connector(conn)
-            .build(hdl);
-        let uri: hyper::Uri = match format!("{}/_matrix/client/r0/login", url).parse() {
+        let server = if domain.contains("://") {
+            domain.trim_end_matches('/').to_string()
+        }
+        else {
+            format!("https://{}", domain)
+        };
+        let uri: hyper::Uri = match format!("{}/.well-known/matrix/client", server).parse() {
             Ok(u) => u,
-            Err(e) => return Box::new(futures::future::err(e.into()))
+            Err(e) => {
+                let why = format!("invalid domain {}: {}", domain, e);
+                return Box::new(futures::future::err(MatrixErrorKind::DiscoveryFailPrompt(why).into()));
+            }
         };
-        let mut req = Request::new(Post, uri);
-        req.set_body(json!({
-            "type": "m.login.password",
-            "user": username,
-            "password": password
-        }).to_string());
-        let resp = client.request(req).map_err(|e| e.into()).and_then(ResponseWrapper::<LoginReply>::wrap);
         let hdl = hdl.clone();
-        let url = url.to_string();
-        Box::new(resp.map(move |rpl| {
-            MatrixClient {
-                hyper: client,
-                access_token: rpl.access_token,
-                user_id: rpl.user_id,
-                url: url,
-                hdl: hdl,
-                txnid: 0
+        let cli = client.clone();
+        let fut = client.get(uri)
+            .map_err(|e| e.into())
+            .and_then(ResponseWrapper::<WellKnownReply>::wrap)
+            .then(move |res| {
+                match res {
+                    Ok(rpl) => {
+                        let is = rpl.identity_server.map(|s| s.base_url.trim_end_matches('/').to_string());
+                        Ok((rpl.homeserver.base_url.trim_end_matches('/').to_string(), is))
+                    },
+                    Err(e) => match *e.kind() {
+                        MatrixErrorKind::HttpCode(hyper::StatusCode::NotFound) |
+                        MatrixErrorKind::BadRequest(_, hyper::StatusCode::NotFound) => Ok((server, None)),
+                        _ => {
+                            let why = format!("couldn't get the well-known file: {}", e);
+                            Err(e).chain_err(|| MatrixErrorKind::DiscoveryFailPrompt(why))
+                        }
+                    }
+                }
+            })
+            .and_then(move |(url, identity_server)| {
+                let uri: hyper::Uri = match format!("{}/_matrix/client/versions", url).parse() {
+                    Ok(u) => u,
+                    Err(e) => {
+                        let why = format!("invalid base URL {}: {}", url, e);
+                        return Either::A(futures::future::err(MatrixErrorKind::DiscoveryFailError(why).into()));
+                    }
+                };
+                Either::B(cli.get(uri)
+                          .map_err(|e| e.into())
+                          .and_then(ResponseWrapper::<VersionsReply>::wrap)
+                          .then(move |res| {
+                              let versions = match res {
+                                  Ok(v) => v,
+                                  Err(e) => {
+                                      let why = format!("{} isn't a Matrix homeserver", url);
+                                      return Err(e).chain_err(|| MatrixErrorKind::DiscoveryFailError(why));
+                                  }
+                              };
+                              let mut ret = Self::from_parts(cli, None, String::new(), url, &hdl);
+                              ret.identity_server = identity_server;
+                              *ret.server_versions.borrow_mut() = Some(versions);
+                              Ok(ret)
+                          }))
+            });
+        Box::new(fut)
+    }
+    /// Find the homeserver for the MXID `user_id` (like
+    /// `@alice:example.org`) with `discover()`, and log in to it with
+    /// `password`.
+    ///
+    /// This fails with a `DiscoveryFailPrompt` error if `user_id` isn't a
+    /// valid MXID.
+    pub fn login_with_discovery(user_id: &str, password: &str, hdl: &Handle) -> MatrixFuture<Self> {
+        let domain = match user_id.find(':') {
+            Some(i) if user_id.starts_with('@') && i > 1 && i + 1 < user_id.len() => &user_id[i+1..],
+            _ => {
+                let why = format!("{} isn't a valid MXID", user_id);
+                return Box::new(futures::future::err(MatrixErrorKind::DiscoveryFailPrompt(why).into()));
             }
+        };
+        let user_id = user_id.to_string();
+        let password = password.to_string();
+        Box::new(Self::discover(domain, hdl)
+                 .and_then(move |cli| cli.login_with_password(&user_id, &password, None, None)))
+    }
+    /// Log in to a Matrix homeserver, and return a client object.
+    ///
+    /// This is `login_password()`, with the arguments in a different order.
+    pub fn login(username: &str, password: &str, url: &str, hdl: &Handle) -> MatrixFuture<Self> {
+        Self::login_password(url, username, password, hdl)
+    }
+    /// Log in to the Matrix homeserver at `url` with a username (or MXID) and
+    /// password, and return a client object.
+    ///
+    /// The access token, MXID and device ID that the homeserver gives back
+    /// are available from the client's `access_token()`, `user_id()` and
+    /// `device_id()` methods, for saving and using with
+    /// `new_from_access_token()` later.
+    ///
+    /// If the credentials are wrong, this fails with a `BadRequest` error
+    /// with the `M_FORBIDDEN` error code.
+    pub fn login_password(url: &str, username: &str, password: &str, hdl: &Handle) -> MatrixFuture<Self> {
+        Self::login_password_device(url, username, password, None, None, hdl)
+    }
+    /// Like `login_password()`, but also specifying the ID of the device to
+    /// log in (to reuse an existing one), and a display name for it if it's
+    /// new.
+    ///
+    /// If `device_id` is `None`, the homeserver makes a new device.
+    pub fn login_password_device(url: &str, username: &str, password: &str, device_id: Option<&str>, initial_device_display_name: Option<&str>, hdl: &Handle) -> MatrixFuture<Self> {
+        Self::login_password_request(url, username, password, device_id, initial_device_display_name, false, hdl)
+    }
+    /// Like `login_password_device()`, but asking the homeserver for a
+    /// refresh token too.
+    ///
+    /// Homeservers that support them will then give out an access token that
+    /// expires; get a new one with `refresh()` before it does. Homeservers
+    /// that don't will give out an ordinary access token, and no refresh
+    /// token.
+    pub fn login_password_refreshable(url: &str, username: &str, password: &str, device_id: Option<&str>, hdl: &Handle) -> MatrixFuture<Self> {
+        Self::login_password_request(url, username, password, device_id, None, true, hdl)
+    }
+    /// Like `login_password_device()`, but logging in a client object made
+    /// with `new_unauthenticated()` or `new_with_hyper()`, so that settings
+    /// made on it beforehand (like `set_allow_insecure_http()`) apply to the
+    /// login request too.
+    pub fn login_with_password(self, username: &str, password: &str, device_id: Option<&str>, initial_device_display_name: Option<&str>) -> MatrixFuture<Self> {
+        self.login_password_with(username, password, device_id, initial_device_display_name, false)
+    }
+    fn login_password_request(url: &str, username: &str, password: &str, device_id: Option<&str>, initial_device_display_name: Option<&str>, refresh_token: bool, hdl: &Handle) -> MatrixFuture<Self> {
+        match Self::new_unauthenticated(url, hdl) {
+            Ok(c) => c.login_password_with(username, password, device_id, initial_device_display_name, refresh_token),
+            Err(e) => Box::new(futures::future::err(e))
+        }
+    }
+    fn login_password_with(mut self, username: &str, password: &str, device_id: Option<&str>, initial_device_display_name: Option<&str>, refresh_token: bool) -> MatrixFuture<Self> {
+        let mut body = json!({
+            "type": "m.login.password",
+            "identifier": {
+                "type": "m.id.user",
+                "user": username
+            },
+            "password": password
+        });
+        if let Some(d) = device_id {
+            body["device_id"] = json!(d);
+        }
+        if let Some(n) = initial_device_display_name {
+            body["initial_device_display_name"] = json!(n);
+        }
+        if refresh_token {
+            body["refresh_token"] = json!(true);
+        }
+        let fut = MatrixRequest::new_with_body_ser(Post, "/login", body).send(&mut self);
+        Box::new(fut.map(move |rpl: LoginReply| {
+            self.set_login_reply(rpl);
+            self
         }))
     }
-    /// Join a room by identifier or alias.
-    pub fn join(&mut self, roomid: &str) -> MatrixFuture<JoinReply> {
-        MatrixRequest::new_basic(Post, format!("/join/{}", roomid))
+    fn set_login_reply(&mut self, rpl: LoginReply) {
+        *self.access_token.borrow_mut() = Some(rpl.access_token);
+        *self.refresh_token.borrow_mut() = rpl.refresh_token;
+        self.token_expiry.set(expiry_after(rpl.expires_in_ms));
+        self.user_id = rpl.user_id;
+        self.device_id = rpl.device_id;
+    }
+    /// Register a new user account, with a given username and password.
+    ///
+    /// The homeserver will probably want some User-Interactive
+    /// Authentication first, in which case this resolves to a
+    /// `UiaaResponse::AuthRequired`; complete one of the flows in it with
+    /// `register_with_auth()`.
+    ///
+    /// Registration doesn't log this client in; if the reply has an access
+    /// token, use `new_from_access_token()` with it.
+    pub fn register(&mut self, username: &str, password: &str) -> MatrixFuture<UiaaResponse<RegisterReply>> {
+        self.register_request(username, password, None)
+    }
+    /// Carry on registering a new user account, with an `auth` object
+    /// completing a stage of User-Interactive Authentication (like
+    /// `{"type": "m.login.dummy"}`).
+    ///
+    /// `session` is the session ID from the `UiaaInfo` the homeserver sent
+    /// back; it's added to `auth` for you.
+    pub fn register_with_auth(&mut self, username: &str, password: &str, session: Option<&str>, mut auth: serde_json::Value) -> MatrixFuture<UiaaResponse<RegisterReply>> {
+        if let Some(s) = session {
+            auth["session"] = json!(s);
+        }
+        self.register_request(username, password, Some(auth))
+    }
+    fn register_request(&mut self, username: &str, password: &str, auth: Option<serde_json::Value>) -> MatrixFuture<UiaaResponse<RegisterReply>> {
+        let mut body = json!({
+            "username": username,
+            "password": password
+        });
+        if let Some(a) = auth {
+            body["auth"] = a;
+        }
+        Box::new(MatrixRequest::new_with_body_ser(Post, "/register", body)
+                 .param("kind", "user")
+                 .send_uiaa(self)
+                 .map_err(Self::registration_error))
+    }
+    /// Register a new account on the homeserver at `url`, and return a
+    /// client object logged in to it, along with the homeserver's reply.
+    ///
+    /// User-Interactive Authentication is done with `stage`, like with
+    /// `send_uiaa_request_with_stages()`; most homeservers (if they allow
+    /// registration at all) want `m.login.dummy` or
+    /// `m.login.registration_token`. If it can't be completed, this fails
+    /// with an `AuthRequired` error.
+    ///
+    /// If the username is taken or not allowed, this fails with a
+    /// `UserInUse` or `InvalidUsername` error respectively (which still have
+    /// the homeserver's `errcode()` and message). With
+    /// `inhibit_login` set, the client returned isn't logged in.
+    pub fn register_account<F>(url: &str, params: &RegisterParams, stage: F, hdl: &Handle) -> MatrixFuture<(Self, RegisterReply)>
+        where F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let mut cli = match Self::new_unauthenticated(url, hdl) {
+            Ok(c) => c,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let mut req = match MatrixRequest::new_with_body_ser(Post, "/register", params)
+            .param("kind", params.kind.as_str())
+            .make_hyper(&cli) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        if req.body_ref().is_none() {
+            // Guest registration has nothing to say, but still needs a body.
+            set_empty_json_body(&mut req);
+        }
+        let timeout = cli.timeout;
+        let guest = params.kind == RegistrationKind::Guest;
+        let fut = cli.send_uiaa_request_with_stages(req, timeout, stage);
+        Box::new(fut
+                 .map_err(Self::registration_error)
+                 .and_then(move |rpl| {
+                     let rpl: RegisterReply = match rpl {
+                         UiaaResponse::Success(r) => r,
+                         UiaaResponse::AuthRequired(info) => return Err(MatrixErrorKind::AuthRequired(Box::new(info)).into())
+                     };
+                     *cli.access_token.borrow_mut() = rpl.access_token.clone();
+                     cli.user_id = rpl.user_id.clone();
+                     cli.device_id = rpl.device_id.clone();
+                     cli.guest = guest;
+                     Ok((cli, rpl))
+                 }))
+    }
+    /// Register a guest account on the homeserver at `url`, and return a
+    /// client object logged in to it.
+    ///
+    /// Guests can read world-readable rooms with `get_messages()` without
+    /// joining them, and join rooms that allow guest access, but can't use
+    /// many other endpoints (those fail with an errcode of
+    /// `M_GUEST_ACCESS_FORBIDDEN`); check `is_guest()` before calling them.
+    /// If the homeserver doesn't allow guest registration, this fails with a
+    /// `BadRequest` error with an errcode of `M_FORBIDDEN`.
+    pub fn register_guest(url: &str, hdl: &Handle) -> MatrixFuture<Self> {
+        Box::new(Self::register_account(url, &RegisterParams::guest(), |_, _| None, hdl)
+                 .map(|(cli, _)| cli))
+    }
+    fn registration_error(e: MatrixError) -> MatrixError {
+        let kind = match *e.kind() {
+            MatrixErrorKind::BadRequest(ref brq, sc) => match &brq.errcode as &str {
+                "M_USER_IN_USE" => MatrixErrorKind::UserInUse(brq.clone(), sc),
+                "M_INVALID_USERNAME" => MatrixErrorKind::InvalidUsername(brq.clone(), sc),
+                _ => return e
+            },
+            _ => return e
+        };
+        MatrixError::with_chain(e, kind)
+    }
+    /// List the current user's devices.
+    pub fn get_devices(&mut self) -> MatrixFuture<Vec<Device>> {
+        Box::new(MatrixRequest::new_basic(Get, "/devices")
+                 .send(self)
+                 .map(|DevicesReply { devices }| devices))
+    }
+    /// Get one of the current user's devices.
+    pub fn get_device(&mut self, device_id: &str) -> MatrixFuture<Device> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["devices", device_id]))
             .send(self)
     }
-    /// Update our presence status.
-    pub fn update_presence(&mut self, p: Presence) -> MatrixFuture<()> {
+    /// Change the display name of one of the current user's devices.
+    pub fn rename_device(&mut self, device_id: &str, display_name: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body(Put, MatrixRequest::endpoint_with_segments(&["devices", device_id]),
+                                     vec![("display_name", display_name)])
+            .discarding_send(self)
+    }
+    /// Delete one of the current user's devices, logging it out.
+    ///
+    /// This needs User-Interactive Authentication, so the first attempt
+    /// (with an `auth` of `None`) usually resolves to a
+    /// `UiaaResponse::AuthRequired`. Complete a flow from it by calling this
+    /// again with an `auth` object, like `{"type": "m.login.password", ...}`;
+    /// unlike with `register_with_auth()`, this should already contain the
+    /// `session` from the `UiaaInfo`.
+    pub fn delete_device(&mut self, device_id: &str, auth: Option<serde_json::Value>) -> MatrixFuture<UiaaResponse<()>> {
+        let body = match auth {
+            Some(a) => json!({ "auth": a }),
+            None => json!({})
+        };
+        Box::new(MatrixRequest::new_with_body_ser(Delete, MatrixRequest::endpoint_with_segments(&["devices", device_id]), body)
+                 .send_uiaa::<serde_json::Value>(self)
+                 .map(|rpl| rpl.map(|_| ())))
+    }
+    /// Delete several of the current user's devices at once, logging them
+    /// out.
+    ///
+    /// Like `delete_device()`, this needs User-Interactive Authentication,
+    /// and only one round of it covers all the devices, so this is the way
+    /// to get rid of lots of stale devices.
+    pub fn delete_devices(&mut self, device_ids: &[&str], auth: Option<serde_json::Value>) -> MatrixFuture<UiaaResponse<()>> {
+        let mut body = json!({ "devices": device_ids });
+        if let Some(a) = auth {
+            body["auth"] = a;
+        }
+        Box::new(MatrixRequest::new_with_body_ser(Post, "/delete_devices", body)
+                 .send_uiaa::<serde_json::Value>(self)
+                 .map(|rpl| rpl.map(|_| ())))
+    }
+    /// Make an `auth` object for completing the `m.login.password` stage of
+    /// User-Interactive Authentication as the current user, with the
+    /// `session` from the `UiaaInfo` the homeserver sent back.
+    ///
+    /// This can be given to `delete_device()` or `delete_devices()`.
+    pub fn password_auth(&self, password: &str, session: Option<&str>) -> serde_json::Value {
+        self.password_auth_data(password).to_json(session)
+    }
+    /// Make the `AuthData` for completing the `m.login.password` stage of
+    /// User-Interactive Authentication as the current user, for use with
+    /// `send_uiaa_request_with_stages()`.
+    pub fn password_auth_data(&self, password: &str) -> AuthData {
+        AuthData::Password {
+            user: self.user_id.clone(),
+            password: password.into()
+        }
+    }
+    /// Make a `stage` function that completes the `m.login.password` stage
+    /// of User-Interactive Authentication as the current user, and no
+    /// others, for `change_password()`, `deactivate_account()` or
+    /// `send_uiaa_request_with_stages()`.
+    pub fn password_stage(&self, password: &str) -> impl FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let auth = self.password_auth_data(password);
+        move |stage: &str, _: &UiaaInfo| if stage == "m.login.password" {
+            Some(auth.clone())
+        } else {
+            None
+        }
+    }
+    /// Change the current user's password to `new_password`.
+    ///
+    /// This needs User-Interactive Authentication, which is done with
+    /// `stage` (usually `password_stage()` with the current password); if
+    /// it can't be completed, this fails with an `AuthRequired` error. If
+    /// `logout_devices` is true, all of the user's other devices are logged
+    /// out; otherwise, they're left alone (this client stays logged in
+    /// either way).
+    pub fn change_password<F>(&mut self, new_password: &str, logout_devices: bool, stage: F) -> MatrixFuture<()>
+        where F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let body = json!({
+            "new_password": new_password,
+            "logout_devices": logout_devices
+        });
+        Box::new(MatrixRequest::new_with_body_ser(Post, "/account/password", body)
+                 .send_uiaa_with_stages::<serde_json::Value, _>(self, stage)
+                 .and_then(Self::uiaa_done))
+    }
+    /// Deactivate the current user's account, so that nobody can log in to
+    /// it again. If `erase` is true, the homeserver is also asked to forget
+    /// the messages the user has sent, as far as it can.
+    ///
+    /// This needs User-Interactive Authentication, like
+    /// `change_password()`. Once it's done, the client behaves as if it had
+    /// been logged out with `logout()`.
+    pub fn deactivate_account<F>(&mut self, erase: bool, stage: F) -> MatrixFuture<()>
+        where F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let access_token = self.access_token.clone();
+        let refresh_token = self.refresh_token.clone();
+        let token_expiry = self.token_expiry.clone();
+        let logged_out = self.logged_out.clone();
+        Box::new(MatrixRequest::new_with_body_ser(Post, "/account/deactivate", json!({ "erase": erase }))
+                 .send_uiaa_with_stages::<serde_json::Value, _>(self, stage)
+                 .and_then(Self::uiaa_done)
+                 .map(move |_| {
+                     *access_token.borrow_mut() = None;
+                     *refresh_token.borrow_mut() = None;
+                     token_expiry.set(None);
+                     logged_out.set(true);
+                 }))
+    }
+    /// List the third-party identifiers (like email addresses) bound to the
+    /// current user's account.
+    pub fn get_3pids(&mut self) -> MatrixFuture<Vec<ThreePid>> {
+        Box::new(MatrixRequest::get("/account/3pid")
+                 .send(self)
+                 .map(|ThreePidsReply { threepids }| threepids))
+    }
+    /// Ask the homeserver to send a validation email to `email`, to start
+    /// adding it to the current user's account.
+    ///
+    /// `client_secret` should be `None` the first time, in which case a new
+    /// one is made up. To send the email again, pass the `client_secret` of
+    /// the `ThreePidSession` returned the first time, and a higher
+    /// `send_attempt`; the homeserver doesn't send another email for the
+    /// same `send_attempt`.
+    pub fn request_email_token(&mut self, email: &str, client_secret: Option<&str>, send_attempt: u32) -> MatrixFuture<ThreePidSession> {
+        let client_secret = match client_secret {
+            Some(s) => s.to_string(),
+            None => match make_client_secret() {
+                Ok(s) => s,
+                Err(e) => return Box::new(futures::future::err(e))
+            }
+        };
+        let body = json!({
+            "client_secret": client_secret,
+            "email": email,
+            "send_attempt": send_attempt
+        });
+        Box::new(MatrixRequest::new_with_body_ser(Post, "/account/3pid/email/requestToken", body)
+                 .send(self)
+                 .map(move |RequestTokenReply { sid, submit_url }| ThreePidSession {
+                     client_secret,
+                     sid,
+                     submit_url
+                 }))
+    }
+    /// Add the third-party identifier validated in `session` to the current
+    /// user's account.
+    ///
+    /// This needs User-Interactive Authentication, like
+    /// `change_password()`. If the user hasn't validated the identifier yet,
+    /// this fails with a `BadRequest` error with an errcode of
+    /// `M_THREEPID_AUTH_FAILED`.
+    pub fn add_3pid<F>(&mut self, session: &ThreePidSession, stage: F) -> MatrixFuture<()>
+        where F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let body = json!({
+            "client_secret": session.client_secret,
+            "sid": session.sid
+        });
+        Box::new(MatrixRequest::new_with_body_ser(Post, "/account/3pid/add", body)
+                 .send_uiaa_with_stages::<serde_json::Value, _>(self, stage)
+                 .and_then(Self::uiaa_done))
+    }
+    /// Remove a third-party identifier (like `email` and an email address)
+    /// from the current user's account.
+    pub fn delete_3pid(&mut self, medium: &str, address: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body(Post, "/account/3pid/delete", vec![("medium", medium), ("address", address)])
+            .discarding_send(self)
+    }
+    /// Turn a `UiaaResponse` that still wants authentication into an
+    /// `AuthRequired` error.
+    fn uiaa_done<T>(rpl: UiaaResponse<T>) -> MatrixResult<()> {
+        match rpl {
+            UiaaResponse::Success(_) => Ok(()),
+            UiaaResponse::AuthRequired(info) => Err(MatrixErrorKind::AuthRequired(Box::new(info)).into())
+        }
+    }
+    /// Upload a filter (usually a `Filter`, but any JSON filter definition
+    /// will do) for the current user, returning its ID.
+    ///
+    /// The ID can be given to `SyncStream::set_filter()` or
+    /// `SyncRequest::filter()`. Filters are stored by the homeserver, so the
+    /// ID can be saved and reused later, instead of uploading the filter
+    /// again each time.
+    pub fn create_filter<T: Serialize>(&mut self, filter: T) -> MatrixFuture<String> {
+        Box::new(MatrixRequest::new_with_body_ser(
+            Post,
+            MatrixRequest::endpoint_with_segments(&["user", &self.user_id, "filter"]),
+            filter
+        ).send(self).map(|FilterReply { filter_id }| filter_id))
+    }
+    /// Download a filter that a user (usually the current one) uploaded
+    /// before, by its ID.
+    ///
+    /// If there's no such filter, this fails with a `BadRequest` error with
+    /// the `M_NOT_FOUND` error code.
+    pub fn get_filter(&mut self, user_id: &str, filter_id: &str) -> MatrixFuture<Filter> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["user", user_id, "filter", filter_id]))
+            .send(self)
+    }
+    /// Find out which user (and device) the client's access token belongs
+    /// to.
+    ///
+    /// If the token is invalid, this fails with a `BadRequest` error with the
+    /// `M_UNKNOWN_TOKEN` error code.
+    pub fn whoami(&mut self) -> MatrixFuture<WhoamiReply> {
+        MatrixRequest::new_basic(Get, "/account/whoami")
+            .send(self)
+    }
+    /// Check that the client's access token is still valid, with `whoami()`,
+    /// giving the client back if so.
+    ///
+    /// If the client's `user_id()` or `device_id()` weren't known (for
+    /// example, because it was made with `new_from_access_token()` and an
+    /// empty `user_id`), they're filled in from the reply.
+    pub fn validate_token(mut self) -> MatrixFuture<Self> {
+        let fut = self.whoami();
+        Box::new(fut.map(move |rpl| {
+            if self.user_id.is_empty() {
+                self.user_id = rpl.user_id;
+            }
+            if self.device_id.is_none() {
+                self.device_id = rpl.device_id;
+            }
+            self
+        }))
+    }
+    /// Swap the client's refresh token for a new access token, which the
+    /// client then uses, resolving to how long the new token is valid for,
+    /// if it expires at all (see also `token_expiry()`).
+    ///
+    /// If the homeserver hands out a new refresh token too, the client uses
+    /// that next time. `SyncStream`s made from the client use the new access
+    /// token too.
+    ///
+    /// This fails with a `NoRefreshToken` error if the client doesn't have a
+    /// refresh token, without sending anything; log in again in that case.
+    pub fn refresh(&mut self) -> MatrixFuture<Option<Duration>> {
+        let refresh_token = match *self.refresh_token.borrow() {
+            Some(ref t) => t.clone(),
+            None => return Box::new(futures::future::err(MatrixErrorKind::NoRefreshToken.into()))
+        };
+        let fut = MatrixRequest::new_with_body_ser(Post, "/refresh", json!({
+            "refresh_token": refresh_token
+        })).send(self);
+        let access_token = self.access_token.clone();
+        let refresh_token = self.refresh_token.clone();
+        let token_expiry = self.token_expiry.clone();
+        Box::new(fut.map(move |rpl: RefreshReply| {
+            *access_token.borrow_mut() = Some(rpl.access_token);
+            if rpl.refresh_token.is_some() {
+                *refresh_token.borrow_mut() = rpl.refresh_token;
+            }
+            token_expiry.set(expiry_after(rpl.expires_in_ms));
+            rpl.expires_in_ms.map(Duration::from_millis)
+        }))
+    }
+    /// Log out, invalidating the client's access token.
+    ///
+    /// Once the homeserver has answered, the client forgets its access token,
+    /// and any requests made with it afterwards fail with a `LoggedOut`
+    /// error without being sent. If logging out fails, the client is left
+    /// as it was.
+    ///
+    /// Logging out with a token that the homeserver doesn't know about anymore
+    /// (`M_UNKNOWN_TOKEN`) counts as a success.
+    pub fn logout(&mut self) -> MatrixFuture<()> {
+        self.logout_at("/logout")
+    }
+    /// Log out all of the user's devices, invalidating every access token
+    /// they have (including this client's).
+    ///
+    /// As with `logout()`, the client can't be used afterwards.
+    pub fn logout_all(&mut self) -> MatrixFuture<()> {
+        self.logout_at("/logout/all")
+    }
+    fn logout_at(&mut self, endpoint: &'static str) -> MatrixFuture<()> {
+        let req = match MatrixRequest::new_basic(Post, endpoint).make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        // An invalid token counts as logged out, so there's no point getting
+        // a new one with the reauthentication handler.
+        let sender = RequestSender { reauth: None, ..self.sender() };
+        let fut = sender.send(req, self.timeout, UnitaryResponseWrapper::wrap);
+        let access_token = self.access_token.clone();
+        let refresh_token = self.refresh_token.clone();
+        let token_expiry = self.token_expiry.clone();
+        let logged_out = self.logged_out.clone();
+        Box::new(fut
+                 .or_else(|e| {
+                     // If the token was already invalid, we're as logged out
+                     // as it gets.
+                     if e.errcode() == Some("M_UNKNOWN_TOKEN") {
+                         Ok(())
+                     }
+                     else {
+                         Err(e)
+                     }
+                 })
+                 .map(move |_| {
+                     *access_token.borrow_mut() = None;
+                     *refresh_token.borrow_mut() = None;
+                     token_expiry.set(None);
+                     logged_out.set(true);
+                 }))
+    }
+    /// Ascertain whether the client has been logged out with `logout()` or
+    /// `logout_all()`, or its account deactivated with
+    /// `deactivate_account()`.
+    pub fn is_logged_out(&self) -> bool {
+        self.logged_out.get()
+    }
+    /// Ascertain whether the client is logged in to a guest account, like
+    /// one made with `register_guest()`.
+    pub fn is_guest(&self) -> bool {
+        self.guest
+    }
+    /// Set whether the client is logged in to a guest account, for clients
+    /// made with `new_from_access_token()` from a saved guest session.
+    pub fn set_guest(&mut self, guest: bool) {
+        self.guest = guest;
+    }
+    /// Get the versions of the client-server API that the homeserver
+    /// supports, and which unstable features it has enabled.
+    ///
+    /// This is sent without the access token, since it's the same for
+    /// everyone. The reply is kept, for `supports()` and
+    /// `supports_version()` to check.
+    pub fn versions(&mut self) -> MatrixFuture<VersionsReply> {
+        let mut req = match MatrixRequest::new(Get, "/versions", (), request::apis::unversioned::ClientApi).make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        if let Err(e) = strip_access_token(&mut req) {
+            return Box::new(futures::future::err(e));
+        }
+        let cache = self.server_versions.clone();
+        Box::new(self.send_request(req)
+                 .map(move |rpl: VersionsReply| {
+                     *cache.borrow_mut() = Some(rpl.clone());
+                     rpl
+                 }))
+    }
+    /// Get the versions of the client-server API that the homeserver
+    /// supports; another name for `versions()`.
+    pub fn get_versions(&mut self) -> MatrixFuture<VersionsReply> {
+        self.versions()
+    }
+    /// Ascertain whether the homeserver has the given unstable feature (like
+    /// `org.matrix.msc3440.stable`) enabled, according to the last reply to
+    /// `versions()`.
+    ///
+    /// This is `false` if `versions()` hasn't been called yet.
+    pub fn supports(&self, feature: &str) -> bool {
+        self.server_versions.borrow().as_ref()
+            .map(|v| v.feature_enabled(feature))
+            .unwrap_or(false)
+    }
+    /// Ascertain whether the homeserver supports the given version of the
+    /// client-server API (like `v1.1`), according to the last reply to
+    /// `versions()`.
+    ///
+    /// This is `false` if `versions()` hasn't been called yet.
+    pub fn supports_version(&self, version: &str) -> bool {
+        self.server_versions.borrow().as_ref()
+            .map(|v| v.supports_version(version))
+            .unwrap_or(false)
+    }
+    /// Get what the homeserver lets the current user do, like changing their
+    /// password or display name, and which room versions it supports.
+    pub fn capabilities(&mut self) -> MatrixFuture<Capabilities> {
+        Box::new(MatrixRequest::new_basic(Get, "/capabilities")
+                 .send(self)
+                 .map(|rpl: CapabilitiesReply| rpl.capabilities))
+    }
+    /// Get what the homeserver lets the current user do; another name for
+    /// `capabilities()`.
+    pub fn get_capabilities(&mut self) -> MatrixFuture<Capabilities> {
+        self.capabilities()
+    }
+    /// Make a new transaction ID, for sending events with.
+    ///
+    /// Transaction IDs are unique to this client: they're made of a counter,
+    /// and a random prefix picked when the client was created.
+    pub fn new_txn_id(&mut self) -> String {
+        self.txnid += 1;
+        format!("{}.{}", self.txn_prefix, self.txnid)
+    }
+    /// Send an event of type `event_type`, with the given content, to a room.
+    ///
+    /// A new transaction ID is used for the event, which stays the same if the
+    /// request gets retried, so the homeserver won't send the event twice.
+    pub fn send_message<T: Serialize>(&mut self, room_id: &str, event_type: &str, content: T) -> MatrixFuture<SendReply> {
+        let txnid = self.new_txn_id();
+        self.send_message_with_txn_id(room_id, event_type, content, &txnid)
+    }
+    /// Like `send_message()`, but with a given transaction ID, for callers
+    /// that keep track of retrying sends themselves.
+    ///
+    /// Sending another event with the same transaction ID (from the same
+    /// device) does nothing, and gives back the event ID of the first one.
+    pub fn send_message_with_txn_id<T: Serialize>(&mut self, room_id: &str, event_type: &str, content: T, txnid: &str) -> MatrixFuture<SendReply> {
         MatrixRequest::new_with_body_ser(
             Put,
-            format!("/presence/{}/status", self.user_id),
-            json!({
-                "presence": p
-            })
-        ).discarding_send(self)
+            MatrixRequest::endpoint_with_segments(&["rooms", room_id, "send", event_type, txnid]),
+            content
+        ).send(self)
     }
-    /// Upload some data (convertible to a `Body`) of a given `ContentType`, like an image.
+    /// Redact the event `event_id` in a room, optionally giving a reason,
+    /// and return the event ID of the redaction.
     ///
-    /// `Body` is accessible via the `http` module. See the documentation there
-    /// for a complete reference of what implements `Into<Body>` - a quick
-    /// shortlist: `Vec<u8>`, `&'static [u8]` (not `&'a [u8]`, sadly), `String`,
-    /// `&'static str`.
+    /// Like `send_message()`, this uses a new transaction ID for the
+    /// redaction, which stays the same if the request gets retried.
+    pub fn redact(&mut self, room_id: &str, event_id: &str, reason: Option<&str>) -> MatrixFuture<SendReply> {
+        let txnid = self.new_txn_id();
+        self.redact_with_txn_id(room_id, event_id, reason, &txnid)
+    }
+    /// Like `redact()`, but with a given transaction ID, for callers that
+    /// keep track of retrying redactions themselves.
     ///
-    /// `ContentType` is accessible via the `http` module. See the documentation
-    /// there for more information on how to use it.
-    pub fn upload<T: Into<Body>>(&mut self, data: T, ct: ContentType) -> MatrixFuture<UploadReply> {
-        let req = MatrixRequest {
-            meth: Post,
-            endpoint: "/upload".into(),
-            params: HashMap::new(),
-            body: (),
-            typ: request::apis::r0::MediaApi
-        }.make_hyper(self);
-        let mut req = match req {
+    /// Redacting with the same transaction ID again (from the same device)
+    /// doesn't make a second redaction event.
+    pub fn redact_with_txn_id(&mut self, room_id: &str, event_id: &str, reason: Option<&str>, txnid: &str) -> MatrixFuture<SendReply> {
+        let mut body = vec![];
+        body.extend(reason.map(|x| ("reason", x)));
+        let req = MatrixRequest::new_with_body(
+            Put,
+            MatrixRequest::endpoint_with_segments(&["rooms", room_id, "redact", event_id, txnid]),
+            body
+        );
+        let mut req = match req.make_hyper(self) {
             Ok(r) => r,
-            Err(e) => return Box::new(futures::future::err(e.into()))
+            Err(e) => return Box::new(futures::future::err(e))
         };
-        req.set_body(data.into());
-        req.headers_mut().set(ct);
+        if reason.is_none() {
+            set_empty_json_body(&mut req);
+        }
         self.send_request(req)
     }
-    /// Get the client's MXID.
-    pub fn user_id(&self) -> &str {
-        &self.user_id
+    /// Send a read receipt (`m.read`) for the event `event_id` in a room,
+    /// marking it (and the events before it) as read.
+    pub fn send_receipt(&mut self, room_id: &str, event_id: &str) -> MatrixFuture<()> {
+        self.send_receipt_of_type(room_id, "m.read", event_id)
     }
-    /// Get a `SyncStream`, a `Stream` used to obtain replies to the `/sync`
-    /// API.
+    /// Like `send_receipt()`, but sends a private read receipt
+    /// (`m.read.private`), which other users don't get to see.
+    pub fn send_private_receipt(&mut self, room_id: &str, event_id: &str) -> MatrixFuture<()> {
+        self.send_receipt_of_type(room_id, "m.read.private", event_id)
+    }
+    fn send_receipt_of_type(&mut self, room_id: &str, typ: &str, event_id: &str) -> MatrixFuture<()> {
+        let req = MatrixRequest::new_basic(Post, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "receipt", typ, event_id]));
+        let mut req = match req.make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        set_empty_json_body(&mut req);
+        self.send_discarding_request(req)
+    }
+    /// Set the read markers in a room: the fully-read marker
+    /// (`m.fully_read`, how far the user has read up to) and/or the read
+    /// receipt (`m.read`, which is sent to other users), in one request.
     ///
-    /// This `SyncStream` is independent from the original `MatrixClient`, and
-    /// does not borrow from it in any way.
-    pub fn get_sync_stream(&self) -> SyncStream {
-        SyncStream {
-            hyper: self.hyper.clone(),
-            last_batch: None,
-            set_presence: true,
-            access_token: self.access_token.clone(),
-            url: self.url.clone(),
-            timeout: 30000,
-            cur_req: None
-        }
+    /// Markers that are `None` are left as they are.
+    pub fn set_read_markers(&mut self, room_id: &str, fully_read: Option<&str>, read_receipt: Option<&str>) -> MatrixFuture<()> {
+        let mut body = vec![];
+        body.extend(fully_read.map(|x| ("m.fully_read", x)));
+        body.extend(read_receipt.map(|x| ("m.read", x)));
+        MatrixRequest::new_with_body(Post, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "read_markers"]), body)
+            .discarding_send(self)
     }
-    /// Sends an arbitrary `Request` to the Matrix homeserver, like one
-    /// generated by `get_request_for()`.
-    pub fn send_request<T>(&mut self, req: Request) -> MatrixFuture<T> where T: DeserializeOwned + 'static {
-        Box::new(self.hyper.request(req)
-                 .map_err(|e| e.into())
-                 .and_then(ResponseWrapper::<T>::wrap))
+    /// React to the event `target` in a room, with an `m.reaction` event
+    /// annotating it with `key` (usually an emoji).
+    pub fn send_reaction(&mut self, room_id: &str, target: &str, key: &str) -> MatrixFuture<SendReply> {
+        let content = Reaction {
+            relates_to: RelatesTo::annotation(target, key)
+        };
+        self.send_message(room_id, "m.reaction", content)
     }
-    /// Like `send_request()`, but discards the return value that the Matrix
-    /// homeserver sends back.
-    pub fn send_discarding_request(&mut self, req: Request) -> MatrixFuture<()> {
-        Box::new(self.hyper.request(req)
-                 .map_err(|e| e.into())
-                 .and_then(UnitaryResponseWrapper::wrap))
+    /// React to the event `target` in a room with `key`; another name for
+    /// `send_reaction()`.
+    pub fn react(&mut self, room_id: &str, target: &str, key: &str) -> MatrixFuture<SendReply> {
+        self.send_reaction(room_id, target, key)
     }
-    /// Get this `MatrixClient`'s underlying `hyper::Client`.
-    pub fn get_hyper(&mut self) -> &mut http::MatrixHyper {
-        &mut self.hyper
+    /// Take back a reaction, by redacting the reaction event `reaction_id`
+    /// (the event ID that `send_reaction()` gave back).
+    pub fn remove_reaction(&mut self, room_id: &str, reaction_id: &str) -> MatrixFuture<SendReply> {
+        self.redact(room_id, reaction_id, None)
     }
-}
-
-impl Drop for MatrixClient {
-    /// Invalidates our access token, so we don't have millions of devices.
-    /// Also sets us as offline.
-    fn drop(&mut self) {
-        let fut = MatrixRequest::new_basic(Post, "/logout")
-            .discarding_send(self).map_err(|_| ()).map(|_| ());
-        self.hdl.spawn(fut);
+    /// Reply to an event in a room with some text, returning the ID of the
+    /// reply.
+    ///
+    /// The reply is sent HTML-formatted, so that (if given a full
+    /// `m.room.message` event) it can quote the original in a fallback for
+    /// clients that don't understand replies; see `Message::reply_to()`.
+    /// `text` and the quoted message are escaped, so neither can inject any
+    /// markup. To reply to an event you only have the ID of, use
+    /// `reply_to_event_id()`.
+    ///
+    /// If `target` is an event without an event ID, this fails with a
+    /// `NoEventId` error without sending anything.
+    pub fn reply<'a, T: Into<ReplyTarget<'a>>>(&mut self, room_id: &str, target: T, text: &str) -> MatrixFuture<SendReply> {
+        match Self::reply_message(text).reply_to(target) {
+            Some(msg) => self.send_message(room_id, "m.room.message", msg),
+            None => Box::new(futures::future::err(MatrixErrorKind::NoEventId.into()))
+        }
+    }
+    /// Like `reply()`, but fetches the event being replied to (with
+    /// `get_event()`) first, so that it can be quoted.
+    pub fn reply_to_event_id(&mut self, room_id: &str, event_id: &str, text: &str) -> MatrixFuture<SendReply> {
+        let txnid = self.new_txn_id();
+        let put = MatrixRequest::put(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "send", "m.room.message", &txnid]));
+        let msg = Self::reply_message(text);
+        let msg = self.get_event(room_id, event_id)
+            .and_then(move |ev| msg.reply_to(&ev).ok_or_else(|| MatrixErrorKind::NoEventId.into()));
+        self.send_with_deferred_body(put, Box::new(msg))
+    }
+    fn reply_message(text: &str) -> Message {
+        Message::html(text, escape_html(text).replace('\n', "<br>"))
+    }
+    /// Get a single event in a room, by its ID.
+    ///
+    /// If there's no such event (or the user can't see it), this fails with
+    /// a `BadRequest` error with the `M_NOT_FOUND` error code.
+    pub fn get_event(&mut self, room_id: &str, event_id: &str) -> MatrixFuture<Event> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "event", event_id]))
+            .send(self)
+    }
+    /// Send a message to the thread starting at the event `thread_root` in a
+    /// room.
+    ///
+    /// For clients that don't understand threads, the message is also made a
+    /// reply to the latest event in the thread, which is looked up with
+    /// `get_relations()` first. If you already know it, use
+    /// `send_thread_message_after()` to save a request.
+    pub fn send_thread_message(&mut self, room_id: &str, thread_root: &str, content: Message) -> MatrixFuture<SendReply> {
+        let txnid = self.new_txn_id();
+        let put = MatrixRequest::put(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "send", "m.room.message", &txnid]));
+        let root = thread_root.to_owned();
+        let msg = self.get_relations(room_id, thread_root, Some("m.thread"), None, Some(1))
+            .map(move |rpl| {
+                let latest = rpl.chunk.first()
+                    .and_then(|ev| ev.event_id())
+                    .unwrap_or(&root as &str)
+                    .to_owned();
+                content.with_relation(RelatesTo::thread(root, latest))
+            });
+        self.send_with_deferred_body(put, Box::new(msg))
+    }
+    /// Like `send_thread_message()`, but with the ID of the latest event in
+    /// the thread (or the root, if there isn't one) given, instead of looked
+    /// up.
+    pub fn send_thread_message_after(&mut self, room_id: &str, thread_root: &str, latest: &str, content: Message) -> MatrixFuture<SendReply> {
+        self.send_message(room_id, "m.room.message", content.with_relation(RelatesTo::thread(thread_root, latest)))
+    }
+    /// Get the events relating to the event `event_id` in a room, most recent
+    /// first, optionally only those with relation type `rel_type` (like
+    /// `m.thread` or `m.annotation`).
+    ///
+    /// `from` is a token from a previous reply to carry on from, and `limit`
+    /// the maximum number of events to get.
+    pub fn get_relations(&mut self, room_id: &str, event_id: &str, rel_type: Option<&str>, from: Option<&str>, limit: Option<u32>) -> MatrixFuture<RelationsReply> {
+        let mut segments = vec!["rooms", room_id, "relations", event_id];
+        segments.extend(rel_type);
+        let mut req = MatrixRequest::get(MatrixRequest::endpoint_with_segments(&segments))
+            .with_api(request::apis::v1::ClientApi);
+        if let Some(from) = from {
+            req = req.param("from", from);
+        }
+        if let Some(limit) = limit {
+            req = req.param("limit", limit);
+        }
+        req.send(self)
+    }
+    /// List the threads in a room, most recently active first, as their root
+    /// events.
+    ///
+    /// `from` is the `next_batch` token of a previous reply, to get the next
+    /// page of threads.
+    pub fn thread_roots(&mut self, room_id: &str, from: Option<&str>) -> MatrixFuture<ThreadsReply> {
+        let mut req = MatrixRequest::get(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "threads"]))
+            .with_api(request::apis::v1::ClientApi);
+        if let Some(from) = from {
+            req = req.param("from", from);
+        }
+        req.send(self)
+    }
+    /// Edit the message `target` in a room, replacing its content with
+    /// `new_content`.
+    ///
+    /// See `Message::edit_of()` for what gets sent.
+    pub fn edit_message(&mut self, room_id: &str, target: &str, new_content: Message) -> MatrixFuture<SendReply> {
+        self.send_message(room_id, "m.room.message", new_content.edit_of(target))
+    }
+    /// Tell the homeserver whether the user is typing in a room.
+    ///
+    /// If `typing` is true, the user is marked as typing for the next
+    /// `timeout` milliseconds (or until this is called again with `false`).
+    /// The timeout is left out when `typing` is false. For staying marked as
+    /// typing while doing something, see `typing_guard()`.
+    pub fn set_typing(&mut self, room_id: &str, typing: bool, timeout: Option<u64>) -> MatrixFuture<()> {
+        let mut body = json!({
+            "typing": typing
+        });
+        if let (true, Some(t)) = (typing, timeout) {
+            body["timeout"] = t.into();
+        }
+        MatrixRequest::new_with_body_ser(
+            Put,
+            MatrixRequest::endpoint_with_segments(&["rooms", room_id, "typing", &self.user_id]),
+            body
+        ).discarding_send(self)
+    }
+    /// Mark the user as typing in a room (with the given timeout, in
+    /// milliseconds), until the returned `TypingGuard` is dropped.
+    ///
+    /// If `refresh` is true, the typing notification is re-sent before the
+    /// timeout runs out, so that it lasts as long as the guard does.
+    pub fn typing_guard(&self, room_id: &str, timeout: u64, refresh: bool) -> MatrixResult<TypingGuard> {
+        TypingGuard::new(self, room_id, timeout, refresh)
+    }
+    /// Create a new room, returning its ID.
+    pub fn create_room(&mut self, params: CreateRoomParams) -> MatrixFuture<Room<'static>> {
+        let mut req = match MatrixRequest::new_with_body_ser(Post, "/createRoom", params).make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        if req.body_ref().is_none() {
+            // All the options were left out, but a body is still needed.
+            set_empty_json_body(&mut req);
+        }
+        Box::new(self.send_request(req)
+                 .map(|CreateRoomReply { room }| room))
+    }
+    /// Join a room by identifier or alias.
+    ///
+    /// The reply contains the ID of the room that was joined, which is handy
+    /// when joining by alias.
+    pub fn join(&mut self, roomid: &str) -> MatrixFuture<JoinReply> {
+        self.join_via(roomid, &[])
+    }
+    /// Join a room by identifier or alias, trying to join through the given
+    /// servers (which the homeserver needs if it isn't in the room already,
+    /// and it can't work out which servers are from the room ID or alias).
+    pub fn join_via(&mut self, roomid: &str, servers: &[&str]) -> MatrixFuture<JoinReply> {
+        let mut req = MatrixRequest::new_basic(Post, MatrixRequest::endpoint_with_segments(&["join", roomid]));
+        for server in servers {
+            // `server_name` is the older name of `via`; send both, so that
+            // older homeservers understand it too.
+            req = req.repeated_param("via", server)
+                .repeated_param("server_name", server);
+        }
+        req.send(self)
+    }
+    /// Leave a room (or reject an invite to it).
+    ///
+    /// See `RoomClient::leave()` for more.
+    pub fn leave(&mut self, room_id: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_basic(Post, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "leave"]))
+            .discarding_send(self)
+    }
+    /// Forget a room, so its history can't be retrieved any more.
+    ///
+    /// See `RoomClient::forget()` for more.
+    pub fn forget(&mut self, room_id: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_basic(Post, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "forget"]))
+            .discarding_send(self)
+    }
+    /// Invite a user to a room.
+    ///
+    /// See `RoomClient::invite_user()` for more.
+    pub fn invite(&mut self, room_id: &str, user_id: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body(Post, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "invite"]),
+                                     vec![("user_id", user_id)])
+            .discarding_send(self)
+    }
+    /// Kick a user from a room, optionally giving a reason (which is left out
+    /// if empty).
+    pub fn kick(&mut self, room_id: &str, user_id: &str, reason: Option<&str>) -> MatrixFuture<()> {
+        self.membership_action(room_id, "kick", user_id, reason)
+    }
+    /// Ban a user from a room (kicking them if they're in it), optionally
+    /// giving a reason (which is left out if empty).
+    pub fn ban(&mut self, room_id: &str, user_id: &str, reason: Option<&str>) -> MatrixFuture<()> {
+        self.membership_action(room_id, "ban", user_id, reason)
+    }
+    /// Unban a user from a room.
+    pub fn unban(&mut self, room_id: &str, user_id: &str) -> MatrixFuture<()> {
+        self.membership_action(room_id, "unban", user_id, None)
+    }
+    fn membership_action(&mut self, room_id: &str, action: &str, user_id: &str, reason: Option<&str>) -> MatrixFuture<()> {
+        let mut body = vec![("user_id", user_id)];
+        body.extend(reason.filter(|x| !x.is_empty()).map(|x| ("reason", x)));
+        MatrixRequest::new_with_body(Post, MatrixRequest::endpoint_with_segments(&["rooms", room_id, action]), body)
+            .discarding_send(self)
+    }
+    /// List the rooms the current user has joined.
+    ///
+    /// This is much cheaper than an initial sync, for finding out which
+    /// rooms a bot is in when it starts up.
+    pub fn joined_rooms(&mut self) -> MatrixFuture<Vec<Room<'static>>> {
+        Box::new(MatrixRequest::get("/joined_rooms")
+                 .send(self)
+                 .map(|JoinedRoomsReply { joined_rooms }| joined_rooms))
+    }
+    /// Get the members that have joined a room, by MXID, with their display
+    /// names and avatars.
+    ///
+    /// The current user has to be in the room.
+    pub fn get_joined_members(&mut self, room_id: &str) -> MatrixFuture<HashMap<String, JoinedMember>> {
+        Box::new(MatrixRequest::get(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "joined_members"]))
+                 .send(self)
+                 .map(|JoinedMembersReply { joined }| joined))
+    }
+    /// Get the `m.room.member` events of a room's members, including those
+    /// who have been invited, left or been banned.
+    ///
+    /// If `membership` is given, only members with that membership are
+    /// returned; if `not_membership` is, members with that membership are
+    /// left out.
+    pub fn get_members(&mut self, room_id: &str, membership: Option<Membership>, not_membership: Option<Membership>) -> MatrixFuture<Vec<Event>> {
+        let mut req = MatrixRequest::get(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "members"]));
+        if let Some(m) = membership {
+            req = req.param("membership", m.as_str());
+        }
+        if let Some(m) = not_membership {
+            req = req.param("not_membership", m.as_str());
+        }
+        Box::new(req.send(self).map(|MembersReply { chunk }| chunk))
+    }
+    /// Get the content of the state event with type `ev_type` and state key
+    /// `key` (or the empty state key, if `None`) in a room.
+    ///
+    /// This is `get_state_event()` with an optional state key.
+    pub fn get_state<T: DeserializeOwned + 'static>(&mut self, room_id: &str, ev_type: &str, key: Option<&str>) -> MatrixFuture<T> {
+        self.get_state_event(room_id, ev_type, key.unwrap_or(""))
+    }
+    /// Send a state event with type `ev_type` and state key `key` (or the
+    /// empty state key, if `None`) to a room.
+    ///
+    /// This is `set_state_event()` with an optional state key.
+    pub fn set_state<T: Serialize>(&mut self, room_id: &str, ev_type: &str, key: Option<&str>, val: T) -> MatrixFuture<SetStateReply> {
+        self.set_state_event(room_id, ev_type, key.unwrap_or(""), val)
+    }
+    /// Get the content of the state event with type `ev_type` and state key
+    /// `state_key` in a room.
+    ///
+    /// Content types for common state events are in the `content::room`
+    /// module; use `serde_json::Value` for others. Most room state (e.g.
+    /// `m.room.topic`) has an empty state key; `m.room.member` events are
+    /// keyed by user ID.
+    pub fn get_state_event<T: DeserializeOwned + 'static>(&mut self, room_id: &str, ev_type: &str, state_key: &str) -> MatrixFuture<T> {
+        MatrixRequest::new_basic(Get, Self::state_endpoint(room_id, ev_type, state_key))
+            .send(self)
+    }
+    /// Send a state event with type `ev_type` and state key `state_key` to a
+    /// room, returning the ID of the new event.
+    pub fn set_state_event<T: Serialize>(&mut self, room_id: &str, ev_type: &str, state_key: &str, content: T) -> MatrixFuture<SetStateReply> {
+        MatrixRequest::new_with_body_ser(Put, Self::state_endpoint(room_id, ev_type, state_key), content)
+            .send(self)
+    }
+    /// `/rooms/{roomId}/state/{eventType}/{stateKey}`, leaving off the last
+    /// segment entirely for the empty state key.
+    fn state_endpoint(room_id: &str, ev_type: &str, state_key: &str) -> String {
+        if state_key.is_empty() {
+            MatrixRequest::endpoint_with_segments(&["rooms", room_id, "state", ev_type])
+        }
+        else {
+            MatrixRequest::endpoint_with_segments(&["rooms", room_id, "state", ev_type, state_key])
+        }
+    }
+    /// Get all the current state events in a room.
+    ///
+    /// The events' content is parsed like that of events from `/sync`, so
+    /// state events of unknown types end up as `Content::Unknown`.
+    pub fn get_all_state(&mut self, room_id: &str) -> MatrixFuture<Vec<Event>> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["rooms", room_id, "state"]))
+            .send(self)
+    }
+    /// Set the power level of `user_id` in a room to `level`.
+    ///
+    /// This fetches the room's current `m.room.power_levels` event, changes
+    /// the user's entry, and sends the whole thing back, returning the ID of
+    /// the new event. The rest of the event is sent back exactly as it was,
+    /// without filling in defaults for keys that aren't there.
+    ///
+    /// Careful: lowering your own power level can't be undone without help
+    /// from someone else in the room.
+    pub fn set_user_power_level(&mut self, room_id: &str, user_id: &str, level: i64) -> MatrixFuture<SetStateReply> {
+        let put = MatrixRequest::new_basic(Put, Self::state_endpoint(room_id, "m.room.power_levels", ""));
+        let user_id = user_id.to_owned();
+        let pl = self.get_state_event::<serde_json::Map<String, serde_json::Value>>(room_id, "m.room.power_levels", "")
+            .map(move |mut pl| {
+                {
+                    let users = pl.entry("users").or_insert_with(|| json!({}));
+                    if !users.is_object() {
+                        *users = json!({});
+                    }
+                    users[&user_id] = json!(level);
+                }
+                pl
+            });
+        self.send_with_deferred_body(put, Box::new(pl))
+    }
+    /// Send `req` once `body` resolves, with that as its body.
+    ///
+    /// This is for requests whose body depends on the result of another
+    /// request, like the second half of a read-modify-write. The request is
+    /// made straight away, while we've got the client, and only sent later.
+    fn send_with_deferred_body<B, R>(&mut self, req: MatrixRequest<()>, body: MatrixFuture<B>) -> MatrixFuture<R>
+        where B: Serialize + 'static, R: DeserializeOwned + 'static {
+        let timeout = req.timeout.or(self.timeout);
+        let mut req = match req.make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let sender = self.sender();
+        Box::new(body
+                 .and_then(move |body| {
+                     set_json_body(&mut req, &body)?;
+                     Ok(req)
+                 })
+                 .and_then(move |req| sender.send(req, timeout, ResponseWrapper::<R>::wrap)))
+    }
+    /// Get one page of the history of a room, in the given direction.
+    ///
+    /// `from` is the token to start from (like the `prev_batch` of a sync
+    /// timeline, or the `end` of a previous reply), `to` a token to stop at,
+    /// `limit` the maximum number of events to get, and `filter` a
+    /// `RoomEventFilter` definition in JSON. To get all of a room's history,
+    /// use `messages()`.
+    ///
+    /// The user doesn't need to be in the room: the history of
+    /// world-readable rooms can be read without joining them (which is how
+    /// guests usually read rooms). In that case, `from` is usually `None`.
+    pub fn get_messages(&mut self, room_id: &str, dir: Direction, from: Option<&str>, to: Option<&str>, limit: Option<u32>, filter: Option<&str>) -> MatrixFuture<MessagesReply> {
+        let mut req = MatrixRequest::get(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "messages"]))
+            .param("dir", dir.as_str());
+        if let Some(from) = from {
+            req = req.param("from", from);
+        }
+        if let Some(to) = to {
+            req = req.param("to", to);
+        }
+        if let Some(limit) = limit {
+            req = req.param("limit", limit);
+        }
+        if let Some(f) = filter {
+            req = req.param("filter", f);
+        }
+        req.send(self)
+    }
+    /// Get an event, along with up to `limit` events from before and after
+    /// it (the homeserver picks the number, if `None`).
+    ///
+    /// The `start` and `end` tokens of the reply can be given to
+    /// `get_messages()` (or `MessagesStream::set_from()`) to carry on going
+    /// backwards or forwards. If there's no such event, this fails with a
+    /// `BadRequest` error with an errcode of `M_NOT_FOUND`.
+    pub fn get_context(&mut self, room_id: &str, event_id: &str, limit: Option<u32>) -> MatrixFuture<ContextReply> {
+        let mut req = MatrixRequest::get(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "context", event_id]));
+        if let Some(limit) = limit {
+            req = req.param("limit", limit);
+        }
+        req.send(self)
+    }
+    /// Page through the history of a room, in the given direction.
+    ///
+    /// See `MessagesStream` for more, and `MessagesStream::set_limit()` to
+    /// cap the number of events in each page.
+    pub fn messages(&mut self, room_id: &str, dir: Direction) -> MessagesStream<'_> {
+        MessagesStream::new(self, room_id, dir)
+    }
+    /// Update our presence status.
+    ///
+    /// This is `set_presence()` for the current user, without a status
+    /// message.
+    pub fn update_presence(&mut self, p: Presence) -> MatrixFuture<()> {
+        let user_id = self.user_id.clone();
+        self.set_presence(&user_id, p, None)
+    }
+    /// Set our presence, and optionally a status message.
+    ///
+    /// This is `set_presence()` for the current user.
+    pub fn set_own_presence(&mut self, p: Presence, status_msg: Option<&str>) -> MatrixFuture<()> {
+        let user_id = self.user_id.clone();
+        self.set_presence(&user_id, p, status_msg)
+    }
+    /// Set the presence (and, optionally, a status message) of a user -
+    /// usually the current one.
+    ///
+    /// If the homeserver has presence disabled, this may fail with a
+    /// `PresenceDisabled` error.
+    pub fn set_presence(&mut self, user_id: &str, p: Presence, status_msg: Option<&str>) -> MatrixFuture<()> {
+        let mut body = json!({
+            "presence": p
+        });
+        if let Some(m) = status_msg {
+            body["status_msg"] = m.into();
+        }
+        Box::new(MatrixRequest::new_with_body_ser(
+            Put,
+            MatrixRequest::endpoint_with_segments(&["presence", user_id, "status"]),
+            body
+        ).discarding_send(self).map_err(Self::presence_error))
+    }
+    /// Get the presence of a user.
+    ///
+    /// If the homeserver has presence disabled, this may fail with a
+    /// `PresenceDisabled` error, which bots can check for to carry on without
+    /// presence information.
+    pub fn get_presence(&mut self, user_id: &str) -> MatrixFuture<PresenceReply> {
+        Box::new(MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["presence", user_id, "status"]))
+                 .send(self)
+                 .map_err(Self::presence_error))
+    }
+    fn presence_error(e: MatrixError) -> MatrixError {
+        let disabled = match *e.kind() {
+            // Homeservers without presence support (or with it turned off)
+            // either don't have the endpoints at all, or say so in the error.
+            MatrixErrorKind::BadRequest(ref brq, _) if brq.errcode == "M_UNRECOGNIZED" => true,
+            MatrixErrorKind::BadRequest(ref brq, _) => brq.error.to_lowercase().contains("presence is disabled"),
+            MatrixErrorKind::HttpCode(hyper::StatusCode::NotFound) => true,
+            _ => false
+        };
+        if disabled {
+            MatrixError::with_chain(e, MatrixErrorKind::PresenceDisabled)
+        }
+        else {
+            e
+        }
+    }
+    /// Get the display name and avatar of a user.
+    ///
+    /// Homeservers differ in how they say that a user has no profile (an
+    /// empty object, or a 404), so both come back as a `ProfileReply` with
+    /// `None` fields.
+    pub fn get_profile(&mut self, user_id: &str) -> MatrixFuture<ProfileReply> {
+        self.get_profile_field(&["profile", user_id])
+    }
+    /// Get the display name of a user, or `None` if they haven't set one.
+    pub fn get_displayname(&mut self, user_id: &str) -> MatrixFuture<Option<String>> {
+        Box::new(self.get_profile_field(&["profile", user_id, "displayname"])
+                 .map(|p| p.displayname))
+    }
+    /// Get the avatar of a user, as an `mxc://` URI, or `None` if they
+    /// haven't set one.
+    pub fn get_avatar_url(&mut self, user_id: &str) -> MatrixFuture<Option<String>> {
+        Box::new(self.get_profile_field(&["profile", user_id, "avatar_url"])
+                 .map(|p| p.avatar_url))
+    }
+    fn get_profile_field(&mut self, segments: &[&str]) -> MatrixFuture<ProfileReply> {
+        Box::new(MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(segments))
+                 .send(self)
+                 .or_else(|e| {
+                     match *e.kind() {
+                         MatrixErrorKind::BadRequest(ref brq, _) if brq.errcode == "M_NOT_FOUND" => {},
+                         MatrixErrorKind::HttpCode(hyper::StatusCode::NotFound) => {},
+                         _ => return Err(e)
+                     }
+                     Ok(ProfileReply::default())
+                 }))
+    }
+    /// Set the display name of a user (usually the current one).
+    pub fn set_displayname(&mut self, user_id: &str, name: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body(Put, MatrixRequest::endpoint_with_segments(&["profile", user_id, "displayname"]),
+                                     vec![("displayname", name)])
+            .discarding_send(self)
+    }
+    /// Set the display name of the current user.
+    ///
+    /// This changes it everywhere; to change it in only one room, use
+    /// `set_room_display_name()`.
+    pub fn set_own_display_name(&mut self, name: &str) -> MatrixFuture<()> {
+        let user_id = self.user_id.clone();
+        self.set_displayname(&user_id, name)
+    }
+    /// Set the display name of the current user in one room only.
+    ///
+    /// This fetches the user's `m.room.member` event in the room, changes the
+    /// `displayname`, and sends it back, returning the ID of the new event.
+    /// The rest of the event (like the membership) is sent back untouched.
+    /// Changing the global display name later will usually overwrite this.
+    pub fn set_room_display_name(&mut self, room_id: &str, name: &str) -> MatrixFuture<SetStateReply> {
+        let user_id = self.user_id.clone();
+        let put = MatrixRequest::new_basic(Put, Self::state_endpoint(room_id, "m.room.member", &user_id));
+        let name = name.to_owned();
+        let member = self.get_state_event::<serde_json::Value>(room_id, "m.room.member", &user_id)
+            .map(move |mut member| {
+                if let Some(obj) = member.as_object_mut() {
+                    obj.insert("displayname".into(), name.into());
+                }
+                member
+            });
+        self.send_with_deferred_body(put, Box::new(member))
+    }
+    /// Set the avatar of a user (usually the current one).
+    ///
+    /// The image needs to be in the content repository already; get a
+    /// `ContentUri` for it with `upload()` first.
+    pub fn set_avatar_url(&mut self, user_id: &str, mxc: &ContentUri) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body(Put, MatrixRequest::endpoint_with_segments(&["profile", user_id, "avatar_url"]),
+                                     vec![("avatar_url", mxc.to_string())])
+            .discarding_send(self)
+    }
+    /// Set the avatar of the current user.
+    pub fn set_own_avatar_url(&mut self, mxc: &ContentUri) -> MatrixFuture<()> {
+        let user_id = self.user_id.clone();
+        self.set_avatar_url(&user_id, mxc)
+    }
+    /// Get some global account data of type `ev_type` for a user (usually the
+    /// current one).
+    ///
+    /// Like with state events, the content can be anything that implements
+    /// `Deserialize`.
+    pub fn get_account_data<T: DeserializeOwned + 'static>(&mut self, user_id: &str, ev_type: &str) -> MatrixFuture<T> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["user", user_id, "account_data", ev_type]))
+            .send(self)
+    }
+    /// Set some global account data of type `ev_type` for a user (usually the
+    /// current one), replacing whatever was there before.
+    pub fn set_account_data<T: Serialize>(&mut self, user_id: &str, ev_type: &str, content: T) -> MatrixFuture<()> {
+        let ep = MatrixRequest::endpoint_with_segments(&["user", user_id, "account_data", ev_type]);
+        self.put_account_data(ep, content)
+    }
+    /// Get some account data of type `ev_type` for a user (usually the current
+    /// one) that's specific to a room.
+    pub fn get_room_account_data<T: DeserializeOwned + 'static>(&mut self, user_id: &str, room_id: &str, ev_type: &str) -> MatrixFuture<T> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["user", user_id, "rooms", room_id,
+                                                                             "account_data", ev_type]))
+            .send(self)
+    }
+    /// Set some account data of type `ev_type` for a user (usually the current
+    /// one) that's specific to a room, replacing whatever was there before.
+    pub fn set_room_account_data<T: Serialize>(&mut self, user_id: &str, room_id: &str, ev_type: &str, content: T) -> MatrixFuture<()> {
+        let ep = MatrixRequest::endpoint_with_segments(&["user", user_id, "rooms", room_id, "account_data", ev_type]);
+        self.put_account_data(ep, content)
+    }
+    /// Get some global account data of type `ev_type` for the current user.
+    pub fn get_own_account_data<T: DeserializeOwned + 'static>(&mut self, ev_type: &str) -> MatrixFuture<T> {
+        let user_id = self.user_id.clone();
+        self.get_account_data(&user_id, ev_type)
+    }
+    /// Set some global account data of type `ev_type` for the current user,
+    /// replacing whatever was there before.
+    pub fn set_own_account_data<T: Serialize>(&mut self, ev_type: &str, content: T) -> MatrixFuture<()> {
+        let user_id = self.user_id.clone();
+        self.set_account_data(&user_id, ev_type, content)
+    }
+    /// Like `get_own_account_data()`, but with an empty `T` if there isn't any
+    /// account data of that type yet.
+    fn get_own_account_data_or_default<T: DeserializeOwned + Default + 'static>(&mut self, ev_type: &str) -> MatrixFuture<T> {
+        Box::new(self.get_own_account_data(ev_type)
+                 .or_else(|e| {
+                     if e.errcode() == Some("M_NOT_FOUND") {
+                         Ok(T::default())
+                     }
+                     else {
+                         Err(e)
+                     }
+                 }))
+    }
+    /// Get the current user's `m.direct` account data: which rooms are direct
+    /// chats, and with whom.
+    ///
+    /// If the user doesn't have any yet, this gives back an empty `Direct`.
+    pub fn get_direct_rooms(&mut self) -> MatrixFuture<Direct> {
+        self.get_own_account_data_or_default("m.direct")
+    }
+    /// Replace the current user's `m.direct` account data.
+    ///
+    /// To add a room, get the current list with `get_direct_rooms()` and
+    /// change it with `Direct::add_room()` first.
+    pub fn set_direct_rooms(&mut self, direct: &Direct) -> MatrixFuture<()> {
+        self.set_own_account_data("m.direct", direct)
+    }
+    /// Get the users that the current user is ignoring, from their
+    /// `m.ignored_user_list` account data.
+    pub fn get_ignored_users(&mut self) -> MatrixFuture<IgnoredUserList> {
+        self.get_own_account_data_or_default("m.ignored_user_list")
+    }
+    /// Start ignoring a user.
+    ///
+    /// This fetches the current user's `m.ignored_user_list`, adds the user to
+    /// it, and sends it back.
+    pub fn ignore_user(&mut self, user_id: &str) -> MatrixFuture<()> {
+        let user_id = user_id.to_owned();
+        self.update_ignored_users(move |list| {
+            list.ignored_users.insert(user_id, Default::default());
+        })
+    }
+    /// Stop ignoring a user.
+    ///
+    /// Like `ignore_user()`, this fetches the whole list and sends it back.
+    pub fn unignore_user(&mut self, user_id: &str) -> MatrixFuture<()> {
+        let user_id = user_id.to_owned();
+        self.update_ignored_users(move |list| {
+            list.ignored_users.remove(&user_id);
+        })
+    }
+    fn update_ignored_users<F>(&mut self, func: F) -> MatrixFuture<()> where F: FnOnce(&mut IgnoredUserList) + 'static {
+        let ep = MatrixRequest::endpoint_with_segments(&["user", &self.user_id as &str, "account_data", "m.ignored_user_list"]);
+        let list = self.get_ignored_users()
+            .map(move |mut list| {
+                func(&mut list);
+                list
+            });
+        Box::new(self.send_with_deferred_body::<_, serde_json::Value>(MatrixRequest::new_basic(Put, ep), Box::new(list))
+                 .map(|_| ()))
+    }
+    /// Get the current user's tags on a room.
+    pub fn get_tags(&mut self, room_id: &str) -> MatrixFuture<Tag> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["user", &self.user_id as &str, "rooms", room_id, "tags"]))
+            .send(self)
+    }
+    /// Tag a room for the current user, with an optional order (from 0 to 1)
+    /// among the other rooms with that tag.
+    ///
+    /// Tags are either ones from the spec, like `m.favourite` and
+    /// `m.lowpriority`, or user-defined ones starting with `u.`.
+    pub fn set_tag(&mut self, room_id: &str, tag: &str, order: Option<f64>) -> MatrixFuture<()> {
+        let ep = MatrixRequest::endpoint_with_segments(&["user", &self.user_id as &str, "rooms", room_id, "tags", tag]);
+        self.put_account_data(ep, RoomTag::new(order))
+    }
+    /// Remove a tag from a room for the current user.
+    pub fn remove_tag(&mut self, room_id: &str, tag: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_basic(Delete, MatrixRequest::endpoint_with_segments(&["user", &self.user_id as &str, "rooms", room_id, "tags", tag]))
+            .discarding_send(self)
+    }
+    /// Get the current user's push rules, which decide what they get
+    /// notified about.
+    pub fn get_push_rules(&mut self) -> MatrixFuture<Ruleset> {
+        Box::new(MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["pushrules", ""]))
+                 .send(self)
+                 .map(|rpl: PushRulesReply| rpl.global))
+    }
+    /// Get one of the current user's push rules, in `scope` (usually
+    /// `global`).
+    pub fn get_push_rule(&mut self, scope: &str, kind: PushRuleKind, rule_id: &str) -> MatrixFuture<PushRule> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["pushrules", scope, kind.as_str(), rule_id]))
+            .send(self)
+    }
+    /// Add (or replace) a push rule for the current user, in `scope`
+    /// (usually `global`).
+    ///
+    /// Only the rule's ID, actions, conditions and pattern are sent; new
+    /// rules are always enabled, and go before the other rules of the same
+    /// kind.
+    pub fn set_push_rule(&mut self, scope: &str, kind: PushRuleKind, rule: &PushRule) -> MatrixFuture<()> {
+        let mut body = json!({ "actions": rule.actions });
+        if let Some(ref c) = rule.conditions {
+            body["conditions"] = json!(c);
+        }
+        if let Some(ref p) = rule.pattern {
+            body["pattern"] = json!(p);
+        }
+        MatrixRequest::new_with_body_ser(Put, MatrixRequest::endpoint_with_segments(&["pushrules", scope, kind.as_str(), &rule.rule_id]), body)
+            .discarding_send(self)
+    }
+    /// Delete one of the current user's push rules.
+    ///
+    /// The homeserver's default rules can't be deleted, only disabled.
+    pub fn delete_push_rule(&mut self, scope: &str, kind: PushRuleKind, rule_id: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_basic(Delete, MatrixRequest::endpoint_with_segments(&["pushrules", scope, kind.as_str(), rule_id]))
+            .discarding_send(self)
+    }
+    /// Enable or disable one of the current user's push rules.
+    pub fn set_push_rule_enabled(&mut self, scope: &str, kind: PushRuleKind, rule_id: &str, enabled: bool) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body_ser(Put, MatrixRequest::endpoint_with_segments(&["pushrules", scope, kind.as_str(), rule_id, "enabled"]),
+                                         json!({ "enabled": enabled }))
+            .discarding_send(self)
+    }
+    /// Change what one of the current user's push rules does when it
+    /// matches.
+    pub fn set_push_rule_actions(&mut self, scope: &str, kind: PushRuleKind, rule_id: &str, actions: &[PushAction]) -> MatrixFuture<()> {
+        MatrixRequest::new_with_body_ser(Put, MatrixRequest::endpoint_with_segments(&["pushrules", scope, kind.as_str(), rule_id, "actions"]),
+                                         json!({ "actions": actions }))
+            .discarding_send(self)
+    }
+    /// Set whether the current user gets notified about every message in a
+    /// room, with a `room` push rule; with `notify` set to `false`, this mutes
+    /// the room.
+    ///
+    /// To go back to the default behaviour, delete the rule with
+    /// `delete_push_rule()`.
+    pub fn set_room_rule(&mut self, room_id: &str, notify: bool) -> MatrixFuture<()> {
+        // An empty list of actions is how to not notify; `dont_notify` is
+        // deprecated, and newer homeservers ignore it.
+        let actions = if notify { vec![PushAction::Notify] } else { vec![] };
+        self.set_push_rule("global", PushRuleKind::Room, &PushRule::new(room_id, actions))
+    }
+    /// List the current user's pushers, which send their notifications
+    /// somewhere (like to their phone).
+    pub fn get_pushers(&mut self) -> MatrixFuture<Vec<Pusher>> {
+        Box::new(MatrixRequest::new_basic(Get, "/pushers")
+                 .send(self)
+                 .map(|rpl: PushersReply| rpl.pushers))
+    }
+    /// Add a pusher for the current user, or change the one with the same
+    /// `app_id` and `pushkey`.
+    ///
+    /// If `append` is `false`, other users' pushers with the same `app_id`
+    /// and `pushkey` are removed, so only one account on a device gets
+    /// notifications sent to it.
+    pub fn set_pusher(&mut self, pusher: &Pusher, append: bool) -> MatrixFuture<()> {
+        let mut body = json!(pusher);
+        if append {
+            body["append"] = json!(true);
+        }
+        MatrixRequest::new_with_body_ser(Post, "/pushers/set", body)
+            .discarding_send(self)
+    }
+    /// Remove the current user's pusher with the given `app_id` and
+    /// `pushkey`.
+    pub fn delete_pusher(&mut self, app_id: &str, pushkey: &str) -> MatrixFuture<()> {
+        // A pusher is removed by setting it again with a `kind` of `null`.
+        MatrixRequest::new_with_body_ser(Post, "/pushers/set", json!({
+            "app_id": app_id,
+            "pushkey": pushkey,
+            "kind": null
+        })).discarding_send(self)
+    }
+    /// Get one page of the notifications the user has got, most recent
+    /// first.
+    ///
+    /// `from` is the `next_token` of a previous reply to carry on from, and
+    /// `limit` the maximum number of notifications to get. If `only` is
+    /// `Some("highlight")`, only notifications for highlighted events (like
+    /// mentions) are returned. To get all of them, use
+    /// `notifications_stream()`.
+    pub fn notifications(&mut self, from: Option<&str>, limit: Option<u32>, only: Option<&str>) -> MatrixFuture<NotificationsReply> {
+        let mut req = MatrixRequest::get("/notifications");
+        if let Some(f) = from {
+            req = req.param("from", f);
+        }
+        if let Some(l) = limit {
+            req = req.param("limit", l);
+        }
+        if let Some(o) = only {
+            req = req.param("only", o);
+        }
+        req.send(self)
+    }
+    /// Go through the notifications the user has got, one at a time, most
+    /// recent first, fetching pages of up to `limit` at a time.
+    ///
+    /// See `NotificationsStream` and `notifications()` for more.
+    pub fn notifications_stream(&mut self, limit: Option<u32>, only: Option<&str>) -> NotificationsStream<'_> {
+        NotificationsStream::new(self, limit, only)
+    }
+    fn put_account_data<T: Serialize>(&mut self, ep: String, content: T) -> MatrixFuture<()> {
+        let mut req = match MatrixRequest::new_with_body_ser(Put, ep, content).make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        if req.body_ref().is_none() {
+            // Empty content is still content.
+            set_empty_json_body(&mut req);
+        }
+        self.send_discarding_request(req)
+    }
+    /// Get one page of a public room directory (our homeserver's, unless
+    /// `PublicRoomsParams::server` is set).
+    ///
+    /// To go through the whole directory, use `public_rooms_stream()`.
+    ///
+    /// Clients that aren't logged in can list the directory (with `GET`), but
+    /// searching it (with `POST`, which `PublicRoomsParams::search()` needs)
+    /// takes an access token.
+    pub fn public_rooms(&mut self, params: &PublicRoomsParams) -> MatrixFuture<PublicRoomsReply> {
+        if self.access_token.borrow().is_none() && params.filter.is_none() {
+            let mut req = MatrixRequest::get("/publicRooms");
+            if let Some(l) = params.limit {
+                req = req.param("limit", l);
+            }
+            if let Some(ref since) = params.since {
+                req = req.param("since", since as &str);
+            }
+            if let Some(ref server) = params.server {
+                req = req.param("server", server as &str);
+            }
+            return req.send(self);
+        }
+        let mut req = MatrixRequest::new_with_body_ser(Post, "/publicRooms", params);
+        if let Some(ref server) = params.server {
+            req = req.param("server", server as &str);
+        }
+        let mut req = match req.make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        if req.body_ref().is_none() {
+            set_empty_json_body(&mut req);
+        }
+        self.send_request(req)
+    }
+    /// Go through a public room directory, one room at a time, starting from
+    /// `params.since` (or the beginning, if it's not set).
+    ///
+    /// See `PublicRoomsStream` for more.
+    pub fn public_rooms_stream(&mut self, params: PublicRoomsParams) -> PublicRoomsStream<'_> {
+        PublicRoomsStream::new(self, params)
+    }
+    /// Search the events in the rooms the user is in, returning one page of
+    /// results.
+    ///
+    /// To get the next page, send the same `criteria` again with the
+    /// `next_batch` token of the reply; `search_stream()` does this for you.
+    pub fn search(&mut self, criteria: &RoomEventsCriteria, next_batch: Option<&str>) -> MatrixFuture<SearchReply> {
+        let body = json!({
+            "search_categories": {
+                "room_events": criteria
+            }
+        });
+        let mut req = MatrixRequest::new_with_body_ser(Post, "/search", body);
+        if let Some(nb) = next_batch {
+            req = req.param("next_batch", nb);
+        }
+        req.send(self)
+    }
+    /// Go through the results of a search, one at a time.
+    ///
+    /// See `SearchStream` for more.
+    pub fn search_stream(&mut self, criteria: RoomEventsCriteria) -> SearchStream<'_> {
+        SearchStream::new(self, criteria)
+    }
+    /// Search the user directory for users whose MXID or display name match
+    /// `search_term`, returning at most `limit` of them (or the homeserver's
+    /// default number, if that's `None`).
+    ///
+    /// Which users are found is up to the homeserver: usually those who share
+    /// a room with the current user, or are in a public room.
+    pub fn search_users(&mut self, search_term: &str, limit: Option<u32>) -> MatrixFuture<UserDirectoryReply> {
+        let mut body = json!({ "search_term": search_term });
+        if let Some(l) = limit {
+            body["limit"] = l.into();
+        }
+        MatrixRequest::new_with_body_ser(Post, "/user_directory/search", body)
+            .send(self)
+    }
+    /// Look up the room a room alias (like `#room:example.org`) points to.
+    pub fn resolve_alias(&mut self, alias: &str) -> MatrixFuture<ResolveAliasReply> {
+        MatrixRequest::new_basic(Get, MatrixRequest::endpoint_with_segments(&["directory", "room", alias]))
+            .send(self)
+    }
+    /// Make a room alias point to a room.
+    ///
+    /// If the alias already exists (even if it points to the same room), this
+    /// fails with an `AliasInUse` error, the original of which is in the
+    /// error's chain.
+    pub fn create_alias(&mut self, alias: &str, room_id: &str) -> MatrixFuture<()> {
+        Box::new(MatrixRequest::new_with_body(Put, MatrixRequest::endpoint_with_segments(&["directory", "room", alias]),
+                                              vec![("room_id", room_id)])
+                 .discarding_send(self)
+                 .map_err(|e| {
+                     let in_use = match *e.kind() {
+                         // Synapse says `M_UNKNOWN` here, but the status code
+                         // is always a 409.
+                         MatrixErrorKind::BadRequest(_, hyper::StatusCode::Conflict) => true,
+                         MatrixErrorKind::HttpCode(hyper::StatusCode::Conflict) => true,
+                         _ => false
+                     };
+                     if in_use {
+                         MatrixError::with_chain(e, MatrixErrorKind::AliasInUse)
+                     }
+                     else {
+                         e
+                     }
+                 }))
+    }
+    /// Delete a room alias.
+    pub fn delete_alias(&mut self, alias: &str) -> MatrixFuture<()> {
+        MatrixRequest::new_basic(Delete, MatrixRequest::endpoint_with_segments(&["directory", "room", alias]))
+            .discarding_send(self)
+    }
+    /// Upload some data (convertible to a `Body`) of a given `ContentType`, like an
+    /// image, to the content repository, and return its `mxc://` URI.
+    ///
+    /// `Body` is accessible via the `http` module. See the documentation there
+    /// for a complete reference of what implements `Into<Body>` - a quick
+    /// shortlist: `Vec<u8>`, `&'static [u8]` (not `&'a [u8]`, sadly), `String`,
+    /// `&'static str`.
+    ///
+    /// `ContentType` is accessible via the `http` module. See the documentation
+    /// there for more information on how to use it.
+    ///
+    /// `filename` is the name of the file being uploaded, if it has one. If the
+    /// homeserver rejects the upload for being too big, this fails with an
+    /// `UploadTooLarge` error.
+    ///
+    /// Since the length of a `Body` isn't known, it's sent in chunks, which
+    /// some homeservers don't accept for uploads; `upload_bytes()` sends a
+    /// `Content-Length` instead.
+    pub fn upload<T: Into<Body>>(&mut self, data: T, ct: ContentType, filename: Option<&str>) -> MatrixFuture<ContentUri> {
+        self.upload_body(data.into(), None, ct, filename)
+    }
+    /// Does the work of `upload()`, sending a `Content-Length` if the length
+    /// of `data` is known (otherwise, `hyper` sends it in chunks).
+    fn upload_body(&mut self, data: Body, len: Option<u64>, ct: ContentType, filename: Option<&str>) -> MatrixFuture<ContentUri> {
+        let mut req = MatrixRequest::new(Post, "/upload", (), request::apis::v3::MediaApi);
+        if let Some(f) = filename {
+            req = req.param("filename", f);
+        }
+        let mut req = match req.make_hyper(self) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        req.set_body(data);
+        req.headers_mut().set(ct);
+        if let Some(len) = len {
+            req.headers_mut().set(ContentLength(len));
+        }
+        Box::new(self.send_request(req)
+                 .map(|rpl: UploadReply| rpl.content_uri)
+                 .map_err(|e| {
+                     let too_large = match *e.kind() {
+                         MatrixErrorKind::BadRequest(ref brq, _) if brq.errcode == "M_TOO_LARGE" => true,
+                         MatrixErrorKind::HttpCode(hyper::StatusCode::PayloadTooLarge) => true,
+                         _ => false
+                     };
+                     if too_large {
+                         MatrixError::with_chain(e, MatrixErrorKind::UploadTooLarge)
+                     }
+                     else {
+                         e
+                     }
+                 }))
+    }
+    /// Upload some bytes to the content repository, with the content type
+    /// given as a string (like `image/png`), returning their `mxc://` URI.
+    ///
+    /// This is `upload()`, for when the content type isn't known until
+    /// runtime. The data is handed to `hyper` as-is, without being copied,
+    /// unless the request might need sending again (because rate-limited
+    /// requests are retried, or a reauthentication handler is set).
+    ///
+    /// If `content_type` isn't a valid MIME type, this fails with an
+    /// `InvalidContentType` error without sending anything. Likewise, if the
+    /// homeserver's upload size limit is known (see `media_config()`) and
+    /// `data` is bigger than it, this fails with an `UploadOverLimit` error.
+    pub fn upload_bytes(&mut self, data: Vec<u8>, content_type: &str, filename: Option<&str>) -> MatrixFuture<ContentUri> {
+        if let Some(limit) = self.upload_size_limit.get() {
+            if data.len() as u64 > limit {
+                return Box::new(futures::future::err(MatrixErrorKind::UploadOverLimit(data.len() as u64, limit).into()));
+            }
+        }
+        let mime = match content_type.parse::<hyper::mime::Mime>() {
+            Ok(m) => m,
+            Err(_) => return Box::new(futures::future::err(MatrixErrorKind::InvalidContentType(content_type.into()).into()))
+        };
+        let len = data.len() as u64;
+        self.upload_body(data.into(), Some(len), ContentType(mime), filename)
+    }
+    /// Upload a file to the content repository, then send it to a room as an
+    /// `m.file` message, returning the event ID of the message.
+    ///
+    /// `content_type` and the size of `data` go in the message's `info`. If
+    /// the upload fails (or `data` is over the homeserver's upload size
+    /// limit; see `upload_bytes()`), no message is sent.
+    pub fn send_file(&mut self, room_id: &str, data: Vec<u8>, content_type: &str, filename: &str) -> MatrixFuture<SendReply> {
+        let info = FileInfo {
+            mimetype: Some(content_type.into()),
+            size: Some(data.len() as u64),
+            ..Default::default()
+        };
+        let upload = self.upload_bytes(data, content_type, Some(filename));
+        let filename = filename.to_owned();
+        let msg = upload.map(move |uri| Message::file(filename, uri.to_string(), Some(info)));
+        self.send_media_message(room_id, msg)
+    }
+    /// Like `send_file()`, but sends an `m.image` message, with the given
+    /// width and height (in pixels) of the image.
+    pub fn send_image(&mut self, room_id: &str, data: Vec<u8>, content_type: &str, filename: &str, width: u32, height: u32) -> MatrixFuture<SendReply> {
+        let info = ImageInfo {
+            mimetype: Some(content_type.into()),
+            size: Some(data.len() as u64),
+            w: Some(width),
+            h: Some(height),
+            ..Default::default()
+        };
+        let upload = self.upload_bytes(data, content_type, Some(filename));
+        let filename = filename.to_owned();
+        let msg = upload.map(move |uri| Message::image(filename, uri.to_string(), Some(info)));
+        self.send_media_message(room_id, msg)
+    }
+    fn send_media_message<F>(&mut self, room_id: &str, msg: F) -> MatrixFuture<SendReply>
+        where F: Future<Item = Message, Error = MatrixError> + 'static {
+        let txnid = self.new_txn_id();
+        let put = MatrixRequest::put(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "send", "m.room.message", &txnid]));
+        self.send_with_deferred_body(put, Box::new(msg))
+    }
+    /// Download a piece of content from the content repository.
+    ///
+    /// Redirects sent by the homeserver are followed.
+    pub fn download(&mut self, uri: &ContentUri) -> MatrixFuture<MediaDownload> {
+        let ep = MatrixRequest::endpoint_with_segments(&["download", &uri.server_name, &uri.media_id]);
+        let req = MatrixRequest::new(Get, ep, (), request::apis::v3::MediaApi)
+            .param("allow_remote", self.allow_remote_media);
+        self.send_media_request(req)
+    }
+    /// Download a thumbnail of a piece of content from the content
+    /// repository, ideally `width` by `height` pixels in size.
+    ///
+    /// The homeserver may give back a thumbnail of a different size, or the
+    /// original content if it can't make one.
+    pub fn thumbnail(&mut self, uri: &ContentUri, width: u32, height: u32, method: ThumbnailMethod) -> MatrixFuture<MediaDownload> {
+        let ep = MatrixRequest::endpoint_with_segments(&["thumbnail", &uri.server_name, &uri.media_id]);
+        let req = MatrixRequest::new(Get, ep, (), request::apis::v3::MediaApi)
+            .param("width", width)
+            .param("height", height)
+            .param("method", method.as_str())
+            .param("allow_remote", self.allow_remote_media);
+        self.send_media_request(req)
+    }
+    /// Like `download()`, but giving back the content as a `Stream` of chunks
+    /// as soon as it starts arriving, instead of waiting for all of it.
+    ///
+    /// The client's request timeout only applies to getting the start of the
+    /// content, and rate-limited requests aren't retried.
+    pub fn download_stream(&mut self, uri: &ContentUri) -> MatrixFuture<MediaStream> {
+        let ep = MatrixRequest::endpoint_with_segments(&["download", &uri.server_name, &uri.media_id]);
+        let req = MatrixRequest::new(Get, ep, (), request::apis::v3::MediaApi)
+            .param("allow_remote", self.allow_remote_media);
+        let timeout = req.timeout.or(self.timeout);
+        let req = match self.make_media_request(req) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let fut = send_streaming_following_redirects(&self.hyper, req)
+            .map(|resp| {
+                MediaStream {
+                    content_type: resp.headers().get::<ContentType>().cloned(),
+                    filename: filename_from_headers(resp.headers()),
+                    content_length: resp.headers().get::<ContentLength>().map(|l| l.0),
+                    body: resp.body()
+                }
+            });
+        with_timeout(Box::new(fut), timeout, &self.hdl)
+    }
+    fn send_media_request<U: request::ApiType>(&mut self, req: MatrixRequest<(), U>) -> MatrixFuture<MediaDownload> {
+        let timeout = req.timeout.or(self.timeout);
+        let req = match self.make_media_request(req) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let fut = send_following_redirects(&self.hyper, &self.hdl, &self.rate_limit, req)
+            .map(|(data, content_type, filename)| MediaDownload { data, content_type, filename });
+        with_timeout(Box::new(fut), timeout, &self.hdl)
+    }
+    /// Makes a request for some media, without the access token: the media
+    /// endpoints don't need it, and a token in the query string would end up
+    /// in the homeserver's (and any CDN's) logs.
+    fn make_media_request<U: request::ApiType>(&self, req: MatrixRequest<(), U>) -> MatrixResult<Request> {
+        let mut req = req.make_hyper(self)?;
+        strip_access_token(&mut req)?;
+        // Media is streamed as it comes in, so it can't be decompressed.
+        req.headers_mut().remove_raw("Accept-Encoding");
+        Ok(req)
+    }
+    /// Get a preview of a URL (like its title and an image), as the
+    /// homeserver sees it, optionally as it was at the time `ts` (in
+    /// milliseconds since the Unix epoch).
+    ///
+    /// If the homeserver has URL previews disabled, this fails with a
+    /// `UrlPreviewsDisabled` error, the original of which is in the error's
+    /// chain.
+    pub fn preview_url(&mut self, url: &str, ts: Option<u64>) -> MatrixFuture<UrlPreview> {
+        let mut req = MatrixRequest::new(Get, "/preview_url", (), request::apis::v3::MediaApi)
+            .param("url", url);
+        if let Some(ts) = ts {
+            req = req.param("ts", ts);
+        }
+        Box::new(req.send(self)
+                 .map_err(|e| {
+                     let disabled = match *e.kind() {
+                         // Synapse doesn't have the endpoint at all unless
+                         // previews are turned on.
+                         MatrixErrorKind::BadRequest(ref brq, _) if brq.errcode == "M_UNRECOGNIZED" => true,
+                         MatrixErrorKind::HttpCode(hyper::StatusCode::NotFound) => true,
+                         _ => false
+                     };
+                     if disabled {
+                         MatrixError::with_chain(e, MatrixErrorKind::UrlPreviewsDisabled)
+                     }
+                     else {
+                         e
+                     }
+                 }))
+    }
+    /// Get the configuration of the homeserver's content repository, like
+    /// the largest upload it accepts.
+    ///
+    /// The upload size limit is remembered, so that `upload_bytes()` (and
+    /// `send_file()` and `send_image()`) can refuse to send anything bigger
+    /// without having to upload it first.
+    pub fn media_config(&mut self) -> MatrixFuture<MediaConfig> {
+        let limit = self.upload_size_limit.clone();
+        Box::new(MatrixRequest::new(Get, "/config", (), request::apis::v3::MediaApi)
+                 .send(self)
+                 .map(move |cfg: MediaConfig| {
+                     limit.set(cfg.upload_size);
+                     cfg
+                 }))
+    }
+    /// Get the homeserver's upload size limit in bytes, as last fetched by
+    /// `media_config()` (or set with `set_upload_size_limit()`).
+    ///
+    /// This is `None` if it isn't known, or the homeserver has no limit.
+    pub fn upload_size_limit(&self) -> Option<u64> {
+        self.upload_size_limit.get()
+    }
+    /// Set the upload size limit that `upload_bytes()` checks against, like
+    /// one saved from a previous `media_config()`.
+    pub fn set_upload_size_limit(&mut self, limit: Option<u64>) {
+        self.upload_size_limit.set(limit);
+    }
+    /// Set whether `download()` and `thumbnail()` ask the homeserver to fetch
+    /// content hosted on other homeservers (with the `allow_remote`
+    /// parameter). If not, such requests fail with `M_NOT_FOUND`.
+    pub fn set_allow_remote_media(&mut self, v: bool) {
+        self.allow_remote_media = v;
+    }
+    /// Ascertain whether content hosted on other homeservers can be fetched.
+    ///
+    /// The default value is `true`.
+    pub fn allow_remote_media(&self) -> bool {
+        self.allow_remote_media
+    }
+    /// Set whether requests can be sent to the homeserver over plain HTTP.
+    ///
+    /// Without this, requests to an `http://` homeserver URL fail with an
+    /// `InsecureHttp` error without being sent, since they'd give away the
+    /// access token (or password) to anyone listening. Homeservers on the
+    /// same machine (`localhost`, or a loopback address) are always allowed.
+    pub fn set_allow_insecure_http(&mut self, v: bool) {
+        self.allow_insecure_http = v;
+    }
+    /// Ascertain whether requests can be sent over plain HTTP.
+    ///
+    /// The default value is `false`.
+    pub fn allow_insecure_http(&self) -> bool {
+        self.allow_insecure_http
+    }
+    /// Send all of the client's requests through `proxy`, or connect
+    /// directly if it's `None`.
+    ///
+    /// By default, the proxy in the environment (see `Proxy::from_env()`)
+    /// is used, if there is one. This replaces the client's `hyper::Client`
+    /// (so it loses any custom TLS settings given to `new_with_hyper()`;
+    /// use `hyper_with_tls_proxy()` for both), and only applies to
+    /// `SyncStream`s made afterwards.
+    pub fn set_proxy(&mut self, proxy: Option<Proxy>) -> MatrixResult<()> {
+        let ssl = http::SslConnectorBuilder::new(http::SslMethod::tls())
+            .chain_err(|| MatrixErrorKind::TlsUnavailable)?;
+        self.hyper = Self::hyper_with_tls_proxy(ssl, proxy.clone(), &self.hdl)?;
+        self.proxy = proxy;
+        Ok(())
+    }
+    /// Send `user_agent` as the `User-Agent` of every request, or none if
+    /// it's `None` (the default).
+    ///
+    /// Like `set_proxy()`, this only applies to `SyncStream`s made
+    /// afterwards.
+    pub fn set_user_agent(&mut self, user_agent: Option<String>) {
+        self.user_agent = user_agent;
+    }
+    /// Get the `User-Agent` sent with every request, if any.
+    pub fn user_agent(&self) -> Option<&str> {
+        self.user_agent.as_ref().map(|x| x as &str)
+    }
+    /// Set whether to ask the homeserver to compress its responses (with
+    /// `Accept-Encoding: gzip, deflate`).
+    ///
+    /// Compressed responses are decompressed before they're parsed, so this
+    /// only changes how much data goes over the network; large `/sync`
+    /// replies shrink a lot. Turn it off if something in between (like a
+    /// misbehaving proxy) mangles them. Media downloads are never
+    /// compressed. Like `set_proxy()`, this only applies to `SyncStream`s
+    /// made afterwards.
+    ///
+    /// A compressed response that decompresses to more than 64 MiB fails
+    /// with a `ResponseTooLarge` error.
+    pub fn set_compression(&mut self, v: bool) {
+        self.compression = v;
+    }
+    /// Ascertain whether the homeserver is asked to compress its responses.
+    ///
+    /// The default value is `true`.
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+    /// Get the identity server that the homeserver's well-known file
+    /// advertises, if this client was made with `discover()` and it
+    /// advertises one.
+    pub fn identity_server(&self) -> Option<&str> {
+        self.identity_server.as_ref().map(|x| x as &str)
+    }
+    /// Get the proxy set with `set_proxy()`, if any.
+    pub fn proxy(&self) -> Option<&Proxy> {
+        self.proxy.as_ref()
+    }
+    /// Get the base URL of the homeserver the client talks to.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+    /// Get the client's MXID.
+    pub fn user_id(&self) -> &str {
+        &self.user_id
+    }
+    /// Get the client's device ID, if known (it is after logging in).
+    pub fn device_id(&self) -> Option<&str> {
+        self.device_id.as_ref().map(|x| x as &str)
+    }
+    /// Get the client's access token, if it has one.
+    ///
+    /// This is a copy, since the token can change under the client's feet
+    /// when a reauthentication handler gets a new one (or `refresh()` does),
+    /// so it can't be lent out like the client's other fields.
+    pub fn access_token(&self) -> Option<String> {
+        self.access_token.borrow().clone()
+    }
+    /// Get rid of the client, but keep its access token, without logging
+    /// out (so the token stays valid).
+    ///
+    /// Dropping a client normally logs it out; this is for when the token
+    /// should outlive the client, like in a reauthentication handler that
+    /// logs in again.
+    ///
+    /// `SyncStream`s made from the client share its token, so they keep
+    /// working afterwards.
+    pub fn into_access_token(self) -> Option<String> {
+        // Only this client is logged out; the token itself is shared.
+        self.logged_out.set(true);
+        let token = self.access_token.borrow().clone();
+        token
+    }
+    /// Set a function to call to get a new access token (say, by logging in
+    /// again) when a request fails because the client's one has stopped
+    /// working, with an `M_UNKNOWN_TOKEN` error.
+    ///
+    /// The client then uses the new token, and sends the failed request again;
+    /// this only happens once per request, so if the new token doesn't work
+    /// either, the request fails as usual. Requests that fail after another
+    /// one has already got a new token use that one, without calling the
+    /// handler; but if several fail before the handler's first call has
+    /// finished, it's called for each of them, so it should cope with
+    /// that.
+    ///
+    /// The handler also applies to `SyncStream`s and `TypingGuard`s made
+    /// afterwards. Streams and requests using the
+    /// `MatrixClient::send_request()` family go through it too, but media
+    /// downloads don't.
+    pub fn set_reauth_handler<F>(&mut self, handler: F) where F: Fn() -> MatrixFuture<String> + 'static {
+        self.reauth_handler = Some(Rc::new(handler));
+    }
+    /// Call `hook` after each request the client sends finishes (whether it
+    /// succeeded or not), with its method, endpoint, status code and how
+    /// long it took; this is for feeding metrics or tracing systems.
+    ///
+    /// The endpoint has IDs replaced by placeholders (see
+    /// `MatrixRequest::endpoint_template()`), so that it's fine to use as a
+    /// metric label. Requests of `SyncStream`s and `TypingGuard`s made
+    /// afterwards are covered too, as are media uploads; media downloads
+    /// aren't.
+    pub fn set_metrics_hook<F>(&mut self, hook: F) where F: Fn(&RequestMetrics) + 'static {
+        self.metrics_hook = Some(Rc::new(hook));
+    }
+    /// Stop using the function set with `set_metrics_hook()`, if any.
+    pub fn remove_metrics_hook(&mut self) {
+        self.metrics_hook = None;
+    }
+    /// Stop using the function set with `set_reauth_handler()`, if any.
+    pub fn remove_reauth_handler(&mut self) {
+        self.reauth_handler = None;
+    }
+    fn reauth(&self) -> Option<Reauth> {
+        self.reauth_handler.as_ref().map(|h| Reauth {
+            handler: h.clone(),
+            token: self.access_token.clone(),
+            token_in_query: self.token_in_query
+        })
+    }
+    /// Get the client's refresh token, if it has one.
+    ///
+    /// Like `access_token()`, this is a copy, since `refresh()` replaces the
+    /// token once the homeserver answers.
+    pub fn refresh_token(&self) -> Option<String> {
+        self.refresh_token.borrow().clone()
+    }
+    /// Set the refresh token for `refresh()` to use, like one saved from
+    /// `refresh_token()` alongside the access token.
+    pub fn set_refresh_token(&mut self, token: Option<String>) {
+        *self.refresh_token.borrow_mut() = token;
+    }
+    /// Get when the client's access token expires, if the homeserver said it
+    /// does (when logging in, or in reply to `refresh()`).
+    ///
+    /// Call `refresh()` a little before then to keep the client working.
+    pub fn token_expiry(&self) -> Option<Instant> {
+        self.token_expiry.get()
+    }
+    /// Set whether the access token is sent in the query string
+    /// (`?access_token=...`), instead of in an `Authorization` header.
+    ///
+    /// Only use this for old homeservers that don't understand the header, as
+    /// tokens in URLs tend to end up in access logs.
+    pub fn set_token_in_query(&mut self, v: bool) {
+        self.token_in_query = v;
+    }
+    /// Ascertain whether the access token is sent in the query string.
+    ///
+    /// The default value is `false`; the token is sent in an
+    /// `Authorization: Bearer` header.
+    pub fn token_in_query(&self) -> bool {
+        self.token_in_query
+    }
+    /// Set the path prefix used for client-server API requests, like
+    /// `/_matrix/client/r0` for homeservers that don't support `v3` yet.
+    ///
+    /// This affects all requests made with the default `ClientApi` request
+    /// type; requests can still target other APIs by specifying another type
+    /// (see the `request::apis` module).
+    pub fn set_api_prefix<T: Into<Cow<'static, str>>>(&mut self, prefix: T) {
+        self.api_prefix = prefix.into();
+    }
+    /// Get the path prefix used for client-server API requests.
+    ///
+    /// The default value is `/_matrix/client/v3`.
+    pub fn api_prefix(&self) -> &str {
+        &self.api_prefix
+    }
+    /// Set how requests that get rate-limited by the homeserver are retried.
+    ///
+    /// This applies to `SyncStream`s made afterwards too. See the
+    /// `RateLimitConfig` docs for more.
+    pub fn set_rate_limit_config(&mut self, cfg: RateLimitConfig) {
+        self.rate_limit = cfg;
+    }
+    /// Retry requests that get rate-limited up to `n` times, waiting for as
+    /// long as the homeserver advises between attempts.
+    ///
+    /// This is shorthand for changing `max_retries` in the client's
+    /// `RateLimitConfig`. If the retries are exhausted, the last
+    /// `M_LIMIT_EXCEEDED` error is returned.
+    pub fn set_rate_limit_retries(&mut self, n: u32) {
+        self.rate_limit.max_retries = n;
+    }
+    /// Get the configuration used to retry rate-limited requests.
+    pub fn rate_limit_config(&self) -> &RateLimitConfig {
+        &self.rate_limit
+    }
+    /// Set the default timeout for requests, after which they fail with a
+    /// `Timeout` error (and the underlying HTTP request is dropped).
+    ///
+    /// This covers the whole request, including any retries. Individual
+    /// `MatrixRequest`s can override it with their `timeout` field.
+    ///
+    /// `SyncStream`s obtained after calling this will use the timeout as a
+    /// grace period on top of their long-polling timeout.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+    /// Get the default timeout for requests.
+    ///
+    /// The default value is `None`; requests can take as long as they like.
+    pub fn request_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+    /// Sync once with the homeserver, from the batch token `since`
+    /// (or doing an initial sync, if `None`), waiting up to `timeout`
+    /// milliseconds for events to arrive.
+    ///
+    /// See `SyncRequest` for more options, and `sync_stream()` for syncing
+    /// continuously.
+    pub fn sync(&mut self, since: Option<&str>, timeout: u64) -> MatrixFuture<SyncReply> {
+        let mut req = SyncRequest::new().timeout(timeout);
+        req.since = since.map(|x| x.to_string());
+        req.send(self)
+    }
+    /// Get a `SyncStream`, a `Stream` used to obtain replies to the `/sync`
+    /// API.
+    ///
+    /// This `SyncStream` does not borrow from the original `MatrixClient`,
+    /// but shares its access token, so it uses any new one the client gets
+    /// (from a reauthentication handler, or `refresh()`). It starts with an
+    /// initial sync, unless given a batch token to resume from with
+    /// `set_since()`.
+    pub fn sync_stream(&self) -> SyncStream {
+        // Stop the proxy (if any) from giving up on long-polling requests.
+        let timeout = match self.proxy.as_ref().and_then(|p| p.get_idle_timeout()) {
+            Some(idle) => ::std::cmp::min(30000, (idle.as_secs() * 1000 + idle.subsec_millis() as u64) * 3 / 4),
+            None => 30000
+        };
+        SyncStream {
+            hyper: self.hyper.clone(),
+            last_batch: None,
+            set_presence: true,
+            access_token: self.access_token.clone(),
+            reauth: self.reauth(),
+            rate_limit: self.rate_limit.clone(),
+            token_in_query: self.token_in_query,
+            api_prefix: self.api_prefix.clone(),
+            url: self.url.clone(),
+            timeout,
+            timeout_grace: self.timeout,
+            hdl: self.hdl.clone(),
+            filter: None,
+            first_full_state: false,
+            reset_stale_since: true,
+            allow_insecure_http: self.allow_insecure_http,
+            user_agent: self.user_agent.clone(),
+            compression: self.compression,
+            metrics_hook: self.metrics_hook.clone(),
+            error_backoff: Some(Duration::from_secs(1)),
+            max_backoff: Duration::from_secs(60),
+            next_backoff: None,
+            backoff_timer: None,
+            cur_req: None
+        }
+    }
+    /// Get a `SyncStream`; another name for `sync_stream()`.
+    pub fn get_sync_stream(&self) -> SyncStream {
+        self.sync_stream()
+    }
+    /// Sends an arbitrary `Request` to the Matrix homeserver, like one
+    /// generated by `get_request_for()`.
+    ///
+    /// If the request gets rate-limited, it may be retried, depending on the
+    /// `RateLimitConfig` in use.
+    ///
+    /// The client's default request timeout (if any) applies.
+    pub fn send_request<T>(&mut self, req: Request) -> MatrixFuture<T> where T: DeserializeOwned + 'static {
+        let timeout = self.timeout;
+        self.send_request_with_timeout(req, timeout)
+    }
+    /// Like `send_request()`, but discards the return value that the Matrix
+    /// homeserver sends back.
+    pub fn send_discarding_request(&mut self, req: Request) -> MatrixFuture<()> {
+        let timeout = self.timeout;
+        self.send_discarding_request_with_timeout(req, timeout)
+    }
+    /// Like `send_request()`, but with a given timeout instead of the
+    /// client's default one.
+    pub fn send_request_with_timeout<T>(&mut self, req: Request, timeout: Option<Duration>) -> MatrixFuture<T> where T: DeserializeOwned + 'static {
+        self.send_wrapped(req, timeout, ResponseWrapper::<T>::wrap)
+    }
+    /// Like `send_discarding_request()`, but with a given timeout instead of
+    /// the client's default one.
+    pub fn send_discarding_request_with_timeout(&mut self, req: Request, timeout: Option<Duration>) -> MatrixFuture<()> {
+        self.send_wrapped(req, timeout, UnitaryResponseWrapper::wrap)
+    }
+    /// Like `send_request_with_timeout()`, but also returns the headers of
+    /// the homeserver's response (like `Retry-After`, or ones specific to
+    /// the homeserver).
+    ///
+    /// Only the headers of the final response are returned, if the request
+    /// was retried.
+    pub fn send_request_with_response<T>(&mut self, req: Request, timeout: Option<Duration>) -> MatrixFuture<(T, http::Headers)> where T: DeserializeOwned + 'static {
+        self.send_wrapped(req, timeout, HeadersResponseWrapper::<T>::wrap)
+    }
+    /// Like `send_request()`, but for requests that might need
+    /// User-Interactive Authentication; if the homeserver asks for some, the
+    /// future resolves to a `UiaaResponse::AuthRequired` describing what it
+    /// wants.
+    pub fn send_uiaa_request<T>(&mut self, req: Request, timeout: Option<Duration>) -> MatrixFuture<UiaaResponse<T>> where T: DeserializeOwned + 'static {
+        self.send_wrapped(req, timeout, UiaaResponseWrapper::<T>::wrap)
+    }
+    /// Like `send_uiaa_request()`, but completes the User-Interactive
+    /// Authentication itself, by calling `stage` with the type of each stage
+    /// that could be done next (and what the homeserver said about it) until
+    /// it gives back an `AuthData` for one, and resubmitting the request with
+    /// it, until the request succeeds.
+    ///
+    /// The session ID is taken care of. If `stage` can't do any of the next
+    /// stages (by returning `None` for all of them), or a stage fails, the
+    /// future resolves to the last `UiaaResponse::AuthRequired`, whose
+    /// `errcode` and `error` say what went wrong.
+    pub fn send_uiaa_request_with_stages<T, F>(&mut self, req: Request, timeout: Option<Duration>, stage: F) -> MatrixFuture<UiaaResponse<T>>
+        where T: DeserializeOwned + 'static, F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let metrics = self.metrics_for(&req);
+        let reauth = self.reauth();
+        let status = metrics.as_ref().map(|m| m.status.clone()).unwrap_or_default();
+        let fut = send_uiaa_with_stages(&self.hyper, &self.hdl, &self.rate_limit, reauth, req, status, stage);
+        instrument(with_timeout(fut, timeout, &self.hdl), metrics)
+    }
+    fn send_wrapped<T, R>(&mut self, req: Request, timeout: Option<Duration>, wrap: fn(hyper::client::Response) -> R) -> MatrixFuture<T>
+        where R: Future<Item=T, Error=MatrixError> + 'static, T: 'static {
+        self.sender().send(req, timeout, wrap)
+    }
+    /// What `instrument()` needs to report on `req`, if there's a metrics
+    /// hook.
+    fn metrics_for(&self, req: &Request) -> Option<Metrics> {
+        self.metrics_hook.as_ref().map(|h| Metrics::new(h, req))
+    }
+    /// Something that sends requests like this client does, for requests
+    /// that are sent later, once the client isn't around.
+    pub(crate) fn sender(&self) -> RequestSender {
+        RequestSender {
+            hyper: self.hyper.clone(),
+            hdl: self.hdl.clone(),
+            rate_limit: self.rate_limit.clone(),
+            reauth: self.reauth(),
+            metrics_hook: self.metrics_hook.clone()
+        }
+    }
+    /// Get this `MatrixClient`'s underlying `hyper::Client`.
+    pub fn get_hyper(&mut self) -> &mut http::MatrixHyper {
+        &mut self.hyper
+    }
+}
+
+impl Drop for MatrixClient {
+    /// Invalidates our access token, so we don't have millions of devices.
+    /// Also sets us as offline.
+    fn drop(&mut self) {
+        if self.logged_out.get() || self.access_token.borrow().is_none() {
+            return;
+        }
+        let req = match MatrixRequest::new_basic(Post, "/logout").make_hyper(self) {
+            Ok(r) => r,
+            Err(_) => return
+        };
+        // Don't let a reauthentication handler log straight back in.
+        let sender = RequestSender { reauth: None, ..self.sender() };
+        let fut = sender.send(req, self.timeout, UnitaryResponseWrapper::wrap);
+        self.hdl.spawn(fut.map_err(|_| ()));
     }
 }
@@ -0,0 +1,58 @@
+//! Types for downloading content from the content repository.
+
+use hyper::{Body, Chunk};
+use hyper::header::ContentType;
+use futures::{Poll, Stream};
+use errors::*;
+pub use types::media::{ContentUri, MediaConfig, UrlPreview};
+
+/// How a thumbnail should be made.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ThumbnailMethod {
+    /// Crop the content to fill the requested size.
+    Crop,
+    /// Scale the content to fit in the requested size, keeping its aspect
+    /// ratio.
+    Scale
+}
+impl ThumbnailMethod {
+    /// Get the value of the `method` parameter for this method.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ThumbnailMethod::Crop => "crop",
+            ThumbnailMethod::Scale => "scale"
+        }
+    }
+}
+/// Some content obtained from `download()` or `thumbnail()`.
+#[derive(Clone, Debug)]
+pub struct MediaDownload {
+    /// The content itself.
+    pub data: Vec<u8>,
+    /// The content's type, if the homeserver said.
+    pub content_type: Option<ContentType>,
+    /// The content's filename, if the homeserver said (in a
+    /// `Content-Disposition` header).
+    pub filename: Option<String>
+}
+/// Some content obtained from `download_stream()`, which arrives bit by bit.
+///
+/// This is a `Stream` of chunks of the content, for content that's too big to
+/// keep in memory all at once.
+pub struct MediaStream {
+    /// The content's type, if the homeserver said.
+    pub content_type: Option<ContentType>,
+    /// The content's filename, if the homeserver said.
+    pub filename: Option<String>,
+    /// The content's size in bytes, if the homeserver said.
+    pub content_length: Option<u64>,
+    pub(crate) body: Body
+}
+impl Stream for MediaStream {
+    type Item = Chunk;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, MatrixError> {
+        self.body.poll().map_err(|e| e.into())
+    }
+}
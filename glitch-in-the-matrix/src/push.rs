@@ -0,0 +1,58 @@
+//! Utilities for notifications, with the `/notifications` API.
+
+use super::MatrixClient;
+use futures::*;
+use errors::*;
+use util::{PageItems, PageStream};
+
+pub use types::push::{Notification, NotificationsReply, PushAction, PushCondition, PushRule, PushRuleKind};
+pub use types::push::{Pusher, PusherData, PusherKind, Ruleset};
+
+/// A `Stream` of the notifications the user has got, most recent first.
+///
+/// Like `PublicRoomsStream`, each item is a single notification; pages are
+/// fetched as needed, following the `next_token` of each reply. The stream
+/// finishes when the homeserver returns no `next_token`, or an empty page.
+///
+/// Obtain one of these with `MatrixClient::notifications_stream()`.
+pub struct NotificationsStream<'a> {
+    inner: PageItems<'a, NotificationsQuery, NotificationsReply, Notification>
+}
+struct NotificationsQuery {
+    limit: Option<u32>,
+    only: Option<String>
+}
+impl<'a> NotificationsStream<'a> {
+    pub(crate) fn new(cli: &'a mut MatrixClient, limit: Option<u32>, only: Option<&str>) -> Self {
+        let query = NotificationsQuery {
+            limit,
+            only: only.map(|x| x.to_owned())
+        };
+        let pages = PageStream::new(cli, query,
+                                    |cli, q, from| cli.notifications(from, q.limit, q.only.as_ref().map(|x| x as &str)),
+                                    |_, rpl| (rpl.notifications, rpl.next_token));
+        NotificationsStream {
+            inner: PageItems::new(pages)
+        }
+    }
+    /// Get the token that the next page will be fetched from, if any.
+    ///
+    /// Notifications that have already been fetched, but not yet returned by
+    /// the stream, aren't covered by this token.
+    pub fn from(&self) -> Option<&str> {
+        self.inner.pages.from.as_ref().map(|x| x as &str)
+    }
+    /// Set the token to fetch the next page from, like one saved from
+    /// `from()`.
+    pub fn set_from(&mut self, from: Option<String>) {
+        self.inner.pages.from = from;
+    }
+}
+impl<'a> Stream for NotificationsStream<'a> {
+    type Item = Notification;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
@@ -1,27 +1,123 @@
 //! Type for making a generic request to the Matrix API.
 
 use std::borrow::Cow;
-use hyper::{Body, Method};
+use hyper::Method;
 use std::collections::HashMap;
+use std::time::Duration;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use hyper::client::Request;
+use hyper::header::{Authorization, Bearer, ContentLength, ContentType, Header, Headers, Raw};
+use hyper::mime::Mime;
 use super::{MatrixFuture, MatrixClient};
-use errors::MatrixResult;
+use types::replies::{AuthData, UiaaInfo, UiaaResponse};
+use errors::{MatrixErrorKind, MatrixResult};
 use serde_json;
-use percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
+use percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
 use futures;
+use util::{ACCEPT_ENCODING, check_insecure_http};
 
+define_encode_set! {
+    /// The set of characters percent-encoded in path segments.
+    ///
+    /// This is `PATH_SEGMENT_ENCODE_SET`, plus `+`: it's allowed in a path,
+    /// but some homeservers (and proxies in front of them) decode it as a
+    /// space anyway, which breaks aliases like `#c++:example.org`.
+    pub PATH_COMPONENT_ENCODE_SET = [PATH_SEGMENT_ENCODE_SET] | {'+'}
+}
+define_encode_set! {
+    /// The set of characters percent-encoded in query-string keys and values.
+    ///
+    /// This is `PATH_SEGMENT_ENCODE_SET`, plus the characters that mean
+    /// something special in a query string (notably `+`, which would otherwise
+    /// be decoded as a space).
+    pub QUERY_COMPONENT_ENCODE_SET = [PATH_SEGMENT_ENCODE_SET] | {'&', '=', '+', ';'}
+}
+fn json_utf8() -> Mime {
+    "application/json; charset=utf-8".parse().unwrap()
+}
 /// Describes the type of a Matrix API.
 pub trait ApiType {
     /// Get the base path which all requests to this API should contain.
     ///
-    /// For example, `ClientApi`, the struct for the client-server API, sets
-    /// this method to return `/_matrix/client/r0`
+    /// For example, `r0::ClientApi`, the struct for the client-server API at
+    /// revision 0, sets this method to return `/_matrix/client/r0`
     fn get_path<'a>(&'a self) -> Cow<'a, str>;
+    /// Get the base path to use when making a request with the given
+    /// `MatrixClient`.
+    ///
+    /// By default, this just returns `get_path()`.
+    fn get_path_for<'a>(&'a self, _cli: &'a MatrixClient) -> Cow<'a, str> {
+        self.get_path()
+    }
 }
 /// Types of Matrix APIs.
 pub mod apis {
+    use request::ApiType;
+    use std::borrow::Cow;
+    use MatrixClient;
+    /// The client-server API, at whatever prefix the `MatrixClient` has been
+    /// configured to use (`/_matrix/client/v3`, unless changed with
+    /// `MatrixClient::set_api_prefix()`).
+    ///
+    /// This is the default API type for a `MatrixRequest`.
+    pub struct ClientApi;
+    impl ApiType for ClientApi {
+        fn get_path(&self) -> Cow<'static, str> {
+            "/_matrix/client/v3".into()
+        }
+        fn get_path_for<'a>(&'a self, cli: &'a MatrixClient) -> Cow<'a, str> {
+            cli.api_prefix().into()
+        }
+    }
+    /// An API prefix chosen at runtime.
+    ///
+    /// This is handy for code that picks which API to use at runtime (say,
+    /// after checking `MatrixClient::versions()`), or for reaching unstable
+    /// and nonstandard endpoints.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub enum ApiPrefix<'a> {
+        /// `/_matrix/client/r0`
+        ClientR0,
+        /// `/_matrix/client/v3`
+        ClientV3,
+        /// `/_matrix/media/r0`
+        MediaR0,
+        /// `/_matrix/media/v3`
+        MediaV3,
+        /// `/_matrix/client/unstable`, followed by the given namespace (like
+        /// `org.matrix.msc2432`) if it isn't empty.
+        ClientUnstable(Cow<'a, str>),
+        /// Any other prefix, like `/_synapse/admin/v1`.
+        Custom(Cow<'a, str>)
+    }
+    impl<'a> ApiType for ApiPrefix<'a> {
+        fn get_path<'b>(&'b self) -> Cow<'b, str> {
+            use self::ApiPrefix::*;
+            match *self {
+                ClientR0 => "/_matrix/client/r0".into(),
+                ClientV3 => "/_matrix/client/v3".into(),
+                MediaR0 => "/_matrix/media/r0".into(),
+                MediaV3 => "/_matrix/media/v3".into(),
+                ClientUnstable(ref ns) if ns.is_empty() => "/_matrix/client/unstable".into(),
+                ClientUnstable(ref ns) => format!("/_matrix/client/unstable/{}", ns).into(),
+                Custom(ref p) => (p as &str).into()
+            }
+        }
+    }
+    /// APIs that aren't versioned.
+    pub mod unversioned {
+        use request::ApiType;
+        use std::borrow::Cow;
+        /// `/_matrix/client`, for endpoints like `/versions` that exist
+        /// outside of any version of the client-server API.
+        pub struct ClientApi;
+        impl ApiType for ClientApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/client".into()
+            }
+        }
+    }
     /// APIs at version r0.
     pub mod r0 {
         use request::ApiType;
@@ -41,15 +137,47 @@ pub mod apis {
             }
         }
     }
+    /// APIs at version v1.
+    pub mod v1 {
+        use request::ApiType;
+        use std::borrow::Cow;
+        /// `/_matrix/client/v1`
+        pub struct ClientApi;
+        impl ApiType for ClientApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/client/v1".into()
+            }
+        }
+    }
+    /// APIs at version v3.
+    pub mod v3 {
+        use request::ApiType;
+        use std::borrow::Cow;
+        /// `/_matrix/client/v3`
+        pub struct ClientApi;
+        impl ApiType for ClientApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/client/v3".into()
+            }
+        }
+        /// `/_matrix/media/v3`
+        pub struct MediaApi;
+        impl ApiType for MediaApi {
+            fn get_path(&self) -> Cow<'static, str> {
+                "/_matrix/media/v3".into()
+            }
+        }
+    }
 }
-use self::apis::r0::*;
+use self::apis::ClientApi;
 /// A arbitrary request to an endpoint in the Matrix API.
 ///
 /// To actually determine what URL is used for the request, two things are
 /// consulted: the request type, and the request endpoint. The request type
 /// specifies what Matrix API is being used (for example, the client-server API
 /// revision 0, under `/_matrix/client/r0`), while the endpoint determines what
-/// method is being called on that API.
+/// method is being called on that API. The default request type, `ClientApi`,
+/// uses whatever prefix the `MatrixClient` is configured with.
 ///
 /// This type has Super `Cow` Powers.
 pub struct MatrixRequest<'a, T, U = ClientApi> {
@@ -59,13 +187,22 @@ pub struct MatrixRequest<'a, T, U = ClientApi> {
     pub endpoint: Cow<'a, str>,
     /// Query-string parameters.
     pub params: HashMap<Cow<'a, str>, Cow<'a, str>>,
+    /// Query-string parameters that can be given more than once (like the
+    /// `via` servers for joining a room), sent after `params`.
+    pub repeated_params: Vec<(Cow<'a, str>, Cow<'a, str>)>,
     /// Request body (some type implementing `Serialize`).
     ///
-    /// If this is empty (serialises to `{}`), it will not be sent. Therefore,
-    /// requests with no body should use `()` here.
+    /// If this is empty (serialises to `{}` or `null`), it will not be sent.
+    /// Therefore, requests with no body should use `()` here. Otherwise, it's
+    /// sent as JSON, with an appropriate `Content-Type`.
     pub body: T,
     /// Request type.
-    pub typ: U
+    pub typ: U,
+    /// Extra headers to send, which override any set by `make_hyper()`.
+    pub headers: Headers,
+    /// How long to wait for this request to complete, overriding the
+    /// `MatrixClient`'s default request timeout if set.
+    pub timeout: Option<Duration>
 }
 impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
     pub fn new<S: Into<Cow<'a, str>>>(meth: Method, endpoint: S, body: T, typ: U) -> Self {
@@ -73,12 +210,105 @@ impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
             meth,
             endpoint: endpoint.into(),
             params: HashMap::new(),
+            repeated_params: vec![],
             body,
-            typ
+            typ,
+            headers: Headers::new(),
+            timeout: None
         }
     }
 }
+/// Path segments that are followed by variable ones, and placeholders for
+/// those (or `None` for ones that are kept, like event types); see
+/// `MatrixRequest::endpoint_template()`.
+const TEMPLATE_SEGMENTS: &[(&str, &[Option<&str>])] = &[
+    ("send", &[None, Some("{txnId}")]),
+    ("sendToDevice", &[None, Some("{txnId}")]),
+    ("state", &[None, Some("{stateKey}")]),
+    ("redact", &[Some("{eventId}"), Some("{txnId}")]),
+    ("devices", &[Some("{deviceId}")]),
+    ("download", &[Some("{serverName}"), Some("{mediaId}"), Some("{fileName}")]),
+    ("thumbnail", &[Some("{serverName}"), Some("{mediaId}")]),
+    ("tags", &[Some("{tag}")]),
+    ("filter", &[Some("{filterId}")]),
+    ("global", &[Some("{kind}"), Some("{ruleId}")])
+];
 impl<'a> MatrixRequest<'a, ()> {
+    /// Make an endpoint out of some path segments, percent-encoding each one.
+    ///
+    /// Room IDs, aliases and event IDs can contain characters (like `#`) that
+    /// aren't allowed in URL paths, so this should be used to build any
+    /// endpoint containing them. For example, `&["rooms", "!abc:example.org",
+    /// "join"]` makes `/rooms/!abc:example.org/join`, and `&["directory",
+    /// "room", "#foo:example.org"]` makes `/directory/room/%23foo:example.org`.
+    pub fn endpoint_with_segments(segments: &[&str]) -> String {
+        let mut ret = String::new();
+        for seg in segments {
+            ret.push('/');
+            ret.extend(utf8_percent_encode(seg, PATH_COMPONENT_ENCODE_SET));
+        }
+        ret
+    }
+    /// Turn the path of a request (like
+    /// `/_matrix/client/v3/rooms/!abc:example.org/send/m.room.message/42`)
+    /// into a template, with the parts that vary from request to request
+    /// replaced by placeholders (like
+    /// `/_matrix/client/v3/rooms/{roomId}/send/m.room.message/{txnId}`).
+    ///
+    /// IDs are recognised by their sigils (`!`, `@`, `#`, `$` and `+`), and
+    /// the other variable segments (like transaction IDs, state keys and
+    /// device IDs) by the segments that come before them. Event types are
+    /// left alone. This is what metrics hooks are given as the endpoint.
+    pub fn endpoint_template(path: &str) -> String {
+        let mut ret = String::new();
+        let mut pending: &[Option<&str>] = &[];
+        for seg in path.split('/').skip(1) {
+            ret.push('/');
+            if let Some((first, rest)) = pending.split_first() {
+                ret += first.unwrap_or(seg);
+                pending = rest;
+                continue;
+            }
+            let decoded = percent_decode(seg.as_bytes()).decode_utf8_lossy();
+            let placeholder = match decoded.chars().next() {
+                Some('!') => "{roomId}",
+                Some('@') => "{userId}",
+                Some('#') => "{roomAlias}",
+                Some('$') => "{eventId}",
+                Some('+') => "{groupId}",
+                _ => {
+                    ret += seg;
+                    pending = TEMPLATE_SEGMENTS.iter()
+                        .find(|&&(s, _)| s == seg)
+                        .map(|&(_, p)| p)
+                        .unwrap_or(&[]);
+                    continue;
+                }
+            };
+            ret += placeholder;
+        }
+        ret
+    }
+    /// Start building a `GET` request to `endpoint`.
+    ///
+    /// The request can be customised further with builder methods like
+    /// `param()`, `header()` and `with_body()`, as in
+    /// `MatrixRequest::get("/sync").param("timeout", 30000)`.
+    pub fn get<S: Into<Cow<'a, str>>>(endpoint: S) -> Self {
+        Self::new_basic(Method::Get, endpoint)
+    }
+    /// Start building a `POST` request to `endpoint`.
+    pub fn post<S: Into<Cow<'a, str>>>(endpoint: S) -> Self {
+        Self::new_basic(Method::Post, endpoint)
+    }
+    /// Start building a `PUT` request to `endpoint`.
+    pub fn put<S: Into<Cow<'a, str>>>(endpoint: S) -> Self {
+        Self::new_basic(Method::Put, endpoint)
+    }
+    /// Start building a `DELETE` request to `endpoint`.
+    pub fn delete<S: Into<Cow<'a, str>>>(endpoint: S) -> Self {
+        Self::new_basic(Method::Delete, endpoint)
+    }
     /// Convenience method for making a `MatrixRequest` from a method and
     /// endpoint.
     pub fn new_basic<S: Into<Cow<'a, str>>>(meth: Method, endpoint: S) -> Self {
@@ -86,8 +316,11 @@ impl<'a> MatrixRequest<'a, ()> {
             meth,
             endpoint: endpoint.into(),
             params: HashMap::new(),
+            repeated_params: vec![],
             body: (),
-            typ: ClientApi
+            typ: ClientApi,
+            headers: Headers::new(),
+            timeout: None
         }
     }
 }
@@ -103,8 +336,11 @@ impl<'a, 'b, 'c> MatrixRequest<'a, HashMap<Cow<'b, str>, Cow<'c, str>>> {
             meth,
             endpoint: endpoint.into(),
             params: HashMap::new(),
+            repeated_params: vec![],
             body,
-            typ: ClientApi
+            typ: ClientApi,
+            headers: Headers::new(),
+            timeout: None
         }
     }
 }
@@ -115,19 +351,84 @@ impl<'a, T> MatrixRequest<'a, T> where T: Serialize {
             meth,
             endpoint: endpoint.into(),
             params: HashMap::new(),
+            repeated_params: vec![],
             body,
-            typ: ClientApi
+            typ: ClientApi,
+            headers: Headers::new(),
+            timeout: None
+        }
+    }
+}
+impl<'a, T, U> MatrixRequest<'a, T, U> {
+    /// Add a query-string parameter (replacing any with the same key).
+    pub fn param<K, V>(mut self, key: K, val: V) -> Self
+        where K: Into<Cow<'a, str>>, V: ToString {
+        self.params.insert(key.into(), val.to_string().into());
+        self
+    }
+    /// Add a query-string parameter, keeping any others with the same key.
+    pub fn repeated_param<K, V>(mut self, key: K, val: V) -> Self
+        where K: Into<Cow<'a, str>>, V: ToString {
+        self.repeated_params.push((key.into(), val.to_string().into()));
+        self
+    }
+    /// Add a header (replacing any of the same type).
+    pub fn header<H: Header>(mut self, header: H) -> Self {
+        self.headers.set(header);
+        self
+    }
+    /// Add a header that doesn't have a typed representation in `hyper`,
+    /// like `X-Forwarded-For`.
+    pub fn raw_header<K, V>(mut self, name: K, val: V) -> Self
+        where K: Into<Cow<'static, str>>, V: Into<Raw> {
+        self.headers.set_raw(name, val);
+        self
+    }
+    /// Wait up to `timeout` for this request to complete, instead of the
+    /// `MatrixClient`'s default request timeout.
+    ///
+    /// This is for requests that legitimately take a while, like uploads,
+    /// or long-polling with a `timeout` parameter (which this should be
+    /// longer than).
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Replace the request body with `body`.
+    pub fn with_body<V: Serialize>(self, body: V) -> MatrixRequest<'a, V, U> {
+        MatrixRequest {
+            meth: self.meth,
+            endpoint: self.endpoint,
+            params: self.params,
+            repeated_params: self.repeated_params,
+            body,
+            typ: self.typ,
+            headers: self.headers,
+            timeout: self.timeout
+        }
+    }
+    /// Replace the request type, to make a request to another API.
+    pub fn with_api<V: ApiType>(self, typ: V) -> MatrixRequest<'a, T, V> {
+        MatrixRequest {
+            meth: self.meth,
+            endpoint: self.endpoint,
+            params: self.params,
+            repeated_params: self.repeated_params,
+            body: self.body,
+            typ,
+            headers: self.headers,
+            timeout: self.timeout
         }
     }
 }
 impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
-    fn body(&self) -> MatrixResult<Option<Body>> {
+    fn body(&self) -> MatrixResult<Option<String>> {
         let body = serde_json::to_string(&self.body)?;
-        Ok(if body == "{}" {
+        Ok(if body == "{}" || body == "null" {
             None
         }
         else {
-            Some(body.into())
+            Some(body)
         })
     }
     /// Makes a hyper `Request` from this type.
@@ -135,23 +436,61 @@ impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
     /// The generated `Request` can then be sent to some unsuspecting Matrix
     /// homeserver using the `send_request()` or `send_discarding_request()`
     /// methods on `MatrixClient`.
+    ///
+    /// If the request has a body, its `Content-Type` is set to
+    /// `application/json`. For endpoints that take something else, like the
+    /// media upload endpoint, replace the body and set a different
+    /// `ContentType` header on the returned `Request`.
+    ///
+    /// This fails with a `LoggedOut` error if the client has been logged out,
+    /// or an `InsecureHttp` error if it would be sent over plain HTTP without
+    /// the client allowing that.
     pub fn make_hyper(&self, client: &MatrixClient) -> MatrixResult<Request> {
+        if client.logged_out.get() {
+            bail!(MatrixErrorKind::LoggedOut);
+        }
+        check_insecure_http(&client.url, client.allow_insecure_http)?;
         let body = self.body()?;
-        let mut params = format!("access_token={}", client.access_token);
-        for (k, v) in self.params.iter() {
-            params += &format!("&{}={}",
-                              utf8_percent_encode(k.as_ref(), DEFAULT_ENCODE_SET),
-                              utf8_percent_encode(v.as_ref(), DEFAULT_ENCODE_SET));
-        }
-        let url = format!("{}{}{}?{}",
-                          client.url,
-                          self.typ.get_path(),
-                          self.endpoint,
-                          params);
+        let mut params = vec![];
+        if let Some(ref tok) = *client.access_token.borrow() {
+            if client.token_in_query {
+                params.push(format!("access_token={}",
+                                    utf8_percent_encode(tok, QUERY_COMPONENT_ENCODE_SET)));
+            }
+        }
+        for (k, v) in self.params.iter().chain(self.repeated_params.iter().map(|(k, v)| (k, v))) {
+            params.push(format!("{}={}",
+                                utf8_percent_encode(k.as_ref(), QUERY_COMPONENT_ENCODE_SET),
+                                utf8_percent_encode(v.as_ref(), QUERY_COMPONENT_ENCODE_SET)));
+        }
+        let mut url = format!("{}{}{}",
+                              client.url,
+                              self.typ.get_path_for(client),
+                              self.endpoint);
+        if !params.is_empty() {
+            url += "?";
+            url += &params.join("&");
+        }
         let mut req = Request::new(self.meth.clone(), url.parse()?);
+        if let Some(ref tok) = *client.access_token.borrow() {
+            if !client.token_in_query {
+                req.headers_mut().set(Authorization(Bearer {
+                    token: tok.clone()
+                }));
+            }
+        }
         if let Some(b) = body {
+            req.headers_mut().set(ContentType(json_utf8()));
+            req.headers_mut().set(ContentLength(b.len() as u64));
             req.set_body(b);
         }
+        if let Some(ref ua) = client.user_agent {
+            req.headers_mut().set_raw("User-Agent", ua.clone());
+        }
+        if client.compression {
+            req.headers_mut().set_raw("Accept-Encoding", ACCEPT_ENCODING);
+        }
+        req.headers_mut().extend(self.headers.iter());
         Ok(req)
     }
     /// Sends this request to a Matrix homeserver, expecting a deserializable
@@ -163,7 +502,40 @@ impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
             Ok(r) => r,
             Err(e) => return Box::new(futures::future::err(e.into()))
         };
-        mxc.send_request(req)
+        let timeout = self.timeout.or(mxc.request_timeout());
+        mxc.send_request_with_timeout(req, timeout)
+    }
+    /// Like `send()`, but uses `MatrixClient::send_request_with_response()`,
+    /// to get the headers of the homeserver's response as well.
+    pub fn send_with_response<R>(&self, mxc: &mut MatrixClient) -> MatrixFuture<(R, Headers)> where R: DeserializeOwned + 'static {
+        let req = match self.make_hyper(mxc) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let timeout = self.timeout.or(mxc.request_timeout());
+        mxc.send_request_with_response(req, timeout)
+    }
+    /// Like `send()`, but uses `MatrixClient::send_uiaa_request()`, for
+    /// endpoints that might need User-Interactive Authentication.
+    pub fn send_uiaa<R>(&self, mxc: &mut MatrixClient) -> MatrixFuture<UiaaResponse<R>> where R: DeserializeOwned + 'static {
+        let req = match self.make_hyper(mxc) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let timeout = self.timeout.or(mxc.request_timeout());
+        mxc.send_uiaa_request(req, timeout)
+    }
+    /// Like `send_uiaa()`, but uses
+    /// `MatrixClient::send_uiaa_request_with_stages()` to complete the
+    /// User-Interactive Authentication with `stage`.
+    pub fn send_uiaa_with_stages<R, F>(&self, mxc: &mut MatrixClient, stage: F) -> MatrixFuture<UiaaResponse<R>>
+        where R: DeserializeOwned + 'static, F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+        let req = match self.make_hyper(mxc) {
+            Ok(r) => r,
+            Err(e) => return Box::new(futures::future::err(e))
+        };
+        let timeout = self.timeout.or(mxc.request_timeout());
+        mxc.send_uiaa_request_with_stages(req, timeout, stage)
     }
     /// Like `send()`, but uses `MatrixClient::send_discarding_request()`.
     pub fn discarding_send(&self, mxc: &mut MatrixClient) -> MatrixFuture<()> {
@@ -171,7 +543,8 @@ impl<'a, T, U> MatrixRequest<'a, T, U> where T: Serialize, U: ApiType {
             Ok(r) => r,
             Err(e) => return Box::new(futures::future::err(e.into()))
         };
-        mxc.send_discarding_request(req)
+        let timeout = self.timeout.or(mxc.request_timeout());
+        mxc.send_discarding_request_with_timeout(req, timeout)
     }
     // incredibly useful and relevant method
     pub fn moo() -> &'static str {
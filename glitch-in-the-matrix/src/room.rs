@@ -3,6 +3,9 @@
 use types::replies::*;
 use types::messages::Message;
 use types::content::room::PowerLevels;
+use types::content::room::types::Membership;
+use types::content::root::Tag;
+use types::events::Event;
 use super::{MatrixClient, MatrixFuture};
 use request::MatrixRequest;
 use serde::Serialize;
@@ -10,8 +13,197 @@ use serde::de::DeserializeOwned;
 use futures::*;
 use errors::*;
 use hyper::Method::*;
+use util::{BufferedRequest, PageItems, PageStream, RequestSender, SharedToken, UnitaryResponseWrapper};
+use serde_json;
+use futures::sync::oneshot;
+use tokio_core::reactor::Interval;
+use std::cmp;
+use std::collections::HashMap;
+use std::time::Duration;
 
-pub use types::room::Room;
+pub use types::room::{Room, CreateRoomParams, InitialStateEvent, RoomPreset, RoomVisibility};
+pub use types::room::{PublicRoomsFilter, PublicRoomsParams};
+
+/// The direction to paginate through a room's history in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// From newer events to older ones.
+    Backwards,
+    /// From older events to newer ones.
+    Forwards
+}
+impl Direction {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match *self {
+            Direction::Backwards => "b",
+            Direction::Forwards => "f"
+        }
+    }
+}
+/// A `Stream` that pages through a room's history, using the
+/// `/rooms/{roomId}/messages` API.
+///
+/// Each item is one page (chunk) of events. The `end` token of each reply is
+/// passed as the `from` parameter of the next request; the stream finishes
+/// when the homeserver returns no `end` token (or one that's the same as the
+/// `start` token), or an empty chunk.
+///
+/// Obtain one of these with `MatrixClient::messages()`.
+pub struct MessagesStream<'a> {
+    inner: PageStream<'a, MessagesQuery, MessagesReply, Event>
+}
+struct MessagesQuery {
+    room_id: String,
+    dir: Direction,
+    to: Option<String>,
+    limit: Option<u32>,
+    filter: Option<String>
+}
+impl<'a> MessagesStream<'a> {
+    pub(crate) fn new(cli: &'a mut MatrixClient, room_id: &str, dir: Direction) -> Self {
+        let query = MessagesQuery {
+            room_id: room_id.into(),
+            dir,
+            to: None,
+            limit: None,
+            filter: None
+        };
+        let inner = PageStream::new(cli, query,
+                                    |cli, q, from| {
+                                        cli.get_messages(&q.room_id, q.dir, from, q.to.as_ref().map(|x| x as &str),
+                                                         q.limit, q.filter.as_ref().map(|x| x as &str))
+                                    },
+                                    |_, rpl| {
+                                        let next = match rpl.end {
+                                            // Some homeservers say they've got to the end by not
+                                            // moving the token at all.
+                                            Some(end) if end != rpl.start => Some(end),
+                                            _ => None
+                                        };
+                                        (rpl.chunk, next)
+                                    });
+        MessagesStream { inner }
+    }
+    /// Get the token that the next request will paginate from, if any.
+    ///
+    /// This can be saved, and given to `set_from()` later, to carry on
+    /// paginating from the same place.
+    pub fn from(&self) -> Option<&str> {
+        self.inner.from.as_ref().map(|x| x as &str)
+    }
+    /// Set the token to paginate from, like the `prev_batch` token of a
+    /// sync timeline.
+    ///
+    /// If this is `None`, pagination starts from the latest (or, when going
+    /// forwards, the earliest) event in the room.
+    pub fn set_from(&mut self, from: Option<String>) {
+        self.inner.from = from;
+    }
+    /// Set a token to stop paginating at.
+    pub fn set_to(&mut self, to: Option<String>) {
+        self.inner.state.to = to;
+    }
+    /// Get the maximum number of events to ask for in each page.
+    pub fn limit(&self) -> Option<u32> {
+        self.inner.state.limit
+    }
+    /// Set the maximum number of events to ask for in each page.
+    ///
+    /// If this is `None`, the homeserver's default (usually 10) is used.
+    pub fn set_limit(&mut self, limit: Option<u32>) {
+        self.inner.state.limit = limit;
+    }
+    /// Set a filter (a `RoomEventFilter` definition in JSON) to apply to the
+    /// returned events.
+    pub fn set_filter(&mut self, filter: Option<String>) {
+        self.inner.state.filter = filter;
+    }
+    /// Turn this into a `MessageStream`, which returns the events one at a
+    /// time instead of a page at a time.
+    pub fn events(self) -> MessageStream<'a> {
+        MessageStream {
+            inner: PageItems::new(self.inner)
+        }
+    }
+}
+impl<'a> Stream for MessagesStream<'a> {
+    type Item = Vec<Event>;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
+/// A `Stream` of the events in a room's history, one at a time.
+///
+/// This works just like `MessagesStream` (which does the actual paginating),
+/// and is obtained with `MessagesStream::events()`. For instance, to go
+/// backwards from the `prev_batch` token of a sync timeline, make a
+/// `MessagesStream` with `Direction::Backwards`, give it the token with
+/// `set_from()`, then call `events()`.
+pub struct MessageStream<'a> {
+    inner: PageItems<'a, MessagesQuery, MessagesReply, Event>
+}
+impl<'a> MessageStream<'a> {
+    /// Get the token that the next page of events will be fetched from, if
+    /// any.
+    ///
+    /// Events that have already been fetched, but not yet returned by the
+    /// stream, aren't covered by this token.
+    pub fn from(&self) -> Option<&str> {
+        self.inner.pages.from.as_ref().map(|x| x as &str)
+    }
+}
+impl<'a> Stream for MessageStream<'a> {
+    type Item = Event;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
+/// A `Stream` of the rooms in a public room directory, using the
+/// `/publicRooms` API.
+///
+/// Unlike `MessagesStream`, each item is a single room; pages are fetched as
+/// needed, following the `next_batch` token of each reply, so something like
+/// `.take(500)` only fetches as many pages as it needs. The stream finishes
+/// when the homeserver returns no `next_batch` token, or an empty page.
+///
+/// Obtain one of these with `MatrixClient::public_rooms_stream()`.
+pub struct PublicRoomsStream<'a> {
+    inner: PageItems<'a, PublicRoomsParams, PublicRoomsReply, PublicRoomsChunk>
+}
+impl<'a> PublicRoomsStream<'a> {
+    pub(crate) fn new(cli: &'a mut MatrixClient, mut params: PublicRoomsParams) -> Self {
+        let since = params.since.take();
+        let mut pages = PageStream::new(cli, params,
+                                        |cli, params, from| {
+                                            params.since = from.map(|x| x.to_owned());
+                                            cli.public_rooms(params)
+                                        },
+                                        |_, rpl| (rpl.chunk, rpl.next_batch));
+        pages.from = since;
+        PublicRoomsStream {
+            inner: PageItems::new(pages)
+        }
+    }
+    /// Get the token that the next page will be fetched from, if any.
+    ///
+    /// Rooms that have already been fetched, but not yet returned by the
+    /// stream, aren't covered by this token.
+    pub fn since(&self) -> Option<&str> {
+        self.inner.pages.from.as_ref().map(|x| x as &str)
+    }
+}
+impl<'a> Stream for PublicRoomsStream<'a> {
+    type Item = PublicRoomsChunk;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
 
 /// Trait used to implement methods on `Room`.
 ///
@@ -38,11 +230,94 @@ pub struct RoomClient<'a, 'b: 'a, 'c> {
     pub room: &'a Room<'b>,
     pub cli: &'c mut MatrixClient
 }
+/// Keeps the user marked as typing in a room, until it's dropped.
+///
+/// Obtain one of these with `MatrixClient::typing_guard()`. Making one sends
+/// a typing notification, and dropping it sends another one saying that the
+/// user has stopped typing.
+///
+/// Typing notifications time out, so if refreshing is turned on, the
+/// notification is sent again before the timeout runs out (every three
+/// quarters of the timeout).
+///
+/// The requests are sent like the client's own (with its retries,
+/// re-authentication and metrics hook), but they're spawned onto the
+/// client's event loop and their results are ignored; use
+/// `MatrixClient::set_typing()` if you want to handle errors.
+pub struct TypingGuard {
+    req: TypingRequest,
+    _stop_refresh: Option<oneshot::Sender<()>>
+}
+/// The typing notification request of a `TypingGuard`, which can be sent
+/// with different bodies.
+#[derive(Clone)]
+struct TypingRequest {
+    sender: RequestSender,
+    req: BufferedRequest,
+    token: SharedToken,
+    token_in_query: bool,
+    timeout: Option<Duration>
+}
+impl TypingRequest {
+    fn send(&self, body: &serde_json::Value) -> MatrixFuture<()> {
+        let mut req = self.req.clone();
+        // Use the client's current access token, in case it's changed since
+        // the guard was made.
+        let res = match *self.token.borrow() {
+            Some(ref t) => req.set_access_token(t, self.token_in_query),
+            None => Ok(())
+        };
+        if let Err(e) = res.and_then(|_| req.set_json_body(body)) {
+            return Box::new(future::err(e));
+        }
+        self.sender.send(req.make(), self.timeout, UnitaryResponseWrapper::wrap)
+    }
+    fn spawn(&self, body: &serde_json::Value) {
+        let fut = self.send(body).then(|_| Ok(()));
+        self.sender.hdl.spawn(fut);
+    }
+}
+impl TypingGuard {
+    pub(crate) fn new(cli: &MatrixClient, room_id: &str, timeout: u64, refresh: bool) -> MatrixResult<Self> {
+        let req = MatrixRequest::put(MatrixRequest::endpoint_with_segments(&["rooms", room_id, "typing", &cli.user_id]))
+            .make_hyper(cli)?;
+        let mut ret = TypingGuard {
+            req: TypingRequest {
+                sender: cli.sender(),
+                req: BufferedRequest::without_body(req),
+                token: cli.access_token.clone(),
+                token_in_query: cli.token_in_query,
+                timeout: cli.timeout
+            },
+            _stop_refresh: None
+        };
+        let start = json!({ "typing": true, "timeout": timeout });
+        ret.req.spawn(&start);
+        if refresh {
+            let (tx, rx) = oneshot::channel();
+            let every = Duration::from_millis(cmp::max(timeout / 4 * 3, 1));
+            let req = ret.req.clone();
+            let fut = Interval::new(every, &ret.req.sender.hdl)?
+                .map_err(|_| ())
+                .for_each(move |_| req.send(&start).then(|_| Ok(())))
+                // Dropping the guard drops `tx`, which stops this.
+                .select(rx.map_err(|_| ()))
+                .then(|_| Ok(()));
+            ret.req.sender.hdl.spawn(fut);
+            ret._stop_refresh = Some(tx);
+        }
+        Ok(ret)
+    }
+}
+impl Drop for TypingGuard {
+    fn drop(&mut self) {
+        self.req.spawn(&json!({ "typing": false }));
+    }
+}
 impl<'a> RoomExt<'a> for Room<'a> {
     fn from_alias(cli: &mut MatrixClient, alias: &str) -> MatrixFuture<Self> {
-        Box::new(MatrixRequest::new_basic(Get, format!("/directory/room/{}", alias))
-                 .send(cli)
-                 .map(|RoomAliasReply { room, .. }| room))
+        Box::new(cli.resolve_alias(alias)
+                 .map(|ResolveAliasReply { room, .. }| room))
     }
     fn cli<'b, 'c>(&'b self, cli: &'c mut MatrixClient) -> RoomClient<'b, 'a, 'c> {
         RoomClient {
@@ -54,41 +329,61 @@ impl<'a> RoomExt<'a> for Room<'a> {
 impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// Sends a message to this room.
     pub fn send(&mut self, msg: Message) -> MatrixFuture<SendReply> {
-        self.cli.txnid += 1;
-        MatrixRequest::new_with_body_ser(
-            Put,
-            format!("/rooms/{}/send/m.room.message/{}",
-                    self.room.id,
-                    self.cli.txnid),
-            msg
-        ).send(self.cli)
+        self.cli.send_message(&self.room.id, "m.room.message", msg)
+    }
+    /// Sends a message to this room, with a given transaction ID.
+    ///
+    /// See `MatrixClient::send_message_with_txn_id()` for more.
+    pub fn send_with_txn_id(&mut self, msg: Message, txnid: &str) -> MatrixFuture<SendReply> {
+        self.cli.send_message_with_txn_id(&self.room.id, "m.room.message", msg, txnid)
     }
     /// Wrapper function that sends a `Message::Notice` with the specified unformatted text
     /// to this room. Provided for convenience purposes.
     pub fn send_simple<T: Into<String>>(&mut self, msg: T) -> MatrixFuture<SendReply> {
-        let msg = Message::Notice {
-            body: msg.into(),
-            formatted_body: None,
-            format: None
-        };
-        self.send(msg)
+        self.send(Message::notice(msg))
     }
     /// Wrapper function that sends a `Message::Notice` with the specified HTML-formatted text
     /// (and accompanying unformatted text, if given) to this room.
     pub fn send_html<T, U>(&mut self, msg: T, unformatted: U) -> MatrixFuture<SendReply>
         where T: Into<String>, U: Into<Option<String>> {
         let m = msg.into();
-        let msg = Message::Notice {
-            body: unformatted.into().unwrap_or(m.clone()),
-            formatted_body: Some(m),
-            format: Some("org.matrix.custom.html".into())
-        };
+        let msg = Message::notice_html(unformatted.into().unwrap_or_else(|| m.clone()), m);
         self.send(msg)
     }
+    /// Get the members that have joined this room.
+    ///
+    /// See `MatrixClient::get_joined_members()` for more.
+    pub fn joined_members(&mut self) -> MatrixFuture<HashMap<String, JoinedMember>> {
+        self.cli.get_joined_members(&self.room.id)
+    }
+    /// Get the `m.room.member` events of this room's members.
+    ///
+    /// See `MatrixClient::get_members()` for more.
+    pub fn members(&mut self, membership: Option<Membership>, not_membership: Option<Membership>) -> MatrixFuture<Vec<Event>> {
+        self.cli.get_members(&self.room.id, membership, not_membership)
+    }
+    /// Upload a file, and send it to this room as an `m.file` message.
+    ///
+    /// See `MatrixClient::send_file()` for more.
+    pub fn send_file(&mut self, data: Vec<u8>, content_type: &str, filename: &str) -> MatrixFuture<SendReply> {
+        self.cli.send_file(&self.room.id, data, content_type, filename)
+    }
+    /// Upload an image, and send it to this room as an `m.image` message.
+    ///
+    /// See `MatrixClient::send_image()` for more.
+    pub fn send_image(&mut self, data: Vec<u8>, content_type: &str, filename: &str, width: u32, height: u32) -> MatrixFuture<SendReply> {
+        self.cli.send_image(&self.room.id, data, content_type, filename, width, height)
+    }
+    /// Edit the message `target` in this room, replacing its content with
+    /// `new_content` (which can be plain or HTML-formatted).
+    ///
+    /// See `MatrixClient::edit_message()` for more.
+    pub fn edit(&mut self, target: &str, new_content: Message) -> MatrixFuture<SendReply> {
+        self.cli.edit_message(&self.room.id, target, new_content)
+    }
     /// Send a read receipt for a given event ID.
     pub fn read_receipt(&mut self, eventid: &str) -> MatrixFuture<()> {
-        MatrixRequest::new_basic(Post, format!("/rooms/{}/receipt/m.read/{}", self.room.id, eventid))
-            .discarding_send(self.cli)
+        self.cli.send_receipt(&self.room.id, eventid)
     }
     /// Looks up the contents of a state event with type `ev_type` and state key
     /// `key` in a room. If the user is joined to the room then the state is
@@ -103,11 +398,13 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// If the event was not found, an error will be thrown of type
     /// `HttpCode(http::StatusCode::NotFound)`.
     pub fn get_state<T: DeserializeOwned + 'static>(&mut self, ev_type: &str, key: Option<&str>) -> MatrixFuture<T> {
-        MatrixRequest::new_basic(Get, format!("/rooms/{}/state/{}/{}",
-                                              self.room.id,
-                                              ev_type,
-                                              key.unwrap_or("")))
-            .send(self.cli)
+        self.cli.get_state(&self.room.id, ev_type, key)
+    }
+    /// Get all the state events in this room.
+    ///
+    /// See `MatrixClient::get_all_state()` for more.
+    pub fn get_all_state(&mut self) -> MatrixFuture<Vec<Event>> {
+        self.cli.get_all_state(&self.room.id)
     }
     /// State events can be sent using this endpoint. These events will be
     /// overwritten if the <event type> (`ev_type`) and <state key> (`key`) all
@@ -117,13 +414,7 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// `Serialize`, allowing you to use the state API to store arbitrary
     /// objects. See the `get_state` docs for more.
     pub fn set_state<T: Serialize>(&mut self, ev_type: &str, key: Option<&str>, val: T) -> MatrixFuture<SetStateReply> {
-        MatrixRequest::new_with_body_ser(
-            Put,
-            format!("/rooms/{}/state/{}/{}",
-                    self.room.id,
-                    ev_type, key.unwrap_or("")),
-            val
-        ).send(self.cli)
+        self.cli.set_state(&self.room.id, ev_type, key, val)
     }
     /// Strips all information out of an event which isn't critical to the
     /// integrity of the server-side representation of the room.
@@ -133,28 +424,15 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// Users may redact their own events, and any user with a power level
     /// greater than or equal to the redact power level of the room may redact
     /// events there.
-    pub fn redact(&mut self, eventid: &str, reason: Option<&str>) -> MatrixFuture<()> {
-        self.cli.txnid += 1;
-        let mut body = vec![];
-        body.extend(reason.map(|x| ("reason", x)));
-        MatrixRequest::new_with_body(Post, format!("/rooms/{}/redact/{}/{}",
-                                                   self.room.id, eventid, self.cli.txnid),
-                                     body)
-            .discarding_send(self.cli)
+    pub fn redact(&mut self, eventid: &str, reason: Option<&str>) -> MatrixFuture<SendReply> {
+        self.cli.redact(&self.room.id, eventid, reason)
     }
     /// This tells the server that the user is typing for the next N
     /// milliseconds where N is the value specified in the timeout key.
     /// Alternatively, if typing is false, it tells the server that the user has
     /// stopped typing.
     pub fn typing(&mut self, typing: bool, timeout: Option<usize>) -> MatrixFuture<()> {
-        self.cli.txnid += 1;
-        let mut body = vec![("typing", typing.to_string())];
-        body.extend(timeout.map(|x| ("timeout", x.to_string())));
-        MatrixRequest::new_with_body(Post, format!("/rooms/{}/typing/{}",
-                                                   self.room.id, self.cli.user_id),
-                                     body)
-            .discarding_send(self.cli)
-
+        self.cli.set_typing(&self.room.id, typing, timeout.map(|x| x as u64))
     }
     /// This API starts a user participating in a particular room, if that user
     /// is allowed to participate in that room. After this call, the client is
@@ -164,7 +442,7 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// After a user has joined a room, the room will appear as an entry in the
     /// values in the `SyncStream`.
     pub fn join(&mut self) -> MatrixFuture<()> {
-        MatrixRequest::new_basic(Post, format!("/rooms/{}/join", self.room.id))
+        MatrixRequest::new_basic(Post, MatrixRequest::endpoint_with_segments(&["rooms", &self.room.id, "join"]))
             .discarding_send(self.cli)
     }
     /// This API stops a user participating in a particular room.
@@ -179,8 +457,7 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// The user will still be allowed to retrieve history from the room which
     /// they were previously allowed to see.
     pub fn leave(&mut self) -> MatrixFuture<()> {
-        MatrixRequest::new_basic(Post, format!("/rooms/{}/leave", self.room.id))
-            .discarding_send(self.cli)
+        self.cli.leave(&self.room.id)
     }
     /// This API stops a user remembering about a particular room.
     ///
@@ -192,32 +469,25 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// If the user is currently joined to the room, they will implicitly leave
     /// the room as part of this API call.
     pub fn forget(&mut self) -> MatrixFuture<()> {
-        MatrixRequest::new_basic(Post, format!("/rooms/{}/forget", self.room.id))
-            .discarding_send(self.cli)
+        self.cli.forget(&self.room.id)
     }
-    /// Kick a user from the room.
+    /// Kick a user from the room, optionally giving a reason (which is left
+    /// out if empty).
     ///
     /// The caller must have the required power level in order to perform this
     /// operation.
     pub fn kick_user(&mut self, user_id: &str, reason: Option<&str>) -> MatrixFuture<()> {
-        let mut body = vec![("user_id", user_id)];
-        body.extend(reason.map(|x| ("reason", x)));
-        MatrixRequest::new_with_body(Post, format!("/rooms/{}/kick", self.room.id),
-                                     body)
-            .discarding_send(self.cli)
+        self.cli.kick(&self.room.id, user_id, reason)
     }
     /// Ban a user in the room. If the user is currently in the room, also kick them.
+    /// As with `kick_user()`, an empty reason is left out.
     ///
     /// When a user is banned from a room, they may not join it or be invited to
     /// it until they are unbanned.
     ///
     /// The caller must have the required power level in order to perform this operation.
     pub fn ban_user(&mut self, user_id: &str, reason: Option<&str>) -> MatrixFuture<()> {
-        let mut body = vec![("user_id", user_id)];
-        body.extend(reason.map(|x| ("reason", x)));
-        MatrixRequest::new_with_body(Post, format!("/rooms/{}/ban", self.room.id),
-                                     body)
-            .discarding_send(self.cli)
+        self.cli.ban(&self.room.id, user_id, reason)
     }
     /// Unban a user from the room. This allows them to be invited to the room,
     /// and join if they would otherwise be allowed to join according to its
@@ -226,9 +496,7 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// The caller must have the required power level in order to perform this
     /// operation.
     pub fn unban_user(&mut self, user_id: &str) -> MatrixFuture<()> {
-        MatrixRequest::new_with_body(Post, format!("/rooms/{}/unban", self.room.id),
-                                     vec![("user_id", user_id)])
-            .discarding_send(self.cli)
+        self.cli.unban(&self.room.id, user_id)
     }
     /// This API invites a user to participate in a particular room. They do not
     /// start participating in the room until they actually join the room.
@@ -239,16 +507,78 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
     /// If the user was invited to the room, the homeserver will append a
     /// m.room.member event to the room.
     ///
-    /// Note that there are two forms of this API, which are documented
-    /// separately. This version of the API requires that the inviter knows the
-    /// Matrix identifier of the invitee. The other is documented in the third
-    /// party invites section of the Matrix spec, and is not implemented in
-    /// *Glitch in the Matrix* (yet!)
+    /// Note that there are two forms of this API. This version of the API
+    /// requires that the inviter knows the Matrix identifier of the invitee;
+    /// the other, `invite_3pid()`, invites someone by email address (or
+    /// another third-party identifier) instead.
+    ///
+    /// Errors can be told apart with `MatrixError::errcode()`: `M_FORBIDDEN`
+    /// if the inviter doesn't have permission to invite (or the invitee is
+    /// already in the room, or banned from it), and `M_LIMIT_EXCEEDED` if the
+    /// request was rate-limited.
     pub fn invite_user(&mut self, user_id: &str) -> MatrixFuture<()> {
-        MatrixRequest::new_with_body(Post, format!("/rooms/{}/invite", self.room.id),
-                                     vec![("user_id", user_id)])
+        self.cli.invite(&self.room.id, user_id)
+    }
+    /// Invite someone to the room by a third-party identifier, like an email
+    /// address (with a `medium` of `email`).
+    ///
+    /// The identity server at `id_server` (a hostname, like
+    /// `vector.im`) is used to look up the Matrix ID that the identifier is
+    /// bound to. If there isn't one, the identity server stores the invite
+    /// until someone binds the identifier, and lets them know about it.
+    /// `id_access_token` is an access token for the identity server, which
+    /// newer homeservers require.
+    ///
+    /// Errors are the same as for `invite_user()`.
+    pub fn invite_3pid(&mut self, id_server: &str, id_access_token: Option<&str>, medium: &str, address: &str) -> MatrixFuture<()> {
+        let mut body = vec![("id_server", id_server), ("medium", medium), ("address", address)];
+        body.extend(id_access_token.map(|x| ("id_access_token", x)));
+        MatrixRequest::new_with_body(Post, MatrixRequest::endpoint_with_segments(&["rooms", &self.room.id, "invite"]),
+                                     body)
             .discarding_send(self.cli)
     }
+    /// Get some of the current user's account data of type `ev_type` for this
+    /// room.
+    ///
+    /// See `MatrixClient::get_room_account_data()` for more.
+    pub fn get_account_data<T: DeserializeOwned + 'static>(&mut self, ev_type: &str) -> MatrixFuture<T> {
+        let user_id = self.cli.user_id().to_owned();
+        self.cli.get_room_account_data(&user_id, &self.room.id, ev_type)
+    }
+    /// Set some of the current user's account data of type `ev_type` for this
+    /// room.
+    ///
+    /// See `MatrixClient::set_room_account_data()` for more.
+    pub fn set_account_data<T: Serialize>(&mut self, ev_type: &str, content: T) -> MatrixFuture<()> {
+        let user_id = self.cli.user_id().to_owned();
+        self.cli.set_room_account_data(&user_id, &self.room.id, ev_type, content)
+    }
+    /// Get the current user's tags on this room.
+    pub fn get_tags(&mut self) -> MatrixFuture<Tag> {
+        self.cli.get_tags(&self.room.id)
+    }
+    /// Tag this room for the current user.
+    ///
+    /// See `MatrixClient::set_tag()` for more.
+    pub fn set_tag(&mut self, tag: &str, order: Option<f64>) -> MatrixFuture<()> {
+        self.cli.set_tag(&self.room.id, tag, order)
+    }
+    /// Remove a tag from this room for the current user.
+    pub fn remove_tag(&mut self, tag: &str) -> MatrixFuture<()> {
+        self.cli.remove_tag(&self.room.id, tag)
+    }
+    /// Set the current user's display name in this room only.
+    ///
+    /// See `MatrixClient::set_room_display_name()` for more.
+    pub fn set_display_name(&mut self, name: &str) -> MatrixFuture<SetStateReply> {
+        self.cli.set_room_display_name(&self.room.id, name)
+    }
+    /// Set a user's power level in this room.
+    ///
+    /// See `MatrixClient::set_user_power_level()` for more.
+    pub fn set_user_power_level(&mut self, user_id: &str, level: i64) -> MatrixFuture<SetStateReply> {
+        self.cli.set_user_power_level(&self.room.id, user_id, level)
+    }
     /// Get a user's power level, falling back on the default value for the room
     /// if not present.
     ///
@@ -264,10 +594,8 @@ impl<'a, 'b, 'c> RoomClient<'a, 'b, 'c> {
                 x.users_default
             }
         }).or_else(|e| {
-            if let &MatrixErrorKind::BadRequest(ref brk) = e.kind() {
-                if brk.errcode == "M_NOT_FOUND" {
-                    return Ok(0)
-                }
+            if e.errcode() == Some("M_NOT_FOUND") {
+                return Ok(0)
             }
             Err(e)
         }))
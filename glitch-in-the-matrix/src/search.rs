@@ -0,0 +1,70 @@
+//! Utilities for server-side search, with the `/search` API.
+
+use super::MatrixClient;
+use futures::*;
+use errors::*;
+use util::{PageItems, PageStream};
+
+pub use types::search::{RoomEventsCriteria, SearchEventContext, SearchGroupKey, SearchKey, SearchOrder};
+pub use types::search::{RoomEventsResults, SearchReply, SearchResult, SearchResultContext, SearchResultGroup};
+
+/// A `Stream` of the results of searching room events.
+///
+/// Like `PublicRoomsStream`, each item is a single result; pages are fetched
+/// as needed, by sending the same search again with the `next_batch` token of
+/// the previous reply. The stream finishes when the homeserver returns no
+/// `next_batch` token, or an empty page.
+///
+/// Obtain one of these with `MatrixClient::search_stream()`.
+pub struct SearchStream<'a> {
+    inner: PageItems<'a, SearchQuery, SearchReply, SearchResult>
+}
+struct SearchQuery {
+    criteria: RoomEventsCriteria,
+    count: Option<u64>
+}
+impl<'a> SearchStream<'a> {
+    pub(crate) fn new(cli: &'a mut MatrixClient, criteria: RoomEventsCriteria) -> Self {
+        let query = SearchQuery {
+            criteria,
+            count: None
+        };
+        let pages = PageStream::new(cli, query,
+                                    |cli, q, from| cli.search(&q.criteria, from),
+                                    |q, rpl| {
+                                        let rpl = rpl.search_categories.room_events;
+                                        if rpl.count.is_some() {
+                                            q.count = rpl.count;
+                                        }
+                                        (rpl.results, rpl.next_batch)
+                                    });
+        SearchStream {
+            inner: PageItems::new(pages)
+        }
+    }
+    /// Get the token that the next page will be fetched from, if any.
+    ///
+    /// Results that have already been fetched, but not yet returned by the
+    /// stream, aren't covered by this token.
+    pub fn next_batch(&self) -> Option<&str> {
+        self.inner.pages.from.as_ref().map(|x| x as &str)
+    }
+    /// Set the token to fetch the next page from, like one saved from
+    /// `next_batch()`.
+    pub fn set_next_batch(&mut self, next_batch: Option<String>) {
+        self.inner.pages.from = next_batch;
+    }
+    /// Get the homeserver's estimate of the total number of results, once
+    /// the first page has been fetched.
+    pub fn count(&self) -> Option<u64> {
+        self.inner.pages.state.count
+    }
+}
+impl<'a> Stream for SearchStream<'a> {
+    type Item = SearchResult;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.inner.poll()
+    }
+}
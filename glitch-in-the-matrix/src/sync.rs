@@ -1,27 +1,163 @@
 //! Utilities for using the long-polling `/sync` API.
 
-use hyper;
 use hyper::Method::*;
-use hyper::client::{HttpConnector, Request};
-use hyper_openssl::HttpsConnector;
-use types::sync::*;
-use super::MatrixFuture;
-use util::ResponseWrapper;
+use hyper::client::Request;
+use hyper::header::{Authorization, Bearer};
+use types::content::root::types::Presence;
+use types::filter::Filter;
+use super::{MatrixClient, MatrixFuture, RateLimitConfig};
+use http::MatrixHyper;
+use request::MatrixRequest;
+use hyper::StatusCode;
+use util::{ACCEPT_ENCODING, Metrics, MetricsHook, Reauth, ResponseWrapper, SharedToken, check_insecure_http, instrument, noting_status, send_with_reauth, with_timeout};
+use request::QUERY_COMPONENT_ENCODE_SET;
+use percent_encoding::utf8_percent_encode;
 use futures::*;
 use errors::*;
+use errors::MatrixErrorKind::*;
+use std::borrow::Cow;
+use std::cmp;
+use std::time::Duration;
+use tokio_core::reactor::{Handle, Timeout};
 
+pub use types::sync::SyncReply;
+
+/// A single request to the `/sync` API.
+///
+/// For syncing continuously, use a `SyncStream` instead.
+#[derive(Clone, Debug, Default)]
+pub struct SyncRequest {
+    /// The batch token to sync from (the `next_batch` of a previous
+    /// `SyncReply`). If this is `None`, an initial sync is done.
+    pub since: Option<String>,
+    /// How long the homeserver should wait for events to arrive, in
+    /// milliseconds, before returning an empty reply.
+    pub timeout: Option<u64>,
+    /// The ID of a filter to apply, or a filter definition in JSON.
+    pub filter: Option<String>,
+    /// Whether to return the full state of every room, even when syncing
+    /// from a batch token.
+    pub full_state: bool,
+    /// The presence to set for the user while syncing. If this is `None`,
+    /// the user is marked as online.
+    pub set_presence: Option<Presence>
+}
+impl SyncRequest {
+    /// Make a `SyncRequest` for an initial sync, with no other options set.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Sync from the given batch token.
+    pub fn since<T: Into<String>>(mut self, since: T) -> Self {
+        self.since = Some(since.into());
+        self
+    }
+    /// Set the long-polling timeout, in milliseconds.
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+    /// Apply a filter (an ID, or a filter definition in JSON).
+    pub fn filter<T: Into<String>>(mut self, filter: T) -> Self {
+        self.filter = Some(filter.into());
+        self
+    }
+    /// Apply a filter definition inline, instead of uploading it with
+    /// `MatrixClient::create_filter()` first.
+    ///
+    /// This saves a request for one-off syncs, but the whole filter is sent
+    /// every time; for syncing continuously, upload it and use its ID.
+    pub fn filter_definition(self, filter: &Filter) -> Self {
+        self.filter(json!(filter).to_string())
+    }
+    /// Set whether to return the full state of every room.
+    pub fn full_state(mut self, full_state: bool) -> Self {
+        self.full_state = full_state;
+        self
+    }
+    /// Set the presence to set for the user while syncing.
+    pub fn set_presence(mut self, presence: Presence) -> Self {
+        self.set_presence = Some(presence);
+        self
+    }
+    /// Make a `MatrixRequest` for this sync request.
+    ///
+    /// Its timeout is set to the client's request timeout plus the
+    /// long-polling timeout, if the client has one.
+    pub fn to_request(&self, mxc: &MatrixClient) -> MatrixRequest<'static, ()> {
+        let mut req = MatrixRequest::get("/sync");
+        if let Some(ref since) = self.since {
+            req = req.param("since", since);
+        }
+        if let Some(t) = self.timeout {
+            req = req.param("timeout", t);
+        }
+        if let Some(ref f) = self.filter {
+            req = req.param("filter", f);
+        }
+        if self.full_state {
+            req = req.param("full_state", true);
+        }
+        if let Some(ref p) = self.set_presence {
+            req = req.param("set_presence", p.as_str());
+        }
+        req.timeout = mxc.request_timeout()
+            .map(|t| t + Duration::from_millis(self.timeout.unwrap_or(0)));
+        req
+    }
+    /// Send this request, returning the homeserver's reply.
+    pub fn send(&self, mxc: &mut MatrixClient) -> MatrixFuture<SyncReply> {
+        let since = self.since.clone();
+        let full_state = self.full_state;
+        Box::new(self.to_request(mxc).send(mxc)
+                 .map(move |mut rpl: SyncReply| {
+                     rpl.since = since;
+                     rpl.full_state = full_state;
+                     rpl
+                 }))
+    }
+}
 /// A `Stream` that yields constant replies to `/sync`.
 ///
 /// This calls the long-polling `/sync` API, which will wait until replies come
 /// in and send them to the client. If you want to reduce the wait time, use the
 /// `set_timeout()` function.
+///
+/// Each reply's `next_batch` token is passed as the `since` parameter of the
+/// next request; it's also available from `since()` as soon as the reply is
+/// returned, to be persisted once the reply has been dealt with. Use
+/// `SyncReply::is_initial()` to tell the full state of an initial sync apart
+/// from the changes in later ones.
+///
+/// Transient errors (like network problems, or the homeserver being down)
+/// are retried after a delay, instead of being returned; see
+/// `set_error_backoff()`. If the homeserver no longer knows about the batch
+/// token being synced from, the stream starts again with an initial sync;
+/// see `set_reset_stale_since()`.
 pub struct SyncStream {
-    pub(crate) hyper: hyper::Client<HttpsConnector<HttpConnector>>,
+    pub(crate) hyper: MatrixHyper,
     pub(crate) last_batch: Option<String>,
     pub(crate) set_presence: bool,
-    pub(crate) access_token: String,
+    pub(crate) access_token: SharedToken,
+    pub(crate) reauth: Option<Reauth>,
+    pub(crate) rate_limit: RateLimitConfig,
+    pub(crate) token_in_query: bool,
     pub(crate) url: String,
+    pub(crate) api_prefix: Cow<'static, str>,
     pub(crate) timeout: u64,
+    pub(crate) timeout_grace: Option<Duration>,
+    pub(crate) hdl: Handle,
+    pub(crate) filter: Option<String>,
+    pub(crate) first_full_state: bool,
+    pub(crate) reset_stale_since: bool,
+    pub(crate) allow_insecure_http: bool,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) compression: bool,
+    pub(crate) metrics_hook: Option<MetricsHook>,
+    pub(crate) error_backoff: Option<Duration>,
+    pub(crate) max_backoff: Duration,
+    pub(crate) next_backoff: Option<Duration>,
+    pub(crate) backoff_timer: Option<Timeout>,
     pub(crate) cur_req: Option<MatrixFuture<SyncReply>>
 }
 impl SyncStream {
@@ -50,20 +186,153 @@ impl SyncStream {
     pub fn set_timeout(&mut self, timeout: u64) {
         self.timeout = timeout;
     }
+    /// Get the request timeout grace period.
+    pub fn timeout_grace(&self) -> Option<Duration> {
+        self.timeout_grace
+    }
+    /// Set a grace period for each `/sync` request, after which it fails with
+    /// a `Timeout` error. This is added on to the long-polling timeout (for
+    /// all but the initial sync, which doesn't long-poll), so that a stalled
+    /// connection doesn't hang the stream forever.
+    ///
+    /// The default value is the `MatrixClient`'s request timeout.
+    pub fn set_timeout_grace(&mut self, grace: Option<Duration>) {
+        self.timeout_grace = grace;
+    }
+    /// Get the batch token that the next request will use as its `since`
+    /// parameter, if any.
+    ///
+    /// Persist this to resume syncing from the same place later, with
+    /// `set_since()`.
+    pub fn since(&self) -> Option<&str> {
+        self.last_batch.as_ref().map(|x| x as &str)
+    }
+    /// Set the batch token to sync from, like one saved from `since()`.
+    ///
+    /// `None` makes the next request an initial sync.
+    pub fn set_since(&mut self, since: Option<String>) {
+        self.last_batch = since;
+    }
+    /// Get the filter ID in use, if any.
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_ref().map(|x| x as &str)
+    }
+    /// Set the ID of a filter (created with `MatrixClient::create_filter()`),
+    /// or a filter definition in JSON, to apply to the sync requests.
+    pub fn set_filter(&mut self, filter: Option<String>) {
+        self.filter = filter;
+    }
+    /// Apply a filter definition inline, instead of the ID of an uploaded
+    /// one.
+    ///
+    /// See `SyncRequest::filter_definition()` for more.
+    pub fn set_filter_definition(&mut self, filter: &Filter) {
+        self.filter = Some(json!(filter).to_string());
+    }
+    /// Ascertain whether the first request asks for the full state of every
+    /// room.
+    pub fn first_full_state(&self) -> bool {
+        self.first_full_state
+    }
+    /// Set whether the first request asks for the full state of every room
+    /// (with `full_state=true`), even if it syncs from a batch token given to
+    /// `set_since()`.
+    ///
+    /// The default value is `false`.
+    pub fn set_first_full_state(&mut self, v: bool) {
+        self.first_full_state = v;
+    }
+    /// Ascertain whether the stream starts again with an initial sync when
+    /// the homeserver rejects the batch token being synced from.
+    pub fn reset_stale_since(&self) -> bool {
+        self.reset_stale_since
+    }
+    /// Set whether the stream starts again with an initial sync when the
+    /// homeserver rejects the batch token being synced from (like one saved
+    /// a long time ago) with an errcode of `M_UNKNOWN`. If this is `false`,
+    /// the error is returned from the stream instead.
+    ///
+    /// The default value is `true`.
+    pub fn set_reset_stale_since(&mut self, v: bool) {
+        self.reset_stale_since = v;
+    }
+    /// Get the delay before retrying after a transient error.
+    pub fn error_backoff(&self) -> Option<Duration> {
+        self.error_backoff
+    }
+    /// Set how long to wait before retrying after a transient error, like a
+    /// connection failure, a timeout, an HTTP 5xx error or a rate limit.
+    ///
+    /// The delay doubles after each consecutive error, up to the maximum set
+    /// with `set_max_backoff()`. If this is `None`, errors are returned from
+    /// the stream instead.
+    ///
+    /// The default value is 1 second.
+    pub fn set_error_backoff(&mut self, backoff: Option<Duration>) {
+        self.error_backoff = backoff;
+    }
+    /// Get the maximum delay before retrying after a transient error.
+    pub fn max_backoff(&self) -> Duration {
+        self.max_backoff
+    }
+    /// Set the maximum delay before retrying after a transient error.
+    ///
+    /// The default value is 60 seconds.
+    pub fn set_max_backoff(&mut self, max: Duration) {
+        self.max_backoff = max;
+    }
+    fn is_transient(err: &MatrixError) -> bool {
+        match *err.kind() {
+            Hyper(_) | Io(_) | Timeout => true,
+            HttpCode(sc) | BadRequest(_, sc) => {
+                sc.is_server_error() || sc == StatusCode::TooManyRequests
+            },
+            _ => false
+        }
+    }
+    fn is_stale_since(&self, err: &MatrixError) -> bool {
+        self.last_batch.is_some() && err.errcode() == Some("M_UNKNOWN") &&
+            err.status_code().map(|sc| sc.is_client_error()).unwrap_or(false)
+    }
     fn req(&mut self) -> Request {
         let mut params = vec![];
+        if let Some(ref tok) = *self.access_token.borrow() {
+            if self.token_in_query {
+                params.push(format!("access_token={}", utf8_percent_encode(tok, QUERY_COMPONENT_ENCODE_SET)));
+            }
+        }
         params.push(format!("set_presence={}", if self.set_presence {
             "online"
         } else { "offline" }));
         if let Some(ref b) = self.last_batch {
-            params.push(format!("since={}", b));
+            params.push(format!("since={}", utf8_percent_encode(b, QUERY_COMPONENT_ENCODE_SET)));
             params.push(format!("timeout={}", self.timeout));
         }
-        Request::new(Get, format!("{}/_matrix/client/r0/sync?access_token={}&{}",
-                                  self.url,
-                                  &self.access_token,
-                                  params.join("&")
-        ).parse().unwrap())
+        if let Some(ref f) = self.filter {
+            params.push(format!("filter={}", utf8_percent_encode(f, QUERY_COMPONENT_ENCODE_SET)));
+        }
+        if self.first_full_state {
+            params.push("full_state=true".into());
+        }
+        let mut req = Request::new(Get, format!("{}{}/sync?{}",
+                                                self.url,
+                                                self.api_prefix,
+                                                params.join("&")
+        ).parse().unwrap());
+        if let Some(ref tok) = *self.access_token.borrow() {
+            if !self.token_in_query {
+                req.headers_mut().set(Authorization(Bearer {
+                    token: tok.clone()
+                }));
+            }
+        }
+        if let Some(ref ua) = self.user_agent {
+            req.headers_mut().set_raw("User-Agent", ua.clone());
+        }
+        if self.compression {
+            req.headers_mut().set_raw("Accept-Encoding", ACCEPT_ENCODING);
+        }
+        req
     }
 }
 
@@ -73,25 +342,56 @@ impl Stream for SyncStream {
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         loop {
+            if self.backoff_timer.is_some() {
+                try_ready!(self.backoff_timer.as_mut().unwrap().poll());
+                self.backoff_timer = None;
+            }
             if self.cur_req.is_some() {
                 match self.cur_req.as_mut().unwrap().poll() {
-                    Ok(Async::Ready(rpl)) => {
+                    Ok(Async::Ready(mut rpl)) => {
+                        rpl.since = self.last_batch.take();
+                        rpl.full_state = self.first_full_state;
                         self.last_batch = Some(rpl.next_batch.clone());
+                        self.first_full_state = false;
                         self.cur_req = None;
+                        self.next_backoff = None;
                         return Ok(Async::Ready(Some(rpl)));
                     },
                     Ok(Async::NotReady) => {
                         return Ok(Async::NotReady);
                     },
                     Err(e) => {
-                        return Err(e);
+                        self.cur_req = None;
+                        if self.reset_stale_since && self.is_stale_since(&e) {
+                            self.last_batch = None;
+                            continue;
+                        }
+                        let backoff = match self.error_backoff {
+                            Some(b) if Self::is_transient(&e) => self.next_backoff.unwrap_or(b),
+                            _ => return Err(e)
+                        };
+                        self.next_backoff = Some(cmp::min(backoff * 2, self.max_backoff));
+                        self.backoff_timer = Some(Timeout::new(backoff, &self.hdl)?);
+                        continue;
                     }
                 }
             }
+            check_insecure_http(&self.url, self.allow_insecure_http)?;
             let req = self.req();
-            self.cur_req = Some(Box::new(self.hyper.request(req)
-                                         .map_err(|e| e.into())
-                                         .and_then(ResponseWrapper::<SyncReply>::wrap)))
+            let metrics = self.metrics_hook.as_ref().map(|h| Metrics::new(h, &req));
+            let status = metrics.as_ref().map(|m| m.status.clone()).unwrap_or_default();
+            let timeout = self.timeout_grace.map(|g| {
+                if self.last_batch.is_some() {
+                    g + Duration::from_millis(self.timeout)
+                }
+                else {
+                    g
+                }
+            });
+            let fut = send_with_reauth(&self.hyper, &self.hdl, &self.rate_limit,
+                                       self.reauth.as_ref(), req,
+                                       noting_status(ResponseWrapper::<SyncReply>::wrap, status));
+            self.cur_req = Some(instrument(with_timeout(fut, timeout, &self.hdl), metrics));
         }
     }
 }
@@ -3,39 +3,201 @@
 use errors::*;
 use errors::MatrixErrorKind::*;
 use types::replies::*;
-use hyper::{Body, StatusCode};
-use hyper::client::Response;
+use hyper::{Body, Headers, HttpVersion, Method, StatusCode, Uri};
+use hyper::client::{Request, Response};
+use hyper::header::{Authorization, Bearer, ContentLength, ContentType, Location, RetryAfter};
+use hyper_openssl::openssl::rand::rand_bytes;
+use serde::Serialize;
 use serde::de::DeserializeOwned;
+use serde_json;
+use tokio_core::reactor::{Handle, Timeout};
 use futures::*;
+use futures::future::{self, Either, Loop};
+use flate2::read::{GzDecoder, ZlibDecoder};
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::io::Read;
 use std::marker::PhantomData;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
 use futures::stream::Concat2;
+use percent_encoding::{percent_decode, utf8_percent_encode};
+use request::{MatrixRequest, QUERY_COMPONENT_ENCODE_SET};
+use super::{MatrixClient, MatrixFuture, RateLimitConfig, RequestMetrics};
+use http::MatrixHyper;
+
+/// An access token that's shared between a client, its sync streams, and
+/// the requests they've got in flight, so that getting a new one updates all
+/// of them.
+pub type SharedToken = Rc<RefCell<Option<String>>>;
+/// What `send_with_reauth()` needs to get a new access token.
+#[derive(Clone)]
+pub struct Reauth {
+    pub handler: Rc<dyn Fn() -> MatrixFuture<String>>,
+    pub token: SharedToken,
+    pub token_in_query: bool
+}
+
+/// A function that's told about every request; see
+/// `MatrixClient::set_metrics_hook()`.
+pub type MetricsHook = Rc<dyn Fn(&RequestMetrics)>;
+/// Where the status code of the last response to a request is kept, for
+/// `instrument()` to report; see `noting_status()`.
+pub type StatusCell = Rc<Cell<Option<StatusCode>>>;
+/// What `instrument()` reports to a `MetricsHook`, besides the outcome.
+pub struct Metrics {
+    pub hook: MetricsHook,
+    pub method: Method,
+    pub endpoint: String,
+    pub status: StatusCell
+}
+impl Metrics {
+    pub fn new(hook: &MetricsHook, req: &Request) -> Self {
+        Metrics {
+            hook: hook.clone(),
+            method: req.method().clone(),
+            endpoint: MatrixRequest::endpoint_template(req.uri().path()),
+            status: Rc::new(Cell::new(None))
+        }
+    }
+}
+/// Makes a response wrapper that does what `wrap` does, but first notes the
+/// response's status code in `status`.
+pub fn noting_status<R>(wrap: fn(Response) -> R, status: StatusCell) -> impl Fn(Response) -> R + Clone {
+    move |r: Response| {
+        status.set(Some(r.status()));
+        wrap(r)
+    }
+}
+/// What's needed to send requests the way a `MatrixClient` does (with its
+/// rate-limiting, re-authentication and metrics hook), without borrowing
+/// the client.
+#[derive(Clone)]
+pub struct RequestSender {
+    pub hyper: MatrixHyper,
+    pub hdl: Handle,
+    pub rate_limit: RateLimitConfig,
+    pub reauth: Option<Reauth>,
+    pub metrics_hook: Option<MetricsHook>
+}
+impl RequestSender {
+    pub fn send<T, R>(&self, req: Request, timeout: Option<Duration>, wrap: fn(Response) -> R) -> MatrixFuture<T>
+        where R: Future<Item=T, Error=MatrixError> + 'static, T: 'static {
+        let metrics = self.metrics_hook.as_ref().map(|h| Metrics::new(h, &req));
+        let status = metrics.as_ref().map(|m| m.status.clone()).unwrap_or_default();
+        let fut = send_with_reauth(&self.hyper, &self.hdl, &self.rate_limit, self.reauth.as_ref(),
+                                   req, noting_status(wrap, status));
+        instrument(with_timeout(fut, timeout, &self.hdl), metrics)
+    }
+}
+/// The `Accept-Encoding` sent when asking for compressed responses.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate";
+/// The most a compressed response is allowed to decompress to, so that a
+/// small malicious (or broken) response can't use up all our memory.
+pub const MAX_DECOMPRESSED_SIZE: u64 = 64 * 1024 * 1024;
 
 pub struct ResponseWrapper<T> {
     inner: Concat2<Body>,
     sc: StatusCode,
+    retry_after: Option<Duration>,
+    encoding: Option<String>,
     _ph: PhantomData<T>,
 }
 pub struct UnitaryResponseWrapper {
     inner: ResponseWrapper<()>
 }
+/// What a `RawResponseWrapper` resolves to.
+pub enum RawReply {
+    /// The homeserver redirected us somewhere else.
+    Redirect(String),
+    /// The response body, its content type, and the filename from its
+    /// `Content-Disposition`.
+    Data(Vec<u8>, Option<ContentType>, Option<String>)
+}
+pub struct UiaaResponseWrapper<T> {
+    inner: ResponseWrapper<T>
+}
+/// Like `ResponseWrapper`, but resolves to the response's headers as well
+/// as its body.
+pub struct HeadersResponseWrapper<T> {
+    inner: ResponseWrapper<T>,
+    headers: Option<Headers>
+}
+pub struct RawResponseWrapper {
+    inner: ResponseWrapper<()>,
+    ct: Option<ContentType>,
+    filename: Option<String>,
+    location: Option<String>
+}
 impl<T: DeserializeOwned> ResponseWrapper<T> {
     pub fn wrap(r: Response) -> Self {
         let sc = r.status();
+        let retry_after = match r.headers().get::<RetryAfter>() {
+            Some(&RetryAfter::Delay(d)) => Some(d),
+            _ => None
+        };
+        let encoding = r.headers().get_raw("Content-Encoding")
+            .and_then(|h| h.one())
+            .map(|e| String::from_utf8_lossy(e).trim().to_lowercase());
         let inner = r.body().concat2();
         let _ph = PhantomData;
-        Self { sc, inner, _ph, }
+        Self { sc, inner, retry_after, encoding, _ph, }
     }
     fn _poll(&mut self) -> Poll<::hyper::Chunk, MatrixError> {
+        let resp = try_ready!(self.poll_body());
+        Ok(Async::Ready(self.check(resp)?))
+    }
+    /// Gets the whole response body, decompressing it if the homeserver
+    /// compressed it.
+    fn poll_body(&mut self) -> Poll<::hyper::Chunk, MatrixError> {
         let resp = try_ready!(self.inner.poll());
+        let mut data = vec![];
+        // Read one byte past the limit, so we can tell when it's been hit.
+        let limit = MAX_DECOMPRESSED_SIZE + 1;
+        match self.encoding.as_ref().map(|x| x as &str) {
+            Some("gzip") | Some("x-gzip") => {
+                GzDecoder::new(&resp[..]).take(limit).read_to_end(&mut data)?;
+            },
+            Some("deflate") => {
+                ZlibDecoder::new(&resp[..]).take(limit).read_to_end(&mut data)?;
+            },
+            _ => return Ok(Async::Ready(resp))
+        }
+        if data.len() as u64 > MAX_DECOMPRESSED_SIZE {
+            bail!(ResponseTooLarge(MAX_DECOMPRESSED_SIZE));
+        }
+        Ok(Async::Ready(data.into()))
+    }
+    /// Turns the response body into an error, if the status code isn't a
+    /// successful one.
+    fn check(&self, resp: ::hyper::Chunk) -> MatrixResult<::hyper::Chunk> {
         if !self.sc.is_success() {
-            if let Ok(e) = ::serde_json::from_slice::<BadRequestReply>(&resp) {
-                bail!(BadRequest(e));
+            if self.sc == StatusCode::Unauthorized {
+                // Endpoints needing User-Interactive Authentication say what
+                // they want, rather than sending a Matrix error.
+                if let Ok(info) = ::serde_json::from_slice::<UiaaInfo>(&resp) {
+                    if !info.flows.is_empty() {
+                        bail!(AuthRequired(Box::new(info)));
+                    }
+                }
+            }
+            if let Ok(mut e) = ::serde_json::from_slice::<BadRequestReply>(&resp) {
+                if e.retry_after_ms.is_none() {
+                    e.retry_after_ms = self.retry_after.map(duration_to_ms);
+                }
+                if e.soft_logout {
+                    // The token's dead, but the device isn't; clients will
+                    // want to log back in, rather than start over.
+                    bail!(SoftLogout(e, self.sc));
+                }
+                bail!(BadRequest(e, self.sc));
             }
             else {
-                bail!(HttpCode(self.sc.clone()));
+                bail!(HttpCode(self.sc));
             }
         }
-        Ok(Async::Ready(resp))
+        Ok(resp)
     }
 }
 impl UnitaryResponseWrapper {
@@ -45,6 +207,40 @@ impl UnitaryResponseWrapper {
         }
     }
 }
+impl<T: DeserializeOwned> UiaaResponseWrapper<T> {
+    pub fn wrap(r: Response) -> Self {
+        Self {
+            inner: ResponseWrapper::<T>::wrap(r)
+        }
+    }
+}
+impl<T: DeserializeOwned> HeadersResponseWrapper<T> {
+    pub fn wrap(r: Response) -> Self {
+        let headers = r.headers().clone();
+        Self {
+            inner: ResponseWrapper::<T>::wrap(r),
+            headers: Some(headers)
+        }
+    }
+}
+impl RawResponseWrapper {
+    pub fn wrap(r: Response) -> Self {
+        let location = if r.status().is_redirection() {
+            r.headers().get::<Location>().map(|l| l.to_string())
+        }
+        else {
+            None
+        };
+        let ct = r.headers().get::<ContentType>().cloned();
+        let filename = filename_from_headers(r.headers());
+        Self {
+            inner: ResponseWrapper::<()>::wrap(r),
+            ct,
+            filename,
+            location
+        }
+    }
+}
 impl<T: DeserializeOwned> Future for ResponseWrapper<T> {
     type Item = T;
     type Error = MatrixError;
@@ -66,3 +262,635 @@ impl Future for UnitaryResponseWrapper {
         Ok(Async::Ready(()))
     }
 }
+impl<T: DeserializeOwned> Future for UiaaResponseWrapper<T> {
+    type Item = UiaaResponse<T>;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let resp = try_ready!(self.inner.poll_body());
+        if self.inner.sc == StatusCode::Unauthorized {
+            if let Ok(info) = ::serde_json::from_slice::<UiaaInfo>(&resp) {
+                if !info.flows.is_empty() {
+                    return Ok(Async::Ready(UiaaResponse::AuthRequired(info)));
+                }
+            }
+        }
+        let resp = self.inner.check(resp)?;
+        let data = ::serde_json::from_slice::<T>(&resp)?;
+        Ok(Async::Ready(UiaaResponse::Success(data)))
+    }
+}
+impl<T: DeserializeOwned> Future for HeadersResponseWrapper<T> {
+    type Item = (T, Headers);
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let data = try_ready!(self.inner.poll());
+        Ok(Async::Ready((data, self.headers.take().unwrap_or_default())))
+    }
+}
+impl Future for RawResponseWrapper {
+    type Item = RawReply;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(l) = self.location.take() {
+            return Ok(Async::Ready(RawReply::Redirect(l)));
+        }
+        let resp = try_ready!(self.inner._poll());
+        Ok(Async::Ready(RawReply::Data(resp.to_vec(), self.ct.take(), self.filename.take())))
+    }
+}
+
+/// Give a request an empty JSON object as its body.
+///
+/// `MatrixRequest` leaves out empty bodies, but some endpoints (like
+/// `/redact`) insist on having one.
+pub fn set_empty_json_body(req: &mut Request) {
+    req.headers_mut().set(ContentType::json());
+    req.headers_mut().set(ContentLength(2));
+    req.set_body("{}");
+}
+/// Give a request `body`, serialized as JSON, as its body.
+///
+/// This is for requests whose body is only known after they've been made,
+/// like the second half of a read-modify-write.
+pub fn set_json_body<T: Serialize>(req: &mut Request, body: &T) -> MatrixResult<()> {
+    let body = serde_json::to_string(body)?;
+    req.headers_mut().set(ContentType::json());
+    req.headers_mut().set(ContentLength(body.len() as u64));
+    req.set_body(body);
+    Ok(())
+}
+/// Check that requests can be sent to the homeserver at `url`: either over
+/// HTTPS, or over HTTP to this machine, or over HTTP anywhere if `allow`.
+pub fn check_insecure_http(url: &str, allow: bool) -> MatrixResult<()> {
+    if allow {
+        return Ok(());
+    }
+    let uri: Uri = url.parse()?;
+    if uri.scheme() != Some("http") {
+        return Ok(());
+    }
+    let host = uri.host().unwrap_or("").trim_start_matches('[').trim_end_matches(']');
+    let local = host.eq_ignore_ascii_case("localhost") ||
+        host.parse::<IpAddr>().map(|ip| ip.is_loopback()).unwrap_or(false);
+    if !local {
+        bail!(InsecureHttp(url.into()));
+    }
+    Ok(())
+}
+/// Make up a random client secret, for validating a third-party identifier.
+///
+/// The spec only allows `[0-9a-zA-Z.=_-]` in these, so this is hex.
+pub fn make_client_secret() -> MatrixResult<String> {
+    let mut buf = [0; 16];
+    rand_bytes(&mut buf)?;
+    Ok(buf.iter().map(|b| format!("{:02x}", b)).collect())
+}
+/// When a token that the homeserver says is valid for `ms` milliseconds
+/// expires, if it does.
+pub fn expiry_after(ms: Option<u64>) -> Option<Instant> {
+    ms.and_then(|ms| Instant::now().checked_add(Duration::from_millis(ms)))
+}
+fn duration_to_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+/// A copy of a `Request` that can be sent more than once.
+#[derive(Clone)]
+pub struct BufferedRequest {
+    meth: Method,
+    uri: Uri,
+    version: HttpVersion,
+    headers: Headers,
+    body: Option<Vec<u8>>
+}
+impl BufferedRequest {
+    pub fn buffer(req: Request) -> MatrixFuture<Self> {
+        let has_body = req.body_ref().is_some();
+        let (meth, uri, version, headers, body) = req.deconstruct();
+        let fut = if has_body {
+            Either::A(body.concat2().map(|c| Some(c.to_vec())).map_err(|e| e.into()))
+        }
+        else {
+            Either::B(future::ok(None))
+        };
+        Box::new(fut.map(move |body| {
+            BufferedRequest { meth, uri, version, headers, body }
+        }))
+    }
+    /// Like `buffer()`, but for a request without a body (yet), which can
+    /// be done straight away.
+    pub fn without_body(req: Request) -> Self {
+        let (meth, uri, version, headers, _) = req.deconstruct();
+        BufferedRequest { meth, uri, version, headers, body: None }
+    }
+    /// Replace the access token that this request is sent with.
+    pub fn set_access_token(&mut self, token: &str, in_query: bool) -> MatrixResult<()> {
+        if !in_query {
+            self.headers.set(Authorization(Bearer {
+                token: token.to_owned()
+            }));
+            return Ok(());
+        }
+        let query = self.uri.query().unwrap_or("").split('&')
+            .map(|p| {
+                if p.starts_with("access_token=") {
+                    format!("access_token={}", utf8_percent_encode(token, QUERY_COMPONENT_ENCODE_SET))
+                }
+                else {
+                    p.to_owned()
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        if let (Some(scheme), Some(auth)) = (self.uri.scheme(), self.uri.authority()) {
+            self.uri = format!("{}://{}{}?{}", scheme, auth, self.uri.path(), query).parse()?;
+        }
+        Ok(())
+    }
+    /// Replace the body of this request with `body`, serialized as JSON.
+    pub fn set_json_body<T: Serialize>(&mut self, body: &T) -> MatrixResult<()> {
+        let body = serde_json::to_vec(body)?;
+        self.headers.set(ContentType::json());
+        self.headers.set(ContentLength(body.len() as u64));
+        self.body = Some(body);
+        Ok(())
+    }
+    pub fn make(&self) -> Request {
+        let mut req = Request::new(self.meth.clone(), self.uri.clone());
+        req.set_version(self.version);
+        *req.headers_mut() = self.headers.clone();
+        if let Some(ref b) = self.body {
+            req.set_body(b.clone());
+        }
+        req
+    }
+}
+impl RateLimitConfig {
+    /// Works out how long to wait before retrying a request that failed with
+    /// `err`, if at all.
+    fn retry_delay(&self, err: &MatrixError, tries: u32, waited: Duration) -> Option<Duration> {
+        if tries >= self.max_retries {
+            return None;
+        }
+        let advice = match *err.kind() {
+            BadRequest(ref brq, _) if brq.errcode == "M_LIMIT_EXCEEDED" => brq.retry_after_ms,
+            HttpCode(StatusCode::TooManyRequests) => None,
+            _ => return None
+        };
+        let delay = match advice {
+            Some(ms) if self.honor_retry_after => Duration::from_millis(ms),
+            _ => self.delay
+        };
+        if waited + delay > self.max_total_wait {
+            return None;
+        }
+        Some(delay)
+    }
+}
+/// Sends a request, using `wrap` to process the response, and retrying it if
+/// it gets rate-limited (as configured by `cfg`).
+pub fn send_with_retries<T, R, W>(hyper: &MatrixHyper,
+                                  hdl: &Handle,
+                                  cfg: &RateLimitConfig,
+                                  req: Request,
+                                  wrap: W) -> MatrixFuture<T>
+    where R: Future<Item=T, Error=MatrixError> + 'static, T: 'static,
+          W: Fn(Response) -> R + Clone + 'static {
+    if cfg.max_retries == 0 {
+        return Box::new(hyper.request(req)
+                        .map_err(|e| e.into())
+                        .and_then(wrap));
+    }
+    let hyper = hyper.clone();
+    let hdl = hdl.clone();
+    let cfg = cfg.clone();
+    Box::new(BufferedRequest::buffer(req).and_then(move |breq| {
+        future::loop_fn((0, Duration::from_millis(0)), move |(tries, waited)| {
+            let hdl = hdl.clone();
+            let cfg = cfg.clone();
+            hyper.request(breq.make())
+                .map_err(|e| e.into())
+                .and_then(wrap.clone())
+                .then(move |res| {
+                    let err = match res {
+                        Ok(x) => return Either::A(future::ok(Loop::Break(x))),
+                        Err(e) => e
+                    };
+                    let delay = match cfg.retry_delay(&err, tries, waited) {
+                        Some(d) => d,
+                        None => return Either::A(future::err(err))
+                    };
+                    let timeout = match Timeout::new(delay, &hdl) {
+                        Ok(t) => t,
+                        Err(e) => return Either::A(future::err(e.into()))
+                    };
+                    Either::B(timeout
+                              .map_err(|e| e.into())
+                              .map(move |_| Loop::Continue((tries + 1, waited + delay))))
+                })
+        })
+    }))
+}
+/// Removes the access token from a request, whether it's in the
+/// `Authorization` header or the query string.
+pub fn strip_access_token(req: &mut Request) -> MatrixResult<()> {
+    req.headers_mut().remove::<Authorization<Bearer>>();
+    let uri = {
+        let uri = req.uri();
+        let query = match uri.query() {
+            Some(q) => q,
+            None => return Ok(())
+        };
+        if !query.split('&').any(|p| p.starts_with("access_token=")) {
+            return Ok(());
+        }
+        let query = query.split('&')
+            .filter(|p| !p.starts_with("access_token="))
+            .collect::<Vec<_>>()
+            .join("&");
+        let mut ret = format!("{}://{}{}", uri.scheme().unwrap_or("https"),
+                              uri.authority().unwrap_or(""), uri.path());
+        if !query.is_empty() {
+            ret += "?";
+            ret += &query;
+        }
+        ret.parse::<Uri>()?
+    };
+    req.set_uri(uri);
+    Ok(())
+}
+/// Like `send_with_retries()`, but if the request fails because its access
+/// token is invalid (`M_UNKNOWN_TOKEN`), gets a new one with `reauth` and
+/// sends the request again. This only happens once per request, so a
+/// handler that gives out bad tokens can't make it loop.
+///
+/// If another request has already got a new token in the meantime, that one
+/// is used instead of calling the handler again. Requests sent without a
+/// token, or whose client has since logged out, fail as usual.
+pub fn send_with_reauth<T, R, W>(hyper: &MatrixHyper,
+                                 hdl: &Handle,
+                                 cfg: &RateLimitConfig,
+                                 reauth: Option<&Reauth>,
+                                 req: Request,
+                                 wrap: W) -> MatrixFuture<T>
+    where R: Future<Item=T, Error=MatrixError> + 'static, T: 'static,
+          W: Fn(Response) -> R + Clone + 'static {
+    let reauth = match reauth {
+        Some(r) => r.clone(),
+        None => return send_with_retries(hyper, hdl, cfg, req, wrap)
+    };
+    let sent_with = reauth.token.borrow().clone();
+    if sent_with.is_none() {
+        return send_with_retries(hyper, hdl, cfg, req, wrap);
+    }
+    let hyper = hyper.clone();
+    let hdl = hdl.clone();
+    let cfg = cfg.clone();
+    Box::new(BufferedRequest::buffer(req).and_then(move |mut breq| {
+        let fut = send_with_retries(&hyper, &hdl, &cfg, breq.make(), wrap.clone());
+        fut.or_else(move |e| {
+            if e.errcode() != Some("M_UNKNOWN_TOKEN") {
+                return Either::A(future::err(e));
+            }
+            let current = reauth.token.borrow().clone();
+            let new_token: MatrixFuture<String> = match current {
+                // We've been logged out since, so there's nothing to renew.
+                None => return Either::A(future::err(e)),
+                Some(ref t) if Some(t) != sent_with.as_ref() => Box::new(future::ok(t.clone())),
+                Some(_) => {
+                    let token = reauth.token.clone();
+                    Box::new((reauth.handler)().map(move |t| {
+                        *token.borrow_mut() = Some(t.clone());
+                        t
+                    }))
+                }
+            };
+            let in_query = reauth.token_in_query;
+            Either::B(new_token
+                      .and_then(move |t| {
+                          breq.set_access_token(&t, in_query)?;
+                          Ok(send_with_retries(&hyper, &hdl, &cfg, breq.make(), wrap))
+                      })
+                      .flatten())
+        })
+    }))
+}
+/// Makes `fut` fail with a `Timeout` error if it doesn't complete within
+/// `timeout`.
+///
+/// When the timeout fires, `fut` is dropped, which cancels any HTTP request it
+/// was waiting on.
+pub fn with_timeout<T: 'static>(fut: MatrixFuture<T>, timeout: Option<Duration>, hdl: &Handle) -> MatrixFuture<T> {
+    let timeout = match timeout {
+        Some(t) => t,
+        None => return fut
+    };
+    let timer = match Timeout::new(timeout, hdl) {
+        Ok(t) => t,
+        Err(e) => return Box::new(future::err(e.into()))
+    };
+    Box::new(fut.select2(timer).then(|res| {
+        match res {
+            Ok(Either::A((x, _))) => Ok(x),
+            Ok(Either::B(_)) => Err(MatrixErrorKind::Timeout.into()),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((e, _))) => Err(e.into())
+        }
+    }))
+}
+/// Report the outcome of `fut`, and how long it took, to a metrics hook (if
+/// `metrics` is `Some`).
+pub fn instrument<T: 'static>(fut: MatrixFuture<T>, metrics: Option<Metrics>) -> MatrixFuture<T> {
+    let metrics = match metrics {
+        Some(m) => m,
+        None => return fut
+    };
+    let start = Instant::now();
+    Box::new(fut.then(move |res| {
+        {
+            let error = res.as_ref().err();
+            (*metrics.hook)(&RequestMetrics {
+                method: &metrics.method,
+                endpoint: &metrics.endpoint,
+                status: metrics.status.get().or_else(|| error.and_then(|e| e.status_code())),
+                elapsed: start.elapsed(),
+                error
+            });
+        }
+        res
+    }))
+}
+/// The maximum number of redirects `send_following_redirects()` follows.
+const MAX_REDIRECTS: u32 = 5;
+/// Works out where a `Location` header, sent in reply to a request for
+/// `base`, points to.
+fn resolve_location(base: &Uri, loc: &str) -> MatrixResult<Uri> {
+    if loc.starts_with('/') {
+        if let (Some(scheme), Some(auth)) = (base.scheme(), base.authority()) {
+            return Ok(format!("{}://{}{}", scheme, auth, loc).parse()?);
+        }
+    }
+    Ok(loc.parse()?)
+}
+/// Sends a request for some raw data (like a media download), following any
+/// redirects the homeserver sends back.
+///
+/// Redirected requests are plain `GET`s, without any of the original headers
+/// (so the access token isn't sent to some other server).
+pub fn send_following_redirects(hyper: &MatrixHyper,
+                                hdl: &Handle,
+                                cfg: &RateLimitConfig,
+                                req: Request) -> MatrixFuture<(Vec<u8>, Option<ContentType>, Option<String>)> {
+    let hyper = hyper.clone();
+    let hdl = hdl.clone();
+    let cfg = cfg.clone();
+    Box::new(future::loop_fn((req, 0), move |(req, hops)| {
+        let base = req.uri().clone();
+        send_with_retries(&hyper, &hdl, &cfg, req, RawResponseWrapper::wrap)
+            .and_then(move |rpl| {
+                match rpl {
+                    RawReply::Data(data, ct, filename) => Ok(Loop::Break((data, ct, filename))),
+                    RawReply::Redirect(loc) => {
+                        if hops >= MAX_REDIRECTS {
+                            bail!(TooManyRedirects);
+                        }
+                        let uri = resolve_location(&base, &loc)?;
+                        Ok(Loop::Continue((Request::new(Method::Get, uri), hops + 1)))
+                    }
+                }
+            })
+    }))
+}
+/// Like `send_following_redirects()`, but resolving to the response as soon
+/// as its headers arrive, so that the body can be streamed instead of
+/// buffered.
+///
+/// Since the body isn't buffered, rate-limited requests aren't retried.
+pub fn send_streaming_following_redirects(hyper: &MatrixHyper,
+                                          req: Request) -> MatrixFuture<Response> {
+    let hyper = hyper.clone();
+    Box::new(future::loop_fn((req, 0), move |(req, hops)| {
+        let base = req.uri().clone();
+        hyper.request(req)
+            .map_err(|e| e.into())
+            .and_then(move |resp| {
+                let sc = resp.status();
+                if sc.is_success() {
+                    return Either::A(future::ok(Loop::Break(resp)));
+                }
+                if let Some(loc) = resp.headers().get::<Location>().map(|l| l.to_string()) {
+                    if sc.is_redirection() {
+                        if hops >= MAX_REDIRECTS {
+                            return Either::A(future::err(TooManyRedirects.into()));
+                        }
+                        let next = resolve_location(&base, &loc)
+                            .map(|uri| Loop::Continue((Request::new(Method::Get, uri), hops + 1)));
+                        return Either::A(future::result(next));
+                    }
+                }
+                // Read the error out of the body.
+                Either::B(ResponseWrapper::<()>::wrap(resp).then(move |res| {
+                    Err(match res {
+                        Err(e) => e,
+                        Ok(_) => HttpCode(sc).into()
+                    })
+                }))
+            })
+    }))
+}
+/// Gets the filename of some content from its `Content-Disposition` header,
+/// if it has one.
+pub fn filename_from_headers(headers: &Headers) -> Option<String> {
+    headers.get_raw("Content-Disposition")
+        .and_then(|raw| raw.one())
+        .and_then(|v| ::std::str::from_utf8(v).ok())
+        .and_then(disposition_filename)
+}
+/// Gets the filename out of the value of a `Content-Disposition` header,
+/// preferring the RFC 5987 `filename*` form (which can be non-ASCII) to
+/// plain `filename`.
+fn disposition_filename(header: &str) -> Option<String> {
+    let mut plain = None;
+    for param in header.split(';').skip(1) {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next().unwrap_or("").trim().to_lowercase();
+        let val = match kv.next() {
+            Some(v) => v.trim(),
+            None => continue
+        };
+        if key == "filename*" {
+            // `charset'language'percent-encoded-name`
+            if let Some(enc) = val.splitn(3, '\'').nth(2) {
+                if let Ok(name) = percent_decode(enc.as_bytes()).decode_utf8() {
+                    return Some(name.into_owned());
+                }
+            }
+        }
+        else if key == "filename" {
+            plain = Some(val.trim_matches('"').to_owned());
+        }
+    }
+    plain
+}
+/// Sends a request that may need User-Interactive Authentication, completing
+/// stages with the `auth` objects that `stage` gives back, until it succeeds.
+///
+/// `stage` is called with the type of each stage that could be completed
+/// next (see `UiaaInfo::next_stages()`), until it gives back some `AuthData`.
+/// If it doesn't for any of them, or a stage fails (the homeserver asks again
+/// without any more stages having been completed), this gives up and
+/// resolves to the last `UiaaResponse::AuthRequired`.
+pub fn send_uiaa_with_stages<T, F>(hyper: &MatrixHyper,
+                                   hdl: &Handle,
+                                   cfg: &RateLimitConfig,
+                                   reauth: Option<Reauth>,
+                                   req: Request,
+                                   status: StatusCell,
+                                   stage: F) -> MatrixFuture<UiaaResponse<T>>
+    where T: DeserializeOwned + 'static, F: FnMut(&str, &UiaaInfo) -> Option<AuthData> + 'static {
+    let hyper = hyper.clone();
+    let hdl = hdl.clone();
+    let cfg = cfg.clone();
+    let stage = Rc::new(RefCell::new(stage));
+    Box::new(BufferedRequest::buffer(req).and_then(move |breq| {
+        let body: serde_json::Value = match breq.body {
+            Some(ref b) => serde_json::from_slice(b)?,
+            None => json!({})
+        };
+        // The `auth` object has to go somewhere.
+        let body = match body {
+            serde_json::Value::Object(o) => o,
+            _ => bail!("requests using User-Interactive Authentication need a JSON object body")
+        };
+        Ok(future::loop_fn((breq, None), move |(mut breq, last_completed): (BufferedRequest, Option<usize>)| {
+            let body = body.clone();
+            let stage = stage.clone();
+            let wrap = noting_status(UiaaResponseWrapper::<T>::wrap, status.clone());
+            send_with_reauth(&hyper, &hdl, &cfg, reauth.as_ref(), breq.make(), wrap)
+                .and_then(move |rpl| {
+                    let info = match rpl {
+                        UiaaResponse::Success(x) => return Ok(Loop::Break(UiaaResponse::Success(x))),
+                        UiaaResponse::AuthRequired(info) => info
+                    };
+                    if last_completed.map(|n| info.completed.len() <= n).unwrap_or(false) {
+                        return Ok(Loop::Break(UiaaResponse::AuthRequired(info)));
+                    }
+                    let auth = {
+                        let mut stage = stage.borrow_mut();
+                        info.next_stages().into_iter()
+                            .filter_map(|s| (*stage)(s, &info))
+                            .next()
+                    };
+                    let auth = match auth {
+                        Some(a) => a.to_json(info.session.as_ref().map(|x| x as &str)),
+                        None => return Ok(Loop::Break(UiaaResponse::AuthRequired(info)))
+                    };
+                    let mut body = body;
+                    body.insert("auth".into(), auth);
+                    breq.set_json_body(&body)?;
+                    Ok(Loop::Continue((breq, Some(info.completed.len()))))
+                })
+        }))
+    }).flatten())
+}
+/// Sends the request for a page of a `PageStream`.
+type PageFetcher<S, R> = fn(&mut MatrixClient, &mut S, Option<&str>) -> MatrixFuture<R>;
+/// Splits the reply for a page of a `PageStream` into its items and the
+/// token for the next page.
+type PageSplitter<S, R, T> = fn(&mut S, R) -> (Vec<T>, Option<String>);
+/// Pages through an API that returns a token to fetch the next page from,
+/// for streams like `MessagesStream` and `PublicRoomsStream`.
+///
+/// `fetch` sends the request for the page at a token (or the first page, if
+/// it's `None`), and `split` splits its reply into the page's items and the
+/// token for the next page. Each item of the stream is a page; it finishes
+/// when there's no next token, or an empty page.
+pub struct PageStream<'a, S, R, T> {
+    cli: &'a mut MatrixClient,
+    /// Whatever `fetch` needs to know, besides the token.
+    pub state: S,
+    /// The token that the next page will be fetched from.
+    pub from: Option<String>,
+    fetch: PageFetcher<S, R>,
+    split: PageSplitter<S, R, T>,
+    done: bool,
+    cur_req: Option<MatrixFuture<R>>
+}
+impl<'a, S, R, T> PageStream<'a, S, R, T> {
+    pub fn new(cli: &'a mut MatrixClient, state: S, fetch: PageFetcher<S, R>, split: PageSplitter<S, R, T>) -> Self {
+        PageStream {
+            cli,
+            state,
+            from: None,
+            fetch,
+            split,
+            done: false,
+            cur_req: None
+        }
+    }
+}
+impl<'a, S, R, T> Stream for PageStream<'a, S, R, T> {
+    type Item = Vec<T>;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.done {
+            return Ok(Async::Ready(None));
+        }
+        if self.cur_req.is_none() {
+            let fut = (self.fetch)(self.cli, &mut self.state, self.from.as_ref().map(|x| x as &str));
+            self.cur_req = Some(fut);
+        }
+        let rpl = match self.cur_req.as_mut().unwrap().poll() {
+            Ok(Async::Ready(r)) => r,
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Err(e) => {
+                self.cur_req = None;
+                return Err(e);
+            }
+        };
+        self.cur_req = None;
+        let (items, next) = (self.split)(&mut self.state, rpl);
+        if items.is_empty() {
+            self.done = true;
+            return Ok(Async::Ready(None));
+        }
+        match next {
+            Some(next) => self.from = Some(next),
+            None => self.done = true
+        }
+        Ok(Async::Ready(Some(items)))
+    }
+}
+/// The items of a `PageStream`, one at a time; pages are only fetched when
+/// the items of the previous one have run out.
+pub struct PageItems<'a, S, R, T> {
+    pub pages: PageStream<'a, S, R, T>,
+    items: VecDeque<T>
+}
+impl<'a, S, R, T> PageItems<'a, S, R, T> {
+    pub fn new(pages: PageStream<'a, S, R, T>) -> Self {
+        PageItems {
+            pages,
+            items: VecDeque::new()
+        }
+    }
+}
+impl<'a, S, R, T> Stream for PageItems<'a, S, R, T> {
+    type Item = T;
+    type Error = MatrixError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(item) = self.items.pop_front() {
+                return Ok(Async::Ready(Some(item)));
+            }
+            match try_ready!(self.pages.poll()) {
+                Some(page) => self.items.extend(page),
+                None => return Ok(Async::Ready(None))
+            }
+        }
+    }
+}
@@ -0,0 +1,3072 @@
+extern crate glitch_in_the_matrix as gm;
+extern crate futures;
+extern crate hyper;
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate serde_json;
+extern crate tokio_core;
+extern crate flate2;
+
+mod mock;
+
+use gm::{MatrixClient, RateLimitConfig, abortable};
+use futures::Stream;
+use gm::errors::*;
+use gm::request::MatrixRequest;
+use gm::types::replies::SendReply;
+use gm::media::{ContentUri, ThumbnailMethod};
+use gm::room::{Direction, Room};
+use hyper::Method::*;
+use hyper::StatusCode;
+use hyper::header::ContentType;
+use mock::{MockServer, MockResponse};
+use tokio_core::reactor::Core;
+use std::time::{Duration, Instant};
+
+const RATE_LIMITED: &str = r#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too Many Requests", "retry_after_ms": 10}"#;
+
+fn client(core: &Core, srv: &MockServer) -> MatrixClient {
+    MatrixClient::new_from_access_token("token", "@bot:example.org", &srv.url, &core.handle())
+        .unwrap()
+}
+/// Get rid of a client without it logging out, which would use up one of
+/// the mock server's responses.
+fn keep_logged_in(mx: MatrixClient) {
+    mx.into_access_token();
+}
+fn send_message(core: &mut Core, mx: &mut MatrixClient) -> MatrixResult<SendReply> {
+    let req = MatrixRequest::new_with_body(Put, "/rooms/!r:example.org/send/m.room.message/1",
+                                           vec![("msgtype", "m.text"), ("body", "hi")]);
+    core.run(req.send(mx))
+}
+
+#[test]
+fn rate_limit_retry() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_rate_limit_config(RateLimitConfig { max_retries: 3, ..Default::default() });
+    let rpl = send_message(&mut core, &mut mx).unwrap();
+    assert_eq!(rpl.event_id, "$ev:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 2);
+    assert_eq!(reqs[0].path, reqs[1].path);
+    assert_eq!(reqs[0].body, reqs[1].body);
+    assert!(reqs[1].body_str().contains("\"body\":\"hi\""));
+}
+
+#[test]
+fn rate_limit_sync_stream() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(200, r#"{"next_batch": "s1"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_rate_limit_retries(1);
+    let mut ss = mx.sync_stream();
+    // Without this, the stream would retry by itself.
+    ss.set_error_backoff(None);
+    let rpl = core.run(ss.into_future()).map_err(|(e, _)| e).unwrap().0.unwrap();
+    assert_eq!(rpl.next_batch, "s1");
+    assert_eq!(srv.requests().len(), 2);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn sync_stream_outlives_access_token() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"next_batch": "s1"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mx = client(&core, &srv);
+    let ss = mx.sync_stream();
+    assert_eq!(mx.into_access_token(), Some("token".into()));
+    let rpl = core.run(ss.into_future()).map_err(|(e, _)| e).unwrap().0.unwrap();
+    assert_eq!(rpl.next_batch, "s1");
+    let reqs = srv.requests();
+    // Nothing was sent to log out.
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].header("Authorization"), Some("Bearer token"));
+}
+
+#[test]
+fn rate_limit_no_retry_by_default() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::BadRequest(ref brq, sc) => {
+            assert_eq!(sc, StatusCode::TooManyRequests);
+            assert_eq!(brq.errcode, "M_LIMIT_EXCEEDED");
+            assert_eq!(brq.retry_after_ms, Some(10));
+        },
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(srv.requests().len(), 1);
+}
+
+#[test]
+fn rate_limit_retries_exhausted() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_rate_limit_retries(2);
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_LIMIT_EXCEEDED"));
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    assert!(reqs.iter().all(|r| r.path.ends_with("/send/m.room.message/1")));
+}
+
+#[test]
+fn rate_limit_retry_after_header() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, r#"{"errcode": "M_LIMIT_EXCEEDED", "error": "Too Many Requests"}"#)
+            .with_header("Retry-After", "120"),
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    // The server asks for longer than `max_total_wait`, so we give up.
+    mx.set_rate_limit_config(RateLimitConfig { max_retries: 3, ..Default::default() });
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::BadRequest(ref brq, _) => assert_eq!(brq.retry_after_ms, Some(120_000)),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(srv.requests().len(), 1);
+}
+
+#[test]
+fn error_codes() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "You are not invited to this room."}"#),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND"}"#),
+        MockResponse::json(502, "<html>Bad Gateway</html>"),
+        MockResponse::json(500, r#"{"error": "no errcode"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    assert_eq!(err.status_code(), Some(StatusCode::Forbidden));
+    assert_eq!(err.api_error().unwrap().error, "You are not invited to this room.");
+    match *err.kind() {
+        MatrixErrorKind::BadRequest(ref brq, sc) => {
+            assert_eq!(brq.error, "You are not invited to this room.");
+            assert_eq!(sc, StatusCode::Forbidden);
+        },
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_NOT_FOUND"));
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), None);
+    match *err.kind() {
+        MatrixErrorKind::HttpCode(sc) => assert_eq!(sc, StatusCode::BadGateway),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert!(err.api_error().is_none());
+    assert_eq!(err.status_code(), Some(StatusCode::InternalServerError));
+}
+
+#[test]
+fn soft_logout() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Access token has expired", "soft_logout": true}"#),
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid access token"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_UNKNOWN_TOKEN"));
+    assert_eq!(err.status_code(), Some(StatusCode::Unauthorized));
+    match *err.kind() {
+        MatrixErrorKind::SoftLogout(ref brq, sc) => {
+            assert!(brq.soft_logout);
+            assert_eq!(sc, StatusCode::Unauthorized);
+        },
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::BadRequest(ref brq, _) => assert!(!brq.soft_logout),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+}
+
+#[test]
+fn request_timeout() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+            .with_delay(Duration::from_secs(5)),
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_request_timeout(Some(Duration::from_millis(100)));
+    let start = Instant::now();
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    match *err.kind() {
+        MatrixErrorKind::Timeout => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert!(err.is_timeout());
+}
+
+#[test]
+fn request_timeout_override() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+            .with_delay(Duration::from_millis(200)),
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_request_timeout(Some(Duration::from_millis(50)));
+    let req = MatrixRequest::new_with_body(Put, "/rooms/!r:example.org/send/m.room.message/1",
+                                           vec![("msgtype", "m.text"), ("body", "hi")])
+        .with_timeout(Duration::from_secs(10));
+    let rpl: SendReply = core.run(req.send(&mut mx)).unwrap();
+    assert_eq!(rpl.event_id, "$ev:example.org");
+}
+
+#[test]
+fn send_with_response() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+            .with_header("X-Example", "yes"),
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let req = MatrixRequest::new_with_body(Put, "/rooms/!r:example.org/send/m.room.message/1",
+                                           vec![("msgtype", "m.text"), ("body", "hi")]);
+    let (rpl, headers): (SendReply, _) = core.run(req.send_with_response(&mut mx)).unwrap();
+    assert_eq!(rpl.event_id, "$ev:example.org");
+    assert_eq!(headers.get_raw("X-Example").and_then(|h| h.one()), Some(&b"yes"[..]));
+    assert_eq!(headers.get::<ContentType>(), Some(&ContentType::json()));
+    keep_logged_in(mx);
+}
+
+/// Accept two connections, and report when each one's request arrives and
+/// whether the client then closes the connection, instead of waiting for the
+/// response (which is never sent).
+fn watch_for_hangups(listener: ::std::net::TcpListener) -> ::std::sync::mpsc::Receiver<bool> {
+    use std::io::Read;
+    let (tx, rx) = ::std::sync::mpsc::channel();
+    ::std::thread::spawn(move || {
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut req = vec![];
+            let mut buf = [0; 1024];
+            let head_len = loop {
+                if let Some(i) = req.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break i + 4;
+                }
+                let n = stream.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+            };
+            // Read the body too, so the read below only sees a hang-up.
+            let body_len = String::from_utf8_lossy(&req[..head_len]).lines()
+                .filter_map(|l| {
+                    let mut kv = l.splitn(2, ':');
+                    match (kv.next(), kv.next()) {
+                        (Some(k), Some(v)) if k.eq_ignore_ascii_case("content-length") => v.trim().parse().ok(),
+                        _ => None
+                    }
+                })
+                .next()
+                .unwrap_or(0);
+            while req.len() < head_len + body_len {
+                let n = stream.read(&mut buf).unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+            tx.send(false).unwrap();
+            stream.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+            let mut buf = [0; 1];
+            tx.send(matches!(stream.read(&mut buf), Ok(0))).unwrap();
+        }
+    });
+    rx
+}
+fn wait_for(core: &mut Core, rx: &::std::sync::mpsc::Receiver<bool>) -> bool {
+    for _ in 0..60 {
+        core.turn(Some(Duration::from_millis(50)));
+        if let Ok(v) = rx.try_recv() {
+            return v;
+        }
+    }
+    panic!("timed out");
+}
+
+#[test]
+fn cancellation() {
+    use futures::Future;
+    use tokio_core::reactor::Timeout;
+
+    let listener = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let rx = watch_for_hangups(listener);
+    let mut core = Core::new().unwrap();
+    let mut mx = MatrixClient::new_from_access_token("token", "@bot:example.org", &url, &core.handle()).unwrap();
+    // Dropping the future (here, by timing out) hangs up.
+    let req = MatrixRequest::new_with_body(Put, "/rooms/!r:example.org/send/m.room.message/1",
+                                           vec![("msgtype", "m.text"), ("body", "hi")])
+        .with_timeout(Duration::from_millis(300));
+    let fut = req.send::<SendReply>(&mut mx);
+    let err = core.run(fut).err().unwrap();
+    assert!(err.is_timeout());
+    assert!(!rx.recv_timeout(Duration::from_secs(1)).unwrap());
+    assert!(wait_for(&mut core, &rx));
+    // So does aborting it from elsewhere.
+    let (fut, handle) = abortable(MatrixRequest::get("/account/whoami").send::<serde_json::Value>(&mut mx));
+    let h = handle.clone();
+    let timer = Timeout::new(Duration::from_millis(300), &core.handle()).unwrap();
+    core.handle().spawn(timer.map(move |_| h.abort()).map_err(|_| ()));
+    match *core.run(fut).err().unwrap().kind() {
+        MatrixErrorKind::Aborted => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert!(handle.is_aborted());
+    assert!(!rx.recv_timeout(Duration::from_secs(1)).unwrap());
+    assert!(wait_for(&mut core, &rx));
+    keep_logged_in(mx);
+}
+
+const VERSIONS: &str = r#"{"versions": ["r0.6.1", "v1.1"]}"#;
+
+#[test]
+fn discover_well_known() {
+    let hs = MockServer::new(vec![MockResponse::json(200, VERSIONS)]);
+    let wk = MockServer::new(vec![
+        MockResponse::json(200, &format!(r#"{{"m.homeserver": {{"base_url": "{}/"}}, "m.identity_server": {{"base_url": "https://id.example.org/"}}}}"#, hs.url))
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let mx = core.run(MatrixClient::discover(&wk.url, &hdl)).unwrap();
+    assert_eq!(wk.requests()[0].path, "/.well-known/matrix/client");
+    assert_eq!(hs.requests()[0].path, "/_matrix/client/versions");
+    assert!(hs.requests()[0].header("Authorization").is_none());
+    assert_eq!(mx.user_id(), "");
+    assert_eq!(mx.url(), hs.url.trim_end_matches('/'));
+    assert_eq!(mx.identity_server(), Some("https://id.example.org"));
+    assert!(mx.supports_version("v1.1"));
+    // Dropping an unauthenticated client shouldn't try to log out.
+    drop(mx);
+    core.turn(Some(Duration::from_millis(50)));
+    assert_eq!(hs.requests().len(), 1);
+}
+
+#[test]
+fn discover_not_found() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND"}"#),
+        MockResponse::json(200, VERSIONS)
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    core.run(MatrixClient::discover(&srv.url, &hdl)).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[1].path, "/_matrix/client/versions");
+}
+
+#[test]
+fn login_with_discovery() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND"}"#),
+        MockResponse::json(200, VERSIONS),
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org", "access_token": "abc123", "device_id": "GHTYAJCE"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let mxid = format!("@bot:{}", srv.url);
+    let mx = core.run(MatrixClient::login_with_discovery(&mxid, "hunter2", &hdl)).unwrap();
+    assert_eq!(mx.access_token(), Some("abc123".into()));
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/.well-known/matrix/client");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/login");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[2].body).unwrap();
+    assert_eq!(body["identifier"], json!({"type": "m.id.user", "user": mxid}));
+    keep_logged_in(mx);
+    let err = core.run(MatrixClient::login_with_discovery("bot", "hunter2", &hdl)).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::DiscoveryFailPrompt(_) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(srv.requests().len(), 3);
+}
+
+#[test]
+fn discover_failures() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"m.homeserver": {}}"#),
+        MockResponse::json(200, r#"{"m.homeserver": {"base_url": "not a url"}}"#),
+        MockResponse::json(404, ""),
+        MockResponse::json(200, "<html>It works!</html>")
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let err = core.run(MatrixClient::discover(&srv.url, &hdl)).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::DiscoveryFailPrompt(_) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = core.run(MatrixClient::discover(&srv.url, &hdl)).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::DiscoveryFailError(_) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = core.run(MatrixClient::discover(&srv.url, &hdl)).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::DiscoveryFailError(_) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+}
+
+#[test]
+fn versions() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"versions": ["r0.6.1", "v1.1"], "unstable_features": {"org.matrix.msc3440.stable": true, "org.example.off": false}}"#),
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    assert!(!mx.supports("org.matrix.msc3440.stable"));
+    let rpl = core.run(mx.get_versions()).unwrap();
+    assert_eq!(srv.requests()[0].path, "/_matrix/client/versions");
+    assert!(srv.requests()[0].header("Authorization").is_none());
+    assert!(mx.supports("org.matrix.msc3440.stable"));
+    assert!(!mx.supports("org.example.off"));
+    assert!(mx.supports_version("r0.6.1"));
+    assert!(!mx.supports_version("v1.2"));
+    assert!(rpl.supports_version("v1.1"));
+    assert!(!rpl.supports_version("v1.2"));
+    assert!(rpl.feature_enabled("org.matrix.msc3440.stable"));
+    assert!(!rpl.feature_enabled("org.example.off"));
+    assert!(!rpl.feature_enabled("org.example.missing"));
+}
+
+#[test]
+fn sync_stream() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(502, "<html>Bad Gateway</html>"),
+        MockResponse::json(200, r#"{"next_batch": "s1/2", "rooms": {"join": {}}}"#),
+        MockResponse::json(200, r#"{"next_batch": "s3"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mx = client(&core, &srv);
+    let mut ss = mx.sync_stream();
+    ss.set_error_backoff(Some(Duration::from_millis(10)));
+    ss.set_filter(Some("42".into()));
+    ss.set_since(Some("s0".into()));
+    ss.set_first_full_state(true);
+    assert_eq!(ss.since(), Some("s0"));
+    let (rpl, ss) = core.run(ss.into_future()).map_err(|(e, _)| e).unwrap();
+    assert_eq!(rpl.unwrap().next_batch, "s1/2");
+    assert_eq!(ss.since(), Some("s1/2"));
+    let rpls = core.run(ss.take(1).collect()).unwrap();
+    assert_eq!(rpls[0].next_batch, "s3");
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    assert!(reqs[0].path.contains("since=s0"));
+    assert!(reqs[0].path.contains("filter=42"));
+    assert!(reqs[1].path.contains("since=s0"));
+    assert!(reqs[1].path.contains("full_state=true"));
+    assert!(reqs[2].path.contains("since=s1%2F2"));
+    assert!(reqs[2].path.contains("timeout=30000"));
+    assert!(!reqs[2].path.contains("full_state"));
+}
+
+#[test]
+fn sync_stream_fatal_error() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mx = client(&core, &srv);
+    let err = core.run(mx.sync_stream().into_future()).err().unwrap().0;
+    assert_eq!(err.errcode(), Some("M_UNKNOWN_TOKEN"));
+}
+
+#[test]
+fn sync_stream_resume() {
+    use gm::sync::SyncRequest;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"next_batch": "s5"}"#),
+        MockResponse::json(400, r#"{"errcode": "M_UNKNOWN", "error": "Unknown sync token"}"#),
+        MockResponse::json(200, r#"{"next_batch": "s9", "rooms": {"join": {}}}"#),
+        MockResponse::json(200, r#"{"next_batch": "s10"}"#),
+        MockResponse::json(400, r#"{"errcode": "M_UNKNOWN", "error": "Unknown sync token"}"#),
+        MockResponse::json(200, r#"{"next_batch": "s2"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    // A stored token makes for an incremental sync.
+    let mut ss = mx.sync_stream();
+    ss.set_since(Some("s4".into()));
+    let (rpl, ss) = core.run(ss.into_future()).map_err(|(e, _)| e).unwrap();
+    let rpl = rpl.unwrap();
+    assert!(!rpl.is_initial());
+    assert_eq!(rpl.since.as_ref().unwrap(), "s4");
+    assert_eq!(ss.since(), Some("s5"));
+    // The homeserver has forgotten the next one, so it starts again.
+    let rpls = core.run(ss.take(2).collect()).unwrap();
+    assert!(rpls[0].is_initial());
+    assert_eq!(rpls[0].next_batch, "s9");
+    assert!(!rpls[1].is_initial());
+    assert_eq!(rpls[1].since.as_ref().unwrap(), "s9");
+    let mut ss = mx.sync_stream();
+    ss.set_since(Some("s0".into()));
+    ss.set_reset_stale_since(false);
+    let err = core.run(ss.into_future()).err().unwrap().0;
+    assert_eq!(err.errcode(), Some("M_UNKNOWN"));
+    let rpl = core.run(SyncRequest::new().since("s1").full_state(true).send(&mut mx)).unwrap();
+    assert!(rpl.is_initial());
+    assert_eq!(rpl.since.as_ref().unwrap(), "s1");
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 6);
+    assert!(reqs[0].path.contains("since=s4"));
+    assert!(reqs[0].path.contains("timeout=30000"));
+    assert!(reqs[1].path.contains("since=s5"));
+    assert!(!reqs[2].path.contains("since="));
+    assert!(reqs[3].path.contains("since=s9"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn connection_reuse() {
+    use gm::http::{SslConnectorBuilder, SslMethod};
+    let srv = MockServer::keep_alive(vec![
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org"}"#),
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org"}"#),
+        MockResponse::json(200, r#"{"user_id": "@other:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let ssl = SslConnectorBuilder::new(SslMethod::tls()).unwrap();
+    let hyper = MatrixClient::hyper_with_tls_proxy(ssl, None, &hdl).unwrap();
+    let mut mx = MatrixClient::new_with_hyper(hyper.clone(), Some("token"), "@bot:example.org", &srv.url, &hdl);
+    core.run(mx.whoami()).unwrap();
+    core.run(mx.whoami()).unwrap();
+    // Another client sharing the pool gets the same connection too.
+    let mut other = MatrixClient::new_with_hyper(hyper, Some("other"), "@other:example.org", &srv.url, &hdl);
+    assert_eq!(core.run(other.whoami()).unwrap().user_id, "@other:example.org");
+    assert_eq!(srv.connections(), 1);
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    assert_eq!(reqs[1].header("Authorization"), Some("Bearer token"));
+    assert_eq!(reqs[2].header("Authorization"), Some("Bearer other"));
+    keep_logged_in(mx);
+    keep_logged_in(other);
+}
+
+#[test]
+fn metrics_hook() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "no"}"#),
+        MockResponse::json(200, r#"{"next_batch": "s1"}"#),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let seen = Rc::new(RefCell::new(vec![]));
+    let seen2 = seen.clone();
+    mx.set_metrics_hook(move |m| {
+        seen2.borrow_mut().push((m.method.to_string(), m.endpoint.to_string(), m.status, m.is_success()));
+    });
+    send_message(&mut core, &mut mx).unwrap();
+    let err = send_message(&mut core, &mut mx).err().unwrap();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    core.run(mx.sync_stream().into_future()).map_err(|(e, _)| e).unwrap();
+    mx.remove_metrics_hook();
+    send_message(&mut core, &mut mx).unwrap();
+    let endpoint = "/_matrix/client/v3/rooms/{roomId}/send/m.room.message/{txnId}".to_string();
+    assert_eq!(*seen.borrow(), vec![
+        ("PUT".to_string(), endpoint.clone(), Some(StatusCode::Ok), true),
+        ("PUT".to_string(), endpoint, Some(StatusCode::Forbidden), false),
+        ("GET".to_string(), "/_matrix/client/v3/sync".to_string(), Some(StatusCode::Ok), true)
+    ]);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn compression() {
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+    use std::io::Write;
+
+    let body = br#"{"event_id": "$ev:example.org"}"#;
+    let mut gz = GzEncoder::new(vec![], Compression::default());
+    gz.write_all(body).unwrap();
+    let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+    zlib.write_all(body).unwrap();
+    let mut err = GzEncoder::new(vec![], Compression::default());
+    err.write_all(br#"{"errcode": "M_FORBIDDEN", "error": "no"}"#).unwrap();
+    let srv = MockServer::new(vec![
+        MockResponse::new(200, &gz.finish().unwrap())
+            .with_header("Content-Type", "application/json")
+            .with_header("Content-Encoding", "gzip"),
+        MockResponse::new(200, &zlib.finish().unwrap())
+            .with_header("Content-Type", "application/json")
+            .with_header("Content-Encoding", "deflate"),
+        MockResponse::new(403, &err.finish().unwrap())
+            .with_header("Content-Type", "application/json")
+            .with_header("Content-Encoding", "gzip"),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    assert!(mx.compression());
+    assert_eq!(send_message(&mut core, &mut mx).unwrap().event_id, "$ev:example.org");
+    assert_eq!(send_message(&mut core, &mut mx).unwrap().event_id, "$ev:example.org");
+    let err = send_message(&mut core, &mut mx).err().unwrap();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    mx.set_compression(false);
+    send_message(&mut core, &mut mx).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].header("Accept-Encoding"), Some("gzip, deflate"));
+    assert!(reqs[3].header("Accept-Encoding").is_none());
+    keep_logged_in(mx);
+}
+
+#[test]
+fn compression_limit() {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    // A small amount of gzipped zeroes that would decompress to
+    // just over 64 MiB.
+    let mut gz = GzEncoder::new(vec![], Compression::best());
+    let zeroes = vec![0; 1024 * 1024];
+    for _ in 0..64 {
+        gz.write_all(&zeroes).unwrap();
+    }
+    gz.write_all(&[0]).unwrap();
+    let srv = MockServer::new(vec![
+        MockResponse::new(200, &gz.finish().unwrap())
+            .with_header("Content-Type", "application/json")
+            .with_header("Content-Encoding", "gzip")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    match *send_message(&mut core, &mut mx).err().unwrap().kind() {
+        MatrixErrorKind::ResponseTooLarge(limit) => assert_eq!(limit, 64 * 1024 * 1024),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    keep_logged_in(mx);
+}
+
+#[test]
+fn builder() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#),
+        MockResponse::json(200, r#"{"next_batch": "s1"}"#),
+        MockResponse::json(200, r#"{"event_id": "$ev2:example.org"}"#)
+            .with_delay(Duration::from_millis(200))
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let mut mx = MatrixClient::builder(&format!("{}//", srv.url), &hdl)
+        .access_token("token", "@bot:example.org")
+        .user_agent("gm-test/1.0")
+        .request_timeout(Some(Duration::from_millis(50)))
+        .keep_alive(false)
+        .proxy(None)
+        .build()
+        .unwrap();
+    assert_eq!(mx.url(), srv.url.trim_end_matches('/'));
+    assert_eq!(mx.user_id(), "@bot:example.org");
+    assert_eq!(mx.user_agent(), Some("gm-test/1.0"));
+    assert!(mx.proxy().is_none());
+    send_message(&mut core, &mut mx).unwrap();
+    core.run(mx.sync_stream().into_future()).map_err(|(e, _)| e).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/1");
+    assert_eq!(reqs[0].header("User-Agent"), Some("gm-test/1.0"));
+    assert!(reqs[1].path.starts_with("/_matrix/client/v3/sync?"));
+    assert_eq!(reqs[1].header("User-Agent"), Some("gm-test/1.0"));
+    match *send_message(&mut core, &mut mx).err().unwrap().kind() {
+        MatrixErrorKind::Timeout => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    keep_logged_in(mx);
+    for url in &["matrix.example.org", "ftp://matrix.example.org", "https://", "https://example.org/?x=1"] {
+        match *MatrixClient::builder(url, &hdl).build().err().unwrap().kind() {
+            MatrixErrorKind::InvalidHomeserverUrl(ref u) => assert_eq!(u, url),
+            ref x => panic!("unexpected error {:?}", x)
+        }
+    }
+}
+
+#[test]
+fn proxy() {
+    use gm::proxy::Proxy;
+    // The mock server keeps connections open, so it can play the proxy and
+    // the homeserver behind it.
+    let srv = MockServer::keep_alive(vec![
+        MockResponse::json(200, ""),
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org"}"#),
+        MockResponse::json(407, "")
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let mut mx = MatrixClient::new_from_access_token("token", "@bot:example.org", "http://matrix.example.org", &hdl).unwrap();
+    mx.set_allow_insecure_http(true);
+    let proxy = Proxy::new(&srv.url).unwrap()
+        .basic_auth("user", "pass")
+        .idle_timeout(Duration::from_secs(20));
+    mx.set_proxy(Some(proxy)).unwrap();
+    assert_eq!(mx.sync_stream().timeout(), 15000);
+    assert_eq!(core.run(mx.whoami()).unwrap().user_id, "@bot:example.org");
+    let mut other = MatrixClient::new_from_access_token("token", "@bot:example.org", "http://other.example.org", &hdl).unwrap();
+    other.set_allow_insecure_http(true);
+    other.set_proxy(Some(Proxy::new(&srv.url).unwrap())).unwrap();
+    assert!(core.run(other.whoami()).is_err());
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    assert_eq!(reqs[0].method, "CONNECT");
+    assert_eq!(reqs[0].path, "matrix.example.org:80");
+    assert_eq!(reqs[0].header("Proxy-Authorization"), Some("Basic dXNlcjpwYXNz"));
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/account/whoami");
+    assert_eq!(reqs[1].header("Host"), Some("matrix.example.org"));
+    assert!(reqs[2].header("Proxy-Authorization").is_none());
+    let proxy = Proxy::new("http://u:p@proxy.example.org:3128").unwrap()
+        .no_proxy("internal.example.org, .lan");
+    assert!(proxy.applies_to(&"https://matrix.example.org".parse().unwrap()));
+    assert!(!proxy.applies_to(&"https://matrix.internal.example.org".parse().unwrap()));
+    assert!(!proxy.applies_to(&"http://hs.lan:8008".parse().unwrap()));
+    assert!(!proxy.applies_to(&"http://127.0.0.1:8008".parse().unwrap()));
+    assert!(Proxy::new("socks5://proxy.example.org").is_err());
+    keep_logged_in(mx);
+    keep_logged_in(other);
+}
+
+#[test]
+fn insecure_http() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org"}"#),
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let mut mx = MatrixClient::new_from_access_token("token", "@bot:example.org", "http://matrix.example.org", &hdl).unwrap();
+    assert!(!mx.allow_insecure_http());
+    match *core.run(mx.whoami()).unwrap_err().kind() {
+        MatrixErrorKind::InsecureHttp(ref url) => assert_eq!(url, "http://matrix.example.org"),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    match *core.run(mx.sync_stream().into_future()).err().unwrap().0.kind() {
+        MatrixErrorKind::InsecureHttp(_) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let https = MatrixClient::new_unauthenticated("https://matrix.example.org", &hdl).unwrap();
+    assert!(MatrixRequest::get("/versions").make_hyper(&https).is_ok());
+    mx.set_allow_insecure_http(true);
+    assert!(MatrixRequest::get("/versions").make_hyper(&mx).is_ok());
+    // Loopback addresses (like the mock server's) are fine anyway.
+    let mut local = client(&core, &srv);
+    core.run(local.whoami()).unwrap();
+    let tls = gm::http::SslConnectorBuilder::new(gm::http::SslMethod::tls()).unwrap();
+    let hyper = MatrixClient::hyper_with_tls(tls, &hdl).unwrap();
+    let mut custom = MatrixClient::new_with_hyper(hyper, Some("token"), "@bot:example.org", &srv.url, &hdl);
+    core.run(custom.whoami()).unwrap();
+    assert!(MatrixClient::hyper_with_ca_cert(b"not a certificate", &hdl).is_err());
+    assert_eq!(srv.requests().len(), 2);
+    keep_logged_in(mx);
+    keep_logged_in(local);
+    keep_logged_in(custom);
+}
+
+#[test]
+fn login_password() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org", "access_token": "abc123", "device_id": "GHTYAJCE"}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "Invalid password"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let mx = core.run(MatrixClient::login_password(&srv.url, "bot", "hunter2", &hdl)).unwrap();
+    assert_eq!(mx.access_token(), Some("abc123".into()));
+    assert_eq!(mx.user_id(), "@bot:example.org");
+    assert_eq!(mx.device_id(), Some("GHTYAJCE"));
+    let req = &srv.requests()[0];
+    assert_eq!(req.path, "/_matrix/client/v3/login");
+    assert!(req.header("Authorization").is_none());
+    let body = req.body_str();
+    assert!(body.contains(r#""identifier":{"type":"m.id.user","user":"bot"}"#));
+    assert!(body.contains(r#""password":"hunter2""#));
+    // Dropping the client would log it out, using up the next response.
+    keep_logged_in(mx);
+    let err = core.run(MatrixClient::login_password(&srv.url, "bot", "hunter3", &hdl)).err().unwrap();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+}
+
+#[test]
+fn upload() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"content_uri": "mxc://example.org/AQwafuaFswefuhsfAFAgsw"}"#),
+        MockResponse::json(413, r#"{"errcode": "M_TOO_LARGE", "error": "Cannot upload files larger than 50M"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let uri = core.run(mx.upload(&b"\x89PNG"[..], ContentType::png(), Some("cat picture.png"))).unwrap();
+    assert_eq!(uri.server_name, "example.org");
+    assert_eq!(uri.media_id, "AQwafuaFswefuhsfAFAgsw");
+    let req = &srv.requests()[0];
+    assert_eq!(req.path, "/_matrix/media/v3/upload?filename=cat%20picture.png");
+    assert_eq!(req.header("Content-Type"), Some("image/png"));
+    assert_eq!(req.body, b"\x89PNG");
+    let err = core.run(mx.upload(&b"\x89PNG"[..], ContentType::png(), None)).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::UploadTooLarge => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(srv.requests()[1].path, "/_matrix/media/v3/upload");
+}
+
+#[test]
+fn upload_bytes() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"content_uri": "mxc://example.org/abc"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let data = vec![0u8; 3 * 1024 * 1024];
+    let uri = core.run(mx.upload_bytes(data, "application/x-example", Some("big.bin"))).unwrap();
+    assert_eq!(uri.to_string(), "mxc://example.org/abc");
+    let err = core.run(mx.upload_bytes(vec![], "not a mime type", None)).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::InvalidContentType(ref ct) => assert_eq!(ct, "not a mime type"),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].path, "/_matrix/media/v3/upload?filename=big.bin");
+    assert_eq!(reqs[0].header("Content-Type"), Some("application/x-example"));
+    // Not chunked, since some homeservers won't take that for uploads.
+    assert_eq!(reqs[0].header("Content-Length"), Some("3145728"));
+    assert!(reqs[0].header("Transfer-Encoding").is_none());
+    assert_eq!(reqs[0].body.len(), 3 * 1024 * 1024);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn send_media() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"content_uri": "mxc://example.org/doc"}"#),
+        MockResponse::json(200, r#"{"event_id": "$file:example.org"}"#),
+        MockResponse::json(200, r#"{"content_uri": "mxc://example.org/pic"}"#),
+        MockResponse::json(200, r#"{"event_id": "$image:example.org"}"#),
+        MockResponse::json(413, r#"{"errcode": "M_TOO_LARGE", "error": "Upload too large"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.send_file("!r:example.org", vec![1, 2, 3], "application/pdf", "notes.pdf")).unwrap();
+    assert_eq!(rpl.event_id, "$file:example.org");
+    let rpl = core.run(mx.send_image("!r:example.org", vec![0; 10], "image/png", "cat.png", 64, 48)).unwrap();
+    assert_eq!(rpl.event_id, "$image:example.org");
+    assert!(core.run(mx.send_file("!r:example.org", vec![0; 10], "text/plain", "big.txt")).is_err());
+    let reqs = srv.requests();
+    // The failed upload doesn't send a message.
+    assert_eq!(reqs.len(), 5);
+    assert_eq!(reqs[0].path, "/_matrix/media/v3/upload?filename=notes.pdf");
+    assert_eq!(reqs[0].header("Content-Type"), Some("application/pdf"));
+    assert!(reqs[1].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/"));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "msgtype": "m.file",
+        "body": "notes.pdf",
+        "filename": "notes.pdf",
+        "url": "mxc://example.org/doc",
+        "info": {"mimetype": "application/pdf", "size": 3}
+    }));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[3].body).unwrap();
+    assert_eq!(body, json!({
+        "msgtype": "m.image",
+        "body": "cat.png",
+        "url": "mxc://example.org/pic",
+        "info": {"mimetype": "image/png", "size": 10, "w": 64, "h": 48}
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn media_config() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"m.upload.size": 100}"#),
+        MockResponse::json(200, r#"{"content_uri": "mxc://example.org/small"}"#),
+        MockResponse::json(200, r#"{"event_id": "$small:example.org"}"#),
+        MockResponse::json(200, r#"{}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    assert_eq!(mx.upload_size_limit(), None);
+    let cfg = core.run(mx.media_config()).unwrap();
+    assert_eq!(cfg.upload_size, Some(100));
+    assert_eq!(mx.upload_size_limit(), Some(100));
+    core.run(mx.send_file("!r:example.org", vec![0; 100], "text/plain", "small.txt")).unwrap();
+    match *core.run(mx.send_file("!r:example.org", vec![0; 101], "text/plain", "big.txt")).unwrap_err().kind() {
+        MatrixErrorKind::UploadOverLimit(101, 100) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    // Nothing was sent for the file over the limit.
+    assert_eq!(srv.requests().len(), 3);
+    // A homeserver with no limit clears it.
+    core.run(mx.media_config()).unwrap();
+    assert_eq!(mx.upload_size_limit(), None);
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/media/v3/config");
+    assert_eq!(reqs[3].path, "/_matrix/media/v3/config");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn logout() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    core.run(mx.logout()).unwrap();
+    assert!(mx.is_logged_out());
+    assert_eq!(mx.access_token(), None);
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::LoggedOut => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    // Dropping a logged-out client shouldn't log out again.
+    drop(mx);
+    core.turn(Some(Duration::from_millis(50)));
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 1);
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/logout");
+    assert_eq!(reqs[0].header("Authorization"), Some("Bearer token"));
+}
+
+#[test]
+fn logout_all() {
+    let srv = MockServer::new(vec![MockResponse::json(200, "{}")]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    core.run(mx.logout_all()).unwrap();
+    assert_eq!(srv.requests()[0].path, "/_matrix/client/v3/logout/all");
+    assert!(mx.is_logged_out());
+}
+
+#[test]
+fn download() {
+    let cdn = MockServer::new(vec![
+        MockResponse::new(200, b"\x89PNG").with_header("Content-Type", "image/png")
+            .with_header("Content-Disposition", "inline; filename=\"cat.png\"")
+    ]);
+    let srv = MockServer::new(vec![
+        MockResponse::new(302, b"").with_header("Location", &format!("{}/cdn/AQwafu", cdn.url)),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let uri: ContentUri = "mxc://example.org/AQwafu".parse().unwrap();
+    let dl = core.run(mx.download(&uri)).unwrap();
+    assert_eq!(dl.content_type, Some(ContentType::png()));
+    assert_eq!(dl.data, b"\x89PNG");
+    assert_eq!(dl.filename.as_ref().map(|x| x as &str), Some("cat.png"));
+    assert_eq!(srv.requests()[0].path, "/_matrix/media/v3/download/example.org/AQwafu?allow_remote=true");
+    assert!(srv.requests()[0].header("Authorization").is_none());
+    let req = &cdn.requests()[0];
+    assert_eq!(req.path, "/cdn/AQwafu");
+    assert!(req.header("Authorization").is_none());
+    mx.set_allow_remote_media(false);
+    let err = core.run(mx.thumbnail(&uri, 64, 32, ThumbnailMethod::Crop)).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_NOT_FOUND"));
+    let path = &srv.requests()[1].path;
+    assert!(path.starts_with("/_matrix/media/v3/thumbnail/example.org/AQwafu?"));
+    for param in &["width=64", "height=32", "method=crop", "allow_remote=false"] {
+        assert!(path.contains(param), "{} missing from {}", param, path);
+    }
+}
+
+#[test]
+fn download_stream() {
+    let cdn = MockServer::new(vec![
+        MockResponse::new(200, &vec![7u8; 100000]).with_header("Content-Type", "application/octet-stream")
+            .with_header("Content-Disposition", "attachment; filename=\"fallback.bin\"; filename*=utf-8''caf%C3%A9.bin")
+    ]);
+    let srv = MockServer::new(vec![
+        MockResponse::new(302, b"").with_header("Location", &format!("{}/cdn/big", cdn.url)),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_token_in_query(true);
+    let uri: ContentUri = "mxc://example.org/big".parse().unwrap();
+    let stream = core.run(mx.download_stream(&uri)).unwrap();
+    assert_eq!(stream.filename.as_ref().map(|x| x as &str), Some("café.bin"));
+    assert_eq!(stream.content_length, Some(100000));
+    let chunks = core.run(stream.collect()).unwrap();
+    let total: usize = chunks.iter().map(|c| c.len()).sum();
+    assert_eq!(total, 100000);
+    assert_eq!(srv.requests()[0].path, "/_matrix/media/v3/download/example.org/big?allow_remote=true");
+    assert_eq!(cdn.requests()[0].path, "/cdn/big");
+    let err = core.run(mx.download_stream(&uri)).err().unwrap();
+    assert_eq!(err.errcode(), Some("M_NOT_FOUND"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn preview_url() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"og:title": "Matrix", "og:description": "An open network",
+                                    "og:image": "mxc://example.org/logo", "og:image:width": 64,
+                                    "og:image:height": 32, "matrix:image:size": 1024,
+                                    "og:site_name": "matrix.org"}"#),
+        MockResponse::json(404, r#"{"errcode": "M_UNRECOGNIZED", "error": "Unrecognized request"}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "IP address blocked"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let preview = core.run(mx.preview_url("https://matrix.org/?a=b&c", Some(1500000000000))).unwrap();
+    assert_eq!(preview.title.as_ref().unwrap(), "Matrix");
+    assert_eq!(preview.description.as_ref().unwrap(), "An open network");
+    assert_eq!(preview.image_uri().unwrap().media_id, "logo");
+    assert_eq!((preview.image_width, preview.image_height, preview.image_size), (Some(64), Some(32), Some(1024)));
+    assert_eq!(preview.extra["og:site_name"], "matrix.org");
+    match *core.run(mx.preview_url("https://matrix.org/", None)).unwrap_err().kind() {
+        MatrixErrorKind::UrlPreviewsDisabled => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = core.run(mx.preview_url("http://10.0.0.1/", None)).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    let reqs = srv.requests();
+    assert!(reqs[0].path.starts_with("/_matrix/media/v3/preview_url?"));
+    assert!(reqs[0].path.contains("url=https:%2F%2Fmatrix.org%2F%3Fa%3Db%26c"));
+    assert!(reqs[0].path.contains("ts=1500000000000"));
+    assert_eq!(reqs[0].header("Authorization"), Some("Bearer token"));
+    assert_eq!(reqs[1].path, "/_matrix/media/v3/preview_url?url=https:%2F%2Fmatrix.org%2F");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn login_password_device() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org", "access_token": "def456", "device_id": "BOTDEVICE", "home_server": "example.org"}"#),
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let fut = MatrixClient::login_password_device(&srv.url, "@bot:example.org", "hunter2",
+                                                  Some("BOTDEVICE"), Some("My bot"), &hdl);
+    let mx = core.run(fut).unwrap();
+    assert_eq!(mx.access_token(), Some("def456".into()));
+    assert_eq!(mx.device_id(), Some("BOTDEVICE"));
+    let body: serde_json::Value = serde_json::from_slice(&srv.requests()[0].body).unwrap();
+    assert_eq!(body["identifier"], json!({"type": "m.id.user", "user": "@bot:example.org"}));
+    assert_eq!(body["device_id"], "BOTDEVICE");
+    assert_eq!(body["initial_device_display_name"], "My bot");
+    // The deprecated top-level `user` field shouldn't be sent.
+    assert!(body.get("user").is_none());
+    keep_logged_in(mx);
+}
+
+#[test]
+fn refresh_tokens() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org", "access_token": "abc", "device_id": "BOTDEVICE",
+                                    "refresh_token": "r1", "expires_in_ms": 60000}"#),
+        MockResponse::json(200, r#"{"access_token": "def", "refresh_token": "r2", "expires_in_ms": 120000}"#),
+        MockResponse::json(200, r#"{"access_token": "ghi"}"#),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let hdl = core.handle();
+    let fut = MatrixClient::login_password_refreshable(&srv.url, "bot", "hunter2", None, &hdl);
+    let start = Instant::now();
+    let mut mx = core.run(fut).unwrap();
+    assert_eq!(mx.refresh_token(), Some("r1".into()));
+    let expires_at = mx.token_expiry().unwrap();
+    assert!(expires_at >= start + Duration::from_secs(60) && expires_at <= Instant::now() + Duration::from_secs(60));
+    let expiry = core.run(mx.refresh()).unwrap();
+    assert_eq!(expiry, Some(Duration::from_secs(120)));
+    assert!(mx.token_expiry().unwrap() >= expires_at + Duration::from_secs(60));
+    assert_eq!(mx.access_token(), Some("def".into()));
+    assert_eq!(mx.refresh_token(), Some("r2".into()));
+    let expiry = core.run(mx.refresh()).unwrap();
+    assert!(expiry.is_none());
+    assert!(mx.token_expiry().is_none());
+    assert_eq!(mx.access_token(), Some("ghi".into()));
+    assert_eq!(mx.refresh_token(), Some("r2".into()));
+    send_message(&mut core, &mut mx).unwrap();
+    let reqs = srv.requests();
+    let body: serde_json::Value = serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body["refresh_token"], true);
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/refresh");
+    assert_eq!(reqs[1].body_str(), r#"{"refresh_token":"r1"}"#);
+    assert_eq!(reqs[2].body_str(), r#"{"refresh_token":"r2"}"#);
+    assert_eq!(reqs[3].header("authorization"), Some("Bearer ghi"));
+    mx.set_refresh_token(None);
+    let err = core.run(mx.refresh()).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::NoRefreshToken => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+}
+
+#[test]
+fn reauth_handler() {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid access token"}"#),
+        MockResponse::json(200, r#"{"event_id": "$ev:example.org"}"#),
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid access token"}"#),
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid access token"}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "Not in room"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let calls = Rc::new(Cell::new(0));
+    let calls2 = calls.clone();
+    mx.set_reauth_handler(move || {
+        calls2.set(calls2.get() + 1);
+        Box::new(::futures::future::ok(format!("token{}", calls2.get())))
+    });
+    let rpl = send_message(&mut core, &mut mx).unwrap();
+    assert_eq!(rpl.event_id, "$ev:example.org");
+    assert_eq!(calls.get(), 1);
+    assert_eq!(mx.access_token(), Some("token1".into()));
+    // The retried request fails too, so we give up rather than looping.
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_UNKNOWN_TOKEN"));
+    assert_eq!(calls.get(), 2);
+    // Other errors don't involve the handler at all.
+    let err = send_message(&mut core, &mut mx).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    assert_eq!(calls.get(), 2);
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 5);
+    assert_eq!(reqs[0].header("authorization"), Some("Bearer token"));
+    assert_eq!(reqs[1].header("authorization"), Some("Bearer token1"));
+    assert_eq!(reqs[1].path, reqs[0].path);
+    assert_eq!(reqs[1].body, reqs[0].body);
+    assert_eq!(reqs[2].header("authorization"), Some("Bearer token1"));
+    assert_eq!(reqs[3].header("authorization"), Some("Bearer token2"));
+    assert_eq!(reqs[4].header("authorization"), Some("Bearer token2"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn capabilities() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"capabilities": {
+            "m.change_password": {"enabled": false},
+            "m.room_versions": {"default": "10", "available": {"9": "stable", "10": "stable", "org.example.v": "unstable"}},
+            "org.example.custom": {"max": 3}
+        }}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let caps = core.run(mx.get_capabilities()).unwrap();
+    assert!(!caps.can_change_password());
+    assert!(caps.can_set_displayname() && caps.can_set_avatar_url());
+    let versions = caps.room_versions.as_ref().unwrap();
+    assert_eq!(versions.default, "10");
+    assert!(versions.is_stable("9"));
+    assert!(!versions.is_stable("org.example.v"));
+    assert!(!versions.is_stable("1"));
+    assert_eq!(caps.extra["org.example.custom"], json!({"max": 3}));
+    assert_eq!(srv.requests()[0].path, "/_matrix/client/v3/capabilities");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn whoami() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"user_id": "@bot:example.org", "device_id": "BOTDEVICE"}"#),
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid macaroon passed."}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mx = MatrixClient::new_from_access_token("token", "", &srv.url, &core.handle()).unwrap();
+    let mut mx = core.run(mx.validate_token()).unwrap();
+    assert_eq!(srv.requests()[0].path, "/_matrix/client/v3/account/whoami");
+    assert_eq!(mx.user_id(), "@bot:example.org");
+    assert_eq!(mx.device_id(), Some("BOTDEVICE"));
+    let err = core.run(mx.whoami()).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_UNKNOWN_TOKEN"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn logout_unknown_token() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"errcode": "M_UNKNOWN_TOKEN", "error": "Invalid macaroon passed."}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    core.run(mx.logout()).unwrap();
+    let mut mx = client(&core, &srv);
+    let err = core.run(mx.logout_all()).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    // A failed logout leaves the client logged in.
+    assert!(!mx.is_logged_out());
+    assert_eq!(mx.access_token(), Some("token".into()));
+    keep_logged_in(mx);
+}
+
+const SYNC_REPLY: &str = r#"{
+    "next_batch": "s72595_4483_1934",
+    "rooms": {
+        "join": {
+            "!r:example.org": {
+                "timeline": {
+                    "events": [
+                        {"type": "m.room.message", "event_id": "$1:example.org", "sender": "@a:example.org",
+                         "origin_server_ts": 1432735824653, "content": {"msgtype": "m.text", "body": "hi"}},
+                        {"type": "org.example.custom", "event_id": "$2:example.org", "sender": "@a:example.org",
+                         "origin_server_ts": 1432735824654, "content": {"foo": [1, 2]}}
+                    ],
+                    "limited": true,
+                    "prev_batch": "t34-23535_0_0"
+                },
+                "ephemeral": {"events": [{"type": "m.typing", "content": {"user_ids": ["@a:example.org"]}}]}
+            }
+        },
+        "invite": {"!i:example.org": {"invite_state": {"events": []}}},
+        "leave": {"!l:example.org": {}}
+    },
+    "presence": {"events": [{"type": "m.presence", "sender": "@a:example.org", "content": {"presence": "online"}}]}
+}"#;
+
+#[test]
+fn sync_once() {
+    use gm::types::events::Event;
+    use gm::types::content::Content;
+    use gm::sync::SyncRequest;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, SYNC_REPLY),
+        MockResponse::json(200, r#"{"next_batch": "s2"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.sync(Some("s1"), 30_000)).unwrap();
+    assert_eq!(rpl.next_batch, "s72595_4483_1934");
+    let room = &rpl.rooms.join[&Room::from_id("!r:example.org")];
+    assert!(room.timeline.limited);
+    assert_eq!(room.timeline.prev_batch.as_ref().unwrap(), "t34-23535_0_0");
+    match room.timeline.events[0] {
+        Event::Full(ref meta, Content::RoomMessage(_)) => assert_eq!(meta.sender, "@a:example.org"),
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    match room.timeline.events[1] {
+        Event::Full(ref meta, Content::Unknown(ref v)) => {
+            assert_eq!(meta.event_type, "org.example.custom");
+            assert_eq!(v["foo"][1], 2);
+        },
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    assert_eq!(room.ephemeral.events.len(), 1);
+    assert_eq!(room.typing_users(), Some(&["@a:example.org".to_string()][..]));
+    assert!(rpl.rooms.invite.contains_key(&Room::from_id("!i:example.org")));
+    assert!(rpl.rooms.leave.contains_key(&Room::from_id("!l:example.org")));
+    assert_eq!(rpl.presence.events.len(), 1);
+    let updates: Vec<_> = rpl.presence_updates().map(|(u, p)| (u, p.presence)).collect();
+    assert_eq!(updates, vec![("@a:example.org", gm::types::content::root::types::Presence::Online)]);
+    let path = &srv.requests()[0].path;
+    assert!(path.starts_with("/_matrix/client/v3/sync?"));
+    assert!(path.contains("since=s1") && path.contains("timeout=30000"));
+    let req = SyncRequest::new().filter("{\"room\":{}}").full_state(true)
+        .set_presence(gm::types::content::root::types::Presence::Offline);
+    core.run(req.send(&mut mx)).unwrap();
+    let path = &srv.requests()[1].path;
+    for param in &["filter=%7B%22room%22:%7B%7D%7D", "full_state=true", "set_presence=offline"] {
+        assert!(path.contains(param), "{} missing from {}", param, path);
+    }
+    assert!(!path.contains("since="));
+}
+
+#[test]
+fn register_dummy_auth() {
+    use gm::types::replies::UiaaResponse;
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.recaptcha"]}, {"stages": ["m.login.dummy"]}],
+                                    "params": {"m.login.recaptcha": {"public_key": "abc"}}, "session": "xxxxxx"}"#),
+        MockResponse::json(200, r#"{"user_id": "@new:example.org", "access_token": "tok", "device_id": "DEV"}"#),
+        MockResponse::json(400, r#"{"errcode": "M_USER_IN_USE", "error": "User ID already taken."}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = MatrixClient::new_unauthenticated(&srv.url, &core.handle()).unwrap();
+    let info = match core.run(mx.register("new", "hunter2")).unwrap() {
+        UiaaResponse::AuthRequired(info) => info,
+        x => panic!("unexpected response {:?}", x)
+    };
+    assert_eq!(info.flows[1].stages, vec!["m.login.dummy"]);
+    assert_eq!(info.params["m.login.recaptcha"]["public_key"], "abc");
+    let fut = mx.register_with_auth("new", "hunter2", info.session.as_ref().map(|x| x as &str),
+                                    json!({"type": "m.login.dummy"}));
+    let rpl = match core.run(fut).unwrap() {
+        UiaaResponse::Success(rpl) => rpl,
+        x => panic!("unexpected response {:?}", x)
+    };
+    assert_eq!(rpl.user_id, "@new:example.org");
+    assert_eq!(rpl.access_token.unwrap(), "tok");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/register?kind=user");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body["auth"], json!({"type": "m.login.dummy", "session": "xxxxxx"}));
+    assert_eq!(body["username"], "new");
+    let err = core.run(mx.register("new", "hunter2")).unwrap_err();
+    match *err.kind() {
+        MatrixErrorKind::UserInUse(..) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(err.errcode(), Some("M_USER_IN_USE"));
+    assert_eq!(err.api_error().unwrap().error, "User ID already taken.");
+}
+
+#[test]
+fn register_account() {
+    use gm::types::account::RegisterParams;
+    use gm::types::replies::{AuthData, UiaaInfo};
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.registration_token"]}], "session": "reg"}"#),
+        MockResponse::json(200, r#"{"user_id": "@bot1:example.org", "access_token": "tok1", "device_id": "BOT1"}"#),
+        MockResponse::json(400, r#"{"errcode": "M_USER_IN_USE", "error": "User ID already taken."}"#),
+        MockResponse::json(400, r#"{"errcode": "M_INVALID_USERNAME", "error": "User ID can only contain characters a-z, 0-9, or '=_-./'"}"#),
+        MockResponse::json(200, r#"{"user_id": "@42:example.org", "access_token": "guest", "device_id": "G"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let params = RegisterParams::new("bot1", "hunter2")
+        .device_id("BOT1")
+        .initial_device_display_name("Bot");
+    let token = |stage: &str, _: &UiaaInfo| if stage == "m.login.registration_token" {
+        Some(AuthData::RegistrationToken { token: "secret".into() })
+    } else {
+        None
+    };
+    let (mx, rpl) = core.run(MatrixClient::register_account(&srv.url, &params, token, &core.handle())).unwrap();
+    assert_eq!(rpl.user_id, "@bot1:example.org");
+    assert_eq!(mx.user_id(), "@bot1:example.org");
+    assert_eq!(mx.device_id(), Some("BOT1"));
+    assert_eq!(mx.access_token(), Some("tok1".into()));
+    let err = core.run(MatrixClient::register_account(&srv.url, &params, token, &core.handle())).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::UserInUse(..) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(err.errcode(), Some("M_USER_IN_USE"));
+    assert_eq!(err.status_code(), Some(StatusCode::BadRequest));
+    let bad = RegisterParams::new("Bot 1", "hunter2");
+    let err = core.run(MatrixClient::register_account(&srv.url, &bad, token, &core.handle())).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::InvalidUsername(..) => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert_eq!(err.errcode(), Some("M_INVALID_USERNAME"));
+    let (guest, _) = core.run(MatrixClient::register_account(&srv.url, &RegisterParams::guest(), token, &core.handle())).unwrap();
+    assert_eq!(guest.user_id(), "@42:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/register?kind=user");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "username": "bot1", "password": "hunter2", "device_id": "BOT1", "initial_device_display_name": "Bot",
+        "auth": {"type": "m.login.registration_token", "token": "secret", "session": "reg"}
+    }));
+    assert_eq!(reqs[4].path, "/_matrix/client/v3/register?kind=guest");
+    assert_eq!(reqs[4].body_str(), "{}");
+    keep_logged_in(mx);
+    keep_logged_in(guest);
+}
+
+#[test]
+fn send_message_txn_ids() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(200, r#"{"event_id": "$1:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$2:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_rate_limit_retries(1);
+    let content = json!({"msgtype": "m.text", "body": "hi"});
+    let rpl = core.run(mx.send_message("!r:example.org", "m.room.message", &content)).unwrap();
+    assert_eq!(rpl.event_id, "$1:example.org");
+    let rpl = core.run(mx.send_message("!r:example.org", "m.room.message", &content)).unwrap();
+    assert_eq!(rpl.event_id, "$2:example.org");
+    let txnids: Vec<_> = srv.requests().iter()
+        .map(|r| {
+            assert!(r.path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/"));
+            r.path.rsplit('/').next().unwrap().to_string()
+        })
+        .collect();
+    // The retry reuses the transaction ID; the next message gets a new one.
+    assert_eq!(txnids[0], txnids[1]);
+    assert!(txnids[1] != txnids[2]);
+    // Other clients with the same token don't reuse them.
+    let mut mx2 = client(&core, &srv);
+    assert!(mx2.new_txn_id() != txnids[0]);
+}
+
+#[test]
+fn room_send_with_txn_id() {
+    use gm::room::RoomExt;
+    use gm::types::messages::Message;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$1:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$2:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let room = Room::from_id("!r:example.org");
+    let rpl = core.run(room.cli(&mut mx).send_with_txn_id(Message::emote("waves"), "my-txn")).unwrap();
+    assert_eq!(rpl.event_id, "$1:example.org");
+    core.run(room.cli(&mut mx).send(Message::notice("beep"))).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/my-txn");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({"msgtype": "m.emote", "body": "waves"}));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body["msgtype"], "m.notice");
+    assert_eq!(body["body"], "beep");
+}
+
+#[test]
+fn messages_stream() {
+    let ev = |id: &str| format!(r#"{{"type": "m.room.message", "event_id": "{}", "sender": "@u:example.org",
+                                    "origin_server_ts": 1, "content": {{"msgtype": "m.text", "body": "hi"}}}}"#, id);
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &format!(r#"{{"start": "t0", "end": "t1/2", "chunk": [{}, {}]}}"#, ev("$a"), ev("$b"))),
+        MockResponse::json(200, &format!(r#"{{"start": "t1/2", "end": "t2", "chunk": [{}]}}"#, ev("$c"))),
+        MockResponse::json(200, r#"{"start": "t2", "end": "t2", "chunk": []}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    {
+        let mut ms = mx.messages("!r:example.org", Direction::Backwards);
+        ms.set_limit(Some(2));
+        ms.set_filter(Some(r#"{"lazy_load_members":true}"#.into()));
+        let pages = core.run(ms.collect()).unwrap();
+        assert_eq!(pages.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![2, 1]);
+    }
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    assert!(reqs[0].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/messages?"));
+    assert!(reqs[0].path.contains("dir=b"));
+    assert!(reqs[0].path.contains("limit=2"));
+    assert!(reqs[0].path.contains("filter=%7B%22lazy_load_members%22:true%7D"));
+    assert!(!reqs[0].path.contains("from="));
+    assert!(reqs[1].path.contains("from=t1%2F2"));
+    assert!(reqs[2].path.contains("from=t2"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn create_filter() {
+    use gm::types::filter::Filter;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"filter_id": "66696p746572"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let filter = Filter::new()
+        .lazy_load_members()
+        .timeline_limit(20)
+        .exclude_types(&["m.room.member"]);
+    let id = core.run(mx.create_filter(filter)).unwrap();
+    assert_eq!(id, "66696p746572");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/user/@bot:example.org/filter");
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({
+        "room": {
+            "state": {"lazy_load_members": true, "not_types": ["m.room.member"]},
+            "timeline": {"lazy_load_members": true, "limit": 20, "not_types": ["m.room.member"]}
+        }
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn get_filter_and_inline_filters() {
+    use gm::types::filter::Filter;
+    use gm::sync::SyncRequest;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"room": {"state": {"lazy_load_members": true}, "timeline": {"lazy_load_members": true, "limit": 5}}}"#),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "No such filter"}"#),
+        MockResponse::json(200, r#"{"next_batch": "s1"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let filter = core.run(mx.get_filter("@bot:example.org", "42")).unwrap();
+    assert_eq!(filter, Filter::new().lazy_load_members().timeline_limit(5));
+    let err = core.run(mx.get_filter("@bot:example.org", "43")).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_NOT_FOUND"));
+    let req = SyncRequest::new().filter_definition(&Filter::new().lazy_load_members());
+    core.run(req.send(&mut mx)).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "GET");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/user/@bot:example.org/filter/42");
+    assert!(reqs[2].path.contains("filter=%7B%22room%22:%7B%22state%22:%7B%22lazy_load_members%22:true%7D"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn reactions_and_edits() {
+    use gm::types::messages::Message;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$reaction:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$edit:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.send_reaction("!r:example.org", "$orig:example.org", "👍")).unwrap();
+    assert_eq!(rpl.event_id, "$reaction:example.org");
+    let rpl = core.run(mx.edit_message("!r:example.org", "$orig:example.org", Message::text("fixed"))).unwrap();
+    assert_eq!(rpl.event_id, "$edit:example.org");
+    let reqs = srv.requests();
+    assert!(reqs[0].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.reaction/"));
+    assert!(reqs[1].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/"));
+    assert!(reqs[0].path.rsplit('/').next() != reqs[1].path.rsplit('/').next());
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({
+        "m.relates_to": {"rel_type": "m.annotation", "event_id": "$orig:example.org", "key": "👍"}
+    }));
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "msgtype": "m.text",
+        "body": "* fixed",
+        "m.new_content": {"msgtype": "m.text", "body": "fixed"},
+        "m.relates_to": {"rel_type": "m.replace", "event_id": "$orig:example.org"}
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn react_and_remove_reaction() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$reaction:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$redaction:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.react("!r:example.org", "$orig:example.org", "🎉")).unwrap();
+    assert_eq!(rpl.event_id, "$reaction:example.org");
+    let rpl = core.run(mx.remove_reaction("!r:example.org", &rpl.event_id)).unwrap();
+    assert_eq!(rpl.event_id, "$redaction:example.org");
+    let reqs = srv.requests();
+    assert!(reqs[0].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.reaction/"));
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({
+        "m.relates_to": {"rel_type": "m.annotation", "event_id": "$orig:example.org", "key": "🎉"}
+    }));
+    assert_eq!(reqs[1].method, "PUT");
+    assert!(reqs[1].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/redact/$reaction:example.org/"));
+    assert_eq!(reqs[1].body_str(), "{}");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn presence() {
+    use gm::types::content::root::types::Presence;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, r#"{"presence": "unavailable", "last_active_ago": 420845, "currently_active": false}"#),
+        MockResponse::json(404, r#"{"errcode": "M_UNRECOGNIZED", "error": "Unrecognized request"}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "You are not allowed to see their presence."}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    core.run(mx.set_own_presence(Presence::Online, Some("beep"))).unwrap();
+    let rpl = core.run(mx.get_presence("@u:example.org")).unwrap();
+    match rpl.presence {
+        Presence::Unavailable => {},
+        x => panic!("unexpected presence {:?}", x)
+    }
+    assert_eq!(rpl.last_active_ago, Some(420845));
+    assert!(!rpl.currently_active);
+    let err = core.run(mx.get_presence("@u:example.org")).err().unwrap();
+    match *err.kind() {
+        MatrixErrorKind::PresenceDisabled => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let err = core.run(mx.get_presence("@u:example.org")).err().unwrap();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "PUT");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/presence/@bot:example.org/status");
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({"presence": "online", "status_msg": "beep"}));
+    assert_eq!(reqs[1].method, "GET");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn redact() {
+    use gm::room::RoomExt;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$redaction1:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$redaction2:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.redact("!r:example.org", "$ev/1:example.org", None)).unwrap();
+    assert_eq!(rpl.event_id, "$redaction1:example.org");
+    let room = Room::from_id("!r:example.org");
+    let rpl = core.run(room.cli(&mut mx).redact("$ev:example.org", Some("spam"))).unwrap();
+    assert_eq!(rpl.event_id, "$redaction2:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "PUT");
+    assert!(reqs[0].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/redact/$ev%2F1:example.org/"));
+    assert_eq!(reqs[0].body_str(), "{}");
+    assert_eq!(reqs[0].header("Content-Type"), Some("application/json"));
+    assert_eq!(reqs[1].method, "PUT");
+    assert_eq!(reqs[1].body_str(), r#"{"reason":"spam"}"#);
+    assert!(reqs[0].path.rsplit('/').next() != reqs[1].path.rsplit('/').next());
+    keep_logged_in(mx);
+}
+
+#[test]
+fn redact_txn_ids() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(429, RATE_LIMITED),
+        MockResponse::json(200, r#"{"event_id": "$redaction1:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$redaction2:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    mx.set_rate_limit_retries(1);
+    let rpl = core.run(mx.redact("!r:example.org", "$ev:example.org", Some("spam"))).unwrap();
+    assert_eq!(rpl.event_id, "$redaction1:example.org");
+    let rpl = core.run(mx.redact_with_txn_id("!r:example.org", "$ev:example.org", None, "my-txn")).unwrap();
+    assert_eq!(rpl.event_id, "$redaction2:example.org");
+    let reqs = srv.requests();
+    // The retry reuses the transaction ID, and the body.
+    assert_eq!(reqs[0].path, reqs[1].path);
+    assert_eq!(reqs[1].body_str(), r#"{"reason":"spam"}"#);
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/rooms/!r:example.org/redact/$ev:example.org/my-txn");
+    assert_eq!(reqs[2].body_str(), "{}");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn typing() {
+    // Runs the event loop until the mock server has had `n` requests.
+    fn wait_for_requests(core: &mut Core, srv: &MockServer, n: usize) {
+        for _ in 0..100 {
+            if srv.requests().len() >= n {
+                return;
+            }
+            core.turn(Some(Duration::from_millis(50)));
+        }
+        panic!("timed out waiting for {} requests", n);
+    }
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    core.run(mx.set_typing("!r:example.org", false, Some(5000))).unwrap();
+    let guard = mx.typing_guard("!r:example.org", 200, true).unwrap();
+    // The notification, and then one refresh of it.
+    wait_for_requests(&mut core, &srv, 3);
+    drop(guard);
+    wait_for_requests(&mut core, &srv, 4);
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 4);
+    for req in &reqs {
+        assert_eq!(req.method, "PUT");
+        assert_eq!(req.path, "/_matrix/client/v3/rooms/!r:example.org/typing/@bot:example.org");
+    }
+    let bodies: Vec<::serde_json::Value> = reqs.iter()
+        .map(|r| ::serde_json::from_slice(&r.body).unwrap())
+        .collect();
+    assert_eq!(bodies, vec![
+        json!({"typing": false}),
+        json!({"typing": true, "timeout": 200}),
+        json!({"typing": true, "timeout": 200}),
+        json!({"typing": false})
+    ]);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn receipts_and_read_markers() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    core.run(mx.send_receipt("!r:example.org", "$ev:example.org")).unwrap();
+    core.run(mx.send_private_receipt("!r:example.org", "$ev:example.org")).unwrap();
+    core.run(mx.set_read_markers("!r:example.org", Some("$ev:example.org"), None)).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/receipt/m.read/$ev:example.org");
+    assert_eq!(reqs[0].body_str(), "{}");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/receipt/m.read.private/$ev:example.org");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/rooms/!r:example.org/read_markers");
+    assert_eq!(reqs[2].body_str(), r#"{"m.fully_read":"$ev:example.org"}"#);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn membership() {
+    use gm::room::RoomExt;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"room_id": "!r:example.org"}"#),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.join_via("#room:example.org", &["example.org", "matrix.org"])).unwrap();
+    assert_eq!(rpl.room.id, "!r:example.org");
+    core.run(rpl.room.cli(&mut mx).kick_user("@u:example.org", Some(""))).unwrap();
+    core.run(rpl.room.cli(&mut mx).ban_user("@u:example.org", Some("spam"))).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/join/%23room:example.org?via=example.org&server_name=example.org\
+                              &via=matrix.org&server_name=matrix.org");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/kick");
+    assert_eq!(reqs[1].body_str(), r#"{"user_id":"@u:example.org"}"#);
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[2].body).unwrap();
+    assert_eq!(body, json!({"user_id": "@u:example.org", "reason": "spam"}));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn joined_rooms_and_members() {
+    use gm::room::RoomExt;
+    use gm::types::content::room::types::Membership;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"joined_rooms": ["!a:example.org", "!b:example.org"]}"#),
+        MockResponse::json(200, r#"{"joined": {
+            "@bot:example.org": {"display_name": "Bot", "avatar_url": "mxc://example.org/bot"},
+            "@=2fw\u00e9ird_user/x:example.org": {}
+        }}"#),
+        MockResponse::json(200, r#"{"chunk": [{"type": "m.room.member", "event_id": "$i", "sender": "@bot:example.org",
+            "origin_server_ts": 1, "state_key": "@u:example.org", "content": {"membership": "invite"}}]}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rooms = core.run(mx.joined_rooms()).unwrap();
+    assert_eq!(rooms, vec![Room::from_id("!a:example.org"), Room::from_id("!b:example.org")]);
+    let room = Room::from_id("!a:example.org");
+    let members = core.run(room.cli(&mut mx).joined_members()).unwrap();
+    assert_eq!(members.len(), 2);
+    assert_eq!(members["@bot:example.org"].display_name.as_ref().unwrap(), "Bot");
+    assert!(members["@=2fw\u{e9}ird_user/x:example.org"].avatar_url.is_none());
+    let evs = core.run(room.cli(&mut mx).members(Some(Membership::Invite), None)).unwrap();
+    assert_eq!(evs.len(), 1);
+    assert_eq!(evs[0].event_id(), Some("$i"));
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "GET");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/joined_rooms");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!a:example.org/joined_members");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/rooms/!a:example.org/members?membership=invite");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn invites() {
+    use gm::room::RoomExt;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "@u:example.org is already in the room."}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let room = Room::from_id("!r:example.org");
+    core.run(room.cli(&mut mx).invite_user("@u:example.org")).unwrap();
+    core.run(room.cli(&mut mx).invite_3pid("id.example.org", Some("idtoken"), "email", "u@example.org")).unwrap();
+    let err = core.run(room.cli(&mut mx).invite_user("@u:example.org")).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_FORBIDDEN"));
+    let reqs = srv.requests();
+    for req in &reqs {
+        assert_eq!(req.method, "POST");
+        assert_eq!(req.path, "/_matrix/client/v3/rooms/!r:example.org/invite");
+    }
+    let bodies: Vec<::serde_json::Value> = reqs[..2].iter()
+        .map(|r| ::serde_json::from_slice(&r.body).unwrap())
+        .collect();
+    assert_eq!(bodies[0], json!({"user_id": "@u:example.org"}));
+    assert_eq!(bodies[1], json!({
+        "id_server": "id.example.org",
+        "id_access_token": "idtoken",
+        "medium": "email",
+        "address": "u@example.org"
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn create_room() {
+    use gm::room::{CreateRoomParams, InitialStateEvent, RoomPreset};
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"room_id": "!new:example.org"}"#),
+        MockResponse::json(200, r#"{"room_id": "!new2:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let room = core.run(mx.create_room(CreateRoomParams {
+        name: Some("Bots".into()),
+        preset: Some(RoomPreset::TrustedPrivateChat),
+        invite: vec!["@u:example.org".into()],
+        is_direct: Some(true),
+        initial_state: vec![InitialStateEvent {
+            event_type: "m.room.guest_access".into(),
+            state_key: "".into(),
+            content: json!({"guest_access": "can_join"})
+        }],
+        ..Default::default()
+    })).unwrap();
+    assert_eq!(room.id, "!new:example.org");
+    let room = core.run(mx.create_room(Default::default())).unwrap();
+    assert_eq!(room.id, "!new2:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/createRoom");
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({
+        "name": "Bots",
+        "preset": "trusted_private_chat",
+        "invite": ["@u:example.org"],
+        "is_direct": true,
+        "initial_state": [{"type": "m.room.guest_access", "state_key": "", "content": {"guest_access": "can_join"}}]
+    }));
+    assert_eq!(reqs[1].body_str(), "{}");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn join_by_id_or_alias() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"room_id": "!r:example.org"}"#),
+        MockResponse::json(200, r#"{"room_id": "!r:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.join("!r:example.org")).unwrap();
+    assert_eq!(rpl.room.id, "!r:example.org");
+    let rpl = core.run(mx.join_via("#foo:remote.example.org", &["remote.example.org"])).unwrap();
+    assert_eq!(rpl.room.id, "!r:example.org");
+    let reqs = srv.requests();
+    // `!` and `:` are fine in a path segment; `#` would start a fragment.
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/join/!r:example.org");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/join/%23foo:remote.example.org?via=remote.example.org\
+                              &server_name=remote.example.org");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn create_room_builder() {
+    use gm::room::{CreateRoomParams, RoomPreset, RoomVisibility};
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"room_id": "!a:example.org"}"#),
+        MockResponse::json(200, r#"{"room_id": "!b:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let params = CreateRoomParams::new()
+        .preset(RoomPreset::TrustedPrivateChat)
+        .visibility(RoomVisibility::Private)
+        .is_direct(true)
+        .encrypted()
+        .room_version("10")
+        .creation_content(json!({"m.federate": false}))
+        .power_level_content_override(json!({"events_default": 50}));
+    for &(user, id) in &[("@a:example.org", "!a:example.org"), ("@b:example.org", "!b:example.org")] {
+        let room = core.run(mx.create_room(params.clone().invite(user))).unwrap();
+        assert_eq!(room.id, id);
+    }
+    let reqs = srv.requests();
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "preset": "trusted_private_chat",
+        "visibility": "private",
+        "is_direct": true,
+        "invite": ["@b:example.org"],
+        "initial_state": [{"type": "m.room.encryption", "state_key": "", "content": {"algorithm": "m.megolm.v1.aes-sha2"}}],
+        "room_version": "10",
+        "creation_content": {"m.federate": false},
+        "power_level_content_override": {"events_default": 50}
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn moderation() {
+    let srv = MockServer::new((0..6).map(|_| MockResponse::json(200, "{}")).collect());
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let room = "!r:example.org";
+    core.run(mx.invite(room, "@u:example.org")).unwrap();
+    core.run(mx.kick(room, "@u:example.org", Some("flooding"))).unwrap();
+    core.run(mx.ban(room, "@u:example.org", None)).unwrap();
+    core.run(mx.unban(room, "@u:example.org")).unwrap();
+    core.run(mx.leave(room)).unwrap();
+    core.run(mx.forget(room)).unwrap();
+    let reqs = srv.requests();
+    let summary: Vec<_> = reqs.iter()
+        .map(|r| {
+            let body = if r.body.is_empty() { json!(null) } else { ::serde_json::from_slice(&r.body).unwrap() };
+            (r.path.rsplit('/').next().unwrap().to_string(), body)
+        })
+        .collect();
+    let s = |a: &str, b| (a.to_string(), b);
+    assert_eq!(summary, vec![
+        s("invite", json!({"user_id": "@u:example.org"})),
+        s("kick", json!({"user_id": "@u:example.org", "reason": "flooding"})),
+        s("ban", json!({"user_id": "@u:example.org"})),
+        s("unban", json!({"user_id": "@u:example.org"})),
+        s("leave", json!(null)),
+        s("forget", json!(null))
+    ]);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn room_state() {
+    use gm::types::content::Content;
+    use gm::types::content::room::{Name, Topic};
+    use gm::types::events::Event;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"name": "Bots"}"#),
+        MockResponse::json(200, r#"{"event_id": "$topic:example.org"}"#),
+        MockResponse::json(200, r#"{"custom": [1, 2]}"#),
+        MockResponse::json(200, r#"[
+            {"type": "m.room.name", "event_id": "$1", "sender": "@u:example.org", "origin_server_ts": 1,
+             "state_key": "", "content": {"name": "Bots"}},
+            {"type": "org.example.custom", "event_id": "$2", "sender": "@u:example.org", "origin_server_ts": 1,
+             "state_key": "k", "content": {"custom": [1, 2]}}
+        ]"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let name: Name = core.run(mx.get_state("!r:example.org", "m.room.name", None)).unwrap();
+    assert_eq!(name.name, "Bots");
+    let rpl = core.run(mx.set_state("!r:example.org", "m.room.topic", None, Topic { topic: "beep".into() })).unwrap();
+    assert_eq!(rpl.event_id, "$topic:example.org");
+    let val: ::serde_json::Value = core.run(mx.get_state("!r:example.org", "org.example.custom", Some("k"))).unwrap();
+    assert_eq!(val, json!({"custom": [1, 2]}));
+    let state = core.run(mx.get_all_state("!r:example.org")).unwrap();
+    assert_eq!(state.len(), 2);
+    match state[0] {
+        Event::Full(_, Content::RoomName(ref n)) => assert_eq!(n.name, "Bots"),
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    match state[1] {
+        Event::Full(ref meta, Content::Unknown(ref v)) => {
+            assert_eq!(meta.state_key.as_ref().unwrap(), "k");
+            assert_eq!(v, &json!({"custom": [1, 2]}));
+        },
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.name");
+    assert_eq!(reqs[1].method, "PUT");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.topic");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/rooms/!r:example.org/state/org.example.custom/k");
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/rooms/!r:example.org/state");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn state_event_keys() {
+    use gm::types::content::room::Member;
+    use gm::types::content::room::types::Membership;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"membership": "join", "displayname": "U"}"#),
+        MockResponse::json(200, r#"{"event_id": "$custom:example.org"}"#),
+        MockResponse::json(200, r#"{"event_id": "$topic:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let member: Member = core.run(mx.get_state_event("!r:example.org", "m.room.member", "@u:example.org")).unwrap();
+    assert!(matches!(member.membership, Membership::Join));
+    assert_eq!(member.displayname.as_ref().unwrap(), "U");
+    let rpl = core.run(mx.set_state_event("!r:example.org", "org.example.custom", "a/b", json!({"x": 1}))).unwrap();
+    assert_eq!(rpl.event_id, "$custom:example.org");
+    let rpl = core.run(mx.set_state_event("!r:example.org", "m.room.topic", "", json!({"topic": "t"}))).unwrap();
+    assert_eq!(rpl.event_id, "$topic:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.member/@u:example.org");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/state/org.example.custom/a%2Fb");
+    assert_eq!(reqs[1].body_str(), r#"{"x":1}"#);
+    assert_eq!(reqs[2].method, "PUT");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.topic");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn set_user_power_level() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{
+            "users": {"@admin:example.org": 100},
+            "events": {"m.room.name": 50},
+            "state_default": 50,
+            "notifications": {"room": 20, "org.example.notif": 5},
+            "org.example.unknown": {"keep": true}
+        }"#),
+        MockResponse::json(200, r#"{"event_id": "$pl:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.set_user_power_level("!r:example.org", "@mod:example.org", 50)).unwrap();
+    assert_eq!(rpl.event_id, "$pl:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "GET");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.power_levels");
+    assert_eq!(reqs[1].method, "PUT");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.power_levels");
+    assert!(reqs[1].header("authorization").is_some());
+    let body: ::serde_json::Value = ::serde_json::from_str(reqs[1].body_str()).unwrap();
+    assert_eq!(body, json!({
+        "users": {"@admin:example.org": 100, "@mod:example.org": 50},
+        "events": {"m.room.name": 50},
+        "state_default": 50,
+        "notifications": {"room": 20, "org.example.notif": 5},
+        "org.example.unknown": {"keep": true}
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn typed_account_data() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "Account data not found"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{"ignored_users": {"@troll:example.org": {}}}"#),
+        MockResponse::json(200, r#"{"ignored_users": {"@troll:example.org": {}}}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "Account data not found"}"#),
+        MockResponse::json(200, r#"{}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let mut direct = core.run(mx.get_direct_rooms()).unwrap();
+    assert!(direct.rooms_for("@friend:example.org").is_empty());
+    direct.add_room("@friend:example.org", "!dm:example.org");
+    direct.add_room("@friend:example.org", "!dm:example.org");
+    core.run(mx.set_direct_rooms(&direct)).unwrap();
+    let ignored = core.run(mx.get_ignored_users()).unwrap();
+    assert!(ignored.is_ignored("@troll:example.org"));
+    core.run(mx.ignore_user("@spam:example.org")).unwrap();
+    core.run(mx.unignore_user("@troll:example.org")).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/user/@bot:example.org/account_data/m.direct");
+    assert_eq!(reqs[1].method, "PUT");
+    assert_eq!(reqs[1].body_str(), r#"{"@friend:example.org":["!dm:example.org"]}"#);
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/user/@bot:example.org/account_data/m.ignored_user_list");
+    assert_eq!(reqs[4].method, "PUT");
+    assert_eq!(reqs[4].path, "/_matrix/client/v3/user/@bot:example.org/account_data/m.ignored_user_list");
+    let body: ::serde_json::Value = ::serde_json::from_str(reqs[4].body_str()).unwrap();
+    assert_eq!(body, json!({"ignored_users": {"@troll:example.org": {}, "@spam:example.org": {}}}));
+    assert_eq!(reqs[6].body_str(), r#"{"ignored_users":{}}"#);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn room_tags() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"tags": {"m.favourite": {"order": 0.25}, "u.old": {"order": "0.5"},
+                                             "u.relay from": {"org.example.extra": true}}}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let tags = core.run(mx.get_tags("!r:example.org")).unwrap();
+    assert_eq!(tags.order("m.favourite"), Some(0.25));
+    assert_eq!(tags.order("u.old"), Some(0.5));
+    assert!(tags.has("u.relay from"));
+    assert_eq!(tags.order("u.relay from"), None);
+    assert_eq!(tags.tags["u.relay from"].extra["org.example.extra"], json!(true));
+    core.run(mx.set_tag("!r:example.org", "u.relay from", None)).unwrap();
+    core.run(mx.set_tag("!r:example.org", "m.favourite", Some(0.5))).unwrap();
+    core.run(mx.remove_tag("!r:example.org", "u.relay from")).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/user/@bot:example.org/rooms/!r:example.org/tags");
+    assert_eq!(reqs[1].method, "PUT");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/user/@bot:example.org/rooms/!r:example.org/tags/u.relay%20from");
+    assert_eq!(reqs[1].body_str(), "{}");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/user/@bot:example.org/rooms/!r:example.org/tags/m.favourite");
+    assert_eq!(reqs[2].body_str(), r#"{"order":0.5}"#);
+    assert_eq!(reqs[3].method, "DELETE");
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/user/@bot:example.org/rooms/!r:example.org/tags/u.relay%20from");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn push_rules() {
+    use gm::types::push::{PushAction, PushCondition, PushRule, PushRuleKind};
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"global": {
+            "override": [{"rule_id": ".m.rule.master", "default": true, "enabled": false, "actions": []},
+                         {"rule_id": ".m.rule.custom", "default": true, "enabled": true,
+                          "conditions": [{"kind": "event_match", "key": "type", "pattern": "m.room.message"},
+                                         {"kind": "contains_display_name"},
+                                         {"kind": "org.example.weird", "frobnicate": 3}],
+                          "actions": ["notify", {"set_tweak": "sound", "value": "default"}, {"set_tweak": "highlight"}]}],
+            "content": [{"rule_id": "lunch", "default": false, "enabled": true, "pattern": "lunch", "actions": ["coalesce"]}],
+            "room": [], "sender": [], "underride": []
+        }}"#),
+        MockResponse::json(200, r#"{"rule_id": "lunch", "default": false, "enabled": true, "pattern": "lunch", "actions": ["notify"]}"#),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rules = core.run(mx.get_push_rules()).unwrap();
+    assert!(!rules.get(PushRuleKind::Override, ".m.rule.master").unwrap().enabled);
+    let custom = rules.get(PushRuleKind::Override, ".m.rule.custom").unwrap();
+    let conds = custom.conditions.as_ref().unwrap();
+    assert_eq!(conds[0], PushCondition::EventMatch { key: "type".into(), pattern: "m.room.message".into() });
+    assert_eq!(conds[1], PushCondition::ContainsDisplayName);
+    assert_eq!(conds[2], PushCondition::Unknown(json!({"kind": "org.example.weird", "frobnicate": 3})));
+    assert_eq!(custom.actions, vec![PushAction::Notify, PushAction::sound("default"),
+                                    PushAction::SetTweak { tweak: "highlight".into(), value: None }]);
+    assert!(custom.notifies());
+    // Unknown conditions go back the way they came.
+    assert_eq!(serde_json::to_value(conds).unwrap(), json!([
+        {"kind": "event_match", "key": "type", "pattern": "m.room.message"},
+        {"kind": "contains_display_name"},
+        {"kind": "org.example.weird", "frobnicate": 3}
+    ]));
+    assert_eq!(rules.rules(PushRuleKind::Content)[0].pattern.as_ref().unwrap(), "lunch");
+    let rule = core.run(mx.get_push_rule("global", PushRuleKind::Content, "lunch")).unwrap();
+    assert_eq!(rule.actions, vec![PushAction::Notify]);
+    let mut rule = PushRule::new("mentions", vec![PushAction::Notify, PushAction::highlight(true)]);
+    rule.conditions = Some(vec![PushCondition::EventMatch { key: "content.body".into(), pattern: "*bot*".into() }]);
+    core.run(mx.set_push_rule("global", PushRuleKind::Override, &rule)).unwrap();
+    core.run(mx.set_push_rule_enabled("global", PushRuleKind::Override, ".m.rule.master", true)).unwrap();
+    core.run(mx.set_push_rule_actions("global", PushRuleKind::Content, "lunch", &[PushAction::DontNotify])).unwrap();
+    core.run(mx.set_room_rule("!r:example.org", false)).unwrap();
+    core.run(mx.delete_push_rule("global", PushRuleKind::Room, "!r:example.org")).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/pushrules/");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/pushrules/global/content/lunch");
+    assert_eq!(reqs[2].method, "PUT");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/pushrules/global/override/mentions");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[2].body).unwrap();
+    assert_eq!(body, json!({
+        "actions": ["notify", {"set_tweak": "highlight", "value": true}],
+        "conditions": [{"kind": "event_match", "key": "content.body", "pattern": "*bot*"}]
+    }));
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/pushrules/global/override/.m.rule.master/enabled");
+    assert_eq!(reqs[3].body_str(), r#"{"enabled":true}"#);
+    assert_eq!(reqs[4].path, "/_matrix/client/v3/pushrules/global/content/lunch/actions");
+    assert_eq!(reqs[4].body_str(), r#"{"actions":["dont_notify"]}"#);
+    assert_eq!(reqs[5].path, "/_matrix/client/v3/pushrules/global/room/!r:example.org");
+    assert_eq!(reqs[5].body_str(), r#"{"actions":[]}"#);
+    assert_eq!(reqs[6].method, "DELETE");
+    assert_eq!(reqs[6].path, "/_matrix/client/v3/pushrules/global/room/!r:example.org");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn pushers() {
+    use gm::types::push::{Pusher, PusherData, PusherKind};
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"pushers": [{
+            "pushkey": "abc", "kind": "http", "app_id": "org.example.app", "app_display_name": "App",
+            "device_display_name": "Phone", "lang": "en",
+            "data": {"url": "https://push.example.org/_matrix/push/v1/notify", "format": "event_id_only", "brand": "x"}
+        }]}"#),
+        MockResponse::json(200, "{}"),
+        MockResponse::json(200, "{}")
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let pushers = core.run(mx.get_pushers()).unwrap();
+    assert_eq!(pushers.len(), 1);
+    assert_eq!(pushers[0].kind, PusherKind::Http);
+    assert_eq!(pushers[0].data.format.as_ref().unwrap(), "event_id_only");
+    assert_eq!(pushers[0].data.extra["brand"], "x");
+    let pusher = Pusher {
+        pushkey: "me@example.org".into(),
+        kind: PusherKind::Email,
+        app_id: "m.email".into(),
+        app_display_name: "Email".into(),
+        device_display_name: "me@example.org".into(),
+        profile_tag: None,
+        lang: "en".into(),
+        data: PusherData::default()
+    };
+    core.run(mx.set_pusher(&pusher, true)).unwrap();
+    core.run(mx.delete_pusher("org.example.app", "abc")).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/pushers");
+    assert_eq!(reqs[1].method, "POST");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/pushers/set");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "pushkey": "me@example.org", "kind": "email", "app_id": "m.email", "app_display_name": "Email",
+        "device_display_name": "me@example.org", "lang": "en", "data": {}, "append": true
+    }));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[2].body).unwrap();
+    assert_eq!(body, json!({"app_id": "org.example.app", "pushkey": "abc", "kind": null}));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn notifications() {
+    use gm::push::PushAction;
+    let notif = |id: &str, read: bool| format!(r#"{{"actions": ["notify", {{"set_tweak": "highlight", "value": true}}],
+        "event": {{"type": "m.room.message", "event_id": "{}", "sender": "@u:example.org", "origin_server_ts": 1,
+                   "content": {{"msgtype": "m.text", "body": "bot: hi"}}}},
+        "read": {}, "room_id": "!r:example.org", "ts": 5}}"#, id, read);
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &format!(r#"{{"notifications": [{}], "next_token": "n2"}}"#, notif("$h", false))),
+        MockResponse::json(200, &format!(r#"{{"notifications": [{}, {}], "next_token": "n2"}}"#,
+                                         notif("$a", true), notif("$b", false))),
+        MockResponse::json(200, &format!(r#"{{"notifications": [{}]}}"#, notif("$c", true)))
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.notifications(None, None, Some("highlight"))).unwrap();
+    assert_eq!(rpl.next_token.as_ref().unwrap(), "n2");
+    assert_eq!(rpl.notifications.len(), 1);
+    let n = &rpl.notifications[0];
+    assert_eq!(n.event.event_id().unwrap(), "$h");
+    assert_eq!(n.room_id, "!r:example.org");
+    assert_eq!(n.ts, 5);
+    assert!(!n.read);
+    assert_eq!(n.actions[0], PushAction::Notify);
+    assert_eq!(n.actions[1], PushAction::highlight(true));
+    let notifs = core.run(mx.notifications_stream(Some(2), None).collect()).unwrap();
+    let ids: Vec<_> = notifs.iter().map(|n| n.event.event_id().unwrap()).collect();
+    assert_eq!(ids, vec!["$a", "$b", "$c"]);
+    assert_eq!(notifs.iter().filter(|n| !n.read).count(), 1);
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    assert_eq!(reqs[0].method, "GET");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/notifications?only=highlight");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/notifications?limit=2");
+    assert!(reqs[2].path.contains("from=n2"));
+    assert!(reqs[2].path.contains("limit=2"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn profiles() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"displayname": "Bot", "avatar_url": "mxc://example.org/abc"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "Profile was not found"}"#),
+        MockResponse::json(200, r#"{"content_uri": "mxc://example.org/new"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let p = core.run(mx.get_profile("@bot:example.org")).unwrap();
+    assert_eq!(p.displayname.as_ref().unwrap(), "Bot");
+    assert_eq!(p.avatar_url.as_ref().unwrap(), "mxc://example.org/abc");
+    let p = core.run(mx.get_profile("@empty:example.org")).unwrap();
+    assert!(p.displayname.is_none() && p.avatar_url.is_none());
+    let p = core.run(mx.get_profile("@missing:example.org")).unwrap();
+    assert!(p.displayname.is_none() && p.avatar_url.is_none());
+    let mxc = core.run(mx.upload(&b"png"[..], ContentType::png(), None)).unwrap();
+    core.run(mx.set_avatar_url("@bot:example.org", &mxc)).unwrap();
+    core.run(mx.set_displayname("@bot:example.org", "Robot")).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/profile/@bot:example.org");
+    assert_eq!(reqs[4].method, "PUT");
+    assert_eq!(reqs[4].path, "/_matrix/client/v3/profile/@bot:example.org/avatar_url");
+    assert_eq!(reqs[4].body_str(), r#"{"avatar_url":"mxc://example.org/new"}"#);
+    assert_eq!(reqs[5].path, "/_matrix/client/v3/profile/@bot:example.org/displayname");
+    assert_eq!(reqs[5].body_str(), r#"{"displayname":"Robot"}"#);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn profile_fields() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"displayname": "Bot"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "Profile was not found"}"#),
+        MockResponse::json(200, r#"{"avatar_url": "mxc://example.org/abc"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{"membership": "join", "displayname": "Bot", "reason": "hi"}"#),
+        MockResponse::json(200, r#"{"event_id": "$member:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let name = core.run(mx.get_displayname("@bot:example.org")).unwrap();
+    assert_eq!(name.as_ref().map(|x| x as &str), Some("Bot"));
+    assert!(core.run(mx.get_displayname("@empty:example.org")).unwrap().is_none());
+    assert!(core.run(mx.get_displayname("@missing:example.org")).unwrap().is_none());
+    let avatar = core.run(mx.get_avatar_url("@bot:example.org")).unwrap();
+    assert_eq!(avatar.as_ref().map(|x| x as &str), Some("mxc://example.org/abc"));
+    core.run(mx.set_own_display_name("Robot")).unwrap();
+    let rpl = core.run(mx.set_room_display_name("!r:example.org", "Room robot")).unwrap();
+    assert_eq!(rpl.event_id, "$member:example.org");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/profile/@bot:example.org/displayname");
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/profile/@bot:example.org/avatar_url");
+    assert_eq!(reqs[4].method, "PUT");
+    assert_eq!(reqs[4].path, "/_matrix/client/v3/profile/@bot:example.org/displayname");
+    assert_eq!(reqs[4].body_str(), r#"{"displayname":"Robot"}"#);
+    assert_eq!(reqs[5].method, "GET");
+    assert_eq!(reqs[5].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.member/@bot:example.org");
+    assert_eq!(reqs[6].method, "PUT");
+    assert_eq!(reqs[6].path, "/_matrix/client/v3/rooms/!r:example.org/state/m.room.member/@bot:example.org");
+    let body: ::serde_json::Value = ::serde_json::from_str(reqs[6].body_str()).unwrap();
+    assert_eq!(body, json!({"membership": "join", "displayname": "Room robot", "reason": "hi"}));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn search_users() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"limited": true, "results": [
+            {"user_id": "@alice:example.org", "display_name": "Alice", "avatar_url": "mxc://example.org/a"},
+            {"user_id": "@alicia:example.org"}
+        ]}"#),
+        MockResponse::json(200, r#"{"limited": false, "results": []}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.search_users("ali", Some(2))).unwrap();
+    assert!(rpl.limited);
+    assert_eq!(rpl.results.len(), 2);
+    assert_eq!(rpl.results[0].display_name.as_ref().unwrap(), "Alice");
+    assert_eq!(rpl.results[1].user_id, "@alicia:example.org");
+    assert!(rpl.results[1].avatar_url.is_none());
+    let rpl = core.run(mx.search_users("nobody", None)).unwrap();
+    assert!(!rpl.limited && rpl.results.is_empty());
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/user_directory/search");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({"search_term": "ali", "limit": 2}));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({"search_term": "nobody"}));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn room_aliases() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"room_id": "!r:example.org", "servers": ["example.org", "other.org"]}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(409, r#"{"errcode": "M_UNKNOWN", "error": "Room alias #bridge:example.org already exists"}"#),
+        MockResponse::json(403, r#"{"errcode": "M_FORBIDDEN", "error": "Not allowed"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{"room_id": "!cpp:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.resolve_alias("#room:example.org")).unwrap();
+    assert_eq!(rpl.room.id, "!r:example.org");
+    assert_eq!(rpl.servers, vec!["example.org", "other.org"]);
+    core.run(mx.create_alias("#bridge:example.org", "!r:example.org")).unwrap();
+    match *core.run(mx.create_alias("#bridge:example.org", "!r:example.org")).unwrap_err().kind() {
+        MatrixErrorKind::AliasInUse => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    match *core.run(mx.create_alias("#other:example.org", "!r:example.org")).unwrap_err().kind() {
+        MatrixErrorKind::BadRequest(ref brq, _) => assert_eq!(brq.errcode, "M_FORBIDDEN"),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    core.run(mx.delete_alias("#bridge:example.org")).unwrap();
+    let rpl = core.run(mx.resolve_alias("#c++:example.org")).unwrap();
+    assert_eq!(rpl.room.id, "!cpp:example.org");
+    assert!(rpl.servers.is_empty());
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/directory/room/%23room:example.org");
+    assert_eq!(reqs[1].method, "PUT");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/directory/room/%23bridge:example.org");
+    assert_eq!(reqs[1].body_str(), r#"{"room_id":"!r:example.org"}"#);
+    assert_eq!(reqs[4].method, "DELETE");
+    assert_eq!(reqs[4].path, "/_matrix/client/v3/directory/room/%23bridge:example.org");
+    assert_eq!(reqs[5].path, "/_matrix/client/v3/directory/room/%23c%2B%2B:example.org");
+    keep_logged_in(mx);
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+struct BotSettings {
+    prefix: String,
+    admins: Vec<String>,
+    quiet: bool
+}
+
+#[test]
+fn account_data() {
+    use gm::room::RoomExt;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{"prefix": "!", "admins": ["@a:example.org"], "quiet": false}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{"prefix": "?", "admins": [], "quiet": true}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "Account data not found"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let settings = BotSettings {
+        prefix: "!".into(),
+        admins: vec!["@a:example.org".into()],
+        quiet: false
+    };
+    core.run(mx.set_account_data("@bot:example.org", "org.example.bot", &settings)).unwrap();
+    let got: BotSettings = core.run(mx.get_account_data("@bot:example.org", "org.example.bot")).unwrap();
+    assert_eq!(got, settings);
+    let room = Room::from_id("!r:example.org");
+    let quiet = BotSettings {
+        prefix: "?".into(),
+        admins: vec![],
+        quiet: true
+    };
+    core.run(room.cli(&mut mx).set_account_data("org.example.bot", &quiet)).unwrap();
+    let got: BotSettings = core.run(room.cli(&mut mx).get_account_data("org.example.bot")).unwrap();
+    assert_eq!(got, quiet);
+    core.run(mx.set_room_account_data("@bot:example.org", "!r:example.org", "org.example.empty", json!({}))).unwrap();
+    let err = core.run(mx.get_account_data::<BotSettings>("@bot:example.org", "org.example.none")).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_NOT_FOUND"));
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "PUT");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/user/@bot:example.org/account_data/org.example.bot");
+    let body: ::serde_json::Value = ::serde_json::from_str(reqs[0].body_str()).unwrap();
+    assert_eq!(body, json!({"prefix": "!", "admins": ["@a:example.org"], "quiet": false}));
+    assert_eq!(reqs[1].method, "GET");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/user/@bot:example.org/account_data/org.example.bot");
+    assert_eq!(reqs[2].path, "/_matrix/client/v3/user/@bot:example.org/rooms/!r:example.org/account_data/org.example.bot");
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/user/@bot:example.org/rooms/!r:example.org/account_data/org.example.bot");
+    assert_eq!(reqs[4].body_str(), "{}");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn public_rooms() {
+    use gm::room::PublicRoomsParams;
+    let room = |id: &str, members: u32| format!(r#"{{"room_id": "{}", "name": "Room {}", "num_joined_members": {},
+                                                "world_readable": false, "guest_can_join": true}}"#, id, id, members);
+    let page1 = format!(r#"{{"chunk": [{}, {}], "next_batch": "p2", "total_room_count_estimate": 5}}"#,
+                        room("!a:example.org", 10), room("!b:example.org", 4));
+    let page2 = format!(r#"{{"chunk": [{}, {}], "next_batch": "p3", "prev_batch": "p1"}}"#,
+                        room("!c:example.org", 2), room("!d:example.org", 1));
+    let page3 = format!(r#"{{"chunk": [{}], "prev_batch": "p2"}}"#, room("!e:example.org", 1));
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"chunk": [{"room_id": "!x:other.org", "num_joined_members": 3, "world_readable": true,
+            "guest_can_join": false, "topic": "Bots", "avatar_url": "mxc://other.org/av"}], "prev_batch": "p0"}"#),
+        MockResponse::json(200, &page1),
+        MockResponse::json(200, &page2),
+        MockResponse::json(200, &page1),
+        MockResponse::json(200, &page2),
+        MockResponse::json(200, &page3)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let params = PublicRoomsParams::new().server("other.org").search("bots").limit(1);
+    let rpl = core.run(mx.public_rooms(&params)).unwrap();
+    assert_eq!(rpl.chunk.len(), 1);
+    assert_eq!(rpl.chunk[0].room.id, "!x:other.org");
+    assert_eq!(rpl.chunk[0].topic.as_ref().unwrap(), "Bots");
+    assert!(rpl.chunk[0].name.is_none());
+    assert!(rpl.next_batch.is_none());
+    assert_eq!(rpl.prev_batch.as_ref().unwrap(), "p0");
+    // Taking 3 rooms only needs the first two pages.
+    let rooms = core.run(mx.public_rooms_stream(PublicRoomsParams::new()).take(3).collect()).unwrap();
+    let ids: Vec<_> = rooms.iter().map(|r| &r.room.id as &str).collect();
+    assert_eq!(ids, vec!["!a:example.org", "!b:example.org", "!c:example.org"]);
+    let rooms = core.run(mx.public_rooms_stream(PublicRoomsParams::new()).collect()).unwrap();
+    assert_eq!(rooms.len(), 5);
+    assert_eq!(rooms[4].num_joined_members, 1);
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 6);
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/publicRooms?server=other.org");
+    let body: ::serde_json::Value = ::serde_json::from_str(reqs[0].body_str()).unwrap();
+    assert_eq!(body, json!({"limit": 1, "filter": {"generic_search_term": "bots"}}));
+    assert_eq!(reqs[1].body_str(), "{}");
+    assert_eq!(reqs[2].body_str(), r#"{"since":"p2"}"#);
+    assert_eq!(reqs[5].body_str(), r#"{"since":"p3"}"#);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn public_rooms_unauthenticated() {
+    use gm::room::PublicRoomsParams;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r##"{"chunk": [{"room_id": "!a:example.org", "name": "A", "topic": "Stuff",
+                                                "canonical_alias": "#a:example.org", "num_joined_members": 3,
+                                                "world_readable": true, "guest_can_join": false,
+                                                "avatar_url": "mxc://example.org/a"}],
+                                     "next_batch": "p2", "total_room_count_estimate": 9}"##)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = MatrixClient::new_unauthenticated(&srv.url, &core.handle()).unwrap();
+    let params = PublicRoomsParams::new().limit(1).since("p1").server("other.org");
+    let rpl = core.run(mx.public_rooms(&params)).unwrap();
+    assert_eq!(rpl.chunk[0].room.id, "!a:example.org");
+    assert_eq!(rpl.chunk[0].num_joined_members, 3);
+    assert_eq!(rpl.chunk[0].canonical_alias.as_ref().unwrap(), "#a:example.org");
+    assert_eq!(rpl.next_batch.as_ref().unwrap(), "p2");
+    assert_eq!(rpl.total_room_count_estimate, Some(9));
+    let req = &srv.requests()[0];
+    assert_eq!(req.method, "GET");
+    assert!(req.header("Authorization").is_none());
+    assert!(req.path.starts_with("/_matrix/client/v3/publicRooms?"));
+    for param in &["limit=1", "since=p1", "server=other.org"] {
+        assert!(req.path.contains(param), "{} missing from {}", param, req.path);
+    }
+}
+
+#[test]
+fn guest_access() {
+    use gm::room::Direction;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"user_id": "@42:example.org", "access_token": "guest", "device_id": "G"}"#),
+        MockResponse::json(200, r#"{"start": "t1", "end": "t2", "chunk": [{"type": "m.room.message", "event_id": "$a",
+            "sender": "@u:example.org", "origin_server_ts": 1, "content": {"msgtype": "m.text", "body": "hi"}}]}"#),
+        MockResponse::json(200, r#"{"next_batch": "s2", "rooms": {"join": {"!r:example.org": {
+            "timeline": {"events": []}, "unread_notifications": {}}}}}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = core.run(MatrixClient::register_guest(&srv.url, &core.handle())).unwrap();
+    assert!(mx.is_guest());
+    assert_eq!(mx.user_id(), "@42:example.org");
+    assert_eq!(mx.access_token(), Some("guest".into()));
+    let rpl = core.run(mx.get_messages("!r:example.org", Direction::Backwards, None, None, None, None)).unwrap();
+    assert_eq!(rpl.chunk.len(), 1);
+    let rpl = core.run(mx.sync(None, 0)).unwrap();
+    assert_eq!(rpl.next_batch, "s2");
+    let room = rpl.rooms.join.values().next().unwrap();
+    assert_eq!(room.unread_notifications.notification_count, 0);
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/register?kind=guest");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/messages?dir=b");
+    assert_eq!(reqs[1].header("Authorization"), Some("Bearer guest"));
+    let other = client(&core, &srv);
+    assert!(!other.is_guest());
+    keep_logged_in(mx);
+    keep_logged_in(other);
+}
+
+#[test]
+fn devices() {
+    use gm::types::replies::UiaaResponse;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"devices": [
+            {"device_id": "PHONE", "display_name": "Phone", "last_seen_ip": "1.2.3.4", "last_seen_ts": 1500000000000},
+            {"device_id": "BOT"}
+        ]}"#),
+        MockResponse::json(200, r#"{"device_id": "PHONE", "display_name": "Phone"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "params": {}, "session": "sess"}"#),
+        MockResponse::json(200, r#"{}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let devices = core.run(mx.get_devices()).unwrap();
+    assert_eq!(devices.len(), 2);
+    assert_eq!(devices[0].last_seen_ip.as_ref().unwrap(), "1.2.3.4");
+    assert_eq!(devices[0].last_seen_ts, Some(1500000000000));
+    assert!(devices[1].display_name.is_none());
+    let dev = core.run(mx.get_device("PHONE")).unwrap();
+    assert_eq!(dev.display_name.unwrap(), "Phone");
+    core.run(mx.rename_device("PHONE", "Old phone")).unwrap();
+    let info = match core.run(mx.delete_device("PHONE", None)).unwrap() {
+        UiaaResponse::AuthRequired(info) => info,
+        x => panic!("unexpected response {:?}", x)
+    };
+    assert_eq!(info.flows[0].stages, vec!["m.login.password"]);
+    let auth = json!({
+        "type": "m.login.password",
+        "identifier": {"type": "m.id.user", "user": "@bot:example.org"},
+        "password": "hunter2",
+        "session": "sess"
+    });
+    assert_eq!(mx.password_auth("hunter2", info.session.as_ref().map(|x| x as &str)), auth);
+    match core.run(mx.delete_device("PHONE", Some(auth.clone()))).unwrap() {
+        UiaaResponse::Success(()) => {},
+        x => panic!("unexpected response {:?}", x)
+    }
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/devices");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/devices/PHONE");
+    assert_eq!(reqs[2].method, "PUT");
+    assert_eq!(reqs[2].body_str(), r#"{"display_name":"Old phone"}"#);
+    assert_eq!(reqs[3].method, "DELETE");
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/devices/PHONE");
+    assert_eq!(reqs[4].method, "DELETE");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[4].body).unwrap();
+    assert_eq!(body, json!({ "auth": auth }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn delete_devices() {
+    use gm::types::replies::UiaaResponse;
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "bulk"}"#),
+        MockResponse::json(200, r#"{}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let stale = ["BOT1", "BOT2", "BOT3"];
+    let session = match core.run(mx.delete_devices(&stale, None)).unwrap() {
+        UiaaResponse::AuthRequired(info) => info.session.unwrap(),
+        x => panic!("unexpected response {:?}", x)
+    };
+    let auth = mx.password_auth("hunter2", Some(&session));
+    match core.run(mx.delete_devices(&stale, Some(auth))).unwrap() {
+        UiaaResponse::Success(()) => {},
+        x => panic!("unexpected response {:?}", x)
+    }
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/delete_devices");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({"devices": ["BOT1", "BOT2", "BOT3"]}));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body["devices"], json!(["BOT1", "BOT2", "BOT3"]));
+    assert_eq!(body["auth"]["session"], "bulk");
+    assert_eq!(body["auth"]["password"], "hunter2");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn change_password_and_deactivate() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "pw"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.sso"]}], "session": "sso"}"#),
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "bye"}"#),
+        MockResponse::json(200, r#"{"id_server_unbind_result": "no-support"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let stage = mx.password_stage("hunter2");
+    core.run(mx.change_password("hunter3", false, stage)).unwrap();
+    let stage = mx.password_stage("hunter3");
+    match *core.run(mx.change_password("hunter4", true, stage)).unwrap_err().kind() {
+        MatrixErrorKind::AuthRequired(ref info) => assert_eq!(info.session.as_ref().unwrap(), "sso"),
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    assert!(!mx.is_logged_out());
+    mx.set_refresh_token(Some("r1".into()));
+    let stage = mx.password_stage("hunter3");
+    core.run(mx.deactivate_account(true, stage)).unwrap();
+    assert!(mx.is_logged_out());
+    assert_eq!(mx.access_token(), None);
+    assert_eq!(mx.refresh_token(), None);
+    match *core.run(mx.whoami()).unwrap_err().kind() {
+        MatrixErrorKind::LoggedOut => {},
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 5);
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/account/password");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({"new_password": "hunter3", "logout_devices": false}));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "new_password": "hunter3", "logout_devices": false,
+        "auth": {"type": "m.login.password", "identifier": {"type": "m.id.user", "user": "@bot:example.org"},
+                 "password": "hunter2", "session": "pw"}
+    }));
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/account/deactivate");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[4].body).unwrap();
+    assert_eq!(body["erase"], true);
+    assert_eq!(body["auth"]["session"], "bye");
+    assert_eq!(reqs[4].header("Authorization"), Some("Bearer token"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn threepids() {
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"threepids": [{"medium": "email", "address": "old@example.org",
+                                                   "validated_at": 1535176800000, "added_at": 1535336848756}]}"#),
+        MockResponse::json(200, r#"{"sid": "abc"}"#),
+        MockResponse::json(200, r#"{"sid": "abc", "submit_url": "https://example.org/submit"}"#),
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "add"}"#),
+        MockResponse::json(200, r#"{}"#),
+        MockResponse::json(200, r#"{"id_server_unbind_result": "success"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let pids = core.run(mx.get_3pids()).unwrap();
+    assert_eq!(pids.len(), 1);
+    assert_eq!(pids[0].address, "old@example.org");
+    assert_eq!(pids[0].validated_at, 1535176800000);
+    let session = core.run(mx.request_email_token("new@example.org", None, 1)).unwrap();
+    assert_eq!(session.sid, "abc");
+    assert_eq!(session.client_secret.len(), 32);
+    assert!(session.submit_url.is_none());
+    let again = core.run(mx.request_email_token("new@example.org", Some(&session.client_secret), 2)).unwrap();
+    assert_eq!(again.client_secret, session.client_secret);
+    assert_eq!(again.submit_url.as_ref().unwrap(), "https://example.org/submit");
+    let stage = mx.password_stage("hunter2");
+    core.run(mx.add_3pid(&session, stage)).unwrap();
+    core.run(mx.delete_3pid("email", "old@example.org")).unwrap();
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].method, "GET");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/account/3pid");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/account/3pid/email/requestToken");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({"client_secret": session.client_secret, "email": "new@example.org", "send_attempt": 1}));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[2].body).unwrap();
+    assert_eq!(body["send_attempt"], 2);
+    assert_eq!(reqs[3].path, "/_matrix/client/v3/account/3pid/add");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[4].body).unwrap();
+    assert_eq!(body["client_secret"], json!(session.client_secret));
+    assert_eq!(body["sid"], "abc");
+    assert_eq!(body["auth"]["session"], "add");
+    assert_eq!(reqs[5].path, "/_matrix/client/v3/account/3pid/delete");
+    let body: serde_json::Value = serde_json::from_slice(&reqs[5].body).unwrap();
+    assert_eq!(body, json!({"medium": "email", "address": "old@example.org"}));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn uiaa_stages() {
+    use gm::types::replies::{AuthData, UiaaResponse};
+    let flows = r#""flows": [{"stages": ["m.login.registration_token", "m.login.dummy"]},
+                               {"stages": ["m.login.password"]}]"#;
+    let srv = MockServer::new(vec![
+        // A plain `send()` gets a distinct error.
+        MockResponse::json(401, &format!(r#"{{{}, "session": "s1"}}"#, flows)),
+        // Two stages, then success.
+        MockResponse::json(401, &format!(r#"{{{}, "session": "s2"}}"#, flows)),
+        MockResponse::json(401, &format!(r#"{{{}, "session": "s2", "completed": ["m.login.registration_token"]}}"#, flows)),
+        MockResponse::json(200, r#"{"user_id": "@new:example.org"}"#),
+        // A wrong password gives up, rather than trying forever.
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "s3"}"#),
+        MockResponse::json(401, r#"{"flows": [{"stages": ["m.login.password"]}], "session": "s3",
+                                    "errcode": "M_FORBIDDEN", "error": "Invalid password"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let req = MatrixRequest::new_with_body_ser(Post, "/register", json!({"username": "new"}));
+    match *core.run(req.send::<serde_json::Value>(&mut mx)).unwrap_err().kind() {
+        MatrixErrorKind::AuthRequired(ref info) => {
+            assert_eq!(info.session.as_ref().unwrap(), "s1");
+            assert_eq!(info.next_stages(), vec!["m.login.registration_token", "m.login.password"]);
+        },
+        ref x => panic!("unexpected error {:?}", x)
+    }
+    let rpl = core.run(req.send_uiaa_with_stages::<serde_json::Value, _>(&mut mx, |stage, _| {
+        match stage {
+            "m.login.registration_token" => Some(AuthData::RegistrationToken { token: "tok".into() }),
+            "m.login.dummy" => Some(AuthData::Dummy),
+            _ => None
+        }
+    })).unwrap();
+    match rpl {
+        UiaaResponse::Success(v) => assert_eq!(v["user_id"], "@new:example.org"),
+        x => panic!("unexpected response {:?}", x)
+    }
+    let auth = mx.password_auth_data("wrong");
+    let del = MatrixRequest::new_with_body_ser(Post, "/delete_devices", json!({"devices": ["OLD"]}));
+    match core.run(del.send_uiaa_with_stages::<serde_json::Value, _>(&mut mx, move |_, _| Some(auth.clone()))).unwrap() {
+        UiaaResponse::AuthRequired(info) => assert_eq!(info.errcode.as_ref().unwrap(), "M_FORBIDDEN"),
+        x => panic!("unexpected response {:?}", x)
+    }
+    // There's nowhere to put `auth` in a body that isn't an object.
+    let bad = MatrixRequest::new_with_body_ser(Post, "/register", json!(["new"]));
+    assert!(core.run(bad.send_uiaa_with_stages::<serde_json::Value, _>(&mut mx, |_, _| Some(AuthData::Dummy))).is_err());
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 6);
+    let body = |i: usize| -> serde_json::Value { serde_json::from_slice(&reqs[i].body).unwrap() };
+    assert!(body(1).get("auth").is_none());
+    assert_eq!(body(2), json!({"username": "new", "auth": {"type": "m.login.registration_token", "token": "tok", "session": "s2"}}));
+    assert_eq!(body(3)["auth"], json!({"type": "m.login.dummy", "session": "s2"}));
+    assert_eq!(body(5)["auth"], json!({
+        "type": "m.login.password",
+        "identifier": {"type": "m.id.user", "user": "@bot:example.org"},
+        "password": "wrong",
+        "session": "s3"
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn message_stream() {
+    use gm::types::events::Event;
+    let ev = |id: &str| format!(r#"{{"type": "m.room.message", "event_id": "{}", "sender": "@u:example.org",
+                                    "origin_server_ts": 1, "content": {{"msgtype": "m.text", "body": "hi"}}}}"#, id);
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &format!(r#"{{"start": "s0", "end": "s1", "chunk": [{}], "state": [
+            {{"type": "m.room.member", "event_id": "$m", "sender": "@u:example.org", "origin_server_ts": 1,
+              "state_key": "@u:example.org", "content": {{"membership": "join"}}}}
+        ]}}"#, ev("$x"))),
+        MockResponse::json(200, &format!(r#"{{"start": "prev", "end": "t1", "chunk": [{}, {}]}}"#, ev("$a"), ev("$b"))),
+        MockResponse::json(200, &format!(r#"{{"start": "t1", "end": "t1", "chunk": [{}]}}"#, ev("$c")))
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let filter = r#"{"types":["m.room.message"]}"#;
+    let rpl = core.run(mx.get_messages("!r:example.org", Direction::Forwards, Some("s0"), Some("s9"), Some(1),
+                                       Some(filter))).unwrap();
+    assert_eq!(rpl.start, "s0");
+    assert_eq!(rpl.end.as_ref().unwrap(), "s1");
+    assert_eq!(rpl.chunk.len(), 1);
+    assert_eq!(rpl.state.len(), 1);
+    let ids: Vec<String> = {
+        let mut ms = mx.messages("!r:example.org", Direction::Backwards);
+        ms.set_from(Some("prev".into()));
+        ms.set_filter(Some(filter.into()));
+        let events = core.run(ms.events().collect()).unwrap();
+        events.into_iter().map(|e| match e {
+            Event::Full(meta, _) => meta.event_id,
+            x => panic!("unexpected event {:?}", x)
+        }).collect()
+    };
+    // The last reply didn't move the token, so there's no fourth request.
+    assert_eq!(ids, vec!["$a", "$b", "$c"]);
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 3);
+    for param in &["dir=f", "from=s0", "to=s9", "limit=1", "filter=%7B%22types%22:[%22m.room.message%22]%7D"] {
+        assert!(reqs[0].path.contains(param), "{} missing from {}", param, reqs[0].path);
+    }
+    assert!(reqs[1].path.contains("dir=b"));
+    assert!(reqs[1].path.contains("from=prev"));
+    assert!(reqs[1].path.contains("filter="));
+    assert!(reqs[2].path.contains("from=t1"));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn event_context() {
+    use gm::types::events::Event;
+    let ev = |id: &str| format!(r#"{{"type": "m.room.message", "event_id": "{}", "sender": "@u:example.org",
+                                    "origin_server_ts": 1, "content": {{"msgtype": "m.text", "body": "hi"}}}}"#, id);
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &format!(r#"{{"start": "before", "end": "after", "events_before": [{}, {}],
+                                              "event": {}, "events_after": [{}], "state": []}}"#,
+                                          ev("$b2"), ev("$b1"), ev("$ab+c/d"), ev("$a1"))),
+        MockResponse::json(404, r#"{"errcode": "M_NOT_FOUND", "error": "Event not found."}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.get_context("!r:example.org", "$ab+c/d", Some(3))).unwrap();
+    assert_eq!(rpl.start.as_ref().unwrap(), "before");
+    assert_eq!(rpl.end.as_ref().unwrap(), "after");
+    assert_eq!(rpl.events_before.len(), 2);
+    assert_eq!(rpl.events_after.len(), 1);
+    match rpl.event {
+        Event::Full(ref meta, _) => assert_eq!(meta.event_id, "$ab+c/d"),
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    let err = core.run(mx.get_context("!r:example.org", "$gone", None)).unwrap_err();
+    assert_eq!(err.errcode(), Some("M_NOT_FOUND"));
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/rooms/!r:example.org/context/$ab%2Bc%2Fd?limit=3");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/context/$gone");
+    keep_logged_in(mx);
+}
+
+#[test]
+fn search() {
+    use gm::search::{RoomEventsCriteria, SearchGroupKey, SearchKey, SearchOrder};
+    let ev = |id: &str| format!(r#"{{"type": "m.room.message", "event_id": "{}", "sender": "@u:example.org",
+                                    "origin_server_ts": 1, "content": {{"msgtype": "m.text", "body": "lunch?"}}}}"#, id);
+    let page1 = format!(r#"{{"search_categories": {{"room_events": {{
+        "count": 3, "highlights": ["lunch"], "next_batch": "n/1",
+        "results": [
+            {{"rank": 0.5, "result": {}, "context": {{
+                "start": "s", "end": "e",
+                "profile_info": {{"@u:example.org": {{"displayname": "U"}}}},
+                "events_before": [{}], "events_after": []
+            }}}},
+            {{"rank": 0.25, "result": {}}}
+        ]
+    }}}}}}"#, ev("$a"), ev("$z"), ev("$b"));
+    let page2 = format!(r#"{{"search_categories": {{"room_events": {{"results": [{{"rank": 0.1, "result": {}}}]}}}}}}"#,
+                        ev("$c"));
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &page1),
+        MockResponse::json(200, &page2)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let criteria = RoomEventsCriteria::new("lunch")
+        .keys(&[SearchKey::ContentBody])
+        .order_by(SearchOrder::Recent)
+        .event_context(1, 0, true)
+        .group_by(SearchGroupKey::RoomId);
+    let results = {
+        let mut ss = mx.search_stream(criteria.clone());
+        let results = core.run(ss.by_ref().collect()).unwrap();
+        assert_eq!(ss.count(), Some(3));
+        results
+    };
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].rank, Some(0.5));
+    let ctx = results[0].context.as_ref().unwrap();
+    assert_eq!(ctx.events_before.len(), 1);
+    assert_eq!(ctx.profile_info["@u:example.org"].displayname, Some("U".into()));
+    assert!(results[1].context.is_none());
+    let reqs = srv.requests();
+    assert_eq!(reqs.len(), 2);
+    assert_eq!(reqs[0].method, "POST");
+    assert_eq!(reqs[0].path, "/_matrix/client/v3/search");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/search?next_batch=n%2F1");
+    assert_eq!(reqs[0].body, reqs[1].body);
+    let body: ::serde_json::Value = ::serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body, json!({
+        "search_categories": {
+            "room_events": {
+                "search_term": "lunch",
+                "keys": ["content.body"],
+                "order_by": "recent",
+                "event_context": {"before_limit": 1, "after_limit": 0, "include_profile": true},
+                "groupings": {"group_by": [{"key": "room_id"}]}
+            }
+        }
+    }));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn search_groupings() {
+    use gm::search::{RoomEventsCriteria, SearchGroupKey};
+    let ev = |id: &str, room: &str, sender: &str| format!(r#"{{"type": "m.room.message", "event_id": "{}", "room_id": "{}",
+                                                            "sender": "{}", "origin_server_ts": 1,
+                                                            "content": {{"msgtype": "m.text", "body": "lunch?"}}}}"#,
+                                                          id, room, sender);
+    let page = format!(r#"{{"search_categories": {{"room_events": {{
+        "results": [{{"rank": 0.5, "result": {}}}, {{"rank": 0.4, "result": {}}}, {{"rank": 0.3, "result": {}}}],
+        "groups": {{
+            "room_id": {{
+                "!a:example.org": {{"order": 1, "results": ["$1", "$3"]}},
+                "!b:example.org": {{"order": 2, "results": ["$2"], "next_batch": "b/2"}}
+            }},
+            "sender": {{
+                "@u:example.org": {{"order": 1, "results": ["$3", "$2", "$1"]}}
+            }}
+        }}
+    }}}}}}"#, ev("$1", "!a:example.org", "@u:example.org"), ev("$2", "!b:example.org", "@u:example.org"),
+       ev("$3", "!a:example.org", "@u:example.org"));
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &page)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let criteria = RoomEventsCriteria::new("lunch")
+        .group_by(SearchGroupKey::RoomId)
+        .group_by(SearchGroupKey::Sender)
+        .group_by(SearchGroupKey::RoomId);
+    let rpl = core.run(mx.search(&criteria, None)).unwrap().search_categories.room_events;
+    let by_room = rpl.groups_by(SearchGroupKey::RoomId).unwrap();
+    assert_eq!(by_room["!b:example.org"].next_batch.as_ref().unwrap(), "b/2");
+    let ids = |key: SearchGroupKey, value: &str| rpl.group_results(key, value).iter()
+        .map(|r| r.result.event_id().unwrap().to_owned())
+        .collect::<Vec<_>>();
+    assert_eq!(ids(SearchGroupKey::RoomId, "!a:example.org"), vec!["$1", "$3"]);
+    assert_eq!(ids(SearchGroupKey::Sender, "@u:example.org"), vec!["$3", "$2", "$1"]);
+    assert!(ids(SearchGroupKey::Sender, "@v:example.org").is_empty());
+    let body: serde_json::Value = serde_json::from_slice(&srv.requests()[0].body).unwrap();
+    assert_eq!(body["search_categories"]["room_events"]["groupings"],
+               json!({"group_by": [{"key": "room_id"}, {"key": "sender"}]}));
+    keep_logged_in(mx);
+}
+
+#[test]
+fn replies() {
+    use gm::types::events::Event;
+    let orig = r#"{"type": "m.room.message", "event_id": "$orig:example.org", "room_id": "!r:example.org",
+                   "sender": "@eve:example.org", "origin_server_ts": 1,
+                   "content": {"msgtype": "m.text", "body": "<script>alert(1)</script>"}}"#;
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, r#"{"event_id": "$reply1:example.org"}"#),
+        MockResponse::json(200, orig),
+        MockResponse::json(200, r#"{"event_id": "$reply2:example.org"}"#)
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let ev: Event = serde_json::from_str(orig).unwrap();
+    let rpl = core.run(mx.reply("!r:example.org", &ev, "no <b>thanks</b>")).unwrap();
+    assert_eq!(rpl.event_id, "$reply1:example.org");
+    let rpl = core.run(mx.reply_to_event_id("!r:example.org", "$orig:example.org", "no <b>thanks</b>")).unwrap();
+    assert_eq!(rpl.event_id, "$reply2:example.org");
+    let reqs = srv.requests();
+    assert!(reqs[0].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/"));
+    assert_eq!(reqs[1].method, "GET");
+    assert_eq!(reqs[1].path, "/_matrix/client/v3/rooms/!r:example.org/event/$orig:example.org");
+    assert!(reqs[2].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/"));
+    assert!(reqs[0].path != reqs[2].path);
+    let body: serde_json::Value = serde_json::from_slice(&reqs[0].body).unwrap();
+    assert_eq!(body["m.relates_to"]["m.in_reply_to"]["event_id"], "$orig:example.org");
+    assert_eq!(body["format"], "org.matrix.custom.html");
+    assert_eq!(body["body"], "> <@eve:example.org> <script>alert(1)</script>\n\nno <b>thanks</b>");
+    let html = body["formatted_body"].as_str().unwrap();
+    assert!(html.ends_with("</mx-reply>no &lt;b&gt;thanks&lt;/b&gt;"));
+    assert!(html.contains("&lt;script&gt;alert(1)&lt;/script&gt;"));
+    assert!(!html.contains("<script>"));
+    let body2: serde_json::Value = serde_json::from_slice(&reqs[2].body).unwrap();
+    assert_eq!(body, body2);
+    keep_logged_in(mx);
+}
+
+#[test]
+fn threads() {
+    use gm::types::messages::Message;
+    let ev = |id: &str| format!(r#"{{"type": "m.room.message", "event_id": "{}", "sender": "@u:example.org",
+                                    "origin_server_ts": 1, "content": {{"msgtype": "m.text", "body": "hi"}}}}"#, id);
+    let srv = MockServer::new(vec![
+        MockResponse::json(200, &format!(r#"{{"chunk": [{}], "next_batch": "r2"}}"#, ev("$latest"))),
+        MockResponse::json(200, r#"{"event_id": "$t1:example.org"}"#),
+        MockResponse::json(200, r#"{"chunk": []}"#),
+        MockResponse::json(200, r#"{"event_id": "$t2:example.org"}"#),
+        MockResponse::json(200, &format!(r#"{{"chunk": [{}, {}], "next_batch": "th2"}}"#, ev("$root"), ev("$root2")))
+    ]);
+    let mut core = Core::new().unwrap();
+    let mut mx = client(&core, &srv);
+    let rpl = core.run(mx.send_thread_message("!r:example.org", "$root", Message::text("one"))).unwrap();
+    assert_eq!(rpl.event_id, "$t1:example.org");
+    let rpl = core.run(mx.send_thread_message("!r:example.org", "$root2", Message::text("two"))).unwrap();
+    assert_eq!(rpl.event_id, "$t2:example.org");
+    let rpl = core.run(mx.thread_roots("!r:example.org", Some("th1"))).unwrap();
+    assert_eq!(rpl.chunk.iter().map(|e| e.event_id().unwrap()).collect::<Vec<_>>(), vec!["$root", "$root2"]);
+    assert_eq!(rpl.next_batch.as_ref().unwrap(), "th2");
+    let reqs = srv.requests();
+    assert_eq!(reqs[0].path, "/_matrix/client/v1/rooms/!r:example.org/relations/$root/m.thread?limit=1");
+    assert!(reqs[1].path.starts_with("/_matrix/client/v3/rooms/!r:example.org/send/m.room.message/"));
+    let body: serde_json::Value = serde_json::from_slice(&reqs[1].body).unwrap();
+    assert_eq!(body, json!({
+        "msgtype": "m.text",
+        "body": "one",
+        "m.relates_to": {
+            "rel_type": "m.thread",
+            "event_id": "$root",
+            "is_falling_back": true,
+            "m.in_reply_to": {"event_id": "$latest"}
+        }
+    }));
+    // An empty thread falls back to replying to the root.
+    let body: serde_json::Value = serde_json::from_slice(&reqs[3].body).unwrap();
+    assert_eq!(body["m.relates_to"]["m.in_reply_to"]["event_id"], "$root2");
+    assert!(reqs[1].path != reqs[3].path);
+    assert_eq!(reqs[4].method, "GET");
+    assert_eq!(reqs[4].path, "/_matrix/client/v1/rooms/!r:example.org/threads?from=th1");
+    keep_logged_in(mx);
+}
@@ -74,3 +74,226 @@ fn deser_sync() {
         println!("test deser_sync: sucessfully parsed sync {}!",v);
     }
 }
+
+#[test]
+fn html_messages() {
+    use matrix_api::types::messages::Message;
+    // From the `m.text` example in the spec.
+    let spec = r#"{
+        "body": "This is an example text message",
+        "format": "org.matrix.custom.html",
+        "formatted_body": "<b>This is an example text message</b>",
+        "msgtype": "m.text"
+    }"#;
+    let spec: ::serde_json::Value = ::serde_json::from_str(spec).unwrap();
+    let msg = Message::html("This is an example text message", "<b>This is an example text message</b>");
+    assert_eq!(::serde_json::to_value(&msg).unwrap(), spec);
+    match ::serde_json::from_value::<Message>(spec).unwrap() {
+        Message::Text { formatted_body: Some(ref f), .. } => assert_eq!(f, "<b>This is an example text message</b>"),
+        ref x => panic!("unexpected message {:?}", x)
+    }
+    let msg = Message::notice_html("*beep*", "<em>beep</em>");
+    let val = ::serde_json::to_value(msg).unwrap();
+    assert_eq!(val["msgtype"], "m.notice");
+    assert_eq!(val["format"], "org.matrix.custom.html");
+    assert_eq!(val["body"], "*beep*");
+    // Unformatted messages don't have `null` format fields.
+    let val = ::serde_json::to_value(Message::text("hi")).unwrap();
+    assert!(val.get("format").is_none() && val.get("formatted_body").is_none());
+}
+
+#[test]
+fn rich_replies() {
+    use matrix_api::types::messages::{Message, RelatesTo};
+    let orig: Event = ::serde_json::from_str(r#"{
+        "type": "m.room.message",
+        "event_id": "$orig:example.org",
+        "room_id": "!r:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 1,
+        "content": {"msgtype": "m.text", "body": "hello\nthere <3"}
+    }"#).unwrap();
+    let val = ::serde_json::to_value(Message::html("hi", "<b>hi</b>").reply_to(&orig).unwrap()).unwrap();
+    assert_eq!(val["m.relates_to"]["m.in_reply_to"]["event_id"], "$orig:example.org");
+    assert_eq!(val["body"], "> <@alice:example.org> hello\n> there <3\n\nhi");
+    assert_eq!(val["formatted_body"],
+               "<mx-reply><blockquote><a href=\"https://matrix.to/#/!r:example.org/$orig:example.org\">In reply to</a> \
+                <a href=\"https://matrix.to/#/@alice:example.org\">@alice:example.org</a><br>hello<br>there &lt;3\
+                </blockquote></mx-reply><b>hi</b>");
+    // Just an event ID: no fallback.
+    let val = ::serde_json::to_value(Message::text("hi").reply_to("$orig:example.org").unwrap()).unwrap();
+    assert_eq!(val["body"], "hi");
+    assert_eq!(val["m.relates_to"]["m.in_reply_to"]["event_id"], "$orig:example.org");
+    // Replies to replies don't quote the original fallback.
+    let reply: Event = ::serde_json::from_value(json_reply()).unwrap();
+    let val = ::serde_json::to_value(Message::text("ok").reply_to(&reply).unwrap()).unwrap();
+    assert_eq!(val["body"], "> <@bob:example.org> hi\n\nok");
+    // Events without an ID (like account data) can't be replied to.
+    let tag: Event = ::serde_json::from_str(&read_file("tests/event-examples/m.tag")).unwrap();
+    assert!(Message::text("hi").reply_to(&tag).is_none());
+    let val = ::serde_json::to_value(Message::text("* hi").with_relation(RelatesTo::replace("$orig:example.org"))).unwrap();
+    assert_eq!(val["m.relates_to"], ::serde_json::json!({"rel_type": "m.replace", "event_id": "$orig:example.org"}));
+    let rel = ::serde_json::to_value(RelatesTo::annotation("$orig:example.org", "👍")).unwrap();
+    assert_eq!(rel["key"], "👍");
+}
+fn json_reply() -> ::serde_json::Value {
+    ::serde_json::json!({
+        "type": "m.room.message",
+        "event_id": "$reply:example.org",
+        "sender": "@bob:example.org",
+        "origin_server_ts": 2,
+        "content": {
+            "msgtype": "m.text",
+            "body": "> <@alice:example.org> hello\n\nhi",
+            "m.relates_to": {"m.in_reply_to": {"event_id": "$orig:example.org"}}
+        }
+    })
+}
+
+#[test]
+fn incoming_edits() {
+    use matrix_api::types::content::Content;
+    let ev: Event = ::serde_json::from_value(::serde_json::json!({
+        "type": "m.room.message",
+        "event_id": "$edit:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 3,
+        "content": {
+            "msgtype": "m.text",
+            "body": "* fixed",
+            "m.new_content": {"msgtype": "m.text", "body": "fixed"},
+            "m.relates_to": {"rel_type": "m.replace", "event_id": "$orig:example.org"}
+        }
+    })).unwrap();
+    match ev {
+        Event::Full(_, Content::RoomMessage(ref m)) => {
+            assert_eq!(m.replaces(), Some("$orig:example.org"));
+            assert_eq!(m.new_content().unwrap().body(), "fixed");
+        },
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    let ev: Event = ::serde_json::from_value(::serde_json::json!({
+        "type": "m.reaction",
+        "event_id": "$reaction:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 4,
+        "content": {"m.relates_to": {"rel_type": "m.annotation", "event_id": "$orig:example.org", "key": "👍"}}
+    })).unwrap();
+    match ev {
+        Event::Full(_, Content::RootReaction(ref r)) => assert_eq!(r.relates_to.key.as_ref().unwrap(), "👍"),
+        ref x => panic!("unexpected event {:?}", x)
+    }
+}
+
+#[test]
+fn media_messages() {
+    use matrix_api::types::content::Content;
+    use matrix_api::types::media::ContentUri;
+    use matrix_api::types::messages::{FileInfo, Message};
+    let ev: Event = ::serde_json::from_str(&read_file("tests/event-examples/m.room.message#m.image")).unwrap();
+    match ev {
+        Event::Full(_, Content::RoomMessage(ref m)) => {
+            let uri: ContentUri = m.content_url().unwrap().parse().unwrap();
+            assert_eq!(uri.media_id, "JWEIFJgwEIhweiWJE");
+            match *m {
+                Message::Image { info: Some(ref info), .. } => {
+                    assert_eq!((info.w, info.h), (Some(394), Some(398)));
+                    assert_eq!(info.mimetype.as_ref().unwrap(), "image/jpeg");
+                },
+                ref x => panic!("unexpected message {:?}", x)
+            }
+        },
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    // Clients often leave most of `info` out.
+    let ev: Event = ::serde_json::from_value(::serde_json::json!({
+        "type": "m.room.message",
+        "event_id": "$file:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 5,
+        "content": {"msgtype": "m.file", "body": "a.txt", "url": "mxc://example.org/a", "info": {"size": 12}}
+    })).unwrap();
+    match ev {
+        Event::Full(_, Content::RoomMessage(Message::File { ref filename, info: Some(ref info), .. })) => {
+            assert!(filename.is_empty());
+            assert_eq!(info.size, Some(12));
+            assert!(info.mimetype.is_none());
+        },
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    // Files can be bigger than 4 GiB.
+    let info: FileInfo = ::serde_json::from_str(r#"{"size": 5000000000}"#).unwrap();
+    assert_eq!(info.size, Some(5_000_000_000));
+}
+
+#[test]
+fn account_data_events() {
+    use matrix_api::types::content::Content;
+    let ev: Event = ::serde_json::from_str(&read_file("tests/event-examples/m.ignored_user_list")).unwrap();
+    match ev {
+        Event::Minimal(_, Content::RootIgnoredUserList(ref l)) => assert!(l.is_ignored("@someone:example.org")),
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    let ev: Event = ::serde_json::from_str(&read_file("tests/event-examples/m.direct")).unwrap();
+    match ev {
+        Event::Minimal(_, Content::RootDirect(ref d)) => {
+            assert_eq!(d.rooms_for("@bob:example.com"), &["!abcdefgh:example.com", "!hgfedcba:example.com"]);
+            assert!(d.rooms_for("@carol:example.com").is_empty());
+        },
+        ref x => panic!("unexpected event {:?}", x)
+    }
+    let ev: Event = ::serde_json::from_str(&read_file("tests/event-examples/m.tag")).unwrap();
+    match ev {
+        Event::Minimal(_, Content::RootTag(ref t)) => assert_eq!(t.order("work"), Some(1.0)),
+        ref x => panic!("unexpected event {:?}", x)
+    }
+}
+
+#[test]
+fn html_edits() {
+    use matrix_api::types::messages::{Message, RelatesTo};
+    let val = ::serde_json::to_value(Message::html("fixed", "<b>fixed</b>").edit_of("$orig:example.org")).unwrap();
+    assert_eq!(val, ::serde_json::json!({
+        "msgtype": "m.text",
+        "body": "* fixed",
+        "format": "org.matrix.custom.html",
+        "formatted_body": "* <b>fixed</b>",
+        "m.new_content": {
+            "msgtype": "m.text",
+            "body": "fixed",
+            "format": "org.matrix.custom.html",
+            "formatted_body": "<b>fixed</b>"
+        },
+        "m.relates_to": {"rel_type": "m.replace", "event_id": "$orig:example.org"}
+    }));
+    // Any relation on the new content (like a reply) is dropped, as the edit
+    // can't change it.
+    let msg = Message::notice("beep").with_relation(RelatesTo::reply("$other:example.org"))
+        .edit_of("$orig:example.org");
+    assert_eq!(msg.replaces(), Some("$orig:example.org"));
+    assert!(msg.new_content().unwrap().relates_to().is_none());
+    assert_eq!(msg.body(), "* beep");
+}
+
+#[test]
+fn redacted_events() {
+    let ev: Event = ::serde_json::from_value(::serde_json::json!({
+        "type": "m.room.message",
+        "event_id": "$ev:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 1,
+        "content": {},
+        "unsigned": {"redacted_by": "$redaction:example.org"}
+    })).unwrap();
+    assert!(ev.is_redacted());
+    assert_eq!(ev.redacted_by(), Some("$redaction:example.org"));
+    let ev: Event = ::serde_json::from_value(::serde_json::json!({
+        "type": "m.room.message",
+        "event_id": "$ev:example.org",
+        "sender": "@alice:example.org",
+        "origin_server_ts": 1,
+        "content": {},
+        "unsigned": {"age": 5, "redacted_because": {"type": "m.room.redaction", "event_id": "$r2:example.org"}}
+    })).unwrap();
+    assert_eq!(ev.redacted_by(), Some("$r2:example.org"));
+}
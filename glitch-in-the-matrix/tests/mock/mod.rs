@@ -0,0 +1,186 @@
+//! A tiny fake homeserver, for testing requests end-to-end.
+//!
+//! It answers each incoming connection with the next canned response (closing
+//! the connection afterwards, unless made with `keep_alive()`), and records
+//! the requests it was sent.
+#![allow(dead_code)]
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// A request received by a `MockServer`.
+#[derive(Clone, Debug)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>
+}
+impl MockRequest {
+    /// Get the value of a header (case-insensitively).
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v as &str)
+    }
+    pub fn body_str(&self) -> &str {
+        ::std::str::from_utf8(&self.body).unwrap()
+    }
+}
+/// A response for a `MockServer` to send.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// How long to wait before sending the response.
+    pub delay: Option<Duration>
+}
+impl MockResponse {
+    pub fn new(status: u16, body: &[u8]) -> Self {
+        MockResponse {
+            status,
+            headers: vec![],
+            body: body.to_vec(),
+            delay: None
+        }
+    }
+    pub fn json(status: u16, body: &str) -> Self {
+        Self::new(status, body.as_bytes())
+            .with_header("Content-Type", "application/json")
+    }
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+    pub fn with_header(mut self, k: &str, v: &str) -> Self {
+        self.headers.push((k.into(), v.into()));
+        self
+    }
+}
+fn read_chunked<R: BufRead>(rdr: &mut R) -> Vec<u8> {
+    let mut body = vec![];
+    loop {
+        let mut line = String::new();
+        rdr.read_line(&mut line).unwrap();
+        let len = usize::from_str_radix(line.trim(), 16).unwrap();
+        let mut chunk = vec![0; len + 2];
+        rdr.read_exact(&mut chunk).unwrap();
+        if len == 0 {
+            return body;
+        }
+        body.extend_from_slice(&chunk[..len]);
+    }
+}
+fn accept(listener: &TcpListener, connections: &Mutex<usize>) -> Option<BufReader<TcpStream>> {
+    let (stream, _) = listener.accept().ok()?;
+    *connections.lock().unwrap() += 1;
+    Some(BufReader::new(stream))
+}
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+    connections: Arc<Mutex<usize>>
+}
+impl MockServer {
+    /// Start a server that will send each of `responses` in turn.
+    pub fn new(responses: Vec<MockResponse>) -> Self {
+        Self::start(responses, false)
+    }
+    /// Like `new()`, but keeps each connection open after sending a
+    /// response, and reads the next request from it.
+    pub fn keep_alive(responses: Vec<MockResponse>) -> Self {
+        Self::start(responses, true)
+    }
+    fn start(responses: Vec<MockResponse>, keep_alive: bool) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let reqs = requests.clone();
+        let connections = Arc::new(Mutex::new(0));
+        let conns = connections.clone();
+        thread::spawn(move || {
+            let mut open = None;
+            'responses: for resp in responses {
+                let (mut rdr, mut reused) = match open.take() {
+                    Some(r) => (r, true),
+                    None => match accept(&listener, &conns) {
+                        Some(r) => (r, false),
+                        None => return
+                    }
+                };
+                let mut line = String::new();
+                while rdr.read_line(&mut line).unwrap_or(0) == 0 {
+                    if !reused {
+                        continue 'responses;
+                    }
+                    // The client closed the connection we kept open.
+                    rdr = match accept(&listener, &conns) {
+                        Some(r) => r,
+                        None => return
+                    };
+                    reused = false;
+                }
+                let mut parts = line.split_whitespace();
+                let method = parts.next().unwrap_or("").to_string();
+                let path = parts.next().unwrap_or("").to_string();
+                let mut headers = vec![];
+                let mut len = 0;
+                let mut chunked = false;
+                loop {
+                    let mut line = String::new();
+                    rdr.read_line(&mut line).unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    let idx = line.find(':').unwrap();
+                    let (k, v) = (line[..idx].trim().to_string(), line[idx+1..].trim().to_string());
+                    if k.eq_ignore_ascii_case("content-length") {
+                        len = v.parse().unwrap();
+                    }
+                    if k.eq_ignore_ascii_case("transfer-encoding") && v == "chunked" {
+                        chunked = true;
+                    }
+                    headers.push((k, v));
+                }
+                let body = if chunked {
+                    read_chunked(&mut rdr)
+                }
+                else {
+                    let mut body = vec![0; len];
+                    rdr.read_exact(&mut body).unwrap();
+                    body
+                };
+                reqs.lock().unwrap().push(MockRequest { method, path, headers, body });
+                if let Some(d) = resp.delay {
+                    thread::sleep(d);
+                }
+                let mut stream = rdr.into_inner();
+                let mut out = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: {}\r\n",
+                                      resp.status, resp.body.len(), if keep_alive { "keep-alive" } else { "close" });
+                for (k, v) in resp.headers {
+                    out += &format!("{}: {}\r\n", k, v);
+                }
+                out += "\r\n";
+                let _ = stream.write_all(out.as_bytes());
+                let _ = stream.write_all(&resp.body);
+                if keep_alive {
+                    open = Some(BufReader::new(stream));
+                }
+            }
+        });
+        MockServer { url, requests, connections }
+    }
+    /// Get the requests received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+    /// Get the number of connections accepted so far.
+    pub fn connections(&self) -> usize {
+        *self.connections.lock().unwrap()
+    }
+}
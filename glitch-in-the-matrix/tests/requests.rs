@@ -0,0 +1,176 @@
+extern crate glitch_in_the_matrix as gm;
+extern crate hyper;
+extern crate tokio_core;
+
+use gm::MatrixClient;
+use gm::request::MatrixRequest;
+use gm::request::apis;
+use gm::types::media::ContentUri;
+use hyper::Method::*;
+use hyper::header::{Authorization, Bearer, ContentType};
+use tokio_core::reactor::Core;
+
+const TOKEN: &str = "s3cr3t_t0ken";
+
+fn client(core: &Core) -> MatrixClient {
+    MatrixClient::new_from_access_token(TOKEN, "@bot:example.org", "https://example.org", &core.handle())
+        .unwrap()
+}
+
+#[test]
+fn token_in_header() {
+    let core = Core::new().unwrap();
+    let mx = client(&core);
+    let req = MatrixRequest::new_basic(Get, "/sync").make_hyper(&mx).unwrap();
+    assert_eq!(req.uri().as_ref(), "https://example.org/_matrix/client/v3/sync");
+    assert!(!req.uri().as_ref().contains(TOKEN));
+    let auth = req.headers().get::<Authorization<Bearer>>().unwrap();
+    assert_eq!(auth.0.token, TOKEN);
+}
+
+#[test]
+fn token_in_query() {
+    let core = Core::new().unwrap();
+    let mut mx = client(&core);
+    mx.set_token_in_query(true);
+    let req = MatrixRequest::new_basic(Get, "/sync").make_hyper(&mx).unwrap();
+    assert_eq!(req.uri().query(), Some("access_token=s3cr3t_t0ken"));
+    assert!(req.headers().get::<Authorization<Bearer>>().is_none());
+}
+
+#[test]
+fn token_not_mixed_into_params() {
+    let core = Core::new().unwrap();
+    let mx = client(&core);
+    let mut req = MatrixRequest::new_basic(Get, "/sync");
+    req.params.insert("timeout".into(), "30000".into());
+    let req = req.make_hyper(&mx).unwrap();
+    assert_eq!(req.uri().query(), Some("timeout=30000"));
+}
+
+#[test]
+fn api_prefixes() {
+    let core = Core::new().unwrap();
+    let mut mx = client(&core);
+    let hierarchy = MatrixRequest::new(Get, "/rooms/!r:example.org/hierarchy", (), apis::v1::ClientApi)
+        .make_hyper(&mx).unwrap();
+    assert_eq!(hierarchy.uri().path(), "/_matrix/client/v1/rooms/!r:example.org/hierarchy");
+    let sync = MatrixRequest::new_basic(Get, "/sync").make_hyper(&mx).unwrap();
+    assert_eq!(sync.uri().path(), "/_matrix/client/v3/sync");
+    mx.set_api_prefix("/_matrix/client/r0");
+    let sync = MatrixRequest::new_basic(Get, "/sync").make_hyper(&mx).unwrap();
+    assert_eq!(sync.uri().path(), "/_matrix/client/r0/sync");
+}
+
+#[test]
+fn token_percent_encoded() {
+    let core = Core::new().unwrap();
+    let mut mx = MatrixClient::new_from_access_token("abc+d/ef=", "@bot:example.org", "https://example.org", &core.handle())
+        .unwrap();
+    mx.set_token_in_query(true);
+    let req = MatrixRequest::new_basic(Get, "/sync").make_hyper(&mx).unwrap();
+    assert_eq!(req.uri().query(), Some("access_token=abc%2Bd%2Fef%3D"));
+}
+
+#[test]
+fn path_segments_encoded() {
+    let core = Core::new().unwrap();
+    let mx = client(&core);
+    let ep = MatrixRequest::endpoint_with_segments(&["rooms", "!abc:example.org", "state", "m.room.name", ""]);
+    assert_eq!(ep, "/rooms/!abc:example.org/state/m.room.name/");
+    let req = MatrixRequest::new_basic(Get, ep).make_hyper(&mx).unwrap();
+    assert_eq!(req.uri().path(), "/_matrix/client/v3/rooms/!abc:example.org/state/m.room.name/");
+    let ep = MatrixRequest::endpoint_with_segments(&["directory", "room", "#foo/bar?:example.org"]);
+    assert_eq!(ep, "/directory/room/%23foo%2Fbar%3F:example.org");
+}
+
+#[test]
+fn endpoint_templates() {
+    let t = MatrixRequest::endpoint_template;
+    assert_eq!(t("/_matrix/client/v3/rooms/!abc:example.org/send/m.room.message/gm1"),
+               "/_matrix/client/v3/rooms/{roomId}/send/m.room.message/{txnId}");
+    assert_eq!(t("/_matrix/client/v3/rooms/!abc:example.org/state/m.room.member/@u:example.org"),
+               "/_matrix/client/v3/rooms/{roomId}/state/m.room.member/{stateKey}");
+    assert_eq!(t("/_matrix/client/v3/rooms/!abc:example.org/state/m.room.name/"),
+               "/_matrix/client/v3/rooms/{roomId}/state/m.room.name/{stateKey}");
+    assert_eq!(t("/_matrix/client/v3/sendToDevice/m.room.encrypted/gm3"),
+               "/_matrix/client/v3/sendToDevice/m.room.encrypted/{txnId}");
+    assert_eq!(t("/_matrix/client/v3/rooms/!abc:example.org/redact/$ev:example.org/gm2"),
+               "/_matrix/client/v3/rooms/{roomId}/redact/{eventId}/{txnId}");
+    assert_eq!(t("/_matrix/client/v3/directory/room/%23foo:example.org"),
+               "/_matrix/client/v3/directory/room/{roomAlias}");
+    assert_eq!(t("/_matrix/client/v3/profile/%40u:example.org/displayname"),
+               "/_matrix/client/v3/profile/{userId}/displayname");
+    assert_eq!(t("/_matrix/client/v3/devices/ABCDEF"), "/_matrix/client/v3/devices/{deviceId}");
+    assert_eq!(t("/_matrix/client/v3/sync"), "/_matrix/client/v3/sync");
+}
+
+#[test]
+fn json_content_type() {
+    let core = Core::new().unwrap();
+    let mx = client(&core);
+    let req = MatrixRequest::new_with_body(Post, "/rooms/!r:example.org/invite", vec![("user_id", "@u:example.org")])
+        .make_hyper(&mx).unwrap();
+    let ct = req.headers().get::<ContentType>().unwrap();
+    assert_eq!(ct.to_string(), "application/json; charset=utf-8");
+    assert!(req.body_ref().is_some());
+    let req = MatrixRequest::new_basic(Post, "/rooms/!r:example.org/leave").make_hyper(&mx).unwrap();
+    assert!(req.headers().get::<ContentType>().is_none());
+    assert!(req.body_ref().is_none());
+}
+
+#[test]
+fn builder() {
+    let core = Core::new().unwrap();
+    let mx = client(&core);
+    let req = MatrixRequest::get("/sync")
+        .param("since", "s72594_4483_1934")
+        .param("timeout", 30000)
+        .param("full_state", false)
+        .raw_header("X-Forwarded-For", "203.0.113.7")
+        .make_hyper(&mx).unwrap();
+    assert_eq!(req.method(), &Get);
+    let mut query: Vec<_> = req.uri().query().unwrap().split('&').collect();
+    query.sort();
+    assert_eq!(query, vec!["full_state=false", "since=s72594_4483_1934", "timeout=30000"]);
+    assert_eq!(req.headers().get_raw("X-Forwarded-For").unwrap(), "203.0.113.7");
+    assert!(req.headers().get::<Authorization<Bearer>>().is_some());
+    let req = MatrixRequest::put("/profile/@bot:example.org/displayname")
+        .with_body(vec![("displayname", "Bot")].into_iter().collect::<::std::collections::HashMap<_, _>>())
+        .header(ContentType::plaintext())
+        .make_hyper(&mx).unwrap();
+    assert_eq!(req.method(), &Put);
+    assert_eq!(req.headers().get::<ContentType>().unwrap(), &ContentType::plaintext());
+    let req = MatrixRequest::post("/upload").with_api(apis::v3::MediaApi).make_hyper(&mx).unwrap();
+    assert_eq!(req.uri().path(), "/_matrix/media/v3/upload");
+}
+
+#[test]
+fn runtime_api_prefixes() {
+    use apis::ApiPrefix;
+    let core = Core::new().unwrap();
+    let mx = client(&core);
+    let path = |typ: ApiPrefix| {
+        MatrixRequest::post("/upload").with_api(typ).make_hyper(&mx).unwrap().uri().path().to_string()
+    };
+    assert_eq!(path(ApiPrefix::MediaR0), "/_matrix/media/r0/upload");
+    assert_eq!(path(ApiPrefix::MediaV3), "/_matrix/media/v3/upload");
+    assert_eq!(path(ApiPrefix::ClientR0), "/_matrix/client/r0/upload");
+    assert_eq!(path(ApiPrefix::ClientV3), "/_matrix/client/v3/upload");
+    assert_eq!(path(ApiPrefix::ClientUnstable("".into())), "/_matrix/client/unstable/upload");
+    assert_eq!(path(ApiPrefix::ClientUnstable("org.matrix.msc2432".into())),
+               "/_matrix/client/unstable/org.matrix.msc2432/upload");
+    assert_eq!(path(ApiPrefix::Custom("/_synapse/admin/v1".into())), "/_synapse/admin/v1/upload");
+}
+
+#[test]
+fn content_uris() {
+    let uri: ContentUri = "mxc://example.org/AQwafuaFswefuhsfAFAgsw".parse().unwrap();
+    assert_eq!(uri.server_name, "example.org");
+    assert_eq!(uri.media_id, "AQwafuaFswefuhsfAFAgsw");
+    assert_eq!(uri.to_string(), "mxc://example.org/AQwafuaFswefuhsfAFAgsw");
+    for bad in &["https://example.org/abc", "mxc://example.org", "mxc://example.org/", "mxc:///abc",
+                 "mxc://example.org/a/b", "mxc://example.org/a?b"] {
+        assert!(bad.parse::<ContentUri>().is_err(), "{} parsed", bad);
+    }
+}
@@ -0,0 +1,130 @@
+//! Types for managing user accounts.
+
+/// The kind of account to register.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum RegistrationKind {
+    /// An ordinary user account.
+    #[default]
+    User,
+    /// A guest account, which can only do a few things (and doesn't need a
+    /// username or password).
+    Guest
+}
+impl RegistrationKind {
+    /// Get the value of the `kind` parameter for this kind, like `user`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RegistrationKind::User => "user",
+            RegistrationKind::Guest => "guest"
+        }
+    }
+}
+/// The options for registering an account with
+/// `MatrixClient::register_account()`.
+///
+/// Fields that are `None` are left out of the request, so the homeserver
+/// uses its defaults (like making up a username).
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RegisterParams {
+    /// The localpart of the new user's MXID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// The new user's password.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// The ID of the device to log in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+    /// A display name for the device logged in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub initial_device_display_name: Option<String>,
+    /// Whether to only register the account, without logging in.
+    #[serde(skip_serializing_if = "::is_false")]
+    pub inhibit_login: bool,
+    /// The kind of account to register.
+    ///
+    /// This goes in the query string, not the body.
+    #[serde(skip)]
+    pub kind: RegistrationKind
+}
+impl RegisterParams {
+    /// Register a user account with the given username and password.
+    pub fn new<T: Into<String>, U: Into<String>>(username: T, password: U) -> Self {
+        RegisterParams {
+            username: Some(username.into()),
+            password: Some(password.into()),
+            ..Default::default()
+        }
+    }
+    /// Register a guest account.
+    pub fn guest() -> Self {
+        RegisterParams {
+            kind: RegistrationKind::Guest,
+            ..Default::default()
+        }
+    }
+    /// Set the ID of the device to log in.
+    pub fn device_id<T: Into<String>>(mut self, device_id: T) -> Self {
+        self.device_id = Some(device_id.into());
+        self
+    }
+    /// Set the display name of the device logged in.
+    pub fn initial_device_display_name<T: Into<String>>(mut self, name: T) -> Self {
+        self.initial_device_display_name = Some(name.into());
+        self
+    }
+    /// Set whether to only register the account, without logging in.
+    pub fn inhibit_login(mut self, inhibit_login: bool) -> Self {
+        self.inhibit_login = inhibit_login;
+        self
+    }
+}
+/// A third-party identifier (like an email address) bound to an account.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ThreePid {
+    /// The kind of identifier, like `email` or `msisdn`.
+    pub medium: String,
+    /// The identifier itself, like an email address.
+    pub address: String,
+    /// When the identifier was validated, in milliseconds since the Unix
+    /// epoch.
+    #[serde(default)]
+    pub validated_at: u64,
+    /// When the identifier was added to the account, in milliseconds since
+    /// the Unix epoch.
+    #[serde(default)]
+    pub added_at: u64
+}
+/// The reply obtained from `/account/3pid`.
+#[derive(Deserialize, Debug)]
+pub struct ThreePidsReply {
+    /// The identifiers bound to the account.
+    #[serde(default)]
+    pub threepids: Vec<ThreePid>
+}
+/// The reply obtained from `/account/3pid/email/requestToken`.
+#[derive(Deserialize, Debug)]
+pub struct RequestTokenReply {
+    /// The ID of the validation session.
+    pub sid: String,
+    /// Where to send the token the user was sent, if anywhere.
+    #[serde(default)]
+    pub submit_url: Option<String>
+}
+/// A validation session for adding a third-party identifier to an account,
+/// from `MatrixClient::request_email_token()`.
+///
+/// The client secret and session ID always go together, so they're kept
+/// together here; give the whole thing to `MatrixClient::add_3pid()` once
+/// the user has followed the link they were sent.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ThreePidSession {
+    /// The secret made up by the client for this session.
+    pub client_secret: String,
+    /// The ID of the session, made up by the homeserver.
+    pub sid: String,
+    /// Where to send the token the user was sent, if the homeserver wants
+    /// it sent somewhere (instead of the user following a link).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submit_url: Option<String>
+}
@@ -26,6 +26,7 @@ pub struct PublicKey {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(untagged)]
 /// The content of an event.
+#[allow(clippy::large_enum_variant)]
 pub enum Content {
     RoomAliases(room::Aliases),
     RoomAvatar(room::Avatar),
@@ -41,10 +42,12 @@ pub enum Content {
     RoomTopic(room::Topic),
     RoomMessage(room::Message),
     RootDirect(root::Direct),
+    RootIgnoredUserList(root::IgnoredUserList),
     RootPresence(root::Presence),
     RootReceipt(root::Receipt),
     RootTag(root::Tag),
     RootTyping(root::Typing),
+    RootReaction(root::Reaction),
     CallInvite(call::Invite),
     CallCandidates(call::Candidates),
     CallAnswer(call::Answer),
@@ -87,10 +90,12 @@ pub fn deserialize_content(typ: &str, val: Value) -> Result<Content, SerdeError>
         "m.room.topic", RoomTopic,
         "m.room.message", RoomMessage,
         "m.direct", RootDirect,
+        "m.ignored_user_list", RootIgnoredUserList,
         "m.presence", RootPresence,
         "m.receipt", RootReceipt,
         "m.tag", RootTag,
         "m.typing", RootTyping,
+        "m.reaction", RootReaction,
         "m.call.invite", CallInvite,
         "m.call.candidates", CallCandidates,
         "m.call.answer", CallAnswer,
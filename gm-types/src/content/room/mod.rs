@@ -155,6 +155,34 @@ pub struct PowerLevels {
     /// is mentioned in the users key. Defaults to 0 if unspecified.
     #[serde(default = "zero")]
     pub users_default: u32,
+    /// The power levels required to trigger certain kinds of notifications.
+    #[serde(default)]
+    pub notifications: NotificationPowerLevels,
+    /// Any other keys in the event that aren't listed above.
+    ///
+    /// These are kept around so that the event can be modified and sent back
+    /// without losing anything.
+    #[serde(flatten)]
+    pub extra: HashMap<String, ::serde_json::Value>,
+}
+/// The `notifications` part of `m.room.power_levels`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NotificationPowerLevels {
+    /// The level required to trigger an `@room` notification. Defaults to 50
+    /// if unspecified.
+    #[serde(default = "fifty")]
+    pub room: u32,
+    /// Any other keys that aren't listed above.
+    #[serde(flatten)]
+    pub extra: HashMap<String, ::serde_json::Value>,
+}
+impl Default for NotificationPowerLevels {
+    fn default() -> Self {
+        Self {
+            room: 50,
+            extra: HashMap::new()
+        }
+    }
 }
 /// `m.room.redaction`
 ///
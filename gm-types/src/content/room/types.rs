@@ -28,6 +28,17 @@ pub enum Membership {
     // reserved word
     // Knock,
 }
+impl Membership {
+    /// Get the string used for this membership in the API, like `join`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Membership::Invite => "invite",
+            Membership::Join => "join",
+            Membership::Leave => "leave",
+            Membership::Ban => "ban"
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all="snake_case")]
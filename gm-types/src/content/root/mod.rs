@@ -1,6 +1,7 @@
 //! Content types for `m.*` events.
 
 use std::collections::HashMap;
+use messages::RelatesTo;
 pub mod types;
 
 /// `m.typing`
@@ -35,22 +36,90 @@ pub struct Presence {
     /// Whether the user is currently active.
     #[serde(default)]
     pub currently_active: bool,
+    /// The user's status message, if they've set one.
+    #[serde(default)]
+    pub status_msg: Option<String>,
     /// The user's ID.
+    ///
+    /// Only older homeservers send this; newer ones leave it out (so it's
+    /// empty), and the user is the `sender` of the event instead.
+    #[serde(default)]
     pub user_id: String,
 }
 /// `m.tag`
 ///
-/// Informs the client of tags on a room.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// Informs the client of tags on a room. This is also what
+/// `/user/{userId}/rooms/{roomId}/tags` returns.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Tag {
-    /// The tags on the room and their contents.
+    /// The tags on the room and their contents, by name (like `m.favourite`,
+    /// or `u.` followed by a user-defined name).
+    #[serde(default)]
     pub tags: HashMap<String, types::RoomTag>
 }
+impl Tag {
+    /// Ascertain whether the room has a tag.
+    pub fn has(&self, tag: &str) -> bool {
+        self.tags.contains_key(tag)
+    }
+    /// Get the order of the room among the rooms with a tag, if it has that
+    /// tag and an order for it.
+    pub fn order(&self, tag: &str) -> Option<f64> {
+        self.tags.get(tag).and_then(|t| t.order)
+    }
+}
+/// Another name for `Tag`, the tags on a room.
+pub type Tags = Tag;
 /// `m.direct`
 ///
 /// A map of which rooms are considered 'direct' rooms for specific users is
 /// kept in account_data in an event of type m.direct. The content of this event
 /// is an object where the keys are the user IDs and values are lists of room ID
 /// strings of the 'direct' rooms for that user ID.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Direct(pub HashMap<String, Vec<String>>);
+impl Direct {
+    /// Get the IDs of the direct rooms with a user.
+    pub fn rooms_for(&self, user_id: &str) -> &[String] {
+        self.0.get(user_id).map(|x| x as &[String]).unwrap_or(&[])
+    }
+    /// Mark a room as a direct room with a user, if it isn't already.
+    pub fn add_room(&mut self, user_id: &str, room_id: &str) {
+        let rooms = self.0.entry(user_id.to_owned()).or_default();
+        if !rooms.iter().any(|r| r == room_id) {
+            rooms.push(room_id.to_owned());
+        }
+    }
+    /// Stop considering a room a direct room (with anyone).
+    pub fn remove_room(&mut self, room_id: &str) {
+        for rooms in self.0.values_mut() {
+            rooms.retain(|r| r != room_id);
+        }
+        self.0.retain(|_, rooms| !rooms.is_empty());
+    }
+}
+/// `m.ignored_user_list`
+///
+/// The users that this user is ignoring, kept in account_data. The homeserver
+/// doesn't send the client any events (or invites) from ignored users.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IgnoredUserList {
+    /// The ignored users, by user ID.
+    pub ignored_users: HashMap<String, types::IgnoredUser>
+}
+impl IgnoredUserList {
+    /// Ascertain whether a user is being ignored.
+    pub fn is_ignored(&self, user_id: &str) -> bool {
+        self.ignored_users.contains_key(user_id)
+    }
+}
+/// `m.reaction`
+///
+/// A reaction to another event, like an emoji (as an `m.annotation`
+/// relation).
 #[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct Direct(HashMap<String, Vec<String>>);
+pub struct Reaction {
+    /// The event being reacted to, and the reaction itself (in `key`).
+    #[serde(rename = "m.relates_to")]
+    pub relates_to: RelatesTo
+}
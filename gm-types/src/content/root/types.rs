@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use serde::{Deserialize, Deserializer};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Receipt {
@@ -12,7 +13,7 @@ pub struct Receipts {
     pub read: HashMap<String,Receipt>
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
 #[serde(rename_all="snake_case")]
 /// Information about whether people are online or not.
 pub enum Presence {
@@ -20,10 +21,46 @@ pub enum Presence {
     Offline,
     Unavailable
 }
+impl Presence {
+    /// Get the string used for this presence in the API, like `online`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Presence::Online => "online",
+            Presence::Offline => "offline",
+            Presence::Unavailable => "unavailable"
+        }
+    }
+}
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+/// An entry in an `m.ignored_user_list`, which has nothing in it (yet).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IgnoredUser {}
+
+/// A tag on a room, in `m.tag`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct RoomTag {
-    // can be a number or a string
-    pub order: Option<::serde_json::Value>,
+    /// Where the room goes among the other rooms with this tag, from 0 to 1.
+    #[serde(default, deserialize_with = "deserialize_order", skip_serializing_if = "Option::is_none")]
+    pub order: Option<f64>,
+    /// Any other keys that aren't listed above.
+    #[serde(flatten)]
+    pub extra: HashMap<String, ::serde_json::Value>,
+}
+impl RoomTag {
+    /// Make a tag with the given order, if any.
+    pub fn new(order: Option<f64>) -> Self {
+        RoomTag {
+            order,
+            extra: HashMap::new()
+        }
+    }
+}
+/// The order of a tag can be a number or (from some older clients) a string.
+fn deserialize_order<'de, D>(d: D) -> Result<Option<f64>, D::Error> where D: Deserializer<'de> {
+    Ok(match Option::<::serde_json::Value>::deserialize(d)? {
+        Some(::serde_json::Value::Number(n)) => n.as_f64(),
+        Some(::serde_json::Value::String(s)) => s.parse().ok(),
+        _ => None
+    })
 }
 
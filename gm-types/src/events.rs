@@ -11,11 +11,17 @@ use room::Room;
 /// The `unsigned` field in many event types.
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct UnsignedData {
+    #[serde(default)]
     pub age: u64,
     pub prev_content: Option<Content>,
     pub prev_sender: Option<String>,
     pub txn_id: Option<String>,
+    /// The redaction event that redacted this event, if it has been
+    /// redacted.
     pub redacted_because: Option<::serde_json::Value>,
+    /// The ID of the redaction event that redacted this event (sent by some
+    /// older homeservers, instead of or as well as `redacted_because`).
+    pub redacted_by: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -74,6 +80,7 @@ enum EventMetadata {
 }
 /// A Matrix event.
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
     /// A full event.
     Full(MetaFull, Content),
@@ -100,7 +107,8 @@ fn parse_event_content(v: &Value) -> Result<Content, SerdeError> {
 impl<'de> Deserialize<'de> for Event {
     fn deserialize<D>(d: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
         let v = Value::deserialize(d)?;
-        let meta: EventMetadata = if v.pointer("/unsigned/redacted_because").is_some() {
+        let meta: EventMetadata = if v.pointer("/unsigned/redacted_because").is_some() ||
+            v.pointer("/unsigned/redacted_by").is_some() {
             EventMetadata::Redacted(::serde_json::from_value(v.clone())
                                     .map_err(|e| de::Error::custom(e.to_string()))?)
         }
@@ -126,6 +134,36 @@ impl<'de> Deserialize<'de> for Event {
 
     }
 }
+impl Event {
+    /// Ascertain whether this event has been redacted.
+    pub fn is_redacted(&self) -> bool {
+        matches!(*self, Event::Redacted(..))
+    }
+    /// Get the ID of this event, if it has one (ephemeral events don't).
+    pub fn event_id(&self) -> Option<&str> {
+        match *self {
+            Event::Full(ref m, _) | Event::FullError(ref m, _) => Some(&m.event_id),
+            Event::Minimal(ref m, _) | Event::MinimalError(ref m, _) => m.event_id.as_ref().map(|x| x as &str),
+            Event::Redacted(ref m) => m.event_id.as_ref().map(|x| x as &str)
+        }
+    }
+    /// Get the ID of the redaction event that redacted this event, if it has
+    /// been redacted (and the homeserver said which event did it).
+    pub fn redacted_by(&self) -> Option<&str> {
+        match *self {
+            Event::Redacted(ref m) => {
+                m.unsigned.redacted_by.as_ref()
+                    .map(|x| x as &str)
+                    .or_else(|| {
+                        m.unsigned.redacted_because.as_ref()
+                            .and_then(|r| r.get("event_id"))
+                            .and_then(|id| id.as_str())
+                    })
+            },
+            _ => None
+        }
+    }
+}
 /// Events in a room.
 #[derive(Deserialize, Default, Debug)]
 pub struct Events {
@@ -0,0 +1,150 @@
+//! Filters, for cutting down on the events returned by `/sync` (and other
+//! endpoints, like `/messages`).
+//!
+//! Every field is optional, and left out of the serialized filter if unset.
+
+/// A filter for non-room events (like presence, or global account data).
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct EventFilter {
+    /// The maximum number of events to return.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// Event types to include. A `*` can be used as a wildcard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    /// Event types to exclude (taking precedence over `types`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_types: Vec<String>,
+    /// Senders to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    /// Senders to exclude (taking precedence over `senders`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_senders: Vec<String>
+}
+/// A filter for room events.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RoomEventFilter {
+    /// The maximum number of events to return.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// Event types to include. A `*` can be used as a wildcard.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub types: Option<Vec<String>>,
+    /// Event types to exclude (taking precedence over `types`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_types: Vec<String>,
+    /// Senders to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub senders: Option<Vec<String>>,
+    /// Senders to exclude (taking precedence over `senders`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_senders: Vec<String>,
+    /// Room IDs to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    /// Room IDs to exclude (taking precedence over `rooms`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_rooms: Vec<String>,
+    /// If set, only include events with (`true`) or without (`false`) a `url`
+    /// in their content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contains_url: Option<bool>,
+    /// Only send the membership events needed to display the senders of the
+    /// returned events, instead of every member of the room.
+    #[serde(default, skip_serializing_if = "::is_false")]
+    pub lazy_load_members: bool,
+    /// When lazy-loading members, send membership events even if the client
+    /// has already been sent them.
+    #[serde(default, skip_serializing_if = "::is_false")]
+    pub include_redundant_members: bool
+}
+/// A filter for the events in rooms.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct RoomFilter {
+    /// Room IDs to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rooms: Option<Vec<String>>,
+    /// Room IDs to exclude (taking precedence over `rooms`).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_rooms: Vec<String>,
+    /// Whether to include rooms that the user has left.
+    #[serde(default, skip_serializing_if = "::is_false")]
+    pub include_leave: bool,
+    /// The ephemeral events (like typing notifications) to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ephemeral: Option<RoomEventFilter>,
+    /// The state events to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub state: Option<RoomEventFilter>,
+    /// The timeline events to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeline: Option<RoomEventFilter>,
+    /// The per-room account data to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<RoomEventFilter>
+}
+/// A filter, as uploaded to `/user/{userId}/filter`.
+///
+/// The methods on this type build filters for a few common cases:
+///
+/// ```rust
+/// # use gm_types::filter::Filter;
+/// let filter = Filter::new()
+///     .lazy_load_members()
+///     .timeline_limit(20)
+///     .exclude_types(&["m.room.member"]);
+/// ```
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Filter {
+    /// The event fields to include (like `content.body`); if unset, all
+    /// fields are included.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_fields: Option<Vec<String>>,
+    /// The format to use for events: `client` or `federation`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_format: Option<String>,
+    /// The presence updates to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub presence: Option<EventFilter>,
+    /// The global account data to include.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub account_data: Option<EventFilter>,
+    /// Filters for the events in rooms.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<RoomFilter>
+}
+impl Filter {
+    /// Make an empty filter, which doesn't filter anything out.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn room_mut(&mut self) -> &mut RoomFilter {
+        self.room.get_or_insert_with(Default::default)
+    }
+    /// Lazy-load room members (for both room state and timelines).
+    pub fn lazy_load_members(mut self) -> Self {
+        {
+            let room = self.room_mut();
+            room.state.get_or_insert_with(Default::default).lazy_load_members = true;
+            room.timeline.get_or_insert_with(Default::default).lazy_load_members = true;
+        }
+        self
+    }
+    /// Limit the timeline of each room to (at most) `limit` events.
+    pub fn timeline_limit(mut self, limit: u32) -> Self {
+        self.room_mut().timeline.get_or_insert_with(Default::default).limit = Some(limit);
+        self
+    }
+    /// Leave out room state and timeline events of the given types.
+    pub fn exclude_types<T: AsRef<str>>(mut self, types: &[T]) -> Self {
+        {
+            let room = self.room_mut();
+            for filt in &mut [&mut room.state, &mut room.timeline] {
+                let filt = filt.get_or_insert_with(Default::default);
+                filt.not_types.extend(types.iter().map(|t| t.as_ref().to_string()));
+            }
+        }
+        self
+    }
+}
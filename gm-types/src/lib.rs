@@ -5,11 +5,22 @@
 
 extern crate serde;
 #[macro_use] extern crate serde_derive;
-extern crate serde_json;
+#[macro_use] extern crate serde_json;
 
 pub mod room;
+pub mod media;
 pub mod messages;
 pub mod content;
 pub mod events;
 pub mod replies;
 pub mod sync;
+pub mod filter;
+pub mod search;
+pub mod push;
+pub mod account;
+
+/// For leaving `false` flags out when serializing, with
+/// `skip_serializing_if`.
+pub(crate) fn is_false(b: &bool) -> bool {
+    !*b
+}
@@ -0,0 +1,112 @@
+//! Types for the content repository.
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error as DeError;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// A `mxc://` URI, which refers to a piece of content (like an image) in the
+/// content repository.
+#[derive(Clone, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub struct ContentUri {
+    /// The server name of the homeserver hosting the content.
+    pub server_name: String,
+    /// The ID of the content on that homeserver.
+    pub media_id: String
+}
+/// The error returned when a string isn't a valid `mxc://` URI.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidContentUri(pub String);
+impl fmt::Display for InvalidContentUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid content URI: {}", self.0)
+    }
+}
+impl Error for InvalidContentUri {
+    fn description(&self) -> &str {
+        "invalid content URI"
+    }
+}
+impl FromStr for ContentUri {
+    type Err = InvalidContentUri;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || InvalidContentUri(s.into());
+        if !s.starts_with("mxc://") {
+            return Err(err());
+        }
+        let mut parts = s["mxc://".len()..].splitn(2, '/');
+        let server_name = parts.next().ok_or_else(err)?;
+        let media_id = parts.next().ok_or_else(err)?;
+        // Media IDs may only contain `A-Z`, `a-z`, `0-9`, `_` and `-`.
+        let valid_id = media_id.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if server_name.is_empty() || media_id.is_empty() || !valid_id {
+            return Err(err());
+        }
+        Ok(ContentUri {
+            server_name: server_name.into(),
+            media_id: media_id.into()
+        })
+    }
+}
+impl fmt::Display for ContentUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mxc://{}/{}", self.server_name, self.media_id)
+    }
+}
+impl Serialize for ContentUri {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+impl<'de> Deserialize<'de> for ContentUri {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let uri: String = Deserialize::deserialize(de)?;
+        uri.parse().map_err(D::Error::custom)
+    }
+}
+/// The configuration of the content repository, obtained with
+/// `media_config()`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct MediaConfig {
+    /// The largest upload the homeserver accepts, in bytes, if it has a
+    /// limit.
+    #[serde(rename = "m.upload.size", default, skip_serializing_if = "Option::is_none")]
+    pub upload_size: Option<u64>
+}
+/// A preview of a URL, obtained from the homeserver with `preview_url()`.
+///
+/// This has the page's OpenGraph data, which the homeserver may fill in from
+/// the page's title and contents if the page doesn't have it.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct UrlPreview {
+    /// The title of the page (`og:title`).
+    #[serde(rename = "og:title", default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// A description of the page (`og:description`).
+    #[serde(rename = "og:description", default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// The `mxc://` URI of an image for the page, which the homeserver has
+    /// copied to its content repository (`og:image`).
+    #[serde(rename = "og:image", default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+    /// The size of the image, in bytes (`matrix:image:size`).
+    #[serde(rename = "matrix:image:size", default, skip_serializing_if = "Option::is_none")]
+    pub image_size: Option<u64>,
+    /// The width of the image, in pixels (`og:image:width`).
+    #[serde(rename = "og:image:width", default, skip_serializing_if = "Option::is_none")]
+    pub image_width: Option<u32>,
+    /// The height of the image, in pixels (`og:image:height`).
+    #[serde(rename = "og:image:height", default, skip_serializing_if = "Option::is_none")]
+    pub image_height: Option<u32>,
+    /// Any other OpenGraph fields (like `og:site_name`).
+    #[serde(flatten)]
+    pub extra: ::std::collections::HashMap<String, ::serde_json::Value>
+}
+impl UrlPreview {
+    /// Get the image for the page as a `ContentUri`, if it has one (and it's
+    /// a valid `mxc://` URI), so it can be downloaded.
+    pub fn image_uri(&self) -> Option<ContentUri> {
+        self.image.as_ref().and_then(|i| i.parse().ok())
+    }
+}
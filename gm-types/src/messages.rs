@@ -1,25 +1,64 @@
 //! Values for the `m.room.message` event's content.
+use events::Event;
+use content::Content;
 
 /// Information about an image.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+///
+/// Every field is optional, since clients often leave some of them out;
+/// fields that are `None` aren't sent.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct ImageInfo {
     /// The height of the image in pixels.
-    pub h: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub h: Option<u32>,
     /// MIME type
-    pub mimetype: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
     /// Size, in bytes
-    pub size: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
     /// The width of the image in pixels.
-    pub w: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w: Option<u32>,
+    /// The URL to a thumbnail of the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    /// Metadata about the image referred to in `thumbnail_url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_info: Option<ThumbnailInfo>
 }
 
 /// Information about a file.
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct FileInfo {
     /// MIME type
-    pub mimetype: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
     /// Size, in bytes
-    pub size: u32
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The URL to a thumbnail of the file.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_url: Option<String>,
+    /// Metadata about the image referred to in `thumbnail_url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub thumbnail_info: Option<ThumbnailInfo>
+}
+/// Information about a thumbnail (of an image, file or video).
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ThumbnailInfo {
+    /// The height of the thumbnail in pixels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub h: Option<u32>,
+    /// MIME type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mimetype: Option<String>,
+    /// Size, in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The width of the thumbnail in pixels.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub w: Option<u32>
 }
 /// Information about an audio clip.
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -48,6 +87,123 @@ pub struct VideoInfo {
     /// Metadata about the image referred to in thumbnail_url.
     pub thumbnail_info: ImageInfo
 }
+/// The value of the `format` field for HTML-formatted messages.
+pub const HTML_FORMAT: &str = "org.matrix.custom.html";
+/// The event an event is in reply to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct InReplyTo {
+    /// The ID of the event being replied to.
+    pub event_id: String
+}
+/// The `m.relates_to` field of an event's content, which describes how it
+/// relates to another event.
+///
+/// Replies use `in_reply_to`; other kinds of relation (like `m.replace` for
+/// edits, or `m.annotation` for reactions) use `rel_type` and `event_id`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct RelatesTo {
+    /// The event this is a reply to.
+    #[serde(rename = "m.in_reply_to", default, skip_serializing_if = "Option::is_none")]
+    pub in_reply_to: Option<InReplyTo>,
+    /// The type of relation, like `m.replace` or `m.annotation`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rel_type: Option<String>,
+    /// The ID of the event this relates to (with `rel_type`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub event_id: Option<String>,
+    /// The annotation itself (for an `m.annotation`, like a reaction emoji).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// Whether `in_reply_to` is only there as a fallback, for clients that
+    /// don't understand threads (with an `m.thread` relation).
+    #[serde(default, skip_serializing_if = "::is_false")]
+    pub is_falling_back: bool
+}
+impl RelatesTo {
+    /// A reply to the event with the given ID.
+    pub fn reply<T: Into<String>>(event_id: T) -> Self {
+        RelatesTo {
+            in_reply_to: Some(InReplyTo { event_id: event_id.into() }),
+            ..Default::default()
+        }
+    }
+    /// A relation of the given type (like `m.thread`) to the event with the
+    /// given ID.
+    pub fn new<T: Into<String>, U: Into<String>>(rel_type: T, event_id: U) -> Self {
+        RelatesTo {
+            rel_type: Some(rel_type.into()),
+            event_id: Some(event_id.into()),
+            ..Default::default()
+        }
+    }
+    /// An edit (`m.replace`) of the event with the given ID.
+    pub fn replace<T: Into<String>>(event_id: T) -> Self {
+        Self::new("m.replace", event_id)
+    }
+    /// A message in the thread (`m.thread`) starting at the event with ID
+    /// `root`.
+    ///
+    /// Clients that don't understand threads see the message as a reply to
+    /// `latest`, which should be the latest event in the thread that the
+    /// client knows about (or the root, if there isn't one).
+    pub fn thread<T: Into<String>, U: Into<String>>(root: T, latest: U) -> Self {
+        RelatesTo {
+            in_reply_to: Some(InReplyTo { event_id: latest.into() }),
+            is_falling_back: true,
+            ..Self::new("m.thread", root)
+        }
+    }
+    /// If this is an `m.thread` relation, get the ID of the thread's root
+    /// event.
+    pub fn thread_root(&self) -> Option<&str> {
+        match *self {
+            RelatesTo { rel_type: Some(ref t), event_id: Some(ref id), .. } if t == "m.thread" => Some(id),
+            _ => None
+        }
+    }
+    /// An annotation (`m.annotation`) of the event with the given ID, like a
+    /// reaction.
+    pub fn annotation<T: Into<String>, U: Into<String>>(event_id: T, key: U) -> Self {
+        RelatesTo {
+            key: Some(key.into()),
+            ..Self::new("m.annotation", event_id)
+        }
+    }
+}
+/// The event to reply to, for `Message::reply_to()`.
+#[derive(Clone, Copy, Debug)]
+pub enum ReplyTarget<'a> {
+    /// A full event, which is quoted in the reply fallback.
+    Event(&'a Event),
+    /// Just an event ID; no reply fallback is generated.
+    EventId(&'a str)
+}
+impl<'a> From<&'a Event> for ReplyTarget<'a> {
+    fn from(ev: &'a Event) -> Self {
+        ReplyTarget::Event(ev)
+    }
+}
+impl<'a> From<&'a str> for ReplyTarget<'a> {
+    fn from(id: &'a str) -> Self {
+        ReplyTarget::EventId(id)
+    }
+}
+/// Escape the characters in `text` that mean something in HTML, so it can
+/// be put in a `formatted_body` as-is.
+pub fn escape_html(text: &str) -> String {
+    let mut ret = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            c => ret.push(c)
+        }
+    }
+    ret
+}
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(tag="msgtype")]
 /// A message sent to a room.
@@ -58,11 +214,17 @@ pub enum Message {
         /// The body of the message.
         body: String,
         /// The formatted body of the message (if the message is formatted).
-        #[serde(default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         formatted_body: Option<String>,
         /// The format of the formatted body (if the message is formatted).
-        #[serde(default)]
-        format: Option<String>
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<String>,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.notice")]
     /// A m.notice message should be considered similar to a plain m.text message except
@@ -76,11 +238,17 @@ pub enum Message {
         /// The notice text to send.
         body: String,
         /// The formatted body of the message (if the message is formatted).
-        #[serde(default)]
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         formatted_body: Option<String>,
         /// The format of the formatted body (if the message is formatted).
-        #[serde(default)]
-        format: Option<String>
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        format: Option<String>,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.image")]
     /// This message represents a single image and an optional thumbnail.
@@ -93,11 +261,22 @@ pub enum Message {
         /// The URL to the image.
         url: String,
         /// Metadata about the image referred to in url.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         info: Option<ImageInfo>,
         /// The URL to the thumbnail of the image.
+        ///
+        /// Clients put this in `info` these days; see `ImageInfo`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         thumbnail_url: Option<String>,
         /// Metadata about the image referred to in thumbnail_url.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         thumbnail_info: Option<ImageInfo>,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.emote")]
     /// This message is similar to m.text except that the sender is 'performing'
@@ -108,6 +287,12 @@ pub enum Message {
     Emote{
         /// The emote action to perform.
         body: String,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.file")]
     /// This message represents a generic file.
@@ -115,16 +300,29 @@ pub enum Message {
         /// A human-readable description of the file. This is recommended to be
         /// the filename of the original upload.
         body: String,
-        /// The original filename of the uploaded file.
+        /// The original filename of the uploaded file (empty if the sender
+        /// left it out).
+        #[serde(default, skip_serializing_if = "String::is_empty")]
         filename: String,
         /// Information about the file referred to in url.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         info: Option<FileInfo>,
         /// Metadata about the image referred to in thumbnail_url.
+        ///
+        /// Clients put this in `info` these days; see `FileInfo`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         thumbnail_info: Option<ImageInfo>,
         /// The URL to the thumbnail of the file.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
         thumbnail_url: Option<String>,
         /// The URL to the file.
-        url: String
+        url: String,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.location")]
     /// This message represents a real-world location.
@@ -134,7 +332,13 @@ pub enum Message {
         /// attachment'.
         body: String,
         /// A geo URI representing this location.
-        geo_uri: String
+        geo_uri: String,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.audio")]
     /// This message represents a single audio clip.
@@ -146,7 +350,13 @@ pub enum Message {
         /// The URL to the audio clip.
         url: String,
         /// Metadata for the audio clip referred to in url.
-        info: Option<AudioInfo>
+        info: Option<AudioInfo>,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
     },
     #[serde(rename="m.video")]
     /// This message represents a single video clip.
@@ -157,6 +367,282 @@ pub enum Message {
         /// The URL to the video clip.
         url: String,
         /// Metadata about the video clip referred to in url.
-        info: Option<VideoInfo>
+        info: Option<VideoInfo>,
+        /// How this message relates to another event, if at all.
+        #[serde(rename = "m.relates_to", default, skip_serializing_if = "Option::is_none")]
+        relates_to: Option<RelatesTo>,
+        /// The new content of the message this edits (for `m.replace` edits).
+        #[serde(rename = "m.new_content", default, skip_serializing_if = "Option::is_none")]
+        new_content: Option<Box<Message>>
+    }
+}
+impl Message {
+    /// Make an `m.text` message with the given (unformatted) body.
+    pub fn text<T: Into<String>>(body: T) -> Self {
+        Message::Text {
+            body: body.into(),
+            formatted_body: None,
+            format: None,
+            relates_to: None,
+            new_content: None
+        }
+    }
+    /// Make an `m.notice` message with the given (unformatted) body.
+    pub fn notice<T: Into<String>>(body: T) -> Self {
+        Message::Notice {
+            body: body.into(),
+            formatted_body: None,
+            format: None,
+            relates_to: None,
+            new_content: None
+        }
+    }
+    /// Make an `m.text` message with an HTML-formatted body.
+    ///
+    /// `plain` is the body shown by clients that can't display HTML (and used
+    /// for things like notifications), so it should say the same thing as
+    /// `html`.
+    pub fn html<T: Into<String>, U: Into<String>>(plain: T, html: U) -> Self {
+        Message::Text {
+            body: plain.into(),
+            formatted_body: Some(html.into()),
+            format: Some(HTML_FORMAT.into()),
+            relates_to: None,
+            new_content: None
+        }
+    }
+    /// Make an `m.notice` message with an HTML-formatted body.
+    ///
+    /// See `html()` for more about `plain`.
+    pub fn notice_html<T: Into<String>, U: Into<String>>(plain: T, html: U) -> Self {
+        Message::Notice {
+            body: plain.into(),
+            formatted_body: Some(html.into()),
+            format: Some(HTML_FORMAT.into()),
+            relates_to: None,
+            new_content: None
+        }
+    }
+    /// Get the body of this message (its plain-text representation).
+    pub fn body(&self) -> &str {
+        use self::Message::*;
+        match *self {
+            Text { ref body, .. } | Notice { ref body, .. } | Image { ref body, .. } |
+            Emote { ref body, .. } | File { ref body, .. } | Location { ref body, .. } |
+            Audio { ref body, .. } | Video { ref body, .. } => body
+        }
+    }
+    /// Get the HTML-formatted body of this message, if it has one.
+    pub fn html_body(&self) -> Option<&str> {
+        match *self {
+            Message::Text { formatted_body: Some(ref f), format: Some(ref fmt), .. } |
+            Message::Notice { formatted_body: Some(ref f), format: Some(ref fmt), .. }
+                if fmt == HTML_FORMAT => Some(f),
+            _ => None
+        }
+    }
+    /// Get how this message relates to another event, if at all.
+    pub fn relates_to(&self) -> Option<&RelatesTo> {
+        use self::Message::*;
+        match *self {
+            Text { ref relates_to, .. } | Notice { ref relates_to, .. } | Image { ref relates_to, .. } |
+            Emote { ref relates_to, .. } | File { ref relates_to, .. } | Location { ref relates_to, .. } |
+            Audio { ref relates_to, .. } | Video { ref relates_to, .. } => relates_to.as_ref()
+        }
+    }
+    /// If this message is an edit (an `m.replace` relation), get the ID of
+    /// the event it edits.
+    pub fn replaces(&self) -> Option<&str> {
+        match self.relates_to() {
+            Some(&RelatesTo { rel_type: Some(ref t), event_id: Some(ref id), .. }) if t == "m.replace" => Some(id),
+            _ => None
+        }
+    }
+    /// Get the new content of the message this edits, if this is an edit.
+    pub fn new_content(&self) -> Option<&Message> {
+        use self::Message::*;
+        match *self {
+            Text { ref new_content, .. } | Notice { ref new_content, .. } | Image { ref new_content, .. } |
+            Emote { ref new_content, .. } | File { ref new_content, .. } | Location { ref new_content, .. } |
+            Audio { ref new_content, .. } | Video { ref new_content, .. } => new_content.as_ref().map(|x| x as &Message)
+        }
+    }
+    fn new_content_mut(&mut self) -> &mut Option<Box<Message>> {
+        use self::Message::*;
+        match *self {
+            Text { ref mut new_content, .. } | Notice { ref mut new_content, .. } |
+            Image { ref mut new_content, .. } | Emote { ref mut new_content, .. } |
+            File { ref mut new_content, .. } | Location { ref mut new_content, .. } |
+            Audio { ref mut new_content, .. } | Video { ref mut new_content, .. } => new_content
+        }
+    }
+    /// Make an edit (an `m.replace` relation) that replaces the content of the
+    /// event with ID `event_id` with this message.
+    ///
+    /// The returned message has `m.new_content` set to this message, and a
+    /// fallback body (for clients that don't understand edits) of the new
+    /// body prefixed with `* `. HTML-formatted text and notice messages get
+    /// the same prefix on their formatted body.
+    pub fn edit_of<T: Into<String>>(mut self, event_id: T) -> Self {
+        *self.relates_to_mut() = None;
+        let mut fallback = self.clone();
+        match fallback {
+            Message::Text { ref mut body, ref mut formatted_body, .. } |
+            Message::Notice { ref mut body, ref mut formatted_body, .. } => {
+                *body = format!("* {}", body);
+                if let Some(ref mut f) = *formatted_body {
+                    *f = format!("* {}", f);
+                }
+            },
+            Message::Emote { ref mut body, .. } | Message::Image { ref mut body, .. } |
+            Message::File { ref mut body, .. } | Message::Location { ref mut body, .. } |
+            Message::Audio { ref mut body, .. } | Message::Video { ref mut body, .. } => {
+                *body = format!("* {}", body);
+            }
+        }
+        *fallback.new_content_mut() = Some(Box::new(self));
+        fallback.with_relation(RelatesTo::replace(event_id))
+    }
+    fn relates_to_mut(&mut self) -> &mut Option<RelatesTo> {
+        use self::Message::*;
+        match *self {
+            Text { ref mut relates_to, .. } | Notice { ref mut relates_to, .. } |
+            Image { ref mut relates_to, .. } | Emote { ref mut relates_to, .. } |
+            File { ref mut relates_to, .. } | Location { ref mut relates_to, .. } |
+            Audio { ref mut relates_to, .. } | Video { ref mut relates_to, .. } => relates_to
+        }
+    }
+    /// Set how this message relates to another event, replacing any existing
+    /// relation.
+    pub fn with_relation(mut self, rel: RelatesTo) -> Self {
+        *self.relates_to_mut() = Some(rel);
+        self
+    }
+    /// Make this message a reply to another event.
+    ///
+    /// This sets `m.relates_to.m.in_reply_to`. If given a full `m.room.message`
+    /// event (rather than just an event ID), the original message is also
+    /// quoted in a fallback for clients that don't understand replies: in
+    /// the `body` of text, notice and emote messages, and (for messages with
+    /// an HTML-formatted body) in an `<mx-reply>` block in `formatted_body`.
+    ///
+    /// This returns `None` if given an event without an event ID (like a
+    /// minimal one from account data), since there's nothing to reply to.
+    pub fn reply_to<'a, T: Into<ReplyTarget<'a>>>(mut self, target: T) -> Option<Self> {
+        let (event_id, fallback) = match target.into() {
+            ReplyTarget::EventId(id) => (id.to_string(), None),
+            ReplyTarget::Event(&Event::Full(ref meta, Content::RoomMessage(ref orig))) => {
+                (meta.event_id.clone(), Some((meta, orig)))
+            },
+            ReplyTarget::Event(&Event::Full(ref meta, _)) |
+            ReplyTarget::Event(&Event::FullError(ref meta, _)) => (meta.event_id.clone(), None),
+            ReplyTarget::Event(ev) => {
+                let id = match *ev {
+                    Event::Minimal(ref m, _) | Event::MinimalError(ref m, _) => m.event_id.clone(),
+                    Event::Redacted(ref m) => m.event_id.clone(),
+                    _ => None
+                };
+                (id?, None)
+            }
+        };
+        if let Some((meta, orig)) = fallback {
+            // Replies to replies shouldn't quote the original's own fallback.
+            let is_reply = orig.relates_to().map(|r| r.in_reply_to.is_some()).unwrap_or(false);
+            let mut orig_body = orig.body();
+            if is_reply {
+                let mut rest = orig_body;
+                while rest.starts_with("> ") || rest.starts_with(">\n") {
+                    rest = rest.find('\n').map(|i| &rest[i+1..]).unwrap_or("");
+                }
+                orig_body = rest.trim_start_matches('\n');
+            }
+            let emote = if let Message::Emote { .. } = *orig { "* " } else { "" };
+            let mut quote = String::new();
+            for (i, line) in orig_body.lines().enumerate() {
+                if i == 0 {
+                    quote += &format!("> {}<{}> {}\n", emote, meta.sender, line);
+                }
+                else {
+                    quote += &format!("> {}\n", line);
+                }
+            }
+            let orig_html = match orig.html_body() {
+                Some(h) => match h.find("</mx-reply>") {
+                    Some(i) if is_reply => h[i + "</mx-reply>".len()..].to_string(),
+                    _ => h.to_string()
+                },
+                None => escape_html(orig_body).replace('\n', "<br>")
+            };
+            let link = match meta.room {
+                Some(ref r) => format!("https://matrix.to/#/{}/{}", r.id, meta.event_id),
+                None => format!("https://matrix.to/#/{}", meta.event_id)
+            };
+            let mx_reply = format!("<mx-reply><blockquote><a href=\"{}\">In reply to</a> {}<a href=\"https://matrix.to/#/{}\">{}</a><br>{}</blockquote></mx-reply>",
+                                   link, emote, meta.sender, escape_html(&meta.sender), orig_html);
+            match self {
+                Message::Text { ref mut body, ref mut formatted_body, ref format, .. } |
+                Message::Notice { ref mut body, ref mut formatted_body, ref format, .. } => {
+                    *body = format!("{}\n{}", quote, body);
+                    if format.as_ref().map(|f| f == HTML_FORMAT).unwrap_or(false) {
+                        if let Some(ref mut f) = *formatted_body {
+                            *f = format!("{}{}", mx_reply, f);
+                        }
+                    }
+                },
+                Message::Emote { ref mut body, .. } => {
+                    *body = format!("{}\n{}", quote, body);
+                },
+                _ => {}
+            }
+        }
+        Some(self.with_relation(RelatesTo::reply(event_id)))
+    }
+    /// Make an `m.image` message for the image at `url` (an `mxc://` URI),
+    /// with `body` as its description (usually the filename).
+    pub fn image<T: Into<String>, U: Into<String>>(body: T, url: U, info: Option<ImageInfo>) -> Self {
+        Message::Image {
+            body: body.into(),
+            url: url.into(),
+            info,
+            thumbnail_url: None,
+            thumbnail_info: None,
+            relates_to: None,
+            new_content: None
+        }
+    }
+    /// Make an `m.file` message for the file called `filename` at `url` (an
+    /// `mxc://` URI).
+    pub fn file<T: Into<String>, U: Into<String>>(filename: T, url: U, info: Option<FileInfo>) -> Self {
+        let filename = filename.into();
+        Message::File {
+            body: filename.clone(),
+            filename,
+            url: url.into(),
+            info,
+            thumbnail_url: None,
+            thumbnail_info: None,
+            relates_to: None,
+            new_content: None
+        }
+    }
+    /// Get the `mxc://` URI of the content of this message, if it's an
+    /// image, file, audio or video message.
+    ///
+    /// Parse it into a `ContentUri` to download it.
+    pub fn content_url(&self) -> Option<&str> {
+        use self::Message::*;
+        match *self {
+            Image { ref url, .. } | File { ref url, .. } |
+            Audio { ref url, .. } | Video { ref url, .. } => Some(url),
+            _ => None
+        }
+    }
+    /// Make an `m.emote` message with the given body.
+    pub fn emote<T: Into<String>>(body: T) -> Self {
+        Message::Emote {
+            body: body.into(),
+            relates_to: None,
+            new_content: None
+        }
     }
 }
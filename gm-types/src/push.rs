@@ -0,0 +1,342 @@
+//! Push rules, which decide which events the user gets notified about (and
+//! how), pushers, which send the notifications somewhere, and the
+//! notifications themselves.
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde_json::Value;
+use events::Event;
+
+/// The kind of a push rule, which decides what the rule matches and the
+/// order in which rules are checked (the order of the variants here).
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PushRuleKind {
+    /// Rules with arbitrary conditions, checked before all others.
+    Override,
+    /// Rules matching the `body` of messages against a `pattern`.
+    Content,
+    /// Rules for all events in a room, whose rule ID is the room ID.
+    Room,
+    /// Rules for all events sent by a user, whose rule ID is their MXID.
+    Sender,
+    /// Rules with arbitrary conditions, checked after all others.
+    Underride
+}
+impl PushRuleKind {
+    /// Get the string used for this kind in the API, like `override`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PushRuleKind::Override => "override",
+            PushRuleKind::Content => "content",
+            PushRuleKind::Room => "room",
+            PushRuleKind::Sender => "sender",
+            PushRuleKind::Underride => "underride"
+        }
+    }
+}
+/// Something to do when a push rule matches an event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PushAction {
+    /// Notify the user about the event.
+    Notify,
+    /// Don't notify the user about the event.
+    ///
+    /// This is deprecated; a rule with no actions at all does the same.
+    DontNotify,
+    /// Notify the user, but possibly together with other events.
+    Coalesce,
+    /// Set a tweak (like `sound` or `highlight`) on the notification.
+    SetTweak {
+        /// The name of the tweak.
+        tweak: String,
+        /// The value of the tweak, if it has one.
+        value: Option<Value>
+    },
+    /// An action this library doesn't know about.
+    Unknown(Value)
+}
+impl PushAction {
+    /// Play the given sound (like `default`) for the notification.
+    pub fn sound<T: Into<String>>(sound: T) -> Self {
+        PushAction::SetTweak {
+            tweak: "sound".into(),
+            value: Some(Value::String(sound.into()))
+        }
+    }
+    /// Set whether the notification should be highlighted.
+    pub fn highlight(highlight: bool) -> Self {
+        PushAction::SetTweak {
+            tweak: "highlight".into(),
+            value: Some(Value::Bool(highlight))
+        }
+    }
+    fn to_value(&self) -> Value {
+        match *self {
+            PushAction::Notify => json!("notify"),
+            PushAction::DontNotify => json!("dont_notify"),
+            PushAction::Coalesce => json!("coalesce"),
+            PushAction::SetTweak { ref tweak, value: Some(ref v) } => json!({ "set_tweak": tweak, "value": v }),
+            PushAction::SetTweak { ref tweak, value: None } => json!({ "set_tweak": tweak }),
+            PushAction::Unknown(ref v) => v.clone()
+        }
+    }
+    fn from_value(v: Value) -> Self {
+        match v {
+            Value::String(ref s) if s == "notify" => PushAction::Notify,
+            Value::String(ref s) if s == "dont_notify" => PushAction::DontNotify,
+            Value::String(ref s) if s == "coalesce" => PushAction::Coalesce,
+            Value::Object(ref o) if o.contains_key("set_tweak") && o.len() <= 2 => {
+                match o["set_tweak"].as_str() {
+                    Some(tweak) => PushAction::SetTweak {
+                        tweak: tweak.into(),
+                        value: o.get("value").cloned()
+                    },
+                    None => PushAction::Unknown(v.clone())
+                }
+            },
+            v => PushAction::Unknown(v)
+        }
+    }
+}
+impl Serialize for PushAction {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        self.to_value().serialize(serializer)
+    }
+}
+impl<'de> Deserialize<'de> for PushAction {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        Ok(PushAction::from_value(Value::deserialize(de)?))
+    }
+}
+/// The conditions this library knows about, as they're (de)serialized.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum KnownCondition {
+    EventMatch { key: String, pattern: String },
+    ContainsDisplayName {},
+    RoomMemberCount { is: String },
+    SenderNotificationPermission { key: String }
+}
+/// A condition that an event must meet for a push rule to match it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PushCondition {
+    /// The field `key` of the event (like `content.body`) matches the glob
+    /// `pattern`.
+    EventMatch {
+        /// The field to match, with dots between the levels.
+        key: String,
+        /// The glob to match against.
+        pattern: String
+    },
+    /// The event's body contains the user's display name in the room.
+    ContainsDisplayName,
+    /// The number of members in the room meets `is`, like `2` or `>10`.
+    RoomMemberCount {
+        /// The comparison, like `==2`, `<3` or just `2`.
+        is: String
+    },
+    /// The sender has a high enough power level to send notifications of
+    /// type `key` (like `room`, for `@room`).
+    SenderNotificationPermission {
+        /// The type of notification.
+        key: String
+    },
+    /// A condition this library doesn't know about, which is kept as-is so it
+    /// doesn't get lost when the rule is sent back.
+    Unknown(Value)
+}
+impl<'de> Deserialize<'de> for PushCondition {
+    fn deserialize<D>(de: D) -> Result<Self, D::Error> where D: Deserializer<'de> {
+        let v = Value::deserialize(de)?;
+        Ok(match ::serde_json::from_value(v.clone()) {
+            Ok(KnownCondition::EventMatch { key, pattern }) => PushCondition::EventMatch { key, pattern },
+            Ok(KnownCondition::ContainsDisplayName {}) => PushCondition::ContainsDisplayName,
+            Ok(KnownCondition::RoomMemberCount { is }) => PushCondition::RoomMemberCount { is },
+            Ok(KnownCondition::SenderNotificationPermission { key }) => PushCondition::SenderNotificationPermission { key },
+            Err(_) => PushCondition::Unknown(v)
+        })
+    }
+}
+impl Serialize for PushCondition {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error> where S: Serializer {
+        let known = match *self {
+            PushCondition::EventMatch { ref key, ref pattern } => KnownCondition::EventMatch { key: key.clone(), pattern: pattern.clone() },
+            PushCondition::ContainsDisplayName => KnownCondition::ContainsDisplayName {},
+            PushCondition::RoomMemberCount { ref is } => KnownCondition::RoomMemberCount { is: is.clone() },
+            PushCondition::SenderNotificationPermission { ref key } => KnownCondition::SenderNotificationPermission { key: key.clone() },
+            PushCondition::Unknown(ref v) => return v.serialize(serializer)
+        };
+        known.serialize(serializer)
+    }
+}
+/// A push rule.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct PushRule {
+    /// The ID of the rule (for `room` and `sender` rules, the room ID or
+    /// MXID it applies to).
+    pub rule_id: String,
+    /// Whether this is one of the homeserver's default rules.
+    #[serde(default)]
+    pub default: bool,
+    /// Whether the rule is enabled.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// What to do when the rule matches.
+    #[serde(default)]
+    pub actions: Vec<PushAction>,
+    /// The conditions an event must meet (for `override` and `underride`
+    /// rules).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conditions: Option<Vec<PushCondition>>,
+    /// The glob to match the body of messages against (for `content`
+    /// rules).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>
+}
+fn default_enabled() -> bool {
+    true
+}
+impl PushRule {
+    /// Make an enabled rule with the given ID and actions, and no conditions
+    /// or pattern.
+    pub fn new<T: Into<String>>(rule_id: T, actions: Vec<PushAction>) -> Self {
+        PushRule {
+            rule_id: rule_id.into(),
+            default: false,
+            enabled: true,
+            actions,
+            conditions: None,
+            pattern: None
+        }
+    }
+    /// Check whether this rule notifies the user.
+    pub fn notifies(&self) -> bool {
+        self.enabled && self.actions.iter().any(|a| *a == PushAction::Notify || *a == PushAction::Coalesce)
+    }
+}
+/// A set of push rules, by kind.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Ruleset {
+    /// The `override` rules.
+    #[serde(rename = "override", default)]
+    pub override_: Vec<PushRule>,
+    /// The `content` rules.
+    #[serde(default)]
+    pub content: Vec<PushRule>,
+    /// The `room` rules.
+    #[serde(default)]
+    pub room: Vec<PushRule>,
+    /// The `sender` rules.
+    #[serde(default)]
+    pub sender: Vec<PushRule>,
+    /// The `underride` rules.
+    #[serde(default)]
+    pub underride: Vec<PushRule>
+}
+impl Ruleset {
+    /// Get the rules of a given kind.
+    pub fn rules(&self, kind: PushRuleKind) -> &[PushRule] {
+        match kind {
+            PushRuleKind::Override => &self.override_,
+            PushRuleKind::Content => &self.content,
+            PushRuleKind::Room => &self.room,
+            PushRuleKind::Sender => &self.sender,
+            PushRuleKind::Underride => &self.underride
+        }
+    }
+    /// Find a rule by kind and ID.
+    pub fn get(&self, kind: PushRuleKind, rule_id: &str) -> Option<&PushRule> {
+        self.rules(kind).iter().find(|r| r.rule_id == rule_id)
+    }
+}
+/// The reply obtained from `/pushrules/`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PushRulesReply {
+    /// The user's rules.
+    #[serde(default)]
+    pub global: Ruleset
+}
+/// The kind of a pusher.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PusherKind {
+    /// Notifications are sent to a push gateway over HTTP.
+    Http,
+    /// Notifications are sent by email.
+    Email
+}
+/// Information for a pusher's push gateway.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PusherData {
+    /// The URL to send notifications to (for `http` pushers), ending in
+    /// `/_matrix/push/v1/notify`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// The format to send notifications in, like `event_id_only`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub format: Option<String>,
+    /// Any other keys that aren't listed above.
+    #[serde(flatten)]
+    pub extra: ::std::collections::HashMap<String, Value>
+}
+/// A pusher, which sends the user's notifications somewhere (like to their
+/// phone), from `/pushers`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Pusher {
+    /// A unique identifier for the pusher, like a device's push token (for
+    /// `http` pushers) or an email address (for `email` pushers).
+    pub pushkey: String,
+    /// The kind of pusher.
+    pub kind: PusherKind,
+    /// An identifier for the application that made the pusher, like
+    /// `com.example.app.ios`.
+    pub app_id: String,
+    /// The name of the application, for the user to see.
+    pub app_display_name: String,
+    /// The name of the device, for the user to see.
+    pub device_display_name: String,
+    /// A string picking which set of device-specific push rules applies.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile_tag: Option<String>,
+    /// The preferred language for notifications, like `en`.
+    pub lang: String,
+    /// Information for the push gateway.
+    #[serde(default)]
+    pub data: PusherData
+}
+/// The reply obtained from `/pushers`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PushersReply {
+    /// The user's pushers.
+    #[serde(default)]
+    pub pushers: Vec<Pusher>
+}
+/// A notification the user got, from `/notifications`.
+#[derive(Deserialize, Debug)]
+pub struct Notification {
+    /// What the push rule that matched the event said to do.
+    #[serde(default)]
+    pub actions: Vec<PushAction>,
+    /// The event the notification is about.
+    pub event: Event,
+    /// The profile tag of the push rule that matched the event, if any.
+    #[serde(default)]
+    pub profile_tag: Option<String>,
+    /// Whether the user has read the event (sent a read receipt for it, or
+    /// a later one).
+    pub read: bool,
+    /// The ID of the room the event is in.
+    pub room_id: String,
+    /// When the notification was made, in milliseconds since the Unix
+    /// epoch.
+    pub ts: u64
+}
+/// The reply obtained from `/notifications`.
+#[derive(Deserialize, Debug)]
+pub struct NotificationsReply {
+    /// A token for getting the next (older) page of notifications. If this
+    /// is `None`, there are no more.
+    #[serde(default)]
+    pub next_token: Option<String>,
+    /// A page of notifications, most recent first.
+    pub notifications: Vec<Notification>
+}
@@ -1,5 +1,10 @@
 //! Replies obtained from calling various API endpoints.
 use room::Room;
+use media::ContentUri;
+use events::Event;
+use content::root::types::Presence;
+use std::collections::HashMap;
+use serde_json::Value;
 
 /// The reply obtained from `/send`.
 #[derive(Deserialize, Clone, Debug)]
@@ -9,7 +14,8 @@ pub struct SendReply {
 /// The reply obtained from `upload()`.
 #[derive(Deserialize, Clone, Debug)]
 pub struct UploadReply {
-    pub content_uri: String
+    /// The `mxc://` URI of the uploaded content.
+    pub content_uri: ContentUri
 }
 /// The reply obtained from `/join`.
 #[derive(Deserialize, Clone, Debug)]
@@ -17,18 +23,260 @@ pub struct JoinReply {
     #[serde(rename = "room_id")]
     pub room: Room<'static>
 }
+/// The reply obtained from `/createRoom`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CreateRoomReply {
+    /// The new room.
+    #[serde(rename = "room_id")]
+    pub room: Room<'static>
+}
 /// The reply obtained from `/login`.
 #[derive(Deserialize, Clone, Debug)]
 pub struct LoginReply {
+    /// The MXID of the user that logged in.
     pub user_id: String,
+    /// An access token for the new session.
     pub access_token: String,
-    pub home_server: String
+    /// The ID of the device that was logged in.
+    #[serde(default)]
+    pub device_id: Option<String>,
+    /// The server name of the homeserver (deprecated, so newer homeservers
+    /// leave it out).
+    #[serde(default)]
+    pub home_server: Option<String>,
+    /// A refresh token, for getting a new access token with `/refresh` once
+    /// this one expires (only if one was asked for).
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// How long the access token is valid for, in milliseconds, if it
+    /// expires at all.
+    #[serde(default)]
+    pub expires_in_ms: Option<u64>
+}
+/// The reply obtained from `/refresh`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RefreshReply {
+    /// The new access token.
+    pub access_token: String,
+    /// A new refresh token, to use next time. If this is `None`, the old one
+    /// stays valid.
+    #[serde(default)]
+    pub refresh_token: Option<String>,
+    /// How long the new access token is valid for, in milliseconds, if it
+    /// expires at all.
+    #[serde(default)]
+    pub expires_in_ms: Option<u64>
+}
+/// The reply obtained from `/account/whoami`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WhoamiReply {
+    /// The MXID of the user that owns the access token.
+    pub user_id: String,
+    /// The ID of the device that the access token belongs to, if any.
+    #[serde(default)]
+    pub device_id: Option<String>
+}
+/// The reply obtained from `/rooms/{roomId}/messages`.
+#[derive(Deserialize, Debug)]
+pub struct MessagesReply {
+    /// The token the pagination starts from.
+    pub start: String,
+    /// The token the pagination ends at. If this is `None`, there are no more
+    /// events to fetch in the requested direction.
+    #[serde(default)]
+    pub end: Option<String>,
+    /// The events, in the order they were paginated in.
+    #[serde(default)]
+    pub chunk: Vec<Event>,
+    /// State events relevant to the events in `chunk` (if lazy-loading
+    /// members was requested in the filter).
+    #[serde(default)]
+    pub state: Vec<Event>
+}
+/// The reply obtained from `/rooms/{roomId}/context/{eventId}`.
+#[derive(Deserialize, Debug)]
+pub struct ContextReply {
+    /// A token for paginating backwards from the start of `events_before`.
+    #[serde(default)]
+    pub start: Option<String>,
+    /// A token for paginating forwards from the end of `events_after`.
+    #[serde(default)]
+    pub end: Option<String>,
+    /// Events from just before the requested event, most recent first.
+    #[serde(default)]
+    pub events_before: Vec<Event>,
+    /// The requested event.
+    pub event: Event,
+    /// Events from just after the requested event, oldest first.
+    #[serde(default)]
+    pub events_after: Vec<Event>,
+    /// The state of the room as of the last event in `events_after`.
+    #[serde(default)]
+    pub state: Vec<Event>
+}
+/// The reply obtained from `/rooms/{roomId}/relations/{eventId}`.
+#[derive(Deserialize, Debug)]
+pub struct RelationsReply {
+    /// The events relating to the event, most recent first.
+    #[serde(default)]
+    pub chunk: Vec<Event>,
+    /// A token for getting the next page of events. If this is `None`, there
+    /// are no more.
+    #[serde(default)]
+    pub next_batch: Option<String>,
+    /// A token for getting the previous page of events, if any.
+    #[serde(default)]
+    pub prev_batch: Option<String>
+}
+/// The reply obtained from `/rooms/{roomId}/threads`.
+#[derive(Deserialize, Debug)]
+pub struct ThreadsReply {
+    /// The root events of the threads, most recently active first.
+    #[serde(default)]
+    pub chunk: Vec<Event>,
+    /// A token for getting the next page of threads. If this is `None`,
+    /// there are no more.
+    #[serde(default)]
+    pub next_batch: Option<String>
+}
+/// The reply obtained from uploading a filter.
+#[derive(Deserialize, Clone, Debug)]
+pub struct FilterReply {
+    /// The ID of the new filter.
+    pub filter_id: String
+}
+/// The reply obtained from `/presence/{userId}/status`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PresenceReply {
+    /// The user's presence state.
+    pub presence: Presence,
+    /// How long ago the user last did something, in milliseconds.
+    #[serde(default)]
+    pub last_active_ago: Option<u64>,
+    /// The user's status message, if they've set one.
+    #[serde(default)]
+    pub status_msg: Option<String>,
+    /// Whether the user is currently active.
+    #[serde(default)]
+    pub currently_active: bool
+}
+/// A room in the public room directory.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PublicRoomsChunk {
+    /// The room's ID.
+    #[serde(rename = "room_id")]
+    pub room: Room<'static>,
+    /// The name of the room, if any.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The topic of the room, if any.
+    #[serde(default)]
+    pub topic: Option<String>,
+    /// The canonical alias of the room, if any.
+    #[serde(default)]
+    pub canonical_alias: Option<String>,
+    /// The number of users joined to the room.
+    pub num_joined_members: u64,
+    /// Whether the room's history can be read without joining it.
+    pub world_readable: bool,
+    /// Whether guest users can join the room.
+    pub guest_can_join: bool,
+    /// The room's avatar, as an `mxc://` URI, if any.
+    #[serde(default)]
+    pub avatar_url: Option<String>
+}
+/// The reply obtained from `/publicRooms`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct PublicRoomsReply {
+    /// A page of rooms.
+    pub chunk: Vec<PublicRoomsChunk>,
+    /// A token for getting the next page of rooms. If this is `None`, there
+    /// are no more.
+    #[serde(default)]
+    pub next_batch: Option<String>,
+    /// A token for getting the previous page of rooms. If this is `None`,
+    /// this is the first page.
+    #[serde(default)]
+    pub prev_batch: Option<String>,
+    /// An estimate of the number of rooms in the directory, if the server
+    /// gives one.
+    #[serde(default)]
+    pub total_room_count_estimate: Option<u64>
+}
+/// The reply obtained from `/profile/{userId}`.
+///
+/// Both fields are `None` if the user hasn't set them.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct ProfileReply {
+    /// The user's display name.
+    #[serde(default)]
+    pub displayname: Option<String>,
+    /// The user's avatar, as an `mxc://` URI.
+    #[serde(default)]
+    pub avatar_url: Option<String>
+}
+/// The reply obtained from `/joined_rooms`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct JoinedRoomsReply {
+    /// The rooms the user has joined.
+    #[serde(default)]
+    pub joined_rooms: Vec<Room<'static>>
+}
+/// A member of a room, from `/rooms/{roomId}/joined_members`.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct JoinedMember {
+    /// The member's display name in the room, if they've set one.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// The member's avatar in the room, as an `mxc://` URI, if they've set
+    /// one.
+    #[serde(default)]
+    pub avatar_url: Option<String>
+}
+/// The reply obtained from `/rooms/{roomId}/joined_members`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct JoinedMembersReply {
+    /// The members of the room, by MXID.
+    #[serde(default)]
+    pub joined: HashMap<String, JoinedMember>
+}
+/// The reply obtained from `/rooms/{roomId}/members`.
+#[derive(Deserialize, Debug)]
+pub struct MembersReply {
+    /// The `m.room.member` events of the members.
+    #[serde(default)]
+    pub chunk: Vec<Event>
+}
+/// A user found by searching the user directory.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UserDirectoryResult {
+    /// The user's MXID.
+    pub user_id: String,
+    /// The user's display name, if they've set one.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// The user's avatar, as an `mxc://` URI, if they've set one.
+    #[serde(default)]
+    pub avatar_url: Option<String>
 }
-/// The reply obtained from getting a room alias.
+/// The reply obtained from `/user_directory/search`.
 #[derive(Deserialize, Clone, Debug)]
-pub struct RoomAliasReply {
+pub struct UserDirectoryReply {
+    /// The users found.
+    pub results: Vec<UserDirectoryResult>,
+    /// Whether there were more results than the limit, in which case a more
+    /// specific search term might help.
+    pub limited: bool
+}
+/// The reply obtained from resolving a room alias, with
+/// `/directory/room/{roomAlias}`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ResolveAliasReply {
+    /// The room the alias points to.
     #[serde(rename = "room_id")]
     pub room: Room<'static>,
+    /// Some servers that know about the room, which can be used to join it.
+    #[serde(default)]
     pub servers: Vec<String>
 }
 /// The reply obtained when calling `Room::set_state`.
@@ -40,6 +288,279 @@ pub struct SetStateReply {
 /// The reply obtained when something's gone wrong.
 #[derive(Deserialize, Clone, Debug)]
 pub struct BadRequestReply {
+    /// The Matrix error code, like `M_FORBIDDEN` or `M_NOT_FOUND`.
     pub errcode: String,
-    pub error: String
+    /// A human-readable error message (possibly empty).
+    #[serde(default)]
+    pub error: String,
+    /// How long to wait before trying the request again, in milliseconds (for
+    /// `M_LIMIT_EXCEEDED` errors).
+    #[serde(default)]
+    pub retry_after_ms: Option<u64>,
+    /// Whether the access token was invalidated by a "soft logout" (for
+    /// `M_UNKNOWN_TOKEN` errors), meaning the device can be logged back in
+    /// without losing its data.
+    #[serde(default)]
+    pub soft_logout: bool
+}
+/// The reply obtained from `/.well-known/matrix/client`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WellKnownReply {
+    /// Information about the homeserver to connect to.
+    #[serde(rename = "m.homeserver")]
+    pub homeserver: WellKnownServer,
+    /// Information about the identity server to use, if any.
+    #[serde(rename = "m.identity_server", default)]
+    pub identity_server: Option<WellKnownServer>
+}
+/// A server in a `WellKnownReply`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct WellKnownServer {
+    /// The base URL of the server, like `https://matrix.example.org`.
+    pub base_url: String
+}
+/// The reply obtained from `/versions`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct VersionsReply {
+    /// The versions of the client-server API supported (like `r0.6.1` or
+    /// `v1.1`).
+    pub versions: Vec<String>,
+    /// Unstable features supported by the homeserver, and whether they're
+    /// enabled.
+    #[serde(default)]
+    pub unstable_features: HashMap<String, bool>
+}
+impl VersionsReply {
+    /// Whether the homeserver supports the given version of the
+    /// client-server API.
+    pub fn supports_version(&self, version: &str) -> bool {
+        self.versions.iter().any(|v| v == version)
+    }
+    /// Whether the homeserver has the given unstable feature enabled.
+    pub fn feature_enabled(&self, feature: &str) -> bool {
+        self.unstable_features.get(feature).cloned().unwrap_or(false)
+    }
+}
+/// A capability that's either enabled or not.
+#[derive(Deserialize, Clone, Debug)]
+pub struct BooleanCapability {
+    /// Whether the capability is enabled.
+    pub enabled: bool
+}
+/// The `m.room_versions` capability: which room versions the homeserver
+/// supports.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RoomVersionsCapability {
+    /// The version that new rooms are made with, by default.
+    pub default: String,
+    /// The supported versions, and whether each is `stable` or `unstable`.
+    pub available: HashMap<String, String>
+}
+impl RoomVersionsCapability {
+    /// Whether the homeserver considers a room version stable.
+    pub fn is_stable(&self, version: &str) -> bool {
+        self.available.get(version).map(|s| s == "stable").unwrap_or(false)
+    }
+}
+/// What the homeserver lets the current user do.
+///
+/// Capabilities that the homeserver doesn't mention are `None`; the
+/// `can_*()` methods fill in the defaults from the spec for those.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Capabilities {
+    /// Whether the user can change their password.
+    #[serde(rename = "m.change_password", default)]
+    pub change_password: Option<BooleanCapability>,
+    /// The room versions the homeserver supports.
+    #[serde(rename = "m.room_versions", default)]
+    pub room_versions: Option<RoomVersionsCapability>,
+    /// Whether the user can change their display name.
+    #[serde(rename = "m.set_displayname", default)]
+    pub set_displayname: Option<BooleanCapability>,
+    /// Whether the user can change their avatar.
+    #[serde(rename = "m.set_avatar_url", default)]
+    pub set_avatar_url: Option<BooleanCapability>,
+    /// Any other capabilities (like vendor-specific ones), by name.
+    #[serde(flatten)]
+    pub extra: HashMap<String, Value>
+}
+impl Capabilities {
+    /// Whether the user can change their password (which they can, unless the
+    /// homeserver says otherwise).
+    pub fn can_change_password(&self) -> bool {
+        self.change_password.as_ref().map(|c| c.enabled).unwrap_or(true)
+    }
+    /// Whether the user can change their display name (which they can,
+    /// unless the homeserver says otherwise).
+    pub fn can_set_displayname(&self) -> bool {
+        self.set_displayname.as_ref().map(|c| c.enabled).unwrap_or(true)
+    }
+    /// Whether the user can change their avatar (which they can, unless the
+    /// homeserver says otherwise).
+    pub fn can_set_avatar_url(&self) -> bool {
+        self.set_avatar_url.as_ref().map(|c| c.enabled).unwrap_or(true)
+    }
+}
+/// The reply obtained from `/capabilities`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct CapabilitiesReply {
+    /// The capabilities.
+    #[serde(default)]
+    pub capabilities: Capabilities
+}
+/// A way of completing User-Interactive Authentication: a list of stages
+/// (like `m.login.password`) to complete, in order.
+#[derive(Deserialize, Clone, Debug)]
+pub struct AuthFlow {
+    /// The types of the stages in this flow.
+    pub stages: Vec<String>
+}
+/// The reply obtained when a request needs (more) User-Interactive
+/// Authentication to complete.
+#[derive(Deserialize, Clone, Debug)]
+pub struct UiaaInfo {
+    /// The ways to complete authentication.
+    pub flows: Vec<AuthFlow>,
+    /// The stages completed so far.
+    #[serde(default)]
+    pub completed: Vec<String>,
+    /// The session ID, to be sent back in the `auth` object of the next
+    /// attempt.
+    #[serde(default)]
+    pub session: Option<String>,
+    /// Information needed to complete particular stages, keyed by stage
+    /// type (like the public key for `m.login.recaptcha`).
+    #[serde(default)]
+    pub params: HashMap<String, Value>,
+    /// The error code, if the last stage attempted failed.
+    #[serde(default)]
+    pub errcode: Option<String>,
+    /// A human-readable error message, if the last stage attempted failed.
+    #[serde(default)]
+    pub error: Option<String>
+}
+impl UiaaInfo {
+    /// Get the types of the stages that could be completed next: the first
+    /// stage not yet completed in each flow that the stages completed so far
+    /// are the start of.
+    pub fn next_stages(&self) -> Vec<&str> {
+        let mut ret: Vec<&str> = vec![];
+        for flow in self.flows.iter() {
+            if flow.stages.len() <= self.completed.len() || !flow.stages.starts_with(&self.completed) {
+                continue;
+            }
+            let next = &flow.stages[self.completed.len()] as &str;
+            if !ret.contains(&next) {
+                ret.push(next);
+            }
+        }
+        ret
+    }
+}
+/// The `auth` object for completing a stage of User-Interactive
+/// Authentication.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AuthData {
+    /// `m.login.password`: the user's password.
+    Password {
+        /// The MXID of the user.
+        user: String,
+        /// Their password.
+        password: String
+    },
+    /// `m.login.dummy`, which always succeeds, for flows that don't need any
+    /// real authentication.
+    Dummy,
+    /// `m.login.registration_token`: a token allowing registration.
+    RegistrationToken {
+        /// The token.
+        token: String
+    },
+    /// Any other stage, as the JSON object to send (including its `type`).
+    Other(Value)
+}
+impl AuthData {
+    /// Get the type of the stage this completes, like `m.login.password`.
+    pub fn stage_type(&self) -> Option<&str> {
+        match *self {
+            AuthData::Password { .. } => Some("m.login.password"),
+            AuthData::Dummy => Some("m.login.dummy"),
+            AuthData::RegistrationToken { .. } => Some("m.login.registration_token"),
+            AuthData::Other(ref v) => v["type"].as_str()
+        }
+    }
+    /// Make the `auth` object to send, for the given session.
+    pub fn to_json(&self, session: Option<&str>) -> Value {
+        let mut ret = match *self {
+            AuthData::Password { ref user, ref password } => json!({
+                "type": "m.login.password",
+                "identifier": {
+                    "type": "m.id.user",
+                    "user": user
+                },
+                "password": password
+            }),
+            AuthData::Dummy => json!({ "type": "m.login.dummy" }),
+            AuthData::RegistrationToken { ref token } => json!({
+                "type": "m.login.registration_token",
+                "token": token
+            }),
+            AuthData::Other(ref v) => v.clone()
+        };
+        if let Some(s) = session {
+            ret["session"] = json!(s);
+        }
+        ret
+    }
+}
+/// The result of a request that may need User-Interactive Authentication.
+#[derive(Clone, Debug)]
+pub enum UiaaResponse<T> {
+    /// The request succeeded.
+    Success(T),
+    /// The homeserver wants (more) authentication before it carries out the
+    /// request; resubmit it with an `auth` object.
+    AuthRequired(UiaaInfo)
+}
+impl<T> UiaaResponse<T> {
+    /// Change the reply in a `Success`, leaving an `AuthRequired` alone.
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> UiaaResponse<U> {
+        match self {
+            UiaaResponse::Success(x) => UiaaResponse::Success(f(x)),
+            UiaaResponse::AuthRequired(info) => UiaaResponse::AuthRequired(info)
+        }
+    }
+}
+/// One of a user's devices, from `/devices`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Device {
+    /// The ID of the device.
+    pub device_id: String,
+    /// The display name of the device, if it has one.
+    #[serde(default)]
+    pub display_name: Option<String>,
+    /// The IP address the device was last seen at.
+    #[serde(default)]
+    pub last_seen_ip: Option<String>,
+    /// When the device was last seen, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub last_seen_ts: Option<u64>
+}
+/// The reply obtained from `/devices`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct DevicesReply {
+    /// The current user's devices.
+    pub devices: Vec<Device>
+}
+/// The reply obtained from `/register`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct RegisterReply {
+    /// The MXID of the new user.
+    pub user_id: String,
+    /// An access token for the new user (unless `inhibit_login` was set).
+    #[serde(default)]
+    pub access_token: Option<String>,
+    /// The ID of the device logged in (unless `inhibit_login` was set).
+    #[serde(default)]
+    pub device_id: Option<String>
 }
@@ -1,6 +1,7 @@
 //! Type for Matrix rooms.
 use serde::{Serialize, Serializer, Deserialize, Deserializer};
 use std::borrow::Cow;
+use serde_json::Value;
 /// A Matrix room. This object is a thin wrapper over a room ID.
 ///
 /// This is defined in this crate (`gm-types`) in order that it can be
@@ -29,3 +30,224 @@ impl<'a> Room<'a> {
         }
     }
 }
+/// A preset for `CreateRoomParams`, which sets a room's join rules, history
+/// visibility and guest access.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomPreset {
+    /// Only invited users can join, and guests can join too.
+    PrivateChat,
+    /// Like `PrivateChat`, but invited users get the same power level as the
+    /// creator.
+    TrustedPrivateChat,
+    /// Anyone can join (guests can't, though).
+    PublicChat
+}
+/// Whether a room is listed in the homeserver's public room directory.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomVisibility {
+    /// The room is listed.
+    Public,
+    /// The room isn't listed.
+    Private
+}
+/// A state event to send when creating a room, as part of
+/// `CreateRoomParams::initial_state`.
+#[derive(Serialize, Clone, Debug)]
+pub struct InitialStateEvent {
+    /// The type of the event, like `m.room.join_rules`.
+    #[serde(rename = "type")]
+    pub event_type: String,
+    /// The state key of the event (usually empty).
+    pub state_key: String,
+    /// The content of the event.
+    pub content: Value
+}
+/// The options for creating a room with `MatrixClient::create_room()`.
+///
+/// Fields that are `None` (or empty) are left out of the request, so the
+/// homeserver uses its defaults.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct CreateRoomParams {
+    /// The name of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// The topic of the room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// The localpart of an alias to make for the room (so `foo` makes
+    /// `#foo:example.org`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_alias_name: Option<String>,
+    /// The preset to use for the room's join rules and visibility settings.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<RoomPreset>,
+    /// Users to invite to the room.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub invite: Vec<String>,
+    /// Whether the room is a direct chat with the invited users.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub is_direct: Option<bool>,
+    /// State events to send in the new room, after the ones set by the
+    /// preset.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub initial_state: Vec<InitialStateEvent>,
+    /// Whether to list the room in the public room directory.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<RoomVisibility>,
+    /// Extra keys to put in the content of the `m.room.create` event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub creation_content: Option<Value>,
+    /// Content to merge into the room's initial `m.room.power_levels` event.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub power_level_content_override: Option<Value>,
+    /// The room version to use (like `"10"`), instead of the homeserver's
+    /// default.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room_version: Option<String>
+}
+impl CreateRoomParams {
+    /// Make a `CreateRoomParams` with nothing set, so the homeserver uses its
+    /// defaults for everything.
+    ///
+    /// The other methods here set each option, so you can build up the
+    /// options like this:
+    ///
+    /// ```rust
+    /// # use gm_types::room::{CreateRoomParams, RoomPreset};
+    /// let params = CreateRoomParams::new()
+    ///     .name("Bots")
+    ///     .preset(RoomPreset::PrivateChat)
+    ///     .encrypted();
+    /// ```
+    ///
+    /// This type is `Clone`, so one set of options can be reused for creating
+    /// lots of similar rooms.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the name of the room.
+    pub fn name<T: Into<String>>(mut self, name: T) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+    /// Set the topic of the room.
+    pub fn topic<T: Into<String>>(mut self, topic: T) -> Self {
+        self.topic = Some(topic.into());
+        self
+    }
+    /// Set the localpart of the alias to make for the room.
+    pub fn room_alias_name<T: Into<String>>(mut self, alias: T) -> Self {
+        self.room_alias_name = Some(alias.into());
+        self
+    }
+    /// Set the preset to use.
+    pub fn preset(mut self, preset: RoomPreset) -> Self {
+        self.preset = Some(preset);
+        self
+    }
+    /// Add a user to invite to the room.
+    pub fn invite<T: Into<String>>(mut self, user_id: T) -> Self {
+        self.invite.push(user_id.into());
+        self
+    }
+    /// Set whether the room is a direct chat.
+    pub fn is_direct(mut self, is_direct: bool) -> Self {
+        self.is_direct = Some(is_direct);
+        self
+    }
+    /// Add a state event to send in the new room.
+    pub fn initial_state<T, U>(mut self, event_type: T, state_key: U, content: Value) -> Self
+        where T: Into<String>, U: Into<String> {
+        self.initial_state.push(InitialStateEvent {
+            event_type: event_type.into(),
+            state_key: state_key.into(),
+            content
+        });
+        self
+    }
+    /// Turn on end-to-end encryption in the new room (by sending an
+    /// `m.room.encryption` event, using the Megolm algorithm).
+    pub fn encrypted(self) -> Self {
+        self.initial_state("m.room.encryption", "", json!({
+            "algorithm": "m.megolm.v1.aes-sha2"
+        }))
+    }
+    /// Set whether to list the room in the public room directory.
+    pub fn visibility(mut self, visibility: RoomVisibility) -> Self {
+        self.visibility = Some(visibility);
+        self
+    }
+    /// Set extra keys for the content of the `m.room.create` event.
+    pub fn creation_content(mut self, content: Value) -> Self {
+        self.creation_content = Some(content);
+        self
+    }
+    /// Set content to merge into the room's initial power levels.
+    pub fn power_level_content_override(mut self, content: Value) -> Self {
+        self.power_level_content_override = Some(content);
+        self
+    }
+    /// Set the room version to use.
+    pub fn room_version<T: Into<String>>(mut self, version: T) -> Self {
+        self.room_version = Some(version.into());
+        self
+    }
+}
+/// The `filter` part of `PublicRoomsParams`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PublicRoomsFilter {
+    /// A string to search for in the rooms' names, topics and aliases.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generic_search_term: Option<String>
+}
+/// The options for listing the public room directory with
+/// `MatrixClient::public_rooms()`.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PublicRoomsParams {
+    /// The maximum number of rooms to return.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<u32>,
+    /// A pagination token from a previous reply (its `next_batch` or
+    /// `prev_batch`).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    /// A filter to apply to the rooms.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<PublicRoomsFilter>,
+    /// The server to get the directory of, instead of our homeserver.
+    ///
+    /// This goes in the query string, not the body.
+    #[serde(skip)]
+    pub server: Option<String>
+}
+impl PublicRoomsParams {
+    /// Make a `PublicRoomsParams` with nothing set, which lists the whole
+    /// directory of our homeserver.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Set the maximum number of rooms to return.
+    pub fn limit(mut self, limit: u32) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+    /// Set the pagination token to start from.
+    pub fn since<T: Into<String>>(mut self, since: T) -> Self {
+        self.since = Some(since.into());
+        self
+    }
+    /// Only return rooms matching a search term.
+    pub fn search<T: Into<String>>(mut self, term: T) -> Self {
+        self.filter = Some(PublicRoomsFilter {
+            generic_search_term: Some(term.into())
+        });
+        self
+    }
+    /// Get the directory of another server.
+    pub fn server<T: Into<String>>(mut self, server: T) -> Self {
+        self.server = Some(server.into());
+        self
+    }
+}
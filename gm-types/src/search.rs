@@ -0,0 +1,266 @@
+//! Types for server-side search, with the `/search` endpoint.
+use std::collections::HashMap;
+use events::Event;
+use filter::RoomEventFilter;
+use replies::ProfileReply;
+
+/// A field of an event's content to search in.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SearchKey {
+    /// `content.body`, the body of messages.
+    #[serde(rename = "content.body")]
+    ContentBody,
+    /// `content.name`, the name in `m.room.name` events.
+    #[serde(rename = "content.name")]
+    ContentName,
+    /// `content.topic`, the topic in `m.room.topic` events.
+    #[serde(rename = "content.topic")]
+    ContentTopic
+}
+/// The order to return search results in.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchOrder {
+    /// Most recent results first.
+    Recent,
+    /// Most relevant results first.
+    Rank
+}
+/// How many events of context to return around each search result.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SearchEventContext {
+    /// The number of events before each result to return.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before_limit: Option<u32>,
+    /// The number of events after each result to return.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after_limit: Option<u32>,
+    /// Whether to return the display names and avatars of the senders of
+    /// the events.
+    #[serde(skip_serializing_if = "::is_false")]
+    pub include_profile: bool
+}
+/// A key to group search results by.
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchGroupKey {
+    /// The room the results are in.
+    RoomId,
+    /// The sender of the results.
+    Sender
+}
+impl SearchGroupKey {
+    /// Get the string used for this key in the API, like `room_id`.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SearchGroupKey::RoomId => "room_id",
+            SearchGroupKey::Sender => "sender"
+        }
+    }
+}
+/// A grouping of search results.
+#[derive(Serialize, Clone, Debug)]
+pub struct SearchGroup {
+    /// The key to group by.
+    pub key: SearchGroupKey
+}
+/// How to group search results.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct SearchGroupings {
+    /// The keys to group by.
+    pub group_by: Vec<SearchGroup>
+}
+/// The options for searching room events with `MatrixClient::search()`.
+///
+/// Fields that are `None` (or empty) are left out of the request, so the
+/// homeserver uses its defaults. The other methods here set each option:
+///
+/// ```rust
+/// # use gm_types::search::{RoomEventsCriteria, SearchOrder};
+/// let criteria = RoomEventsCriteria::new("lunch")
+///     .order_by(SearchOrder::Recent)
+///     .event_context(2, 2, true);
+/// ```
+#[derive(Serialize, Clone, Debug)]
+pub struct RoomEventsCriteria {
+    /// The string to search for.
+    pub search_term: String,
+    /// The fields to search in. If this is `None`, all of them are searched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keys: Option<Vec<SearchKey>>,
+    /// A filter to apply to the events searched.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filter: Option<RoomEventFilter>,
+    /// The order to return results in.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub order_by: Option<SearchOrder>,
+    /// The context to return around each result.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_context: Option<SearchEventContext>,
+    /// Whether to return the current state of the rooms that results are in.
+    #[serde(skip_serializing_if = "::is_false")]
+    pub include_state: bool,
+    /// How to group the results.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub groupings: Option<SearchGroupings>
+}
+impl RoomEventsCriteria {
+    /// Search for `search_term`, with no other options set.
+    pub fn new<T: Into<String>>(search_term: T) -> Self {
+        RoomEventsCriteria {
+            search_term: search_term.into(),
+            keys: None,
+            filter: None,
+            order_by: None,
+            event_context: None,
+            include_state: false,
+            groupings: None
+        }
+    }
+    /// Only search in the given fields.
+    pub fn keys(mut self, keys: &[SearchKey]) -> Self {
+        self.keys = Some(keys.to_vec());
+        self
+    }
+    /// Set a filter to apply to the events searched.
+    pub fn filter(mut self, filter: RoomEventFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+    /// Set the order to return results in.
+    pub fn order_by(mut self, order: SearchOrder) -> Self {
+        self.order_by = Some(order);
+        self
+    }
+    /// Return `before` events before and `after` events after each result,
+    /// and (if `include_profile` is set) the profiles of their senders.
+    pub fn event_context(mut self, before: u32, after: u32, include_profile: bool) -> Self {
+        self.event_context = Some(SearchEventContext {
+            before_limit: Some(before),
+            after_limit: Some(after),
+            include_profile
+        });
+        self
+    }
+    /// Set whether to return the current state of the rooms that results are
+    /// in.
+    pub fn include_state(mut self, include_state: bool) -> Self {
+        self.include_state = include_state;
+        self
+    }
+    /// Group the results by `key`, as well as any keys already given.
+    ///
+    /// The groups are in `RoomEventsResults::groups`; see
+    /// `RoomEventsResults::group_results()`.
+    pub fn group_by(mut self, key: SearchGroupKey) -> Self {
+        let groupings = self.groupings.get_or_insert_with(Default::default);
+        if !groupings.group_by.iter().any(|g| g.key == key) {
+            groupings.group_by.push(SearchGroup { key });
+        }
+        self
+    }
+}
+/// The events around a search result.
+#[derive(Deserialize, Debug)]
+pub struct SearchResultContext {
+    /// A token for paginating backwards from the start of `events_before`.
+    #[serde(default)]
+    pub start: Option<String>,
+    /// A token for paginating forwards from the end of `events_after`.
+    #[serde(default)]
+    pub end: Option<String>,
+    /// The profiles of the senders of the events, by MXID (if
+    /// `include_profile` was set).
+    #[serde(default)]
+    pub profile_info: HashMap<String, ProfileReply>,
+    /// Events from just before the result, most recent first.
+    #[serde(default)]
+    pub events_before: Vec<Event>,
+    /// Events from just after the result, oldest first.
+    #[serde(default)]
+    pub events_after: Vec<Event>
+}
+/// A single search result.
+#[derive(Deserialize, Debug)]
+pub struct SearchResult {
+    /// How well the result matched the search term.
+    #[serde(default)]
+    pub rank: Option<f64>,
+    /// The event that matched.
+    pub result: Event,
+    /// The events around the result, if `event_context` was set.
+    #[serde(default)]
+    pub context: Option<SearchResultContext>
+}
+/// A group of search results.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SearchResultGroup {
+    /// A token for getting more results in this group.
+    #[serde(default)]
+    pub next_batch: Option<String>,
+    /// Where this group comes in the order of groups.
+    #[serde(default)]
+    pub order: Option<u64>,
+    /// The IDs of the events in this group.
+    #[serde(default)]
+    pub results: Vec<String>
+}
+/// The results of searching room events.
+#[derive(Deserialize, Debug, Default)]
+pub struct RoomEventsResults {
+    /// An estimate of the total number of results.
+    #[serde(default)]
+    pub count: Option<u64>,
+    /// Words to highlight in the results, derived from the search term.
+    #[serde(default)]
+    pub highlights: Vec<String>,
+    /// A page of results.
+    #[serde(default)]
+    pub results: Vec<SearchResult>,
+    /// The current state of the rooms that results are in, by room ID (if
+    /// `include_state` was set).
+    #[serde(default)]
+    pub state: HashMap<String, Vec<Event>>,
+    /// The results, grouped by each of the grouping keys, then by the value
+    /// of that key.
+    #[serde(default)]
+    pub groups: HashMap<String, HashMap<String, SearchResultGroup>>,
+    /// A token for getting the next page of results. If this is `None`, there
+    /// are no more.
+    #[serde(default)]
+    pub next_batch: Option<String>
+}
+impl RoomEventsResults {
+    /// Get the groups made by grouping by `key`, by the value of the key
+    /// (like a room ID).
+    pub fn groups_by(&self, key: SearchGroupKey) -> Option<&HashMap<String, SearchResultGroup>> {
+        self.groups.get(key.as_str())
+    }
+    /// Get the results on this page in the group with the given value of
+    /// `key`, in the order the homeserver gave them.
+    ///
+    /// The groups cover all results, so those from other pages aren't
+    /// returned.
+    pub fn group_results(&self, key: SearchGroupKey, value: &str) -> Vec<&SearchResult> {
+        let group = match self.groups_by(key).and_then(|g| g.get(value)) {
+            Some(g) => g,
+            None => return vec![]
+        };
+        group.results.iter()
+            .filter_map(|id| self.results.iter().find(|r| r.result.event_id() == Some(id as &str)))
+            .collect()
+    }
+}
+/// The categories of results in a `SearchReply`.
+#[derive(Deserialize, Debug, Default)]
+pub struct SearchCategories {
+    /// The results of searching room events.
+    #[serde(default)]
+    pub room_events: RoomEventsResults
+}
+/// The reply obtained from `/search`.
+#[derive(Deserialize, Debug)]
+pub struct SearchReply {
+    /// The results, by category.
+    pub search_categories: SearchCategories
+}
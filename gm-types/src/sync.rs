@@ -2,25 +2,29 @@
 use std::collections::HashMap;
 use room::Room;
 use events::{Event, Events};
+use content::{root, Content};
 use std::slice;
 
 /// Counts of unread notifications for a room.
 #[derive(Deserialize, Debug, Default)]
 pub struct UnreadNotificationCounts {
     /// The number of unread notifications for this room with the highlight flag set.
+    #[serde(default)]
     pub highlight_count: u32,
     /// The total number of unread notifications for this room.
+    #[serde(default)]
     pub notification_count: u32
 }
 /// A timeline of messages and state changes in a room.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct Timeline {
     /// List of events.
     #[serde(default)]
     pub events: Vec<Event>,
     /// A token that can be supplied as the `from` parameter of the
-    /// `/rooms/{roomId}/messages` endpoint.
-    pub prev_batch: String,
+    /// `/rooms/{roomId}/messages` endpoint, to get earlier events.
+    #[serde(default)]
+    pub prev_batch: Option<String>,
     /// True if the number of events returned was limited by the `limit` on the
     /// filter.
     #[serde(default)]
@@ -35,6 +39,7 @@ pub struct JoinedRoom {
     #[serde(default)]
     pub state: Events,
     /// The timeline of messages and state changes in the room.
+    #[serde(default)]
     pub timeline: Timeline,
     /// The ephemeral events in the room that aren't recorded in the timeline or
     /// state of the room. e.g. typing.
@@ -47,6 +52,22 @@ pub struct JoinedRoom {
     #[serde(default)]
     pub unread_notifications: UnreadNotificationCounts
 }
+impl JoinedRoom {
+    /// Get the users currently typing in this room, from the last `m.typing`
+    /// event in `ephemeral`.
+    ///
+    /// This is `None` if there wasn't one, meaning that who's typing hasn't
+    /// changed since the last sync.
+    pub fn typing_users(&self) -> Option<&[String]> {
+        self.ephemeral.events.iter().rev()
+            .filter_map(|ev| match *ev {
+                Event::Minimal(_, Content::RootTyping(ref t)) |
+                Event::Full(_, Content::RootTyping(ref t)) => Some(&t.user_ids as &[String]),
+                _ => None
+            })
+            .next()
+    }
+}
 /// Information about a room the user has left, or been banned from.
 #[derive(Deserialize, Debug)]
 pub struct LeftRoom {
@@ -55,6 +76,7 @@ pub struct LeftRoom {
     pub state: Events,
     /// The timeline of messages and state changes in the room up to the point
     /// when the user left.
+    #[serde(default)]
     pub timeline: Timeline
 }
 /// Information about a room the user has been invited to.
@@ -73,7 +95,7 @@ pub struct InvitedRoom {
     pub invite_state: Events
 }
 /// The `rooms` component of a `SyncReply`.
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Default)]
 pub struct SyncRooms {
     /// The rooms that the user has joined.
     #[serde(default)]
@@ -92,15 +114,50 @@ pub struct SyncReply {
     /// request.
     pub next_batch: String,
     /// Updates to rooms.
+    #[serde(default)]
     pub rooms: SyncRooms,
     /// The global private data created by this user.
     #[serde(default)]
     pub account_data: Events,
     /// The updates to the presence status of other users.
     #[serde(default)]
-    pub presence: Events
+    pub presence: Events,
+    /// The batch token this reply was synced from, or `None` if it's the
+    /// reply to an initial sync.
+    ///
+    /// This isn't sent by the homeserver; it's filled in by the client.
+    #[serde(skip)]
+    pub since: Option<String>,
+    /// Whether the full state of every room was asked for, even though this
+    /// was synced from a batch token.
+    ///
+    /// This isn't sent by the homeserver; it's filled in by the client.
+    #[serde(skip)]
+    pub full_state: bool
 }
 impl SyncReply {
+    /// Ascertain whether this reply has the full state of every room (like
+    /// the reply to an initial sync), rather than only what's changed since
+    /// the previous one.
+    pub fn is_initial(&self) -> bool {
+        self.since.is_none() || self.full_state
+    }
+    /// Get the presence updates in this reply, as pairs of a user ID and
+    /// their new presence.
+    pub fn presence_updates(&self) -> impl Iterator<Item=(&str, &root::Presence)> {
+        self.presence.events.iter()
+            .filter_map(|ev| {
+                let (sender, p) = match *ev {
+                    Event::Minimal(ref m, Content::RootPresence(ref p)) => (m.sender.as_ref().map(|x| x as &str), p),
+                    Event::Full(ref m, Content::RootPresence(ref p)) => (Some(&m.sender as &str), p),
+                    _ => return None
+                };
+                if !p.user_id.is_empty() {
+                    return Some((&p.user_id as &str, p));
+                }
+                sender.map(|s| (s, p))
+            })
+    }
     pub fn iter_events(&self) -> SyncEventIter {
         let mut rooms = vec![];
         for (id, room) in self.rooms.join.iter() {